@@ -1,4 +1,5 @@
 use anyhow::{Result, anyhow};
+use std::io::Read;
 use std::path::Path;
 
 #[derive(Clone, Debug)]
@@ -27,20 +28,37 @@ impl AudioData {
     fn load_wav(path: &Path) -> Result<Self> {
         let reader = hound::WavReader::open(path)
             .map_err(|e| anyhow!("Failed to open WAV file: {}", e))?;
+        Self::decode_wav(reader)
+    }
+
+    /// Decode WAV data from any reader (an upload body, an in-memory buffer, ...)
+    pub fn from_wav_reader<R: Read>(reader: R) -> Result<Self> {
+        let reader = hound::WavReader::new(reader)
+            .map_err(|e| anyhow!("Failed to read WAV data: {}", e))?;
+        Self::decode_wav(reader)
+    }
 
+    fn decode_wav<R: Read>(reader: hound::WavReader<R>) -> Result<Self> {
         let spec = reader.spec();
         
         // Convert samples to f32
-        let samples: Result<Vec<f32>> = reader
-            .into_samples::<i32>()
-            .map(|s| {
-                s.map(|sample| {
-                    // Normalize 32-bit integer to float (-1.0 to 1.0)
-                    sample as f32 / (i32::MAX as f32)
-                })
-                .map_err(|e| anyhow!("Failed to read WAV sample: {}", e))
-            })
-            .collect();
+        let samples: Result<Vec<f32>> = match spec.sample_format {
+            hound::SampleFormat::Float => reader
+                .into_samples::<f32>()
+                .map(|s| s.map_err(|e| anyhow!("Failed to read WAV sample: {}", e)))
+                .collect(),
+            hound::SampleFormat::Int => {
+                // Normalize by the file's bit depth to float (-1.0 to 1.0)
+                let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .into_samples::<i32>()
+                    .map(|s| {
+                        s.map(|sample| sample as f32 / scale)
+                            .map_err(|e| anyhow!("Failed to read WAV sample: {}", e))
+                    })
+                    .collect()
+            }
+        };
 
         Ok(AudioData {
             samples: samples?,
@@ -54,6 +72,28 @@ impl AudioData {
         Err(anyhow!("OGG support not yet implemented"))
     }
 
+    /// Save as a 16-bit WAV file, clipping samples to -1.0..1.0
+    pub fn save_wav(&self, path: &Path) -> Result<()> {
+        let spec = hound::WavSpec {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec)
+            .map_err(|e| anyhow!("Failed to create WAV file: {}", e))?;
+
+        for &sample in &self.samples {
+            writer
+                .write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+                .map_err(|e| anyhow!("Failed to write WAV sample: {}", e))?;
+        }
+        writer
+            .finalize()
+            .map_err(|e| anyhow!("Failed to finalize WAV file: {}", e))?;
+        Ok(())
+    }
+
     /// Convert multi-channel audio to mono by averaging channels
     pub fn to_mono(&self) -> Result<Vec<f32>> {
         if self.channels == 1 {
@@ -95,4 +135,23 @@ mod tests {
         };
         assert_eq!(audio.duration(), 0.5);
     }
+
+    #[test]
+    fn test_wav_round_trip_keeps_level() {
+        let audio = AudioData {
+            samples: vec![0.0, 0.5, -0.5, 0.25],
+            sample_rate: 8000,
+            channels: 2,
+        };
+        let path = std::env::temp_dir().join(format!("rhythm-pi-audio-{}.wav", std::process::id()));
+        audio.save_wav(&path).unwrap();
+        let loaded = AudioData::load(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(loaded.channels, 2);
+        assert_eq!(loaded.sample_rate, 8000);
+        for (a, b) in audio.samples.iter().zip(&loaded.samples) {
+            assert!((a - b).abs() < 0.001, "{} loaded as {}", a, b);
+        }
+    }
 }
@@ -0,0 +1,119 @@
+use crate::audio::AudioData;
+use crate::exporter::{ChartExport, ChartFormat};
+use crate::song_settings::SongSettings;
+use crate::{Charter, CharterConfig};
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+
+/// Instruments charted for every song
+pub const INSTRUMENTS: [&str; 4] = ["vocals", "bass", "drums", "lead"];
+
+/// Audio file for a song (`{id}.wav` in the songs directory)
+pub fn find_audio(songs_dir: &Path, song_id: &str) -> Option<PathBuf> {
+    let path = songs_dir.join(format!("{}.wav", song_id));
+    path.exists().then_some(path)
+}
+
+/// Ids of every song with chartable audio in a directory, sorted
+pub fn song_ids(songs_dir: &Path) -> Result<Vec<String>> {
+    let mut ids: Vec<String> = std::fs::read_dir(songs_dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().and_then(|e| e.to_str()) == Some("wav"))
+        .filter_map(|p| p.file_stem().and_then(|s| s.to_str()).map(|s| s.to_string()))
+        .collect();
+    ids.sort();
+    Ok(ids)
+}
+
+/// Config for one song: `base` with the `charter` block of `{id}.json` applied on top
+pub fn song_config(songs_dir: &Path, song_id: &str, base: &CharterConfig) -> Result<CharterConfig> {
+    let settings = SongSettings::load(&songs_dir.join(format!("{}.json", song_id)))?;
+    let mut config = base.clone();
+    settings.apply(&mut config);
+    Ok(config)
+}
+
+/// Generate every difficulty of the given instruments for one song
+pub fn generate_song(
+    songs_dir: &Path,
+    song_id: &str,
+    base: &CharterConfig,
+    instruments: &[&str],
+) -> Result<Vec<ChartExport>> {
+    let audio_path = find_audio(songs_dir, song_id)
+        .ok_or_else(|| anyhow!("No audio for {} in {}", song_id, songs_dir.display()))?;
+    let charter = Charter::new(song_config(songs_dir, song_id, base)?);
+    let audio = AudioData::load(&audio_path)?;
+
+    let mut charts = Vec::new();
+    for instrument in instruments {
+        log::info!("Generating {} charts for {}", instrument, song_id);
+        charts.extend(charter.generate_from_audio(&audio, song_id, instrument)?);
+    }
+    Ok(charts)
+}
+
+/// Generate and save charts for one song, returning the written paths. Charts already in
+/// `out_dir` are kept unless `overwrite`.
+pub fn write_song_charts(
+    songs_dir: &Path,
+    song_id: &str,
+    out_dir: &Path,
+    base: &CharterConfig,
+    instruments: &[&str],
+    format: ChartFormat,
+    overwrite: bool,
+) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(out_dir)?;
+
+    let mut written = Vec::new();
+    for chart in generate_song(songs_dir, song_id, base, instruments)? {
+        let path = out_dir.join(chart.file_name(format));
+        if !overwrite && path.exists() {
+            log::info!("Keeping existing {}", path.display());
+            continue;
+        }
+        chart.save(&path, format)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rhythm-pi-batch-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_song_ids_and_config() {
+        let dir = temp_dir("ids");
+        let silence = AudioData {
+            samples: vec![0.0; 800],
+            sample_rate: 8000,
+            channels: 1,
+        };
+        silence.save_wav(&dir.join("b_song.wav")).unwrap();
+        silence.save_wav(&dir.join("a_song.wav")).unwrap();
+        std::fs::write(
+            dir.join("a_song.json"),
+            r#"{ "SongTitle": "A", "charter": { "bpm": 90.0, "exclude": [[0.0, 1.0]] } }"#,
+        )
+        .unwrap();
+
+        let ids = song_ids(&dir).unwrap();
+        let with_settings = song_config(&dir, "a_song", &CharterConfig::default()).unwrap();
+        let without = song_config(&dir, "b_song", &CharterConfig::default()).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(ids, vec!["a_song", "b_song"]);
+        assert_eq!(with_settings.bpm, Some(90.0));
+        assert_eq!(with_settings.exclude, vec![(0.0, 1.0)]);
+        assert_eq!(without.bpm, None);
+    }
+}
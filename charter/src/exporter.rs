@@ -1,4 +1,5 @@
 use crate::beat_detection::Note;
+use crate::stats::ChartStats;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::path::Path;
@@ -10,7 +11,10 @@ pub struct ChartExport {
     pub difficulty: String,
     pub columns: u8,
     pub bpm: f32,
-    pub generated_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generated_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<ChartStats>,
     pub notes: Vec<NoteExport>,
 }
 
@@ -18,7 +22,7 @@ pub struct ChartExport {
 pub struct NoteExport {
     pub time: f32,
     pub col: u8,
-    #[serde(skip_serializing_if = "is_zero")]
+    #[serde(default, skip_serializing_if = "is_zero")]
     pub duration: f32,
 }
 
@@ -35,10 +39,7 @@ impl ChartExport {
         bpm: f32,
         notes: Vec<Note>,
     ) -> Self {
-        let generated_at = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .map(|d| d.as_secs() as i64)
-            .unwrap_or(0);
+        let generated_at = GeneratedAt::Now.resolve();
 
         let notes = notes
             .into_iter()
@@ -49,15 +50,29 @@ impl ChartExport {
             })
             .collect();
 
-        ChartExport {
+        let mut chart = ChartExport {
             song_id,
             instrument,
             difficulty,
             columns,
             bpm,
             generated_at,
+            stats: None,
             notes,
-        }
+        };
+        chart.refresh_stats();
+        chart
+    }
+
+    /// Replace the `generated_at` stamp (e.g. fixed or omitted for reproducible output)
+    pub fn with_generated_at(mut self, generated_at: GeneratedAt) -> Self {
+        self.generated_at = generated_at.resolve();
+        self
+    }
+
+    /// Recompute the `stats` block after the notes have changed
+    pub fn refresh_stats(&mut self) {
+        self.stats = Some(ChartStats::compute(self));
     }
 
     /// Export to JSON format
@@ -80,6 +95,9 @@ impl ChartExport {
         output.push_str(&format!("  Difficulty = {}\n", self.difficulty));
         output.push_str(&format!("  Columns = {}\n", self.columns));
         output.push_str(&format!("  Notes = {}\n", self.notes.len()));
+        if let Some(stats) = &self.stats {
+            output.push_str(&format!("  Rating = {:.2}\n", stats.rating));
+        }
         output.push_str(":\n");
 
         for note in &self.notes {
@@ -103,6 +121,91 @@ impl ChartExport {
         output
     }
 
+    /// Load a chart saved as JSON or `.chart` (picked by file extension)
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        match ChartFormat::from_path(path) {
+            Some(ChartFormat::Chart) => Self::from_chart(&content),
+            _ => Ok(serde_json::from_str(&content)?),
+        }
+    }
+
+    /// Parse the text format written by `to_chart`
+    pub fn from_chart(text: &str) -> Result<Self> {
+        let mut song_id = String::new();
+        let mut instrument = String::new();
+        let mut difficulty = String::new();
+        let mut columns = 4;
+        let mut bpm = 120.0;
+        let mut notes: Vec<NoteExport> = Vec::new();
+        let mut in_notes = false;
+        let mut pending_hold = false;
+
+        for (line_no, raw) in text.lines().enumerate() {
+            let line = raw.trim();
+            if line.is_empty() || line.starts_with('[') {
+                continue;
+            }
+            if line == ":" {
+                in_notes = true;
+                continue;
+            }
+            if line == ";" {
+                break;
+            }
+
+            if in_notes {
+                let parts: Vec<&str> = line.split('|').collect();
+                if parts.len() != 3 {
+                    anyhow::bail!("line {}: expected type|col|time, got {:?}", line_no + 1, line);
+                }
+                let col: u8 = parts[1].trim().parse()?;
+                let value: f32 = parts[2].trim().parse()?;
+
+                // A hold is written as `2|col|time` followed by `2|col|duration`
+                match (parts[0].trim(), pending_hold) {
+                    ("2", true) => {
+                        if let Some(last) = notes.last_mut() {
+                            last.duration = value;
+                        }
+                        pending_hold = false;
+                    }
+                    (kind @ ("1" | "2"), false) => {
+                        notes.push(NoteExport { time: value, col, duration: 0.0 });
+                        pending_hold = kind == "2";
+                    }
+                    (kind, _) => anyhow::bail!("line {}: unexpected note type {}", line_no + 1, kind),
+                }
+                continue;
+            }
+
+            if let Some((key, value)) = line.split_once('=') {
+                let value = value.trim().trim_matches('"');
+                match key.trim() {
+                    "Title" => song_id = value.to_string(),
+                    "BPM" => bpm = value.parse()?,
+                    "Instrument" => instrument = value.to_string(),
+                    "Difficulty" => difficulty = value.to_string(),
+                    "Columns" => columns = value.parse()?,
+                    _ => {}
+                }
+            }
+        }
+
+        let mut chart = ChartExport {
+            song_id,
+            instrument,
+            difficulty,
+            columns,
+            bpm,
+            generated_at: None,
+            stats: None,
+            notes,
+        };
+        chart.refresh_stats();
+        Ok(chart)
+    }
+
     /// Save chart to file
     pub fn save(&self, path: &Path, format: ChartFormat) -> Result<()> {
         let content = match format {
@@ -113,6 +216,53 @@ impl ChartExport {
         std::fs::write(path, content)?;
         Ok(())
     }
+
+    /// Standard file name: `{song_id}_{instrument}_{difficulty}.{ext}`
+    pub fn file_name(&self, format: ChartFormat) -> String {
+        format!(
+            "{}_{}_{}.{}",
+            self.song_id,
+            self.instrument.to_lowercase(),
+            self.difficulty.to_lowercase(),
+            format.extension()
+        )
+    }
+}
+
+/// How the `generated_at` field of an exported chart is filled in
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum GeneratedAt {
+    /// Current system time (not reproducible)
+    #[default]
+    Now,
+    /// Fixed unix timestamp in seconds
+    Fixed(i64),
+    /// Leave the field out of the export
+    Omit,
+}
+
+impl GeneratedAt {
+    /// Parse `now`, `omit`/`none` or a unix timestamp
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "now" => Some(GeneratedAt::Now),
+            "omit" | "none" => Some(GeneratedAt::Omit),
+            other => other.parse().ok().map(GeneratedAt::Fixed),
+        }
+    }
+
+    pub fn resolve(&self) -> Option<i64> {
+        match self {
+            GeneratedAt::Now => Some(
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_secs() as i64)
+                    .unwrap_or(0),
+            ),
+            GeneratedAt::Fixed(ts) => Some(*ts),
+            GeneratedAt::Omit => None,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -130,6 +280,13 @@ impl ChartFormat {
         }
     }
 
+    /// Format implied by a file's extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()
+            .and_then(|e| e.to_str())
+            .and_then(Self::from_str)
+    }
+
     pub fn extension(&self) -> &'static str {
         match self {
             ChartFormat::Json => "json",
@@ -162,6 +319,7 @@ mod tests {
         let json = chart.to_json().unwrap();
         assert!(json.contains("\"time\": 0.5"));
         assert!(json.contains("\"col\": 2"));
+        assert!(json.contains("\"stats\""));
     }
 
     #[test]
@@ -187,6 +345,78 @@ mod tests {
         assert!(chart_text.contains("Columns = 4"));
     }
 
+    #[test]
+    fn test_chart_json_round_trip() {
+        let notes = vec![
+            Note { time: 0.5, col: 1, duration: 0.0 },
+            Note { time: 1.0, col: 2, duration: 0.5 },
+        ];
+        let chart = ChartExport::new(
+            "test_song".to_string(),
+            "vocals".to_string(),
+            "Easy".to_string(),
+            4,
+            120.0,
+            notes,
+        );
+
+        let loaded: ChartExport = serde_json::from_str(&chart.to_json().unwrap()).unwrap();
+        assert_eq!(loaded.notes.len(), 2);
+        assert_eq!(loaded.notes[0].duration, 0.0);
+        assert_eq!(loaded.stats, chart.stats);
+    }
+
+    #[test]
+    fn test_generated_at_modes() {
+        let chart = ChartExport::new(
+            "test_song".to_string(),
+            "vocals".to_string(),
+            "Easy".to_string(),
+            4,
+            120.0,
+            Vec::new(),
+        );
+        assert!(chart.generated_at.is_some());
+
+        let fixed = chart.clone().with_generated_at(GeneratedAt::Fixed(1_700_000_000));
+        assert_eq!(fixed.generated_at, Some(1_700_000_000));
+
+        let omitted = chart.with_generated_at(GeneratedAt::Omit);
+        assert!(!omitted.to_json().unwrap().contains("generated_at"));
+
+        assert_eq!(GeneratedAt::parse("none"), Some(GeneratedAt::Omit));
+        assert_eq!(GeneratedAt::parse("42"), Some(GeneratedAt::Fixed(42)));
+        assert!(GeneratedAt::parse("yesterday").is_none());
+    }
+
+    #[test]
+    fn test_chart_text_round_trip() {
+        let notes = vec![
+            Note { time: 0.5, col: 1, duration: 0.0 },
+            Note { time: 1.0, col: 2, duration: 0.5 },
+            Note { time: 1.25, col: 3, duration: 0.0 },
+        ];
+        let chart = ChartExport::new(
+            "test_song".to_string(),
+            "bass".to_string(),
+            "Hard".to_string(),
+            4,
+            128.0,
+            notes,
+        );
+
+        let parsed = ChartExport::from_chart(&chart.to_chart()).unwrap();
+        assert_eq!(parsed.song_id, "test_song");
+        assert_eq!(parsed.instrument, "bass");
+        assert_eq!(parsed.difficulty, "Hard");
+        assert_eq!(parsed.columns, 4);
+        assert_eq!(parsed.bpm, 128.0);
+        assert_eq!(parsed.notes.len(), 3);
+        assert_eq!(parsed.notes[1].col, 2);
+        assert!((parsed.notes[1].duration - 0.5).abs() < 0.001);
+        assert_eq!(parsed.notes[2].duration, 0.0);
+    }
+
     #[test]
     fn test_chart_format_detection() {
         assert_eq!(ChartFormat::from_str("json").unwrap().extension(), "json");
@@ -1,4 +1,11 @@
 use crate::beat_detection::Note;
+use rustfft::{FftPlanner, num_complex::Complex};
+
+/// Width of one spectrum bin in the frequency data passed to `detect_holds`
+pub const BIN_HZ: f32 = 100.0;
+
+/// How long after a note's (quantized) time the sustain may take to start
+const ONSET_SLACK: f32 = 0.1;
 
 #[derive(Clone, Debug)]
 pub struct HoldDetector {
@@ -14,6 +21,55 @@ impl HoldDetector {
         }
     }
 
+    /// Build the frequency data used by `detect_holds`: one `(time, spectrum)` pair per
+    /// frame, with `BIN_HZ`-wide bins normalized so the loudest frame sums to 1.0
+    pub fn spectrum_frames(samples: &[f32], sample_rate: u32) -> Vec<(f32, Vec<f32>)> {
+        let fft_size = 2048;
+        let hop_size = 512;
+        if samples.len() < fft_size || sample_rate == 0 {
+            return Vec::new();
+        }
+
+        let mut planner = FftPlanner::new();
+        let fft = planner.plan_fft_forward(fft_size);
+        let freq_resolution = sample_rate as f32 / fft_size as f32;
+        let num_bins = ((sample_rate as f32 / 2.0) / BIN_HZ) as usize + 1;
+
+        let mut frames = Vec::new();
+        let mut loudest = 0.0f32;
+        let mut start = 0;
+
+        while start + fft_size <= samples.len() {
+            let mut buffer: Vec<Complex<f32>> = samples[start..start + fft_size]
+                .iter()
+                .enumerate()
+                .map(|(idx, &sample)| {
+                    let window = 0.5 * (1.0 - ((2.0 * std::f32::consts::PI * idx as f32) / (fft_size as f32 - 1.0)).cos());
+                    Complex::new(sample * window, 0.0)
+                })
+                .collect();
+            fft.process(&mut buffer);
+
+            let mut spectrum = vec![0.0f32; num_bins];
+            for (bin, coeff) in buffer.iter().take(fft_size / 2).enumerate() {
+                let idx = ((bin as f32 * freq_resolution) / BIN_HZ) as usize;
+                spectrum[idx.min(num_bins - 1)] += coeff.norm();
+            }
+
+            loudest = loudest.max(spectrum.iter().sum());
+            frames.push((start as f32 / sample_rate as f32, spectrum));
+            start += hop_size;
+        }
+
+        if loudest > 0.0 {
+            for (_, spectrum) in &mut frames {
+                spectrum.iter_mut().for_each(|v| *v /= loudest);
+            }
+        }
+
+        frames
+    }
+
     /// Detect holds by analyzing sustained energy in specific frequency bands
     pub fn detect_holds(
         &self,
@@ -53,7 +109,30 @@ impl HoldDetector {
             }
         }
 
-        notes_with_holds
+        self.trim_overlapping_holds(notes_with_holds)
+    }
+
+    /// End each hold before the next note in the same lane; drop holds that get too short
+    fn trim_overlapping_holds(&self, mut notes: Vec<Note>) -> Vec<Note> {
+        for i in 0..notes.len() {
+            if notes[i].duration <= 0.0 {
+                continue;
+            }
+
+            let next_in_lane = notes[i + 1..]
+                .iter()
+                .filter(|n| n.col == notes[i].col && n.time > notes[i].time)
+                .map(|n| n.time)
+                .fold(f32::INFINITY, f32::min);
+
+            let note = &mut notes[i];
+            note.duration = note.duration.min(next_in_lane - note.time - 0.05);
+            if note.duration < self.min_hold_duration {
+                note.duration = 0.0;
+            }
+        }
+
+        notes
     }
 
     /// Find how long energy is sustained in a frequency range after a start time
@@ -67,8 +146,11 @@ impl HoldDetector {
         let mut times: Vec<u32> = spectrum_map.keys().cloned().collect();
         times.sort();
 
-        let start_idx = times.binary_search(&start_time_ms).unwrap_or_default();
+        // First frame at or after the note
+        let start_idx = times.binary_search(&start_time_ms).unwrap_or_else(|idx| idx);
+        let slack_ms = start_time_ms + (ONSET_SLACK * 1000.0) as u32;
         let mut hold_duration = 0.0;
+        let mut sustaining = false;
 
         // Check for sustained energy in subsequent frames
         for &time_ms in &times[start_idx..] {
@@ -76,9 +158,10 @@ impl HoldDetector {
                 let energy = self.get_band_energy(spectrum, freq_low, freq_high);
                 
                 if energy >= self.sustain_threshold {
+                    sustaining = true;
                     hold_duration = (time_ms as f32 - start_time_ms as f32) / 1000.0;
-                } else {
-                    // Energy dropped below threshold, hold ends
+                } else if sustaining || time_ms > slack_ms {
+                    // Energy dropped below threshold (or never rose), hold ends
                     break;
                 }
             }
@@ -89,8 +172,12 @@ impl HoldDetector {
 
     /// Calculate energy in a frequency band
     fn get_band_energy(&self, spectrum: &[f32], freq_low: f32, freq_high: f32) -> f32 {
-        let low_bin = (freq_low / 100.0) as usize;
-        let high_bin = (freq_high / 100.0) as usize;
+        if spectrum.is_empty() {
+            return 0.0;
+        }
+
+        let low_bin = (freq_low / BIN_HZ) as usize;
+        let high_bin = (freq_high / BIN_HZ) as usize;
 
         spectrum
             .get(low_bin..=high_bin.min(spectrum.len() - 1))
@@ -140,6 +227,66 @@ mod tests {
         assert!(energy > 0.0);
     }
 
+    #[test]
+    fn test_detect_holds_on_sustained_band() {
+        let detector = HoldDetector::new(0.5, 0.25);
+        // 1.0s of energy in the 300-600 Hz bins starting at 0.5s, silence elsewhere
+        let frames: Vec<(f32, Vec<f32>)> = (0..300)
+            .map(|i| {
+                let time = i as f32 * 0.01;
+                let level = if (0.5..1.5).contains(&time) { 0.3 } else { 0.0 };
+                (time, vec![0.0, 0.0, 0.0, level, level, level, 0.0])
+            })
+            .collect();
+        let ranges = [(0, 300.0, 600.0), (1, 300.0, 600.0)];
+        let notes = vec![
+            Note { time: 0.48, col: 0, duration: 0.0 }, // slightly before the sustain starts
+            Note { time: 2.0, col: 1, duration: 0.0 },  // after the sustain ended
+        ];
+
+        let held = detector.detect_holds(notes, &frames, &ranges);
+        assert!((held[0].duration - 1.0).abs() < 0.05);
+        assert_eq!(held[1].duration, 0.0);
+    }
+
+    #[test]
+    fn test_holds_end_before_next_note_in_lane() {
+        let detector = HoldDetector::new(0.5, 0.25);
+        let frames: Vec<(f32, Vec<f32>)> = (0..300)
+            .map(|i| (i as f32 * 0.01, vec![1.0; 5]))
+            .collect();
+        let ranges = [(0, 0.0, 400.0), (1, 0.0, 400.0)];
+        let notes = vec![
+            Note { time: 0.0, col: 0, duration: 0.0 },
+            Note { time: 0.2, col: 1, duration: 0.0 },
+            Note { time: 1.0, col: 0, duration: 0.0 },
+        ];
+
+        let held = detector.detect_holds(notes, &frames, &ranges);
+        assert!((held[0].duration - 0.95).abs() < 0.001);
+        assert!(held[1].duration > 2.0); // nothing follows in lane 1
+    }
+
+    #[test]
+    fn test_spectrum_frames_peak_in_tone_bin() {
+        let sample_rate = 44100;
+        let samples: Vec<f32> = (0..sample_rate)
+            .map(|i| (2.0 * std::f32::consts::PI * 440.0 * i as f32 / sample_rate as f32).sin())
+            .collect();
+
+        let frames = HoldDetector::spectrum_frames(&samples, sample_rate);
+        assert!(!frames.is_empty());
+        let (_, spectrum) = &frames[frames.len() / 2];
+        let loudest_bin = spectrum
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.partial_cmp(b.1).unwrap())
+            .map(|(idx, _)| idx)
+            .unwrap();
+        assert_eq!(loudest_bin, 4); // 400-500 Hz
+        assert!(spectrum.iter().sum::<f32>() <= 1.0 + 1e-3);
+    }
+
     #[test]
     fn test_merge_nearby_notes() {
         let detector = HoldDetector::new(0.5, 0.25);
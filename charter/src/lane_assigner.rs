@@ -14,6 +14,23 @@ pub enum LaneAssignmentStrategy {
 pub struct LaneAssigner {
     pub strategy: LaneAssignmentStrategy,
     pub num_lanes: u8, // 4 for Easy/Normal/Hard, 5 for Expert
+    pub seed: u64,     // seed for the Random strategy
+}
+
+impl LaneAssignmentStrategy {
+    /// Parse a strategy name (sequential, frequency, random)
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "sequential" => Some(LaneAssignmentStrategy::Sequential),
+            "frequency" => Some(LaneAssignmentStrategy::FrequencyBased {
+                low_hz: 100.0,
+                mid_hz: 500.0,
+                high_hz: 2000.0,
+            }),
+            "random" => Some(LaneAssignmentStrategy::Random),
+            _ => None,
+        }
+    }
 }
 
 impl LaneAssigner {
@@ -21,9 +38,16 @@ impl LaneAssigner {
         LaneAssigner {
             strategy,
             num_lanes,
+            seed: 0,
         }
     }
 
+    /// Use a specific seed for the Random strategy
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
     /// Assign lanes to detected notes based on the strategy
     pub fn assign_lanes(&self, notes: Vec<Note>, frequency_data: Option<&HashMap<u32, Vec<f32>>>) -> Vec<Note> {
         match &self.strategy {
@@ -39,6 +63,60 @@ impl LaneAssigner {
         }
     }
 
+    /// Flip lanes left-to-right (lane 0 becomes the last lane)
+    pub fn mirror_lanes(&self, mut notes: Vec<Note>) -> Vec<Note> {
+        let last = self.num_lanes.saturating_sub(1);
+        for note in &mut notes {
+            note.col = last - note.col.min(last);
+        }
+        notes
+    }
+
+    /// Map notes charted for `from_lanes` onto this assigner's lane count (e.g. 5K to 4K).
+    /// Lanes are scaled proportionally; if two notes of a chord land on the same lane the
+    /// second moves to the nearest free lane, or is dropped when the row is full.
+    pub fn convert_lanes(&self, mut notes: Vec<Note>, from_lanes: u8) -> Vec<Note> {
+        notes.sort_by(|a, b| {
+            a.time
+                .partial_cmp(&b.time)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.col.cmp(&b.col))
+        });
+
+        let to_last = self.num_lanes.saturating_sub(1) as f32;
+        let from_last = from_lanes.saturating_sub(1).max(1) as f32;
+        let mut converted: Vec<Note> = Vec::with_capacity(notes.len());
+        let mut row_time: Option<f32> = None;
+        let mut used: Vec<u8> = Vec::new();
+
+        for mut note in notes {
+            // Start a new row unless this note is part of the current chord
+            match row_time {
+                Some(t) if (note.time - t).abs() <= 0.001 => {}
+                _ => {
+                    row_time = Some(note.time);
+                    used.clear();
+                }
+            }
+
+            let target = (note.col.min(from_lanes.saturating_sub(1)) as f32 * to_last / from_last).round() as u8;
+            let free = (0..self.num_lanes)
+                .filter(|lane| !used.contains(lane))
+                .min_by_key(|&lane| (lane as i16 - target as i16).abs());
+
+            match free {
+                Some(lane) => {
+                    note.col = lane;
+                    used.push(lane);
+                    converted.push(note);
+                }
+                None => log::debug!("dropping note at {:.3}s: no free lane", note.time),
+            }
+        }
+
+        converted
+    }
+
     /// Assign lanes based on frequency content at note time
     fn assign_by_frequency(
         &self,
@@ -66,7 +144,10 @@ impl LaneAssigner {
                 } else {
                     note_time_ms - time
                 };
-                if distance < closest_distance {
+                // Break ties on the earlier time so HashMap order never matters
+                if distance < closest_distance
+                    || (distance == closest_distance && closest_time.is_some_and(|t| time < t))
+                {
                     closest_distance = distance;
                     closest_time = Some(time);
                 }
@@ -134,16 +215,9 @@ impl LaneAssigner {
         notes
     }
 
-    /// Assign lanes randomly (for testing)
+    /// Assign lanes randomly (for testing); the same seed gives the same lanes
     fn assign_random(&self, mut notes: Vec<Note>) -> Vec<Note> {
-        use std::time::{SystemTime, UNIX_EPOCH};
-
-        let seed = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos() as u64;
-
-        let mut rng = SimpleLcg::new(seed);
+        let mut rng = SimpleLcg::new(self.seed);
 
         for note in &mut notes {
             note.col = (rng.next() % self.num_lanes as u64) as u8;
@@ -188,6 +262,52 @@ mod tests {
         assert_eq!(assigned[2].col, 2);
     }
 
+    #[test]
+    fn test_random_assignment_is_seeded() {
+        let notes = || -> Vec<Note> {
+            (0..32)
+                .map(|i| Note { time: i as f32, col: 0, duration: 0.0 })
+                .collect()
+        };
+        let lanes = |seed| -> Vec<u8> {
+            LaneAssigner::new(LaneAssignmentStrategy::Random, 4)
+                .with_seed(seed)
+                .assign_lanes(notes(), None)
+                .iter()
+                .map(|n| n.col)
+                .collect()
+        };
+
+        assert_eq!(lanes(42), lanes(42));
+        assert_ne!(lanes(42), lanes(7));
+        assert!(lanes(42).iter().all(|&c| c < 4));
+    }
+
+    #[test]
+    fn test_mirror_lanes() {
+        let assigner = LaneAssigner::new(LaneAssignmentStrategy::Sequential, 5);
+        let notes = (0..5).map(|i| Note { time: i as f32, col: i, duration: 0.0 }).collect();
+        let mirrored: Vec<u8> = assigner.mirror_lanes(notes).iter().map(|n| n.col).collect();
+        assert_eq!(mirrored, vec![4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn test_convert_five_to_four_lanes() {
+        let assigner = LaneAssigner::new(LaneAssignmentStrategy::Sequential, 4);
+        let singles: Vec<Note> = (0..5).map(|i| Note { time: i as f32, col: i, duration: 0.0 }).collect();
+        let converted = assigner.convert_lanes(singles, 5);
+        assert_eq!(converted.len(), 5);
+        assert!(converted.iter().all(|n| n.col < 4));
+        assert_eq!(converted[0].col, 0);
+        assert_eq!(converted[4].col, 3);
+
+        // A five-note chord keeps four notes on distinct lanes
+        let chord: Vec<Note> = (0..5).map(|i| Note { time: 1.0, col: i, duration: 0.0 }).collect();
+        let mut lanes: Vec<u8> = assigner.convert_lanes(chord, 5).iter().map(|n| n.col).collect();
+        lanes.sort();
+        assert_eq!(lanes, vec![0, 1, 2, 3]);
+    }
+
     #[test]
     fn test_lane_wrapping() {
         let assigner = LaneAssigner::new(LaneAssignmentStrategy::Sequential, 4);
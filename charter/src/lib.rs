@@ -5,6 +5,12 @@ pub mod lane_assigner;
 pub mod hold_detector;
 pub mod exporter;
 pub mod frequency_filter;
+pub mod stats;
+pub mod render;
+pub mod transform;
+pub mod progress;
+pub mod song_settings;
+pub mod batch;
 
 use anyhow::Result;
 use audio::AudioData;
@@ -12,8 +18,11 @@ use beat_detection::BeatDetection;
 use quantizer::Quantizer;
 use lane_assigner::{LaneAssigner, LaneAssignmentStrategy};
 use hold_detector::HoldDetector;
-use exporter::ChartExport;
+use exporter::{ChartExport, GeneratedAt};
 use frequency_filter::{FrequencyBand, bandpass_filter};
+use progress::{CancelToken, Progress, Stage};
+use std::collections::HashMap;
+use std::io::Read;
 use std::path::Path;
 
 /// Main charter configuration
@@ -24,6 +33,11 @@ pub struct CharterConfig {
     pub sustain_threshold: f32,        // Energy threshold for holds
     pub min_hold_duration: f32,        // Minimum hold duration in seconds
     pub lane_strategy: LaneAssignmentStrategy,
+    pub seed: u64,                     // Seed for randomized steps; same seed => same chart
+    pub generated_at: GeneratedAt,     // Timestamp written to exported charts
+    pub offset: f32,                   // Seconds where the beat grid starts
+    pub exclude: Vec<(f32, f32)>,      // Time ranges (seconds) that never get notes
+    pub band_overrides: HashMap<String, (f32, f32)>, // Instrument -> (low_hz, high_hz)
 }
 
 impl Default for CharterConfig {
@@ -34,10 +48,49 @@ impl Default for CharterConfig {
             sustain_threshold: 0.5,
             min_hold_duration: 0.25,
             lane_strategy: LaneAssignmentStrategy::Sequential,
+            seed: 0,
+            generated_at: GeneratedAt::Now,
+            offset: 0.0,
+            exclude: Vec::new(),
+            band_overrides: HashMap::new(),
         }
     }
 }
 
+impl CharterConfig {
+    /// Frequency band for an instrument, with any override applied
+    pub fn band_for(&self, instrument: &str) -> FrequencyBand {
+        let mut band = FrequencyBand::for_instrument(instrument);
+        if let Some(&(low_hz, high_hz)) = self.band_overrides.get(&instrument.to_lowercase()) {
+            band.low_hz = low_hz;
+            band.high_hz = high_hz;
+        }
+        band
+    }
+
+    /// Whether a time falls inside one of the excluded ranges
+    pub fn is_excluded(&self, time: f32) -> bool {
+        self.exclude.iter().any(|&(start, end)| time >= start && time <= end)
+    }
+}
+
+/// Difficulties generated for every instrument, with their column counts
+pub const DIFFICULTIES: [(&str, u8); 4] = [
+    ("Easy", 4),
+    ("Normal", 4),
+    ("Hard", 4),
+    ("Expert", 5),
+];
+
+/// Audio analysis shared by every difficulty of one instrument
+struct AudioAnalysis {
+    sample_rate: u32,
+    beat_detection: BeatDetection,
+    bpm: f32,
+    spectrum: Vec<(f32, Vec<f32>)>,
+    band: FrequencyBand,
+}
+
 /// Main charter that orchestrates the entire process
 pub struct Charter {
     config: CharterConfig,
@@ -56,10 +109,60 @@ impl Charter {
         instrument: &str,
     ) -> Result<Vec<ChartExport>> {
         let audio = AudioData::load(audio_path)?;
+        self.generate_from_audio(&audio, song_id, instrument)
+    }
+
+    /// Generate charts for all difficulties from audio already in memory
+    pub fn generate_from_audio(
+        &self,
+        audio: &AudioData,
+        song_id: &str,
+        instrument: &str,
+    ) -> Result<Vec<ChartExport>> {
+        self.generate_with_progress(audio, song_id, instrument, |_| {}, &CancelToken::new())
+    }
+
+    /// Decode WAV data from `reader` and generate all difficulties, reporting progress.
+    /// See `generate_with_progress` for the callback and cancellation behaviour.
+    pub fn generate_from_reader<R: Read>(
+        &self,
+        reader: R,
+        song_id: &str,
+        instrument: &str,
+        mut on_progress: impl FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<Vec<ChartExport>> {
+        cancel.check()?;
+        on_progress(Progress { stage: Stage::Decode, fraction: 0.0 });
+        let audio = AudioData::from_wav_reader(reader)?;
+        self.generate_with_progress(&audio, song_id, instrument, &mut on_progress, cancel)
+    }
+
+    /// Generate charts for all difficulties, calling `on_progress` as each stage starts
+    /// (send into a channel from the closure to report to another thread). The token is
+    /// checked between stages and between difficulties; once cancelled this returns a
+    /// `progress::Cancelled` error.
+    pub fn generate_with_progress(
+        &self,
+        audio: &AudioData,
+        song_id: &str,
+        instrument: &str,
+        mut on_progress: impl FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<Vec<ChartExport>> {
+        let mut fraction = progress::DECODE_WEIGHT;
+        let mut stage = |stage: Stage, weight: f32| -> Result<()> {
+            cancel.check()?;
+            on_progress(Progress { stage, fraction });
+            fraction += weight;
+            Ok(())
+        };
+
+        stage(Stage::Filter, progress::FILTER_WEIGHT)?;
         let mono = audio.to_mono()?;
 
         // Get frequency band for this instrument
-        let freq_band = FrequencyBand::for_instrument(instrument);
+        let freq_band = self.config.band_for(instrument);
         
         // Filter audio to instrument's frequency band
         log::info!("Filtering audio to {} frequency band ({}-{} Hz)", 
@@ -67,70 +170,47 @@ impl Charter {
         let filtered = bandpass_filter(&mono, audio.sample_rate, &freq_band);
 
         // Detect beats in the filtered signal (instrument-specific)
+        stage(Stage::Detect, progress::DETECT_WEIGHT)?;
         let beat_detection = BeatDetection::detect(&filtered, audio.sample_rate)?;
         let bpm = self.config.bpm.unwrap_or(beat_detection.bpm);
 
-        // Create charts for each difficulty
-        let mut charts = Vec::new();
+        // Spectrum of the filtered signal, used for hold detection
+        stage(Stage::Spectrum, progress::SPECTRUM_WEIGHT)?;
+        let spectrum = HoldDetector::spectrum_frames(&filtered, audio.sample_rate);
 
-        // Easy - 4 columns
-        charts.push(self.generate_chart(
-            audio.sample_rate,
-            &beat_detection,
-            bpm,
-            song_id,
-            instrument,
-            "Easy",
-            4,
-        )?);
-
-        // Normal - 4 columns
-        charts.push(self.generate_chart(
-            audio.sample_rate,
-            &beat_detection,
+        let analysis = AudioAnalysis {
+            sample_rate: audio.sample_rate,
+            beat_detection,
             bpm,
-            song_id,
-            instrument,
-            "Normal",
-            4,
-        )?);
-
-        // Hard - 4 columns
-        charts.push(self.generate_chart(
-            audio.sample_rate,
-            &beat_detection,
-            bpm,
-            song_id,
-            instrument,
-            "Hard",
-            4,
-        )?);
-
-        // Expert - 5 columns
-        charts.push(self.generate_chart(
-            audio.sample_rate,
-            &beat_detection,
-            bpm,
-            song_id,
-            instrument,
-            "Expert",
-            5,
-        )?);
+            spectrum,
+            band: freq_band,
+        };
+
+        // Create charts for each difficulty
+        let mut charts = Vec::with_capacity(DIFFICULTIES.len());
+        for &(difficulty, num_lanes) in &DIFFICULTIES {
+            stage(
+                Stage::Difficulty(difficulty.to_string()),
+                progress::CHARTS_WEIGHT / DIFFICULTIES.len() as f32,
+            )?;
+            charts.push(self.generate_chart(&analysis, song_id, instrument, difficulty, num_lanes)?);
+        }
 
+        on_progress(Progress { stage: Stage::Done, fraction: 1.0 });
         Ok(charts)
     }
 
     /// Generate a single difficulty chart
     fn generate_chart(
         &self,
-        sample_rate: u32,
-        beat_detection: &BeatDetection,
-        bpm: f32,
+        analysis: &AudioAnalysis,
         song_id: &str,
         instrument: &str,
         difficulty: &str,
         num_lanes: u8,
     ) -> Result<ChartExport> {
+        let beat_detection = &analysis.beat_detection;
+
         // Apply difficulty-specific filtering to note density
         let filtered_peaks = match difficulty {
             "Easy" => {
@@ -152,34 +232,46 @@ impl Charter {
             _ => beat_detection.peaks.clone(),
         };
 
-        // Create notes from detected peaks
+        // Create notes from detected peaks, relative to the start of the beat grid
+        let offset = self.config.offset;
         let mut notes: Vec<beat_detection::Note> = filtered_peaks
             .iter()
             .map(|&time| beat_detection::Note {
-                time,
+                time: time - offset,
                 col: 0,
                 duration: 0.0,
             })
             .collect();
 
-        // Quantize notes to the beat grid
-        let quantizer = Quantizer::new(bpm, sample_rate, self.config.grid_division);
+        // Quantize notes to the beat grid, then drop anything before 0s or in an excluded range
+        let quantizer = Quantizer::new(analysis.bpm, analysis.sample_rate, self.config.grid_division);
         notes = quantizer.quantize_notes(notes);
+        notes.retain_mut(|note| {
+            note.time += offset;
+            note.time >= 0.0 && !self.config.is_excluded(note.time)
+        });
 
-        // Assign lanes with frequency-based strategy for variety
-        let lane_assigner = LaneAssigner::new(LaneAssignmentStrategy::Sequential, num_lanes);
+        // Assign lanes; each difficulty gets its own seed derived from the config seed
+        let lane_assigner = LaneAssigner::new(self.config.lane_strategy.clone(), num_lanes)
+            .with_seed(Self::difficulty_seed(self.config.seed, difficulty));
         notes = lane_assigner.assign_lanes(notes, None);
 
-        // Detect holds (basic implementation)
+        // Detect holds from sustained energy. Frequency-based lanes each watch their own
+        // band; otherwise every lane watches the instrument's band.
         let hold_detector = HoldDetector::new(self.config.sustain_threshold, self.config.min_hold_duration);
-        let lane_freq_ranges = vec![
-            (0, 50.0, 150.0),
-            (1, 150.0, 300.0),
-            (2, 300.0, 600.0),
-            (3, 600.0, 2000.0),
-            (4, 2000.0, 8000.0),
-        ];
-        notes = hold_detector.detect_holds(notes, &[], &lane_freq_ranges);
+        let lane_freq_ranges = match self.config.lane_strategy {
+            LaneAssignmentStrategy::FrequencyBased { .. } => vec![
+                (0, 50.0, 150.0),
+                (1, 150.0, 300.0),
+                (2, 300.0, 600.0),
+                (3, 600.0, 2000.0),
+                (4, 2000.0, 8000.0),
+            ],
+            _ => (0..num_lanes)
+                .map(|lane| (lane, analysis.band.low_hz, analysis.band.high_hz))
+                .collect(),
+        };
+        notes = hold_detector.detect_holds(notes, &analysis.spectrum, &lane_freq_ranges);
 
         // Create chart export
         let chart = ChartExport::new(
@@ -187,13 +279,21 @@ impl Charter {
             instrument.to_string(),
             difficulty.to_string(),
             num_lanes,
-            bpm,
+            analysis.bpm,
             notes,
-        );
+        )
+        .with_generated_at(self.config.generated_at);
 
         Ok(chart)
     }
 
+    /// Mix the difficulty name into the seed (FNV-1a) so difficulties don't share patterns
+    fn difficulty_seed(seed: u64, difficulty: &str) -> u64 {
+        difficulty.bytes().fold(seed ^ 0xcbf2_9ce4_8422_2325, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+    }
+
     /// Reduce note count by filtering out weaker peaks
     fn reduce_notes(&self, peaks: &[f32], keep_ratio: f32) -> Vec<f32> {
         if peaks.is_empty() {
@@ -245,5 +345,79 @@ mod tests {
         let config = CharterConfig::default();
         assert_eq!(config.grid_division, 4);
         assert!(config.bpm.is_none());
+        assert_eq!(config.seed, 0);
+    }
+
+    /// Two seconds of 8 kHz audio with a short burst every half second
+    fn clicks() -> AudioData {
+        let samples = (0..16000)
+            .map(|i| if i % 4000 < 80 { ((i % 7) as f32 - 3.0) / 3.0 } else { 0.0 })
+            .collect();
+        AudioData {
+            samples,
+            sample_rate: 8000,
+            channels: 1,
+        }
+    }
+
+    #[test]
+    fn test_progress_reports_every_stage() {
+        let mut events = Vec::new();
+        let charts = Charter::new(CharterConfig::default())
+            .generate_with_progress(&clicks(), "song", "drums", |p| events.push(p), &CancelToken::new())
+            .unwrap();
+
+        assert_eq!(charts.len(), DIFFICULTIES.len());
+        let stages: Vec<Stage> = events.iter().map(|p| p.stage.clone()).collect();
+        assert_eq!(&stages[..3], &[Stage::Filter, Stage::Detect, Stage::Spectrum]);
+        assert_eq!(stages[3], Stage::Difficulty("Easy".to_string()));
+        assert_eq!(stages.last(), Some(&Stage::Done));
+        assert!(events.windows(2).all(|w| w[0].fraction < w[1].fraction));
+        assert_eq!(events.last().unwrap().fraction, 1.0);
+    }
+
+    #[test]
+    fn test_cancel_stops_generation() {
+        let cancel = CancelToken::new();
+        let mut stages = Vec::new();
+        let result = Charter::new(CharterConfig::default()).generate_with_progress(
+            &clicks(),
+            "song",
+            "drums",
+            |p| {
+                if p.stage == Stage::Detect {
+                    cancel.cancel();
+                }
+                stages.push(p.stage);
+            },
+            &cancel,
+        );
+
+        assert!(result.unwrap_err().is::<progress::Cancelled>());
+        assert_eq!(stages, vec![Stage::Filter, Stage::Detect]);
+    }
+
+    #[test]
+    fn test_generate_from_reader() {
+        let mut wav = std::io::Cursor::new(Vec::new());
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::new(&mut wav, spec).unwrap();
+        for s in clicks().samples {
+            writer.write_sample((s * i16::MAX as f32) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        wav.set_position(0);
+
+        let mut first = None;
+        let charts = Charter::new(CharterConfig::default())
+            .generate_from_reader(wav, "song", "drums", |p| { first.get_or_insert(p.stage); }, &CancelToken::new())
+            .unwrap();
+        assert_eq!(charts.len(), DIFFICULTIES.len());
+        assert_eq!(first, Some(Stage::Decode));
     }
 }
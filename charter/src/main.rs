@@ -1,11 +1,135 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use std::path::PathBuf;
 use anyhow::Result;
-use rhythm_pi_charter::{Charter, CharterConfig, lane_assigner::LaneAssignmentStrategy, exporter::ChartFormat};
+use rhythm_pi_charter::{Charter, CharterConfig, lane_assigner::LaneAssignmentStrategy, exporter::{ChartExport, ChartFormat, GeneratedAt}, stats::ChartStats};
+use rhythm_pi_charter::{audio::AudioData, render::{RenderOptions, render_preview}, transform::{self, Transform}};
+use rhythm_pi_charter::batch;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Audio Chart Generator for Rhythm Pi", long_about = None)]
-struct Args {
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Running without a subcommand generates charts (same as `generate`)
+    #[command(flatten)]
+    generate: Option<GenerateArgs>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Generate charts for all difficulties from an audio file
+    Generate(GenerateArgs),
+    /// Print difficulty rating and statistics for existing charts
+    Stats(StatsArgs),
+    /// Mix a click per note over the song to check a chart by ear
+    Render(RenderArgs),
+    /// Apply edits (offset, re-quantize, mirror, column count, holds) to an existing chart
+    Transform(TransformArgs),
+    /// Generate charts for every song in a directory, using each song's `charter` settings
+    Batch(BatchArgs),
+}
+
+#[derive(Args, Debug)]
+struct BatchArgs {
+    /// Directory with `{id}.wav` audio and optional `{id}.json` metadata
+    #[arg(short, long)]
+    songs: PathBuf,
+
+    /// Output directory for charts
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Only chart these song ids (repeatable; default is every song)
+    #[arg(long = "song")]
+    song_ids: Vec<String>,
+
+    /// Instruments to chart (comma-separated)
+    #[arg(long, value_delimiter = ',', default_value = "vocals,bass,drums,lead")]
+    instruments: Vec<String>,
+
+    /// Chart format (json or chart)
+    #[arg(long, default_value = "json")]
+    format: String,
+
+    /// Value for `generated_at` (now, omit, or a unix timestamp)
+    #[arg(long, default_value = "now")]
+    timestamp: String,
+
+    /// Enable verbose logging
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+#[derive(Args, Debug)]
+struct TransformArgs {
+    /// Chart file to edit (JSON or .chart)
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Output file (may be the same as the input)
+    #[arg(short, long)]
+    out: PathBuf,
+
+    /// Operation to apply, in order (repeatable): shift:<ms>, quantize:<grid>[:<bpm>],
+    /// mirror, columns:<n>, strip-holds
+    #[arg(long = "op", required = true, allow_hyphen_values = true)]
+    ops: Vec<String>,
+
+    /// Output format (json or chart); defaults to the output file's extension
+    #[arg(long)]
+    format: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct RenderArgs {
+    /// Chart file (JSON)
+    #[arg(short, long)]
+    chart: PathBuf,
+
+    /// Original song audio (WAV)
+    #[arg(short, long)]
+    audio: PathBuf,
+
+    /// Output WAV file
+    #[arg(short, long)]
+    out: PathBuf,
+
+    /// Volume of the original song (0.0-1.0)
+    #[arg(long, default_value = "0.6")]
+    music_volume: f32,
+
+    /// Volume of the clicks and hold tones (0.0-1.0)
+    #[arg(long, default_value = "0.5")]
+    click_volume: f32,
+
+    /// Shift every click by this many milliseconds
+    #[arg(long, default_value = "0", allow_hyphen_values = true)]
+    offset_ms: f32,
+
+    /// Keep all clicks centered instead of panning by column
+    #[arg(long)]
+    no_pan: bool,
+}
+
+#[derive(Args, Debug)]
+struct StatsArgs {
+    /// Chart files (JSON) to analyze
+    #[arg(required = true)]
+    charts: Vec<PathBuf>,
+
+    /// Print the statistics as JSON instead of a table
+    #[arg(long)]
+    json: bool,
+
+    /// Write the computed stats back into each chart file
+    #[arg(long)]
+    write: bool,
+}
+
+#[derive(Args, Debug)]
+struct GenerateArgs {
     /// Path to audio file (WAV or OGG)
     #[arg(short, long)]
     audio: PathBuf,
@@ -42,23 +166,50 @@ struct Args {
     #[arg(long, default_value = "0.25")]
     min_hold_duration: f32,
 
-    /// Lane assignment strategy (sequential, frequency)
+    /// Lane assignment strategy (sequential, frequency, random)
     #[arg(long, default_value = "sequential")]
     lane_strategy: String,
 
+    /// Seed for randomized steps; the same audio, options and seed give identical charts
+    #[arg(long, default_value = "0")]
+    seed: u64,
+
+    /// Value for `generated_at` (now, omit, or a unix timestamp)
+    #[arg(long, default_value = "now")]
+    timestamp: String,
+
     /// Enable verbose logging
     #[arg(short, long)]
     verbose: bool,
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let cli = Cli::parse();
 
-    // Initialize logging
-    let level = if args.verbose { "debug" } else { "info" };
+    match (cli.command, cli.generate) {
+        (Some(Command::Generate(args)), _) | (None, Some(args)) => generate(args),
+        (Some(Command::Stats(args)), _) => stats(args),
+        (Some(Command::Render(args)), _) => render(args),
+        (Some(Command::Transform(args)), _) => transform(args),
+        (Some(Command::Batch(args)), _) => batch(args),
+        (None, None) => {
+            use clap::CommandFactory;
+            Cli::command().print_help()?;
+            Ok(())
+        }
+    }
+}
+
+fn init_logging(verbose: bool) -> Result<()> {
+    let level = if verbose { "debug" } else { "info" };
     env_logger::Builder::from_default_env()
         .filter_level(level.parse()?)
         .init();
+    Ok(())
+}
+
+fn generate(args: GenerateArgs) -> Result<()> {
+    init_logging(args.verbose)?;
 
     log::info!("Starting chart generation for: {}", args.song_id);
     log::info!("Instrument: {}, Format: {}", args.instrument, args.format);
@@ -68,16 +219,11 @@ fn main() -> Result<()> {
         .ok_or_else(|| anyhow::anyhow!("Invalid format: {}", args.format))?;
 
     // Parse lane assignment strategy
-    let lane_strategy = match args.lane_strategy.to_lowercase().as_str() {
-        "sequential" => LaneAssignmentStrategy::Sequential,
-        "frequency" => LaneAssignmentStrategy::FrequencyBased {
-            low_hz: 100.0,
-            mid_hz: 500.0,
-            high_hz: 2000.0,
-        },
-        "random" => LaneAssignmentStrategy::Random,
-        s => return Err(anyhow::anyhow!("Unknown lane strategy: {}", s)),
-    };
+    let lane_strategy = LaneAssignmentStrategy::parse(&args.lane_strategy)
+        .ok_or_else(|| anyhow::anyhow!("Unknown lane strategy: {}", args.lane_strategy))?;
+
+    let generated_at = GeneratedAt::parse(&args.timestamp)
+        .ok_or_else(|| anyhow::anyhow!("Invalid timestamp: {}", args.timestamp))?;
 
     // Create charter config
     let config = CharterConfig {
@@ -86,6 +232,9 @@ fn main() -> Result<()> {
         sustain_threshold: args.sustain_threshold,
         min_hold_duration: args.min_hold_duration,
         lane_strategy,
+        seed: args.seed,
+        generated_at,
+        ..Default::default()
     };
 
     let charter = Charter::new(config);
@@ -98,14 +247,7 @@ fn main() -> Result<()> {
 
     // Save charts
     for chart in &charts {
-        let filename = format!(
-            "{}_{}_{}",
-            args.song_id.replace(" ", " "),
-            args.instrument.to_lowercase(),
-            chart.difficulty.to_lowercase()
-        );
-        let filename = format!("{}.{}", filename, format.extension());
-        let output_path = args.output.join(&filename);
+        let output_path = args.output.join(chart.file_name(format));
 
         chart.save(&output_path, format)?;
         log::info!(
@@ -121,14 +263,174 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn print_summary(charts: &[rhythm_pi_charter::exporter::ChartExport]) {
+fn batch(args: BatchArgs) -> Result<()> {
+    init_logging(args.verbose)?;
+
+    let format = ChartFormat::from_str(&args.format)
+        .ok_or_else(|| anyhow::anyhow!("Invalid format: {}", args.format))?;
+    let generated_at = GeneratedAt::parse(&args.timestamp)
+        .ok_or_else(|| anyhow::anyhow!("Invalid timestamp: {}", args.timestamp))?;
+    let base = CharterConfig {
+        generated_at,
+        ..Default::default()
+    };
+
+    let song_ids = if args.song_ids.is_empty() {
+        batch::song_ids(&args.songs)?
+    } else {
+        args.song_ids.clone()
+    };
+    let instruments: Vec<&str> = args.instruments.iter().map(|s| s.as_str()).collect();
+
+    let mut failed = Vec::new();
+    for song_id in &song_ids {
+        log::info!("Charting {}", song_id);
+        match batch::write_song_charts(&args.songs, song_id, &args.output, &base, &instruments, format, true) {
+            Ok(written) => log::info!("Saved {} charts for {}", written.len(), song_id),
+            Err(e) => {
+                log::error!("Failed to chart {}: {}", song_id, e);
+                failed.push(song_id.as_str());
+            }
+        }
+    }
+
+    log::info!("Charted {} of {} songs", song_ids.len() - failed.len(), song_ids.len());
+    if !failed.is_empty() {
+        return Err(anyhow::anyhow!("Failed songs: {}", failed.join(", ")));
+    }
+    Ok(())
+}
+
+fn stats(args: StatsArgs) -> Result<()> {
+    init_logging(false)?;
+
+    let mut all = Vec::new();
+    for path in &args.charts {
+        let mut chart = ChartExport::load(path)
+            .map_err(|e| anyhow::anyhow!("Failed to load {}: {}", path.display(), e))?;
+        chart.refresh_stats();
+
+        if args.write {
+            chart.save(path, ChartFormat::Json)?;
+            log::info!("Wrote stats to: {}", path.display());
+        }
+        all.push((path, chart));
+    }
+
+    if args.json {
+        let out: Vec<_> = all
+            .iter()
+            .map(|(path, chart)| serde_json::json!({
+                "path": path.display().to_string(),
+                "song_id": chart.song_id,
+                "instrument": chart.instrument,
+                "difficulty": chart.difficulty,
+                "stats": chart.stats,
+            }))
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&out)?);
+        return Ok(());
+    }
+
+    for (path, chart) in &all {
+        let stats = chart.stats.clone().unwrap_or_default();
+        println!("\n=== {} ===", path.display());
+        println!("{} / {} / {} columns", chart.instrument, chart.difficulty, chart.columns);
+        print_stats(&stats);
+    }
+    println!();
+
+    Ok(())
+}
+
+fn render(args: RenderArgs) -> Result<()> {
+    init_logging(false)?;
+
+    let chart = ChartExport::load(&args.chart)
+        .map_err(|e| anyhow::anyhow!("Failed to load {}: {}", args.chart.display(), e))?;
+    let audio = AudioData::load(&args.audio)?;
+
+    let options = RenderOptions {
+        music_volume: args.music_volume,
+        click_volume: args.click_volume,
+        offset: args.offset_ms / 1000.0,
+        pan: !args.no_pan,
+    };
+
+    log::info!(
+        "Rendering {} notes ({} / {}) over {}",
+        chart.notes.len(),
+        chart.instrument,
+        chart.difficulty,
+        args.audio.display()
+    );
+    let preview = render_preview(&audio, &chart, &options);
+    preview.save_wav(&args.out)?;
+    log::info!("Saved preview ({:.1}s) to: {}", preview.duration(), args.out.display());
+
+    Ok(())
+}
+
+fn transform(args: TransformArgs) -> Result<()> {
+    init_logging(false)?;
+
+    let ops = args
+        .ops
+        .iter()
+        .map(|op| Transform::parse(op))
+        .collect::<Result<Vec<_>>>()?;
+
+    let format = match &args.format {
+        Some(f) => ChartFormat::from_str(f).ok_or_else(|| anyhow::anyhow!("Invalid format: {}", f))?,
+        None => ChartFormat::from_path(&args.out).unwrap_or(ChartFormat::Json),
+    };
+
+    let mut chart = ChartExport::load(&args.input)
+        .map_err(|e| anyhow::anyhow!("Failed to load {}: {}", args.input.display(), e))?;
+    let before = chart.notes.len();
+
+    transform::apply_all(&mut chart, &ops)?;
+
+    chart.save(&args.out, format)?;
+    log::info!(
+        "Saved {} notes (was {}) to: {}",
+        chart.notes.len(),
+        before,
+        args.out.display()
+    );
+    print_summary(std::slice::from_ref(&chart));
+
+    Ok(())
+}
+
+fn print_stats(stats: &ChartStats) {
+    let balance: Vec<String> = stats
+        .lane_balance
+        .iter()
+        .map(|s| format!("{:.0}%", s * 100.0))
+        .collect();
+
+    println!("  Rating:        {:.2}", stats.rating);
+    println!("  Notes:         {} ({} holds)", stats.note_count, stats.hold_count);
+    println!("  Duration:      {:.1}s", stats.duration);
+    println!("  NPS:           {:.2} avg / {:.2} peak", stats.average_nps, stats.peak_nps);
+    println!("  Lane balance:  {}", balance.join(" "));
+    println!("  Jacks:         {}", stats.jacks);
+    println!("  Trills:        {}", stats.trills);
+    println!("  Chords:        {}", stats.chords);
+    println!("  Hold coverage: {:.0}%", stats.hold_coverage * 100.0);
+}
+
+fn print_summary(charts: &[ChartExport]) {
     println!("\n=== Chart Summary ===");
     for chart in charts {
+        let rating = chart.stats.as_ref().map(|s| s.rating).unwrap_or(0.0);
         println!(
-            "{:<10} | {} notes | {} columns",
+            "{:<10} | {} notes | {} columns | rating {:.2}",
             chart.difficulty,
             chart.notes.len(),
-            chart.columns
+            chart.columns,
+            rating
         );
     }
     println!("=== End Summary ===\n");
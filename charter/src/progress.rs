@@ -0,0 +1,84 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Step of the generation pipeline being worked on
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stage {
+    Decode,
+    Filter,
+    Detect,
+    Spectrum,
+    Difficulty(String), // building the chart for this difficulty
+    Done,
+}
+
+/// Progress report sent to the caller's callback
+#[derive(Clone, Debug, PartialEq)]
+pub struct Progress {
+    pub stage: Stage,
+    pub fraction: f32, // overall completion (0.0-1.0) when the stage starts
+}
+
+/// Share of the total work spent in each stage, used to compute `fraction`
+pub(crate) const DECODE_WEIGHT: f32 = 0.1;
+pub(crate) const FILTER_WEIGHT: f32 = 0.15;
+pub(crate) const DETECT_WEIGHT: f32 = 0.35;
+pub(crate) const SPECTRUM_WEIGHT: f32 = 0.2;
+pub(crate) const CHARTS_WEIGHT: f32 = 0.2;
+
+/// Shared flag for cooperative cancellation. Clones refer to the same flag, so one
+/// can be handed to a worker thread and the other kept to call `cancel()`.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask the running generation to stop at the next stage boundary
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// `Err(Cancelled)` once `cancel()` has been called
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Returned (inside `anyhow::Error`) when generation stops because of a `CancelToken`.
+/// Check for it with `err.is::<Cancelled>()`.
+#[derive(Debug, thiserror::Error)]
+#[error("chart generation cancelled")]
+pub struct Cancelled;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_is_shared_between_clones() {
+        let token = CancelToken::new();
+        let worker = token.clone();
+        assert!(worker.check().is_ok());
+
+        token.cancel();
+        assert!(worker.is_cancelled());
+        assert!(worker.check().is_err());
+    }
+
+    #[test]
+    fn test_stage_weights_sum_to_one() {
+        let total = DECODE_WEIGHT + FILTER_WEIGHT + DETECT_WEIGHT + SPECTRUM_WEIGHT + CHARTS_WEIGHT;
+        assert!((total - 1.0).abs() < 0.001);
+    }
+}
@@ -58,6 +58,29 @@ impl Quantizer {
 
         deduped
     }
+
+    /// Snap an existing chart's notes to this grid. Unlike `quantize_notes`, chords are
+    /// kept (only exact duplicates in the same lane are removed) and hold ends are
+    /// snapped as well.
+    pub fn snap_notes(&self, mut notes: Vec<Note>) -> Vec<Note> {
+        for note in &mut notes {
+            let start = self.quantize(note.time).0;
+            if note.duration > 0.0 {
+                let end = self.quantize(note.time + note.duration).0;
+                note.duration = (end - start).max(0.0);
+            }
+            note.time = start;
+        }
+
+        notes.sort_by(|a, b| {
+            a.time
+                .partial_cmp(&b.time)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.col.cmp(&b.col))
+        });
+        notes.dedup_by(|b, a| a.col == b.col && (a.time - b.time).abs() < 0.001);
+        notes
+    }
 }
 
 #[cfg(test)]
@@ -79,6 +102,24 @@ mod tests {
         assert!((time - 0.125).abs() < 0.001);
     }
 
+    #[test]
+    fn test_snap_notes_keeps_chords_and_holds() {
+        let quantizer = Quantizer::new(120.0, 44100, 4);
+        let notes = vec![
+            Note { time: 0.49, col: 0, duration: 0.0 },
+            Note { time: 0.51, col: 1, duration: 0.0 },  // chord with the first note
+            Note { time: 0.52, col: 1, duration: 0.0 },  // duplicate in lane 1
+            Note { time: 1.01, col: 2, duration: 0.48 }, // hold ending near 1.5s
+        ];
+
+        let snapped = quantizer.snap_notes(notes);
+        assert_eq!(snapped.len(), 3);
+        assert!((snapped[0].time - 0.5).abs() < 0.001);
+        assert!((snapped[1].time - 0.5).abs() < 0.001);
+        assert!((snapped[2].time - 1.0).abs() < 0.001);
+        assert!((snapped[2].duration - 0.5).abs() < 0.001);
+    }
+
     #[test]
     fn test_quantize_time() {
         let quantizer = Quantizer::new(120.0, 44100, 4);
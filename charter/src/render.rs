@@ -0,0 +1,215 @@
+use crate::audio::AudioData;
+use crate::exporter::ChartExport;
+use std::f32::consts::PI;
+
+/// Length of the click played for every note
+const CLICK_DURATION: f32 = 0.04;
+/// Fade in/out applied to hold tones to avoid pops
+const HOLD_FADE: f32 = 0.01;
+
+/// Options for mixing an audition track
+#[derive(Clone, Debug)]
+pub struct RenderOptions {
+    pub music_volume: f32, // gain applied to the original song
+    pub click_volume: f32, // gain applied to clicks and hold tones
+    pub offset: f32,       // seconds added to every note time
+    pub pan: bool,         // spread columns from left to right
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            music_volume: 0.6,
+            click_volume: 0.5,
+            offset: 0.0,
+            pan: true,
+        }
+    }
+}
+
+/// Mix a click per note (and a tone per hold) over the song, for checking a chart by ear.
+/// Each column gets its own pitch and stereo position. Output is stereo at the song's rate.
+pub fn render_preview(audio: &AudioData, chart: &ChartExport, options: &RenderOptions) -> AudioData {
+    let sample_rate = audio.sample_rate;
+    let mut out = to_stereo(audio);
+    out.iter_mut().for_each(|s| *s *= options.music_volume);
+
+    // Make sure notes past the end of the song still get rendered
+    let chart_end = chart
+        .notes
+        .iter()
+        .map(|n| n.time + n.duration.max(CLICK_DURATION) + options.offset)
+        .fold(0.0f32, f32::max);
+    let needed = (chart_end * sample_rate as f32).ceil() as usize * 2;
+    if out.len() < needed {
+        out.resize(needed, 0.0);
+    }
+
+    for note in &chart.notes {
+        let start = note.time + options.offset;
+        if start < 0.0 {
+            continue;
+        }
+
+        let frequency = column_frequency(note.col);
+        let (left, right) = column_pan(note.col, chart.columns, options.pan);
+
+        // Click: short decaying sine
+        mix(&mut out, sample_rate, start, CLICK_DURATION, left, right, |t| {
+            options.click_volume * (2.0 * PI * frequency * t).sin() * (-t * 80.0).exp()
+        });
+
+        // Hold: quieter steady tone one octave lower for the whole duration
+        if note.duration > 0.001 {
+            let length = note.duration;
+            mix(&mut out, sample_rate, start, length, left, right, |t| {
+                let fade = (t / HOLD_FADE).min((length - t) / HOLD_FADE).clamp(0.0, 1.0);
+                options.click_volume * 0.3 * fade * (PI * frequency * t).sin()
+            });
+        }
+    }
+
+    // Scale down instead of clipping if the mix got too loud
+    let peak = out.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+    if peak > 1.0 {
+        out.iter_mut().for_each(|s| *s /= peak);
+    }
+
+    AudioData {
+        samples: out,
+        sample_rate,
+        channels: 2,
+    }
+}
+
+/// Interleaved stereo copy of the song (mono is duplicated, extra channels are dropped)
+fn to_stereo(audio: &AudioData) -> Vec<f32> {
+    let channels = audio.channels.max(1) as usize;
+    let mut out = Vec::with_capacity(audio.samples.len() / channels * 2);
+    for frame in audio.samples.chunks_exact(channels) {
+        let left = frame[0];
+        let right = if channels > 1 { frame[1] } else { frame[0] };
+        out.push(left);
+        out.push(right);
+    }
+    out
+}
+
+/// Pitch per column: a major-pentatonic step upward from A5
+fn column_frequency(col: u8) -> f32 {
+    const STEPS: [f32; 5] = [0.0, 2.0, 4.0, 7.0, 9.0];
+    let octave = (col / 5) as f32;
+    let step = STEPS[(col % 5) as usize];
+    880.0 * 2f32.powf(octave + step / 12.0)
+}
+
+/// Constant-power pan from left (column 0) to right (last column)
+fn column_pan(col: u8, columns: u8, pan: bool) -> (f32, f32) {
+    if !pan || columns < 2 {
+        return (0.707, 0.707);
+    }
+    let position = col.min(columns - 1) as f32 / (columns - 1) as f32;
+    let angle = position * PI / 2.0;
+    (angle.cos(), angle.sin())
+}
+
+/// Add `f(t)` to interleaved stereo `out` for `length` seconds from `start`
+fn mix(
+    out: &mut [f32],
+    sample_rate: u32,
+    start: f32,
+    length: f32,
+    left: f32,
+    right: f32,
+    f: impl Fn(f32) -> f32,
+) {
+    let first = (start * sample_rate as f32) as usize;
+    let count = (length * sample_rate as f32) as usize;
+    for i in 0..count {
+        let idx = (first + i) * 2;
+        if idx + 1 >= out.len() {
+            break;
+        }
+        let value = f(i as f32 / sample_rate as f32);
+        out[idx] += value * left;
+        out[idx + 1] += value * right;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beat_detection::Note;
+
+    fn silent(seconds: f32) -> AudioData {
+        AudioData {
+            samples: vec![0.0; (seconds * 8000.0) as usize],
+            sample_rate: 8000,
+            channels: 1,
+        }
+    }
+
+    fn chart(notes: Vec<Note>) -> ChartExport {
+        ChartExport::new(
+            "test_song".to_string(),
+            "drums".to_string(),
+            "Easy".to_string(),
+            4,
+            120.0,
+            notes,
+        )
+    }
+
+    /// Sum of |sample| for one channel between two times
+    fn energy(audio: &AudioData, channel: usize, from: f32, to: f32) -> f32 {
+        let rate = audio.sample_rate as f32;
+        audio
+            .samples
+            .chunks(2)
+            .skip((from * rate) as usize)
+            .take(((to - from) * rate) as usize)
+            .map(|frame| frame[channel].abs())
+            .sum()
+    }
+
+    #[test]
+    fn test_clicks_at_note_times() {
+        let notes = vec![Note { time: 0.5, col: 0, duration: 0.0 }];
+        let out = render_preview(&silent(1.0), &chart(notes), &RenderOptions::default());
+
+        assert_eq!(out.channels, 2);
+        assert_eq!(out.samples.len(), 8000 * 2);
+        assert_eq!(energy(&out, 0, 0.0, 0.49), 0.0);
+        assert!(energy(&out, 0, 0.5, 0.55) > 0.0);
+        assert_eq!(energy(&out, 0, 0.6, 1.0), 0.0);
+    }
+
+    #[test]
+    fn test_columns_are_panned() {
+        let notes = vec![
+            Note { time: 0.1, col: 0, duration: 0.0 },
+            Note { time: 0.5, col: 3, duration: 0.0 },
+        ];
+        let out = render_preview(&silent(1.0), &chart(notes), &RenderOptions::default());
+
+        assert!(energy(&out, 0, 0.1, 0.2) > energy(&out, 1, 0.1, 0.2));
+        assert!(energy(&out, 1, 0.5, 0.6) > energy(&out, 0, 0.5, 0.6));
+        assert!(column_frequency(3) > column_frequency(0));
+    }
+
+    #[test]
+    fn test_holds_render_for_their_duration() {
+        let notes = vec![Note { time: 0.2, col: 1, duration: 0.5 }];
+        let out = render_preview(&silent(1.0), &chart(notes), &RenderOptions::default());
+
+        assert!(energy(&out, 0, 0.6, 0.65) > 0.0);
+        assert_eq!(energy(&out, 0, 0.71, 1.0), 0.0);
+    }
+
+    #[test]
+    fn test_output_extends_past_song_end() {
+        let notes = vec![Note { time: 2.0, col: 0, duration: 0.0 }];
+        let out = render_preview(&silent(1.0), &chart(notes), &RenderOptions::default());
+        assert!(out.duration() >= 2.0);
+    }
+}
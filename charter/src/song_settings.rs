@@ -0,0 +1,171 @@
+use crate::CharterConfig;
+use crate::lane_assigner::LaneAssignmentStrategy;
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Per-song generation settings, stored as the `charter` block of the song's `{id}.json`:
+///
+/// ```json
+/// "charter": {
+///   "bpm": 128.0,
+///   "offset_ms": 35,
+///   "grid_division": 8,
+///   "lane_strategy": "frequency",
+///   "seed": 7,
+///   "bands": { "bass": { "low_hz": 40.0, "high_hz": 180.0 } },
+///   "exclude": [[0.0, 4.2], [181.5, 190.0]]
+/// }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SongSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bpm: Option<f32>, // overrides the detected BPM
+    pub offset_ms: f32, // where the beat grid starts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grid_division: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lane_strategy: Option<String>, // sequential, frequency, random
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub bands: BTreeMap<String, BandOverride>, // per-instrument frequency bands
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<(f32, f32)>, // [start, end] ranges in seconds that get no notes
+}
+
+/// Replacement frequency band for one instrument
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BandOverride {
+    pub low_hz: f32,
+    pub high_hz: f32,
+}
+
+impl SongSettings {
+    /// Read the `charter` block from a song metadata file.
+    /// A missing file or a file without the block gives the default settings.
+    pub fn load(metadata_path: &Path) -> Result<Self> {
+        if !metadata_path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(metadata_path)?;
+        let metadata: serde_json::Value = serde_json::from_str(&content)
+            .map_err(|e| anyhow!("Invalid metadata {}: {}", metadata_path.display(), e))?;
+        Self::from_metadata(&metadata)
+            .map_err(|e| anyhow!("Invalid charter settings in {}: {}", metadata_path.display(), e))
+    }
+
+    /// Extract and validate the `charter` block of parsed metadata
+    pub fn from_metadata(metadata: &serde_json::Value) -> Result<Self> {
+        let settings = match metadata.get("charter") {
+            Some(block) => serde_json::from_value(block.clone())?,
+            None => Self::default(),
+        };
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.bpm.is_some_and(|bpm| bpm <= 0.0 || bpm.is_nan()) {
+            bail!("bpm must be positive");
+        }
+        if self.grid_division == Some(0) {
+            bail!("grid_division must be at least 1");
+        }
+        if let Some(strategy) = &self.lane_strategy {
+            if LaneAssignmentStrategy::parse(strategy).is_none() {
+                bail!("unknown lane_strategy: {}", strategy);
+            }
+        }
+        for (instrument, band) in &self.bands {
+            if band.low_hz < 0.0 || band.low_hz >= band.high_hz {
+                bail!("band for {} must have 0 <= low_hz < high_hz", instrument);
+            }
+        }
+        for &(start, end) in &self.exclude {
+            if start < 0.0 || start >= end {
+                bail!("exclude range [{}, {}] must have 0 <= start < end", start, end);
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply these settings on top of a base config
+    pub fn apply(&self, config: &mut CharterConfig) {
+        if self.bpm.is_some() {
+            config.bpm = self.bpm;
+        }
+        config.offset = self.offset_ms / 1000.0;
+        if let Some(grid_division) = self.grid_division {
+            config.grid_division = grid_division;
+        }
+        if let Some(strategy) = self.lane_strategy.as_deref().and_then(LaneAssignmentStrategy::parse) {
+            config.lane_strategy = strategy;
+        }
+        if let Some(seed) = self.seed {
+            config.seed = seed;
+        }
+        for (instrument, band) in &self.bands {
+            config
+                .band_overrides
+                .insert(instrument.to_lowercase(), (band.low_hz, band.high_hz));
+        }
+        config.exclude = self.exclude.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_missing_block_is_default() {
+        let metadata = json!({ "SongTitle": "Song", "Artists": ["Someone"], "start_offset_Ms": 0 });
+        assert_eq!(SongSettings::from_metadata(&metadata).unwrap(), SongSettings::default());
+    }
+
+    #[test]
+    fn test_apply_settings_to_config() {
+        let metadata = json!({
+            "SongTitle": "Song",
+            "charter": {
+                "bpm": 128.0,
+                "offset_ms": 35,
+                "grid_division": 8,
+                "lane_strategy": "random",
+                "seed": 7,
+                "bands": { "Bass": { "low_hz": 40.0, "high_hz": 180.0 } },
+                "exclude": [[0.0, 4.2]]
+            }
+        });
+        let settings = SongSettings::from_metadata(&metadata).unwrap();
+        let mut config = CharterConfig::default();
+        settings.apply(&mut config);
+
+        assert_eq!(config.bpm, Some(128.0));
+        assert!((config.offset - 0.035).abs() < 1e-6);
+        assert_eq!(config.grid_division, 8);
+        assert!(matches!(config.lane_strategy, LaneAssignmentStrategy::Random));
+        assert_eq!(config.seed, 7);
+        assert_eq!(config.band_for("bass").high_hz, 180.0);
+        assert_eq!(config.band_for("drums").high_hz, 5000.0);
+        assert!(config.is_excluded(2.0));
+        assert!(!config.is_excluded(5.0));
+    }
+
+    #[test]
+    fn test_invalid_settings_are_rejected() {
+        for block in [
+            json!({ "bpm": 0.0 }),
+            json!({ "grid_division": 0 }),
+            json!({ "lane_strategy": "diagonal" }),
+            json!({ "bands": { "bass": { "low_hz": 200.0, "high_hz": 100.0 } } }),
+            json!({ "exclude": [[5.0, 1.0]] }),
+        ] {
+            assert!(SongSettings::from_metadata(&json!({ "charter": block })).is_err());
+        }
+    }
+}
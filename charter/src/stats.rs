@@ -0,0 +1,250 @@
+use crate::exporter::{ChartExport, NoteExport};
+use serde::{Deserialize, Serialize};
+
+/// Notes closer together than this are treated as a single chord row
+const CHORD_TOLERANCE: f32 = 0.001;
+/// Maximum gap between two rows for them to count as a jack or trill
+const PATTERN_WINDOW: f32 = 0.25;
+/// Window used for peak NPS (notes per second)
+const NPS_WINDOW: f32 = 1.0;
+
+/// Density and pattern statistics for a single chart
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ChartStats {
+    pub note_count: usize,
+    pub hold_count: usize,
+    pub duration: f32,          // seconds from first note to last note end
+    pub peak_nps: f32,          // most notes in any one-second window
+    pub average_nps: f32,       // note intervals divided by duration
+    pub lane_balance: Vec<f32>, // share of notes per lane (sums to 1.0)
+    pub jacks: usize,           // same lane hit in consecutive rows
+    pub trills: usize,          // A-B-A alternations between two lanes
+    pub chords: usize,          // rows with two or more simultaneous notes
+    pub hold_coverage: f32,     // share of the duration covered by holds (0.0-1.0)
+    pub rating: f32,            // single difficulty number, roughly 0-10+
+}
+
+impl ChartStats {
+    /// Compute statistics for a chart
+    pub fn compute(chart: &ChartExport) -> Self {
+        let mut notes: Vec<&NoteExport> = chart.notes.iter().collect();
+        notes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(std::cmp::Ordering::Equal));
+
+        let columns = chart.columns.max(1) as usize;
+        let mut stats = ChartStats {
+            lane_balance: vec![0.0; columns],
+            ..Default::default()
+        };
+
+        if notes.is_empty() {
+            return stats;
+        }
+
+        stats.note_count = notes.len();
+        stats.hold_count = notes.iter().filter(|n| n.duration > 0.001).count();
+
+        let start = notes[0].time;
+        let end = notes
+            .iter()
+            .map(|n| n.time + n.duration)
+            .fold(start, f32::max);
+        stats.duration = end - start;
+
+        stats.peak_nps = Self::peak_nps(&notes);
+        // Count intervals rather than notes so a steady stream matches its peak
+        stats.average_nps = if notes.len() > 1 && stats.duration > 0.0 {
+            (notes.len() - 1) as f32 / stats.duration
+        } else {
+            stats.peak_nps
+        };
+
+        // Lane balance
+        for note in &notes {
+            let lane = (note.col as usize).min(columns - 1);
+            stats.lane_balance[lane] += 1.0;
+        }
+        for share in &mut stats.lane_balance {
+            *share /= notes.len() as f32;
+        }
+
+        // Group notes into rows of simultaneous hits
+        let rows = Self::rows(&notes);
+        stats.chords = rows.iter().filter(|(_, cols)| cols.len() > 1).count();
+
+        for pair in rows.windows(2) {
+            let (t0, cols0) = &pair[0];
+            let (t1, cols1) = &pair[1];
+            if t1 - t0 <= PATTERN_WINDOW && cols1.iter().any(|c| cols0.contains(c)) {
+                stats.jacks += 1;
+            }
+        }
+
+        for triple in rows.windows(3) {
+            let (t0, a) = &triple[0];
+            let (_, b) = &triple[1];
+            let (t2, c) = &triple[2];
+            let single = a.len() == 1 && b.len() == 1 && c.len() == 1;
+            if single && t2 - t0 <= PATTERN_WINDOW * 2.0 && a[0] == c[0] && a[0] != b[0] {
+                stats.trills += 1;
+            }
+        }
+
+        stats.hold_coverage = if stats.duration > 0.0 {
+            (Self::hold_union(&notes) / stats.duration).min(1.0)
+        } else {
+            0.0
+        };
+
+        stats.rating = Self::rating(&stats, rows.len());
+        stats
+    }
+
+    /// Largest number of notes starting within any `NPS_WINDOW` span
+    fn peak_nps(notes: &[&NoteExport]) -> f32 {
+        let mut peak = 0;
+        let mut window_start = 0;
+        for (i, note) in notes.iter().enumerate() {
+            while note.time - notes[window_start].time >= NPS_WINDOW {
+                window_start += 1;
+            }
+            peak = peak.max(i - window_start + 1);
+        }
+        peak as f32 / NPS_WINDOW
+    }
+
+    /// Collapse time-sorted notes into (time, columns) rows
+    fn rows(notes: &[&NoteExport]) -> Vec<(f32, Vec<u8>)> {
+        let mut rows: Vec<(f32, Vec<u8>)> = Vec::new();
+        for note in notes {
+            match rows.last_mut() {
+                Some((time, cols)) if (note.time - *time).abs() <= CHORD_TOLERANCE => {
+                    if !cols.contains(&note.col) {
+                        cols.push(note.col);
+                    }
+                }
+                _ => rows.push((note.time, vec![note.col])),
+            }
+        }
+        rows
+    }
+
+    /// Total time covered by at least one hold
+    fn hold_union(notes: &[&NoteExport]) -> f32 {
+        let mut covered = 0.0;
+        let mut current: Option<(f32, f32)> = None;
+
+        for note in notes.iter().filter(|n| n.duration > 0.001) {
+            let (start, end) = (note.time, note.time + note.duration);
+            current = match current {
+                Some((s, e)) if start <= e => Some((s, e.max(end))),
+                Some((s, e)) => {
+                    covered += e - s;
+                    Some((start, end))
+                }
+                None => Some((start, end)),
+            };
+        }
+
+        if let Some((s, e)) = current {
+            covered += e - s;
+        }
+        covered
+    }
+
+    /// Combine density and pattern counts into a single difficulty number
+    fn rating(stats: &ChartStats, row_count: usize) -> f32 {
+        if row_count == 0 {
+            return 0.0;
+        }
+
+        let rows = row_count as f32;
+        let density = stats.average_nps * 0.6 + stats.peak_nps * 0.4;
+        let pattern = (stats.jacks as f32 * 1.5 + stats.trills as f32 + stats.chords as f32 * 1.2) / rows;
+        let holds = stats.hold_coverage * 0.5;
+
+        // Uneven lane usage is easier to read than a full spread
+        let max_share = stats.lane_balance.iter().cloned().fold(0.0f32, f32::max);
+        let spread = 1.0 - (max_share - 1.0 / stats.lane_balance.len() as f32).max(0.0);
+
+        let rating = density * spread * (1.0 + pattern) + holds;
+        (rating * 100.0).round() / 100.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beat_detection::Note;
+
+    fn chart(columns: u8, notes: &[(f32, u8, f32)]) -> ChartExport {
+        let notes = notes
+            .iter()
+            .map(|&(time, col, duration)| Note { time, col, duration })
+            .collect();
+        ChartExport::new(
+            "test_song".to_string(),
+            "drums".to_string(),
+            "Hard".to_string(),
+            columns,
+            120.0,
+            notes,
+        )
+    }
+
+    #[test]
+    fn test_empty_chart_stats() {
+        let stats = ChartStats::compute(&chart(4, &[]));
+        assert_eq!(stats.note_count, 0);
+        assert_eq!(stats.lane_balance.len(), 4);
+        assert_eq!(stats.rating, 0.0);
+    }
+
+    #[test]
+    fn test_nps_and_balance() {
+        // 8 notes over 2 seconds, cycling through 4 lanes
+        let notes: Vec<_> = (0..8).map(|i| (i as f32 * 0.25, (i % 4) as u8, 0.0)).collect();
+        let stats = ChartStats::compute(&chart(4, &notes));
+        assert_eq!(stats.note_count, 8);
+        assert_eq!(stats.peak_nps, 4.0);
+        assert!((stats.average_nps - 4.0).abs() < 0.01);
+        assert!(stats.lane_balance.iter().all(|&s| (s - 0.25).abs() < 0.001));
+        assert_eq!(stats.jacks, 0);
+    }
+
+    #[test]
+    fn test_pattern_counts() {
+        let stats = ChartStats::compute(&chart(
+            4,
+            &[
+                (0.0, 0, 0.0),
+                (0.0, 2, 0.0), // chord
+                (0.2, 2, 0.0), // jack on lane 2
+                (0.4, 1, 0.0),
+                (0.6, 2, 0.0), // trill 2-1-2
+                (0.8, 1, 0.0), // trill 1-2-1
+            ],
+        ));
+        assert_eq!(stats.chords, 1);
+        assert_eq!(stats.jacks, 1);
+        assert_eq!(stats.trills, 2);
+    }
+
+    #[test]
+    fn test_hold_coverage_merges_overlaps() {
+        let stats = ChartStats::compute(&chart(
+            4,
+            &[(0.0, 0, 1.0), (0.5, 1, 1.0), (3.0, 2, 0.0), (4.0, 3, 0.0)],
+        ));
+        assert_eq!(stats.hold_count, 2);
+        assert!((stats.hold_coverage - 1.5 / 4.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_denser_chart_rates_higher() {
+        let sparse: Vec<_> = (0..8).map(|i| (i as f32, (i % 4) as u8, 0.0)).collect();
+        let dense: Vec<_> = (0..32).map(|i| (i as f32 * 0.25, (i % 4) as u8, 0.0)).collect();
+        let sparse = ChartStats::compute(&chart(4, &sparse));
+        let dense = ChartStats::compute(&chart(4, &dense));
+        assert!(dense.rating > sparse.rating);
+    }
+}
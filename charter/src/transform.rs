@@ -0,0 +1,217 @@
+use crate::beat_detection::Note;
+use crate::exporter::{ChartExport, NoteExport};
+use crate::lane_assigner::{LaneAssigner, LaneAssignmentStrategy};
+use crate::quantizer::Quantizer;
+use anyhow::{Result, anyhow, bail};
+
+/// A single edit applied to an existing chart (no audio needed)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transform {
+    /// Move every note by this many seconds; notes pushed before 0s are dropped
+    Shift(f32),
+    /// Snap notes to a 1/`grid_division` beat grid, optionally at a new BPM
+    Quantize { grid_division: u8, bpm: Option<f32> },
+    /// Flip lanes left-to-right
+    Mirror,
+    /// Convert to a different column count (e.g. 5K to 4K)
+    Columns(u8),
+    /// Turn every hold into a tap
+    StripHolds,
+}
+
+impl Transform {
+    /// Parse one operation:
+    /// `shift:<ms>`, `quantize:<grid>[:<bpm>]`, `mirror`, `columns:<n>` or `strip-holds`
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut parts = s.trim().split(':');
+        let name = parts.next().unwrap_or("").to_lowercase();
+        let args: Vec<&str> = parts.collect();
+
+        let transform = match (name.as_str(), args.as_slice()) {
+            ("shift", [ms]) => Transform::Shift(ms.parse::<f32>()? / 1000.0),
+            ("quantize", [grid]) => Transform::Quantize {
+                grid_division: grid.parse()?,
+                bpm: None,
+            },
+            ("quantize", [grid, bpm]) => Transform::Quantize {
+                grid_division: grid.parse()?,
+                bpm: Some(bpm.parse()?),
+            },
+            ("mirror", []) => Transform::Mirror,
+            ("columns", [n]) => Transform::Columns(n.parse()?),
+            ("strip-holds", []) => Transform::StripHolds,
+            _ => bail!("Unknown transform: {}", s),
+        };
+
+        match transform {
+            Transform::Quantize { grid_division: 0, .. } => Err(anyhow!("Grid division must be at least 1")),
+            Transform::Quantize { bpm: Some(bpm), .. } if !valid_bpm(bpm) => Err(anyhow!("BPM must be positive")),
+            Transform::Columns(n) if !(1..=8).contains(&n) => Err(anyhow!("Columns must be between 1 and 8")),
+            t => Ok(t),
+        }
+    }
+
+    /// Apply this operation to the chart's notes. Fails, leaving the chart alone, when
+    /// quantizing to the chart's own BPM and that is not usable.
+    pub fn apply(&self, chart: &mut ChartExport) -> Result<()> {
+        let notes = to_notes(chart);
+
+        let notes = match self {
+            Transform::Shift(seconds) => {
+                let before = notes.len();
+                let shifted: Vec<Note> = notes
+                    .into_iter()
+                    .map(|mut n| {
+                        n.time += seconds;
+                        n
+                    })
+                    .filter(|n| n.time >= 0.0)
+                    .collect();
+                if shifted.len() < before {
+                    log::warn!("Shift dropped {} notes that moved before 0s", before - shifted.len());
+                }
+                shifted
+            }
+            Transform::Quantize { grid_division, bpm } => {
+                let bpm = bpm.unwrap_or(chart.bpm);
+                if !valid_bpm(bpm) {
+                    bail!("Cannot quantize to the chart's BPM of {}; give one as quantize:<grid>:<bpm>", bpm);
+                }
+                chart.bpm = bpm;
+                Quantizer::new(chart.bpm, 0, *grid_division).snap_notes(notes)
+            }
+            Transform::Mirror => {
+                LaneAssigner::new(LaneAssignmentStrategy::Sequential, chart.columns).mirror_lanes(notes)
+            }
+            Transform::Columns(columns) => {
+                let from = chart.columns;
+                chart.columns = *columns;
+                LaneAssigner::new(LaneAssignmentStrategy::Sequential, *columns).convert_lanes(notes, from)
+            }
+            Transform::StripHolds => notes
+                .into_iter()
+                .map(|mut n| {
+                    n.duration = 0.0;
+                    n
+                })
+                .collect(),
+        };
+
+        chart.notes = notes
+            .into_iter()
+            .map(|n| NoteExport {
+                time: n.time,
+                col: n.col,
+                duration: n.duration,
+            })
+            .collect();
+        Ok(())
+    }
+}
+
+/// Apply operations in order and refresh the chart's stats
+pub fn apply_all(chart: &mut ChartExport, transforms: &[Transform]) -> Result<()> {
+    for transform in transforms {
+        log::info!("Applying {:?}", transform);
+        transform.apply(chart)?;
+    }
+    chart.refresh_stats();
+    Ok(())
+}
+
+fn valid_bpm(bpm: f32) -> bool {
+    bpm.is_finite() && bpm > 0.0
+}
+
+fn to_notes(chart: &ChartExport) -> Vec<Note> {
+    chart
+        .notes
+        .iter()
+        .map(|n| Note {
+            time: n.time,
+            col: n.col,
+            duration: n.duration,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chart(columns: u8, notes: &[(f32, u8, f32)]) -> ChartExport {
+        let notes = notes
+            .iter()
+            .map(|&(time, col, duration)| Note { time, col, duration })
+            .collect();
+        ChartExport::new(
+            "test_song".to_string(),
+            "lead".to_string(),
+            "Expert".to_string(),
+            columns,
+            120.0,
+            notes,
+        )
+    }
+
+    #[test]
+    fn test_parse_transforms() {
+        assert_eq!(Transform::parse("shift:-25").unwrap(), Transform::Shift(-0.025));
+        assert_eq!(
+            Transform::parse("quantize:8:140").unwrap(),
+            Transform::Quantize { grid_division: 8, bpm: Some(140.0) }
+        );
+        assert_eq!(Transform::parse("mirror").unwrap(), Transform::Mirror);
+        assert_eq!(Transform::parse("columns:4").unwrap(), Transform::Columns(4));
+        assert_eq!(Transform::parse("strip-holds").unwrap(), Transform::StripHolds);
+        assert!(Transform::parse("quantize:0").is_err());
+        assert!(Transform::parse("columns:0").is_err());
+        assert!(Transform::parse("explode").is_err());
+    }
+
+    #[test]
+    fn test_shift_drops_negative_notes() {
+        let mut c = chart(4, &[(0.01, 0, 0.0), (1.0, 1, 0.0)]);
+        Transform::Shift(-0.05).apply(&mut c).unwrap();
+        assert_eq!(c.notes.len(), 1);
+        assert!((c.notes[0].time - 0.95).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_quantize_to_new_bpm() {
+        let mut c = chart(4, &[(0.48, 0, 0.0), (0.97, 1, 0.0)]);
+        Transform::Quantize { grid_division: 1, bpm: Some(60.0) }.apply(&mut c).unwrap();
+        assert_eq!(c.bpm, 60.0);
+        assert_eq!(c.notes[0].time, 0.0);
+        assert_eq!(c.notes[1].time, 1.0);
+    }
+
+    #[test]
+    fn test_quantize_needs_a_usable_bpm() {
+        assert!(Transform::parse("quantize:4:nan").is_err());
+        for bpm in [0.0, -120.0, f32::NAN, f32::INFINITY] {
+            let mut c = chart(4, &[(0.48, 0, 0.0)]);
+            c.bpm = bpm;
+            assert!(Transform::Quantize { grid_division: 4, bpm: None }.apply(&mut c).is_err());
+            assert_eq!(c.notes[0].time, 0.48);
+        }
+    }
+
+    #[test]
+    fn test_pipeline_converts_and_refreshes_stats() {
+        let mut c = chart(5, &[(0.0, 4, 0.5), (1.0, 0, 0.0)]);
+        apply_all(
+            &mut c,
+            &[Transform::Columns(4), Transform::Mirror, Transform::StripHolds],
+        )
+        .unwrap();
+
+        assert_eq!(c.columns, 4);
+        assert_eq!(c.notes[0].col, 0);
+        assert_eq!(c.notes[1].col, 3);
+        assert!(c.notes.iter().all(|n| n.duration == 0.0));
+        let stats = c.stats.unwrap();
+        assert_eq!(stats.lane_balance.len(), 4);
+        assert_eq!(stats.hold_count, 0);
+    }
+}
@@ -0,0 +1,132 @@
+//! Synthetic audio for charter integration tests. Everything is generated in memory
+//! at known BPMs and offsets so the expected chart is known up front.
+
+#![allow(dead_code)]
+
+use rhythm_pi_charter::audio::AudioData;
+use std::f32::consts::PI;
+use std::path::{Path, PathBuf};
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// Mono audio of the given length, silent
+pub fn silence(seconds: f32) -> Vec<f32> {
+    vec![0.0; (seconds * SAMPLE_RATE as f32) as usize]
+}
+
+/// Add `f(t)` for `t` in `[0, length)` seconds starting at `start`
+pub fn mix_at(buffer: &mut [f32], start: f32, length: f32, f: impl Fn(f32) -> f32) {
+    let first = (start * SAMPLE_RATE as f32) as usize;
+    let count = (length * SAMPLE_RATE as f32) as usize;
+    for i in 0..count {
+        if let Some(sample) = buffer.get_mut(first + i) {
+            *sample += f(i as f32 / SAMPLE_RATE as f32);
+        }
+    }
+}
+
+/// Beat times for `bpm` starting at `offset`, up to `seconds`
+pub fn beat_times(bpm: f32, offset: f32, seconds: f32) -> Vec<f32> {
+    let beat = 60.0 / bpm;
+    (0..)
+        .map(|i| offset + i as f32 * beat)
+        .take_while(|&t| t < seconds)
+        .collect()
+}
+
+/// Short decaying 440 Hz bursts on every beat
+pub fn click_track(bpm: f32, offset: f32, seconds: f32) -> Vec<f32> {
+    let mut buffer = silence(seconds);
+    for t in beat_times(bpm, offset, seconds) {
+        mix_at(&mut buffer, t, 0.1, |x| 0.6 * (2.0 * PI * 440.0 * x).sin() * (-x * 30.0).exp());
+    }
+    buffer
+}
+
+/// Slowly decaying tones of `frequency` starting at each time in `starts`
+pub fn tones(starts: &[f32], frequency: f32, length: f32, seconds: f32) -> Vec<f32> {
+    let mut buffer = silence(seconds);
+    for &t in starts {
+        mix_at(&mut buffer, t, length, |x| {
+            let attack = (x / 0.005).min(1.0);
+            let release = ((length - x) / 0.02).min(1.0);
+            0.6 * attack * release * (-x * 0.8).exp() * (2.0 * PI * frequency * x).sin()
+        });
+    }
+    buffer
+}
+
+/// Deterministic white noise in [-1, 1]
+pub struct Noise(u64);
+
+impl Noise {
+    pub fn new(seed: u64) -> Self {
+        Noise(seed)
+    }
+
+    pub fn next(&mut self) -> f32 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((self.0 >> 40) as f32 / (1u64 << 24) as f32) * 2.0 - 1.0
+    }
+}
+
+/// Rock beat: kick on every beat, snare on 2 and 4, quiet hi-hat on eighths
+pub fn drum_pattern(bpm: f32, offset: f32, seconds: f32) -> Vec<f32> {
+    let mut buffer = silence(seconds);
+    let mut noise = Noise::new(1);
+    let beat = 60.0 / bpm;
+
+    for (i, t) in beat_times(bpm, offset, seconds).into_iter().enumerate() {
+        // Kick: pitch-dropping sine from 120 Hz down to 50 Hz
+        mix_at(&mut buffer, t, 0.15, |x| {
+            let freq = 50.0 + 70.0 * (-x * 40.0).exp();
+            0.8 * (2.0 * PI * freq * x).sin() * (-x * 20.0).exp()
+        });
+
+        if i % 2 == 1 {
+            let snare: Vec<f32> = (0..(0.12 * SAMPLE_RATE as f32) as usize).map(|_| noise.next()).collect();
+            mix_at(&mut buffer, t, 0.12, |x| {
+                let idx = ((x * SAMPLE_RATE as f32) as usize).min(snare.len() - 1);
+                0.4 * snare[idx] * (-x * 35.0).exp()
+            });
+        }
+
+        let hat: Vec<f32> = (0..(0.03 * SAMPLE_RATE as f32) as usize).map(|_| noise.next()).collect();
+        for hat_time in [t, t + beat / 2.0] {
+            mix_at(&mut buffer, hat_time, 0.03, |x| {
+                let idx = ((x * SAMPLE_RATE as f32) as usize).min(hat.len() - 1);
+                0.05 * hat[idx] * (-x * 150.0).exp()
+            });
+        }
+    }
+    buffer
+}
+
+pub fn mono(samples: Vec<f32>) -> AudioData {
+    AudioData {
+        samples,
+        sample_rate: SAMPLE_RATE,
+        channels: 1,
+    }
+}
+
+/// Write audio as a 16-bit WAV (for tests that go through the file loader)
+pub fn write_wav(path: &Path, audio: &AudioData) {
+    let spec = hound::WavSpec {
+        channels: audio.channels,
+        sample_rate: audio.sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec).expect("create wav");
+    for &sample in &audio.samples {
+        writer
+            .write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+            .expect("write sample");
+    }
+    writer.finalize().expect("finalize wav");
+}
+
+pub fn temp_wav(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rhythm-pi-charter-{}-{}.wav", name, std::process::id()))
+}
@@ -0,0 +1,72 @@
+//! Chart generation must be byte-identical for the same audio, config and seed.
+//!
+//! Golden files live in `tests/golden`. After an intentional algorithm change,
+//! regenerate them with `UPDATE_GOLDEN=1 cargo test -p rhythm-pi-charter --test determinism`.
+
+use rhythm_pi_charter::exporter::GeneratedAt;
+use rhythm_pi_charter::lane_assigner::LaneAssignmentStrategy;
+use rhythm_pi_charter::{Charter, CharterConfig};
+use std::path::Path;
+
+mod common;
+
+fn write_click_track(path: &Path, bpm: f32, seconds: f32) {
+    common::write_wav(path, &common::mono(common::click_track(bpm, 0.0, seconds)));
+}
+
+fn config() -> CharterConfig {
+    CharterConfig {
+        lane_strategy: LaneAssignmentStrategy::Random,
+        seed: 7,
+        generated_at: GeneratedAt::Omit,
+        ..CharterConfig::default()
+    }
+}
+
+fn generate(audio: &Path, config: CharterConfig) -> Vec<String> {
+    Charter::new(config)
+        .generate_all_difficulties(audio, "click_120", "drums")
+        .expect("generate charts")
+        .iter()
+        .map(|chart| chart.to_json().expect("serialize chart"))
+        .collect()
+}
+
+#[test]
+fn same_seed_gives_identical_charts() {
+    let audio = common::temp_wav("same-seed");
+    write_click_track(&audio, 120.0, 8.0);
+
+    let first = generate(&audio, config());
+    let second = generate(&audio, config());
+    let reseeded = generate(&audio, CharterConfig { seed: 8, ..config() });
+    std::fs::remove_file(&audio).ok();
+
+    assert_eq!(first, second);
+    assert_ne!(first, reseeded);
+    assert!(first.iter().all(|json| !json.contains("generated_at")));
+}
+
+#[test]
+fn matches_golden_files() {
+    let audio = common::temp_wav("golden");
+    write_click_track(&audio, 120.0, 8.0);
+    let charts = generate(&audio, config());
+    std::fs::remove_file(&audio).ok();
+
+    let golden_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let difficulties = ["easy", "normal", "hard", "expert"];
+    assert_eq!(charts.len(), difficulties.len());
+
+    for (json, difficulty) in charts.iter().zip(difficulties) {
+        let path = golden_dir.join(format!("click_120_drums_{}.json", difficulty));
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(&path, json).expect("write golden file");
+            continue;
+        }
+
+        let expected = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("missing golden file {}: {}", path.display(), e));
+        assert_eq!(json, &expected, "{} differs from golden output", path.display());
+    }
+}
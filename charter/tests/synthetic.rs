@@ -0,0 +1,217 @@
+//! End-to-end checks of the charter pipeline on synthetic audio with known timing.
+//! Run these after any change to detection, quantization, lanes or holds.
+
+mod common;
+
+use common::{beat_times, click_track, drum_pattern, mono, tones};
+use rhythm_pi_charter::exporter::ChartExport;
+use rhythm_pi_charter::lane_assigner::LaneAssignmentStrategy;
+use rhythm_pi_charter::{Charter, CharterConfig, DIFFICULTIES};
+
+/// Detection jitter allowed on top of the quantization error
+const DETECTION_TOLERANCE: f32 = 0.025;
+
+fn generate(samples: Vec<f32>, instrument: &str, config: CharterConfig) -> Vec<ChartExport> {
+    Charter::new(config)
+        .generate_from_audio(&mono(samples), "synthetic", instrument)
+        .expect("generate charts")
+}
+
+fn chart<'a>(charts: &'a [ChartExport], difficulty: &str) -> &'a ChartExport {
+    charts
+        .iter()
+        .find(|c| c.difficulty == difficulty)
+        .unwrap_or_else(|| panic!("no {} chart", difficulty))
+}
+
+/// Half a grid step (the most quantization can move a note) plus detection jitter
+fn tolerance(bpm: f32, grid_division: u8) -> f32 {
+    60.0 / bpm / grid_division as f32 / 2.0 + DETECTION_TOLERANCE
+}
+
+fn nearest(times: &[f32], t: f32) -> f32 {
+    times.iter().map(|x| (x - t).abs()).fold(f32::INFINITY, f32::min)
+}
+
+#[test]
+fn detects_click_track_bpm() {
+    for bpm in [90.0, 120.0, 150.0] {
+        let charts = generate(click_track(bpm, 0.0, 10.0), "drums", CharterConfig::default());
+        for chart in &charts {
+            assert!(
+                (chart.bpm - bpm).abs() < 1.0,
+                "{} BPM click detected as {} ({})",
+                bpm,
+                chart.bpm,
+                chart.difficulty
+            );
+        }
+    }
+}
+
+#[test]
+fn click_notes_land_on_beats() {
+    for (bpm, offset) in [(120.0, 0.3), (150.0, 0.1), (90.0, 0.0)] {
+        let config = CharterConfig::default();
+        let tol = tolerance(bpm, config.grid_division);
+        let charts = generate(click_track(bpm, offset, 10.0), "lead", config);
+        let hard = chart(&charts, "Hard");
+        let beats = beat_times(bpm, offset, 10.0);
+
+        // Every note is on a click...
+        for note in &hard.notes {
+            assert!(
+                nearest(&beats, note.time) <= tol,
+                "note at {:.3}s is not on a beat ({} BPM, offset {})",
+                note.time,
+                bpm,
+                offset
+            );
+        }
+
+        // ...and every click after the first frame has a note
+        let times: Vec<f32> = hard.notes.iter().map(|n| n.time).collect();
+        for &beat in beats.iter().filter(|&&b| b > 0.05 && b < 9.9) {
+            assert!(
+                nearest(&times, beat) <= tol,
+                "missing note for click at {:.3}s ({} BPM)",
+                beat,
+                bpm
+            );
+        }
+    }
+}
+
+#[test]
+fn drum_pattern_follows_kicks() {
+    let bpm = 100.0;
+    let offset = 0.2;
+    let config = CharterConfig::default();
+    let tol = tolerance(bpm, config.grid_division);
+    let beats = beat_times(bpm, offset, 10.0);
+
+    for instrument in ["drums", "bass"] {
+        let charts = generate(drum_pattern(bpm, offset, 10.0), instrument, config.clone());
+        let hard = chart(&charts, "Hard");
+        assert!((hard.bpm - bpm).abs() < 1.0, "{}: detected {} BPM", instrument, hard.bpm);
+
+        for note in &hard.notes {
+            assert!(
+                nearest(&beats, note.time) <= tol,
+                "{}: note at {:.3}s is off the kick pattern",
+                instrument,
+                note.time
+            );
+        }
+        assert!(hard.notes.len() >= beats.len() - 2, "{}: too few notes", instrument);
+    }
+}
+
+#[test]
+fn lanes_stay_in_range() {
+    let strategies = [
+        LaneAssignmentStrategy::Sequential,
+        LaneAssignmentStrategy::Random,
+        LaneAssignmentStrategy::FrequencyBased {
+            low_hz: 100.0,
+            mid_hz: 500.0,
+            high_hz: 2000.0,
+        },
+    ];
+
+    for lane_strategy in strategies {
+        let config = CharterConfig {
+            lane_strategy: lane_strategy.clone(),
+            seed: 3,
+            ..CharterConfig::default()
+        };
+        let charts = generate(drum_pattern(128.0, 0.0, 8.0), "drums", config);
+        assert_eq!(charts.len(), DIFFICULTIES.len());
+
+        for (chart, &(difficulty, columns)) in charts.iter().zip(DIFFICULTIES.iter()) {
+            assert_eq!(chart.difficulty, difficulty);
+            assert_eq!(chart.columns, columns);
+            assert!(
+                chart.notes.iter().all(|n| n.col < columns),
+                "{:?}: {} chart has a note outside {} columns",
+                lane_strategy,
+                difficulty,
+                columns
+            );
+        }
+    }
+}
+
+#[test]
+fn sustained_tones_become_holds() {
+    let starts = [0.5, 2.5, 4.5, 6.5];
+    let config = CharterConfig {
+        bpm: Some(120.0),
+        ..CharterConfig::default()
+    };
+    let tol = tolerance(120.0, config.grid_division);
+    let charts = generate(tones(&starts, 440.0, 1.5, 9.0), "vocals", config);
+
+    for chart in &charts {
+        for &start in &starts {
+            if let Some(note) = chart.notes.iter().find(|n| (n.time - start).abs() <= tol) {
+                assert!(
+                    note.duration >= 0.5,
+                    "{}: tone at {}s charted as {:.2}s hold",
+                    chart.difficulty,
+                    start,
+                    note.duration
+                );
+            }
+        }
+    }
+
+    let hard = chart(&charts, "Hard");
+    assert_eq!(hard.notes.iter().filter(|n| n.duration > 0.0).count(), starts.len());
+}
+
+#[test]
+fn clicks_do_not_become_holds() {
+    let charts = generate(click_track(120.0, 0.0, 8.0), "drums", CharterConfig::default());
+    for chart in &charts {
+        assert!(
+            chart.notes.iter().all(|n| n.duration == 0.0),
+            "{} chart has holds on a click track",
+            chart.difficulty
+        );
+    }
+}
+
+#[test]
+fn difficulties_increase_monotonically() {
+    let inputs = [
+        ("drums", drum_pattern(100.0, 0.2, 10.0)),
+        ("lead", click_track(150.0, 0.1, 10.0)),
+        ("vocals", tones(&[0.5, 2.5, 4.5, 6.5], 440.0, 1.5, 9.0)),
+    ];
+
+    for (instrument, samples) in inputs {
+        let charts = generate(samples, instrument, CharterConfig::default());
+        for pair in charts.windows(2) {
+            let (easier, harder) = (&pair[0], &pair[1]);
+            let rating = |c: &ChartExport| c.stats.as_ref().map(|s| s.rating).unwrap_or(0.0);
+
+            assert!(
+                easier.notes.len() <= harder.notes.len(),
+                "{}: {} has more notes than {}",
+                instrument,
+                easier.difficulty,
+                harder.difficulty
+            );
+            assert!(
+                rating(easier) <= rating(harder),
+                "{}: {} rated {} above {} at {}",
+                instrument,
+                easier.difficulty,
+                rating(easier),
+                harder.difficulty,
+                rating(harder)
+            );
+        }
+    }
+}
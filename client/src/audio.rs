@@ -1,4 +1,5 @@
 use anyhow::Result;
+use rodio::buffer::SamplesBuffer;
 use rodio::{Decoder, OutputStream, Sink};
 use std::io::Cursor;
 use std::sync::{Arc, Mutex};
@@ -33,6 +34,14 @@ impl AudioContext {
         Ok(())
     }
     
+    /// Queue raw interleaved samples to play after whatever is queued already, so
+    /// decoded audio can be played as it streams in
+    pub fn queue_pcm(&self, channels: u16, sample_rate: u32, samples: Vec<i16>) {
+        let sink = self.sink.lock().unwrap();
+        sink.append(SamplesBuffer::new(channels, sample_rate, samples));
+        sink.play();
+    }
+    
     /// Check if audio is playing
     pub fn is_playing(&self) -> bool {
         if let Ok(sink) = self.sink.lock() {
@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use log::info;
 
+pub use rhythm_pi_scoring::{ChartNote, HitAccuracy, Replay};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Song {
     pub id: String,
@@ -9,15 +11,6 @@ pub struct Song {
     pub bpm: f32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ChartNote {
-    pub time: f32,
-    #[serde(alias = "fret")]
-    pub col: u32,
-    #[serde(default)]
-    pub duration: f32,
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Chart {
     #[serde(default)]
@@ -36,6 +29,9 @@ pub struct Chart {
     pub notes: Vec<ChartNote>,
     #[serde(default)]
     pub offset: Option<f32>,
+    /// `rhythm_pi_scoring::chart_hash` of the file this chart was loaded from
+    #[serde(skip)]
+    pub hash: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -55,37 +51,6 @@ impl Chart {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum HitAccuracy {
-    Perfect,   // ±50ms - 300 points
-    Great,     // ±100ms - 200 points
-    Good,      // ±150ms - 100 points
-    Ok,        // ±200ms - 50 points
-    Miss,      // Outside window - 0 points
-}
-
-impl HitAccuracy {
-    pub fn points(&self) -> u32 {
-        match self {
-            Self::Perfect => 300,
-            Self::Great => 200,
-            Self::Good => 100,
-            Self::Ok => 50,
-            Self::Miss => 0,
-        }
-    }
-
-    pub fn combo_multiplier(&self) -> f32 {
-        match self {
-            Self::Perfect => 2.0,
-            Self::Great => 1.5,
-            Self::Good => 1.0,
-            Self::Ok => 0.5,
-            Self::Miss => 0.0,
-        }
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct HitEvent {
     pub note_index: usize,
@@ -106,6 +71,7 @@ pub struct GameState {
     pub notes_hit: Vec<HitEvent>,
     pub is_playing: bool,
     pub is_paused: bool,
+    pub replay: Replay,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -129,23 +95,12 @@ impl GameState {
             notes_hit: Vec::new(),
             is_playing: false,
             is_paused: false,
+            replay: Replay::new(),
         }
     }
 
     pub fn record_hit(&mut self, note_index: usize, note: &ChartNote, hit_time: f32) -> HitAccuracy {
-        let time_diff = (hit_time - note.time).abs();
-        
-        let accuracy = if time_diff <= 0.05 {
-            HitAccuracy::Perfect
-        } else if time_diff <= 0.1 {
-            HitAccuracy::Great
-        } else if time_diff <= 0.15 {
-            HitAccuracy::Good
-        } else if time_diff <= 0.2 {
-            HitAccuracy::Ok
-        } else {
-            HitAccuracy::Miss
-        };
+        let accuracy = HitAccuracy::judge(hit_time - note.time);
 
         // Update accuracy counts
         match accuracy {
@@ -161,11 +116,7 @@ impl GameState {
         }
 
         // Award points
-        let base_points = accuracy.points();
-        let combo_bonus = (self.combo as f32 * 0.1).min(100.0); // Max 100 bonus points
-        let total_points = (base_points as f32 + combo_bonus) as u32;
-
-        self.score += total_points;
+        self.score += accuracy.points_with_combo(self.combo);
 
         // Update combo
         if accuracy != HitAccuracy::Miss {
@@ -2,8 +2,10 @@ pub mod audio;
 pub mod websocket;
 pub mod game;
 pub mod input;
+pub mod scores;
 
 pub use audio::AudioContext;
 pub use websocket::WebSocketClient;
 pub use game::{Song, Chart, Note, GameState, HitAccuracy, HitEvent, ChartNote};
 pub use input::{InputHandler, KeyBindings, InputEvent};
+pub use scores::{ScoreSubmission, submit_score};
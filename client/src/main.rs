@@ -10,6 +10,7 @@ mod audio;
 mod websocket;
 mod game;
 mod input;
+mod scores;
 
 slint::include_modules!();
 
@@ -60,56 +61,173 @@ fn fetch_chart_from_server(server_url: &str, song_id: &str, instrument: &str, di
         .timeout(std::time::Duration::from_secs(5))
         .call()?;
     
-    let chart: game::Chart = response.into_json()?;
+    // hash the exact bytes so a score can name the chart it was played on
+    let body = response.into_string()?;
+    let mut chart: game::Chart = serde_json::from_str(&body)?;
+    chart.hash = Some(rhythm_pi_scoring::chart_hash(body.as_bytes()));
     
     Ok(chart)
 }
 
-fn stream_audio_from_websocket(ws_url: &str, song_id: &str) -> Result<Vec<u8>> {
-    use tokio_tungstenite::{connect_async, tungstenite::Message};
-    use futures::{SinkExt, StreamExt};
+/// Rate decoded audio is asked for at, when the server does the decoding
+const PCM_SAMPLE_RATE: u32 = 44100;
+
+/// Have the server decode the song to 16-bit stereo, from `from_ms` into it when
+/// practising, and hand each block to `play` with the index of its first sample frame as
+/// it arrives, so playback starts before the song is all here and nothing is decoded locally
+fn stream_pcm_from_websocket(ws_url: &str, song_id: &str, from_ms: Option<u64>, mut play: impl FnMut(u16, u32, u64, Vec<i16>)) -> Result<()> {
+    use websocket::{AudioStreamClient, PcmEncoding, PcmFormat, ServerMessage};
     
-    let url = format!("{}/ws/audio/{}", ws_url, song_id);
-    info!("Connecting to WebSocket: {}", url);
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        // decoding the file as uploaded sounds best and costs no more bytes
+        let mut client = AudioStreamClient::connect(ws_url, song_id, Some("original")).await?;
+        let format = PcmFormat { encoding: PcmEncoding::S16le, sample_rate: PCM_SAMPLE_RATE, channels: 2 };
+        client.request_pcm(format)?;
+        match from_ms {
+            Some(ms) => client.start_at_ms(ms)?,
+            None => client.start_stream()?,
+        }
+        
+        let mut play_chunk = |chunk: websocket::AudioChunk| {
+            let samples = chunk.data.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+            play(format.channels, format.sample_rate, chunk.frame.unwrap_or(0), samples);
+        };
+        loop {
+            // waiting on chunks a little at a time leaves room to notice `complete`
+            match tokio::time::timeout(Duration::from_millis(100), client.recv_audio_chunk_async()).await {
+                Ok(Some(chunk)) => play_chunk(chunk),
+                Ok(None) => anyhow::bail!("audio stream closed early"),
+                Err(_) => {}
+            }
+            while let Some(status) = client.recv_status() {
+                match status {
+                    ServerMessage::Complete { frames, .. } => {
+                        // every chunk is queued before the server's `complete` is seen
+                        for chunk in client.recv_all_chunks() {
+                            play_chunk(chunk);
+                        }
+                        info!("Decoded audio stream complete in {} chunks", frames);
+                        client.stop_stream()?;
+                        return Ok(());
+                    }
+                    ServerMessage::Error { error } => anyhow::bail!("audio stream failed: {}", error),
+                    _ => {}
+                }
+            }
+        }
+    })
+}
+
+/// When a song would have started for audio from `ms` into it to be starting now
+fn started_at(ms: u64) -> Instant {
+    let now = Instant::now();
+    now.checked_sub(Duration::from_millis(ms)).unwrap_or(now)
+}
+
+/// Times a dropped audio download is picked up again before giving up
+const AUDIO_RECONNECTS: u32 = 3;
+
+/// The song's audio as downloaded so far
+struct Download {
+    audio: Vec<u8>,
+    /// Where in the file the next bytes go; None until audio from the file has arrived
+    next_offset: Option<u64>,
+    /// Time the audio starts at
+    start_ms: u64,
+}
+
+impl Download {
+    fn take(&mut self, chunk: websocket::AudioChunk) {
+        if let Some(offset) = chunk.offset {
+            self.next_offset = Some(offset + chunk.data.len() as u64);
+        }
+        self.audio.extend_from_slice(&chunk.data);
+    }
+}
+
+/// Download the song's audio, from `from_ms` into it when practising. Returns the audio and
+/// the time it starts at. A dropped connection is made again and the download carries on
+/// from the last byte received.
+fn stream_audio_from_websocket(ws_url: &str, song_id: &str, from_ms: Option<u64>) -> Result<(Vec<u8>, u64)> {
+    use websocket::{AudioStreamClient, ServerMessage};
+    
+    // the server sends a compact transcoded variant; AUDIO_QUALITY=original gets the
+    // file as uploaded
+    let quality = std::env::var("AUDIO_QUALITY").unwrap_or_else(|_| "low".to_string());
     
     // Create a new runtime for this blocking operation
     let rt = tokio::runtime::Runtime::new()?;
     
     rt.block_on(async {
-        let (ws_stream, _) = connect_async(&url).await?;
-        let (mut write, mut read) = ws_stream.split();
-        
-        // Send start command
-        write.send(Message::Text("start".to_string())).await?;
-        
-        let mut audio_data = Vec::new();
-        let mut chunk_count = 0;
-        
-        while let Some(msg) = read.next().await {
-            match msg? {
-                Message::Binary(data) => {
-                    chunk_count += 1;
-                    audio_data.extend_from_slice(&data);
-                    if chunk_count % 10 == 0 {
-                        info!("Received {} audio chunks", chunk_count);
-                    }
+        let mut download = Download { audio: Vec::new(), next_offset: None, start_ms: 0 };
+        // the file first connected to, so a resumed download is not spliced into another
+        let mut file = None;
+        let mut reconnects = 0;
+        loop {
+            let mut client = match AudioStreamClient::connect(ws_url, song_id, Some(&quality)).await {
+                Ok(client) => client,
+                Err(e) if file.is_some() && reconnects < AUDIO_RECONNECTS => {
+                    log::warn!("Cannot reconnect to the audio stream: {}", e);
+                    reconnects += 1;
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
                 }
-                Message::Text(text) => {
-                    info!("Server message: {}", text);
-                    if text.contains("complete") {
-                        info!("Audio stream complete: {} total bytes", audio_data.len());
-                        break;
+                Err(e) => return Err(e),
+            };
+            let this_file = (client.meta.quality.clone(), client.meta.total_bytes);
+            if file.as_ref().is_some_and(|f| *f != this_file) {
+                info!("Song audio changed while downloading, starting again");
+                download = Download { audio: Vec::new(), next_offset: None, start_ms: 0 };
+            }
+            file = Some(this_file);
+            match (download.next_offset, from_ms) {
+                (Some(offset), _) => client.start_at_bytes(offset)?,
+                (None, Some(ms)) => {
+                    // header bytes from a cut-off attempt are sent again
+                    download.audio.clear();
+                    client.start_at_ms(ms)?
+                }
+                (None, None) => {
+                    download.audio.clear();
+                    client.start_stream()?
+                }
+            }
+            
+            loop {
+                // waiting on chunks a little at a time leaves room to notice `complete`
+                let chunk = tokio::time::timeout(Duration::from_millis(100), client.recv_audio_chunk_async()).await;
+                let closed = matches!(chunk, Ok(None));
+                if let Ok(Some(chunk)) = chunk {
+                    download.take(chunk);
+                }
+                while let Some(status) = client.recv_status() {
+                    match status {
+                        ServerMessage::Started { start_ms: Some(ms), .. } => download.start_ms = ms,
+                        ServerMessage::Complete { .. } => {
+                            // every chunk is queued before the server's `complete` is seen
+                            for chunk in client.recv_all_chunks() {
+                                download.take(chunk);
+                            }
+                            info!("Audio stream complete: {} total bytes", download.audio.len());
+                            // the server keeps the stream open for seeks until told otherwise
+                            client.stop_stream().ok();
+                            return Ok((download.audio, download.start_ms));
+                        }
+                        ServerMessage::Error { error } => anyhow::bail!("audio stream failed: {}", error),
+                        _ => {}
                     }
                 }
-                Message::Close(_) => {
-                    info!("Connection closed");
+                if closed {
                     break;
                 }
-                _ => {}
             }
+            if reconnects >= AUDIO_RECONNECTS {
+                anyhow::bail!("audio stream kept closing early");
+            }
+            reconnects += 1;
+            log::warn!("Audio stream closed after {} bytes, reconnecting", download.audio.len());
         }
-        
-        Ok::<Vec<u8>, anyhow::Error>(audio_data)
     })
 }
 
@@ -124,6 +242,11 @@ fn main() -> Result<()> {
     // Server configuration
     let server_url = std::env::var("SERVER_URL").unwrap_or_else(|_| "http://localhost:8080".to_string());
     let ws_url = std::env::var("WS_URL").unwrap_or_else(|_| "ws://localhost:8080".to_string());
+    // the refresh token from /api/login sends scores online; without one they are kept offline
+    let player = std::env::var("PLAYER_NAME").unwrap_or_else(|_| "player".to_string());
+    let score_session = std::env::var("SCORE_REFRESH_TOKEN").ok().map(|t| Arc::new(scores::Session::new(t)));
+    // practice mode starts songs this far in; practice runs are not submitted
+    let practice_from_ms: Option<u64> = std::env::var("PRACTICE_FROM_MS").ok().and_then(|v| v.parse().ok());
     
     // Shared state
     let audio_context = Arc::new(Mutex::new(None));
@@ -165,6 +288,8 @@ fn main() -> Result<()> {
         let game_running = game_running.clone();
         let game_start_time = game_start_time.clone();
         let game_timer = game_timer.clone();
+        let player = player.clone();
+        let score_session = score_session.clone();
         
         move |song_id, difficulty, instrument| {
             info!("Playing song: {} ({} - {})", song_id, difficulty, instrument);
@@ -186,6 +311,9 @@ fn main() -> Result<()> {
             let game_running_timer = game_running.clone();
             let game_start_time_timer = game_start_time.clone();
             let chart_data_timer = chart_data.clone();
+            let server_url_timer = server_url.clone();
+            let player_timer = player.clone();
+            let score_session_timer = score_session.clone();
             
             let timer = slint::Timer::default();
             let frame_count = Arc::new(Mutex::new(0));
@@ -248,7 +376,7 @@ fn main() -> Result<()> {
                             let time_diff = current_time - note.time;
                             
                             // Only check notes that are slightly past their hit time
-                            if time_diff > 0.2 && time_diff < 0.5 {
+                            if time_diff > rhythm_pi_scoring::MISS_AFTER && time_diff < 0.5 {
                                 if !game.notes_hit.iter().any(|h| h.note_index == i) {
                                     game.record_hit(i, note, current_time);
                                 }
@@ -292,6 +420,39 @@ fn main() -> Result<()> {
                         if let Some(ui) = ui_clone.upgrade() {
                             ui.set_current_score(score_data);
                         }
+                        
+                        // the song is over once every note is judged and the last has passed
+                        // (in practice the notes before the start never are); the server
+                        // re-scores the replay before ranking the score
+                        let last_end = chart.notes.iter().map(|n| n.time + n.duration).fold(0.0, f32::max);
+                        let judged = practice_from_ms.is_some() || game.notes_hit.len() >= chart.notes.len();
+                        if !chart.notes.is_empty() && judged && current_time > last_end + 1.0 {
+                            *game_running_timer.lock().unwrap() = false;
+                            game.is_playing = false;
+                            if practice_from_ms.is_some() {
+                                info!("Practice run finished with {} points", game.score);
+                                return;
+                            }
+                            info!("Song finished with {} points", game.score);
+                            
+                            let mut submission = scores::ScoreSubmission::from_game(chart, &game, &player_timer, score_session_timer.is_some());
+                            let server_url = server_url_timer.clone();
+                            let session = score_session_timer.clone();
+                            thread::spawn(move || {
+                                let token = session.and_then(|session| match session.access_token(&server_url) {
+                                    Ok(token) => Some(token),
+                                    Err(e) => {
+                                        log::error!("{:#}; keeping this score offline", e);
+                                        submission.online = false;
+                                        None
+                                    }
+                                });
+                                match scores::submit_score(&server_url, token.as_deref(), &submission) {
+                                    Ok(response) => info!("Score submitted: {}", response),
+                                    Err(e) => log::error!("Failed to submit score: {}", e),
+                                }
+                            });
+                        }
                     }
                 }
             });
@@ -353,12 +514,33 @@ fn main() -> Result<()> {
             
             thread::spawn(move || {
                 let encoded_song_id = urlencoding::encode(&song_id_clone);
-                match stream_audio_from_websocket(&ws_url_clone, &encoded_song_id) {
-                    Ok(audio_data) => {
-                        info!("Audio stream received: {} bytes", audio_data.len());
+                
+                // AUDIO_PCM=1 has the server decode, for boards too slow to do it themselves
+                if std::env::var("AUDIO_PCM").is_ok_and(|v| v == "1") {
+                    let mut started = false;
+                    let result = stream_pcm_from_websocket(&ws_url_clone, &encoded_song_id, practice_from_ms, |channels, rate, frame, samples| {
+                        if let Some(ctx) = audio_context_clone.lock().unwrap().as_ref() {
+                            if !started {
+                                // the first block plays from its frame's time into the song
+                                *game_start_time_clone.lock().unwrap() = Some(started_at(frame * 1000 / u64::from(rate)));
+                                info!("Audio playback started");
+                                started = true;
+                            }
+                            ctx.queue_pcm(channels, rate, samples);
+                        }
+                    });
+                    if let Err(e) = result {
+                        log::error!("Failed to stream audio: {}", e);
+                    }
+                    return;
+                }
+                
+                match stream_audio_from_websocket(&ws_url_clone, &encoded_song_id, practice_from_ms) {
+                    Ok((audio_data, start_ms)) => {
+                        info!("Audio stream received: {} bytes from {} ms", audio_data.len(), start_ms);
                         
                         if let Some(ctx) = audio_context_clone.lock().unwrap().as_ref() {
-                            *game_start_time_clone.lock().unwrap() = Some(Instant::now());
+                            *game_start_time_clone.lock().unwrap() = Some(started_at(start_ms));
                             if let Err(e) = ctx.play_bytes(audio_data) {
                                 log::error!("Failed to play audio: {}", e);
                             } else {
@@ -432,6 +614,8 @@ fn main() -> Result<()> {
                 info!("Handling key '{}' at game time {:.3}s", key_char, game.current_time);
                 
                 if let Some(event) = input.handle_key_press(key_char, game.current_time) {
+                    game.replay.press(event.lane, event.timestamp);
+
                     // Log input pressed state for the lane
                     info!("UI Input state lane {} pressed?: {}", event.lane, input.is_lane_pressed(event.lane));
                     // Highlight the key button
@@ -447,7 +631,7 @@ fn main() -> Result<()> {
                     
                     // Check for nearby notes in the chart
                     if let Some(chart) = chart_data.lock().unwrap().as_ref() {
-                        let hit_window = 0.3; // 300ms hit window
+                        let hit_window = rhythm_pi_scoring::HIT_WINDOW;
 
                         // Gather candidate notes in the hit window for this lane and log them
                         let candidates: Vec<(usize, &game::ChartNote)> = chart.notes.iter().enumerate()
@@ -576,7 +760,8 @@ fn main() -> Result<()> {
                             // Process the release event similarly to a press
                             if let Some(chart) = chart_clone.lock().unwrap().as_ref() {
                                 let mut game = game_state_clone.lock().unwrap();
-                                let hit_window = 0.3; // 300ms hit window
+                                game.replay.release(event.lane, event.timestamp);
+                                let hit_window = rhythm_pi_scoring::HIT_WINDOW;
 
                                 // Gather candidate notes in the hit window for this lane
                                 let candidates: Vec<(usize, &game::ChartNote)> = chart.notes.iter().enumerate()
@@ -699,6 +884,8 @@ fn main() -> Result<()> {
                             let mut game = game_state_kb.lock().unwrap();
 
                             if let Some(event) = input.handle_key_press(ch, game.current_time) {
+                                game.replay.press(event.lane, event.timestamp);
+
                                 //eprintln!("Key mapped to lane {}", event.lane);
 
                                 // Show whether input state believes the lane is pressed
@@ -717,7 +904,7 @@ fn main() -> Result<()> {
 
                                 // Check for nearby notes in the chart
                                 if let Some(chart) = chart_data_kb.lock().unwrap().as_ref() {
-                                    let hit_window = 0.3;
+                                    let hit_window = rhythm_pi_scoring::HIT_WINDOW;
 
                                     // Gather candidate notes in the hit window for this lane and log them
                                     let candidates_kb: Vec<(usize, &game::ChartNote)> = chart.notes.iter().enumerate()
@@ -813,7 +1000,8 @@ fn main() -> Result<()> {
                                 // Process the release event similarly to a press
                                 if let Some(chart) = chart_data_kb.lock().unwrap().as_ref() {
                                     let mut game = game_state_kb.lock().unwrap();
-                                    let hit_window = 0.3; // 300ms hit window
+                                    game.replay.release(event.lane, event.timestamp);
+                                    let hit_window = rhythm_pi_scoring::HIT_WINDOW;
 
                                     // Gather candidate notes in the hit window for this lane
                                     let candidates: Vec<(usize, &game::ChartNote)> = chart.notes.iter().enumerate()
@@ -0,0 +1,100 @@
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::game::{Chart, GameState, Replay};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Judgements {
+    pub perfect: u32,
+    pub great: u32,
+    pub good: u32,
+    pub ok: u32,
+    pub miss: u32,
+}
+
+/// Body of `POST /api/scores`. The server re-scores `replay` against the chart named by
+/// `chart_hash`, so `score` is only what the player saw.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScoreSubmission {
+    pub song_id: String,
+    pub player: String,
+    pub score: i64,
+    pub online: bool,
+    pub instrument: String,
+    pub difficulty: String,
+    pub chart_hash: Option<String>,
+    pub max_combo: u32,
+    pub judgements: Judgements,
+    pub replay: Replay,
+}
+
+impl ScoreSubmission {
+    pub fn from_game(chart: &Chart, game: &GameState, player: &str, online: bool) -> Self {
+        Self {
+            song_id: chart.song_id.clone(),
+            player: player.to_string(),
+            score: game.score as i64,
+            online,
+            instrument: chart.instrument.clone(),
+            difficulty: chart.difficulty.clone(),
+            chart_hash: chart.hash.clone(),
+            max_combo: game.max_combo,
+            judgements: Judgements {
+                perfect: game.accuracy_count.perfect,
+                great: game.accuracy_count.great,
+                good: game.accuracy_count.good,
+                ok: game.accuracy_count.ok,
+                miss: game.accuracy_count.miss,
+            },
+            replay: game.replay.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenPair {
+    access_token: String,
+    refresh_token: String,
+}
+
+/// A login to the score server. Access tokens expire after 15 minutes, well within a
+/// session, so each submission trades the refresh token from `/api/login` for a new pair.
+/// Refresh tokens work once; the new one is kept for the next submission.
+pub struct Session {
+    refresh_token: Mutex<String>,
+}
+
+impl Session {
+    pub fn new(refresh_token: String) -> Self {
+        Self { refresh_token: Mutex::new(refresh_token) }
+    }
+
+    /// A fresh access token; fails once the refresh token has expired or been revoked
+    pub fn access_token(&self, server_url: &str) -> Result<String> {
+        let mut refresh_token = self.refresh_token.lock().unwrap();
+        let tokens: TokenPair = ureq::post(&format!("{}/api/refresh", server_url))
+            .timeout(std::time::Duration::from_secs(5))
+            .send_json(serde_json::json!({ "refresh_token": *refresh_token }))
+            .context("login expired; log in again to send scores online")?
+            .into_json()?;
+        *refresh_token = tokens.refresh_token;
+        Ok(tokens.access_token)
+    }
+}
+
+/// Submit a finished play; online submissions need an access token from `Session`
+pub fn submit_score(server_url: &str, token: Option<&str>, submission: &ScoreSubmission) -> Result<serde_json::Value> {
+    let url = format!("{}/api/scores", server_url);
+
+    info!("Submitting score {} for {} to {}", submission.score, submission.song_id, url);
+
+    let mut request = ureq::post(&url).timeout(std::time::Duration::from_secs(5));
+    if let Some(token) = token {
+        request = request.set("Authorization", &format!("Bearer {}", token));
+    }
+
+    Ok(request.send_json(submission)?.into_json()?)
+}
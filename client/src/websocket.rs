@@ -6,17 +6,21 @@ use tokio_tungstenite::{connect_async, tungstenite::Message};
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
 
+/// Version of the server's audio control protocol this client speaks
+pub const PROTOCOL_VERSION: u32 = 1;
+
 #[derive(Debug, Clone)]
 pub struct AudioChunk {
+    /// Position of the chunk in the stream, from 0
+    pub seq: u32,
+    /// Index of the first sample frame, when the server sends decoded audio
+    pub frame: Option<u64>,
+    /// Where `data` belongs in the audio file; None for header bytes the server sends
+    /// again ahead of audio started from a time
+    pub offset: Option<u64>,
     pub data: Vec<u8>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct ServerStatus {
-    pub status: Option<String>,
-    pub error: Option<String>,
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GameStateUpdate {
     pub score: u32,
@@ -24,25 +28,146 @@ pub struct GameStateUpdate {
     pub health: f32,
 }
 
+/// What the server says about the audio when the socket opens
+#[derive(Debug, Clone, Deserialize)]
+pub struct AudioMeta {
+    pub codec: Option<String>,
+    pub content_type: String,
+    pub quality: String,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<usize>,
+    pub duration_ms: Option<u64>,
+    pub total_bytes: u64,
+    pub header_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PcmEncoding {
+    S16le,
+    F32le,
+}
+
+/// Decoded audio to ask the server for in place of the file, so nothing has to be
+/// decoded here
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PcmFormat {
+    pub encoding: PcmEncoding,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+/// Control messages from the server
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Meta(AudioMeta),
+    /// Frames from `seq` on carry `header_bytes` of the file's header, then the file from
+    /// `offset_bytes`; for decoded audio, from sample frame `frame`. The audio plays from
+    /// `start_ms`, which the server leaves out when started from a byte offset.
+    Started { seq: u32, offset_bytes: u64, header_bytes: u64, start_ms: Option<u64>, frame: Option<u64> },
+    /// Decoded audio will be sent in this format
+    Format {
+        #[serde(flatten)]
+        format: PcmFormat,
+        frame_bytes: usize,
+        total_frames: Option<u64>,
+    },
+    /// Frames before `seq` may still arrive; no more until `resume`
+    Paused { seq: u32 },
+    Resumed { seq: u32 },
+    /// Every byte up to the end of the file has been sent
+    Complete { frames: u32, total_bytes: u64 },
+    Error { error: String },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Command {
+    Start {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        offset_bytes: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        offset_ms: Option<u64>,
+    },
+    Seek {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        offset_bytes: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        offset_ms: Option<u64>,
+    },
+    Pause,
+    Resume,
+    Ack { seq: u32 },
+    Stop,
+    Format(PcmFormat),
+}
+
+#[derive(Serialize)]
+struct Versioned<'a> {
+    v: u32,
+    #[serde(flatten)]
+    command: &'a Command,
+}
+
+fn command_message(command: &Command) -> Result<Message> {
+    Ok(Message::Text(serde_json::to_string(&Versioned { v: PROTOCOL_VERSION, command })?))
+}
+
+/// Where the frames of the current `start` or `seek` belong
+struct Run {
+    first_seq: u32,
+    header_left: u64,
+    next_offset: u64,
+}
+
 pub struct AudioStreamClient {
+    pub meta: AudioMeta,
     sender: mpsc::UnboundedSender<Message>,
     audio_receiver: mpsc::UnboundedReceiver<AudioChunk>,
+    status_receiver: mpsc::UnboundedReceiver<ServerMessage>,
 }
 
 impl AudioStreamClient {
-    /// Connect to the server's WebSocket audio streaming endpoint
-    /// URL format: ws://server:port/ws/audio/{song_id}
+    /// Connect to the server's WebSocket audio streaming endpoint and read what it says
+    /// about the audio. URL format: ws://server:port/ws/audio/{song_id}
     pub async fn connect_audio_stream(server_url: &str, song_id: &str) -> Result<Self> {
-        let ws_url = format!("{}/ws/audio/{}", server_url, song_id);
+        Self::connect(server_url, song_id, None).await
+    }
+
+    /// Connect asking for a quality: `low`, `medium`, `high` or `original`
+    pub async fn connect(server_url: &str, song_id: &str, quality: Option<&str>) -> Result<Self> {
+        let mut ws_url = format!("{}/ws/audio/{}", server_url, song_id);
+        if let Some(quality) = quality {
+            ws_url.push_str(&format!("?quality={}", quality));
+        }
         info!("Connecting to audio stream at {}", ws_url);
         
         let (ws_stream, response) = connect_async(&ws_url).await?;
         info!("WebSocket connected, response: {:?}", response.status());
         
         let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+
+        // the server describes the audio before anything else
+        let meta = loop {
+            match ws_receiver.next().await {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str::<ServerMessage>(&text) {
+                    Ok(ServerMessage::Meta(meta)) => break meta,
+                    Ok(ServerMessage::Error { error }) => anyhow::bail!("audio stream refused: {}", error),
+                    Ok(other) => warn!("Unexpected message before audio metadata: {:?}", other),
+                    Err(e) => warn!("Failed to parse status message: {} - {}", e, text),
+                },
+                Some(Ok(Message::Close(reason))) => anyhow::bail!("audio stream closed before it started: {:?}", reason),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => anyhow::bail!("audio stream closed before it started"),
+            }
+        };
+        info!("Streaming {} ({} bytes, {:?} ms)", meta.content_type, meta.total_bytes, meta.duration_ms);
         
         let (cmd_tx, mut cmd_rx) = mpsc::unbounded_channel();
         let (audio_tx, audio_rx) = mpsc::unbounded_channel();
+        let (status_tx, status_rx) = mpsc::unbounded_channel();
         
         // Spawn task to handle outgoing messages (commands to server)
         tokio::spawn(async move {
@@ -56,35 +181,77 @@ impl AudioStreamClient {
         });
         
         // Spawn task to receive messages from server
+        let acks = cmd_tx.clone();
         tokio::spawn(async move {
+            let mut run: Option<Run> = None;
+            // decoded audio frames carry the index of their first sample frame
+            let mut pcm = false;
             while let Some(result) = futures::stream::StreamExt::next(&mut ws_receiver).await {
                 match result {
                     Ok(Message::Binary(data)) => {
-                        debug!("Received audio chunk: {} bytes", data.len());
-                        let chunk = AudioChunk { data };
-                        if audio_tx.send(chunk).is_err() {
+                        // a big-endian sequence number, then the audio
+                        if data.len() < 4 {
+                            error!("Audio frame too short: {} bytes", data.len());
+                            break;
+                        }
+                        let seq = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                        let (frame, data) = if pcm {
+                            if data.len() < 12 {
+                                error!("Decoded audio frame too short: {} bytes", data.len());
+                                break;
+                            }
+                            let mut index = [0u8; 8];
+                            index.copy_from_slice(&data[4..12]);
+                            (Some(u64::from_be_bytes(index)), data[12..].to_vec())
+                        } else {
+                            (None, data[4..].to_vec())
+                        };
+                        let Some(run) = run.as_mut().filter(|r| seq >= r.first_seq) else {
+                            // sent before the last seek; only the acknowledgement matters
+                            debug!("Dropping audio chunk {} from before a seek", seq);
+                            if let Ok(ack) = command_message(&Command::Ack { seq }) {
+                                let _ = acks.send(ack);
+                            }
+                            continue;
+                        };
+                        // header bytes come in frames of their own
+                        let offset = if run.header_left > 0 {
+                            run.header_left = run.header_left.saturating_sub(data.len() as u64);
+                            None
+                        } else {
+                            let offset = run.next_offset;
+                            run.next_offset += data.len() as u64;
+                            Some(offset)
+                        };
+                        debug!("Received audio chunk {}: {} bytes at {:?}", seq, data.len(), offset);
+                        if audio_tx.send(AudioChunk { seq, frame, offset, data }).is_err() {
                             warn!("Audio receiver dropped, stopping stream");
                             break;
                         }
                     }
                     Ok(Message::Text(text)) => {
-                        match serde_json::from_str::<ServerStatus>(&text) {
-                            Ok(status) => {
-                                if let Some(s) = status.status {
-                                    info!("Server status: {}", s);
-                                    if s == "complete" {
-                                        info!("Audio stream complete");
-                                        break;
-                                    }
-                                }
-                                if let Some(e) = status.error {
-                                    error!("Server error: {}", e);
-                                    break;
-                                }
-                            }
+                        let message = match serde_json::from_str::<ServerMessage>(&text) {
+                            Ok(message) => message,
                             Err(e) => {
                                 warn!("Failed to parse status message: {} - {}", e, text);
+                                continue;
                             }
+                        };
+                        match &message {
+                            ServerMessage::Format { format, .. } => {
+                                info!("Server will send {:?} audio", format);
+                                pcm = true;
+                            }
+                            ServerMessage::Started { seq, offset_bytes, header_bytes, start_ms, .. } => {
+                                info!("Audio stream started at byte {} ({:?} ms, frame {})", offset_bytes, start_ms, seq);
+                                run = Some(Run { first_seq: *seq, header_left: *header_bytes, next_offset: *offset_bytes });
+                            }
+                            ServerMessage::Complete { frames, .. } => info!("Audio stream complete: {} frames", frames),
+                            ServerMessage::Error { error } => error!("Server error: {}", error),
+                            other => debug!("Server status: {:?}", other),
+                        }
+                        if status_tx.send(message).is_err() {
+                            break;
                         }
                     }
                     Ok(Message::Close(reason)) => {
@@ -111,28 +278,86 @@ impl AudioStreamClient {
         });
         
         Ok(Self {
+            meta,
             sender: cmd_tx,
             audio_receiver: audio_rx,
+            status_receiver: status_rx,
         })
     }
+
+    fn command(&self, command: Command) -> Result<()> {
+        self.sender.send(command_message(&command)?)?;
+        Ok(())
+    }
     
-    /// Start the audio stream (send "start" command to server)
+    /// Ask for decoded audio instead of the file; only before starting
+    pub fn request_pcm(&self, format: PcmFormat) -> Result<()> {
+        info!("Asking for {:?} audio", format);
+        self.command(Command::Format(format))
+    }
+    
+    /// Start the audio stream from the beginning
     pub fn start_stream(&self) -> Result<()> {
         info!("Sending start command to server");
-        self.sender.send(Message::Text("start".to_string()))?;
-        Ok(())
+        self.command(Command::Start { offset_bytes: None, offset_ms: None })
+    }
+
+    /// Start from a byte offset, to finish a download that was cut off
+    pub fn start_at_bytes(&self, offset: u64) -> Result<()> {
+        info!("Resuming audio stream at byte {}", offset);
+        self.command(Command::Start { offset_bytes: Some(offset), offset_ms: None })
+    }
+
+    /// Start playback about `ms` into the song, as practice mode does; `started` says
+    /// where the audio really starts
+    pub fn start_at_ms(&self, ms: u64) -> Result<()> {
+        info!("Starting audio stream at {} ms", ms);
+        self.command(Command::Start { offset_bytes: None, offset_ms: Some(ms) })
     }
     
-    /// Stop the audio stream (send "stop" command to server)
+    /// Move a started stream to a byte offset of the file. Chunks sent before the server's
+    /// `started` are dropped here.
+    pub fn seek_bytes(&self, offset: u64) -> Result<()> {
+        info!("Seeking audio stream to byte {}", offset);
+        self.command(Command::Seek { offset_bytes: Some(offset), offset_ms: None })
+    }
+
+    /// Move a started stream to about `ms` into the song; `started` says where the audio
+    /// really starts
+    pub fn seek_ms(&self, ms: u64) -> Result<()> {
+        info!("Seeking audio stream to {} ms", ms);
+        self.command(Command::Seek { offset_bytes: None, offset_ms: Some(ms) })
+    }
+
+    /// Stop the server sending; chunks already on their way still arrive
+    pub fn pause(&self) -> Result<()> {
+        self.command(Command::Pause)
+    }
+
+    /// Carry on sending from where `pause` stopped
+    pub fn resume(&self) -> Result<()> {
+        self.command(Command::Resume)
+    }
+    
+    /// Stop the audio stream; the server closes the connection
     pub fn stop_stream(&self) -> Result<()> {
         info!("Sending stop command to server");
-        self.sender.send(Message::Text("stop".to_string()))?;
-        Ok(())
+        self.command(Command::Stop)
+    }
+    
+    /// Tell the server a chunk has been taken, so it sends more; it only streams a few
+    /// chunks ahead of these acknowledgements
+    fn acknowledge(&self, chunk: &AudioChunk) {
+        if self.command(Command::Ack { seq: chunk.seq }).is_err() {
+            debug!("Stream closed before chunk {} was acknowledged", chunk.seq);
+        }
     }
     
     /// Receive next audio chunk (non-blocking)
     pub fn recv_audio_chunk(&mut self) -> Option<AudioChunk> {
-        self.audio_receiver.try_recv().ok()
+        let chunk = self.audio_receiver.try_recv().ok()?;
+        self.acknowledge(&chunk);
+        Some(chunk)
     }
     
     /// Receive all available audio chunks
@@ -141,12 +366,22 @@ impl AudioStreamClient {
         while let Ok(chunk) = self.audio_receiver.try_recv() {
             chunks.push(chunk);
         }
+        if let Some(last) = chunks.last() {
+            self.acknowledge(last);
+        }
         chunks
     }
     
     /// Wait for next audio chunk (blocking)
     pub async fn recv_audio_chunk_async(&mut self) -> Option<AudioChunk> {
-        self.audio_receiver.recv().await
+        let chunk = self.audio_receiver.recv().await?;
+        self.acknowledge(&chunk);
+        Some(chunk)
+    }
+
+    /// Next control message from the server, such as `complete` (non-blocking)
+    pub fn recv_status(&mut self) -> Option<ServerMessage> {
+        self.status_receiver.try_recv().ok()
     }
 }
 
@@ -10,7 +10,8 @@ LOGFILE="./server-dev.log"
 
 echo "Starting Rhythm PI server (logs -> ${LOGFILE})..."
 # run server in background and capture its PID
-cargo run -p rhythm-pi-server --bin rhythm-pi-server --release > "$LOGFILE" 2>&1 &
+# RHYTHM_DEV allows the built-in JWT secret; never set it in production
+RHYTHM_DEV=1 cargo run -p rhythm-pi-server --bin rhythm-pi-server --release > "$LOGFILE" 2>&1 &
 SERVER_PID=$!
 
 cleanup() {
@@ -0,0 +1,123 @@
+//! Judgement and scoring rules shared by the client and the server.
+//!
+//! The client scores hits live with these rules; the server re-simulates a submitted
+//! [`Replay`] against the stored chart with the same rules to check the claimed score.
+
+pub mod replay;
+
+pub use replay::{simulate, Replay, ReplayInput, ReplayResult};
+
+use serde::{Deserialize, Serialize};
+
+/// Presses further than this from a note (seconds) don't consider it at all
+pub const HIT_WINDOW: f32 = 0.3;
+
+/// A note not hit this long (seconds) after its time is a miss
+pub const MISS_AFTER: f32 = 0.2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChartNote {
+    pub time: f32,
+    #[serde(alias = "fret")]
+    pub col: u32,
+    #[serde(default)]
+    pub duration: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HitAccuracy {
+    Perfect,   // ±50ms - 300 points
+    Great,     // ±100ms - 200 points
+    Good,      // ±150ms - 100 points
+    Ok,        // ±200ms - 50 points
+    Miss,      // Outside window - 0 points
+}
+
+impl HitAccuracy {
+    /// Judge a hit `time_diff` seconds away from its note (either side)
+    pub fn judge(time_diff: f32) -> Self {
+        let time_diff = time_diff.abs();
+
+        if time_diff <= 0.05 {
+            Self::Perfect
+        } else if time_diff <= 0.1 {
+            Self::Great
+        } else if time_diff <= 0.15 {
+            Self::Good
+        } else if time_diff <= 0.2 {
+            Self::Ok
+        } else {
+            Self::Miss
+        }
+    }
+
+    pub fn points(&self) -> u32 {
+        match self {
+            Self::Perfect => 300,
+            Self::Great => 200,
+            Self::Good => 100,
+            Self::Ok => 50,
+            Self::Miss => 0,
+        }
+    }
+
+    pub fn combo_multiplier(&self) -> f32 {
+        match self {
+            Self::Perfect => 2.0,
+            Self::Great => 1.5,
+            Self::Good => 1.0,
+            Self::Ok => 0.5,
+            Self::Miss => 0.0,
+        }
+    }
+
+    /// Points for this judgement with `combo` notes hit in a row before it.
+    /// A miss breaks the combo, so it never gets a bonus.
+    pub fn points_with_combo(&self, combo: u32) -> u32 {
+        if *self == Self::Miss {
+            return 0;
+        }
+        let combo_bonus = (combo as f32 * 0.1).min(100.0); // Max 100 bonus points
+        (self.points() as f32 + combo_bonus) as u32
+    }
+}
+
+/// Identifies the exact chart file a score was played on: FNV-1a (64 bit) of its bytes as hex.
+/// Not a security measure; the server hashes its own copy of the chart.
+pub fn chart_hash(bytes: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_judge_windows() {
+        assert_eq!(HitAccuracy::judge(0.0), HitAccuracy::Perfect);
+        assert_eq!(HitAccuracy::judge(-0.05), HitAccuracy::Perfect);
+        assert_eq!(HitAccuracy::judge(0.09), HitAccuracy::Great);
+        assert_eq!(HitAccuracy::judge(-0.12), HitAccuracy::Good);
+        assert_eq!(HitAccuracy::judge(0.2), HitAccuracy::Ok);
+        assert_eq!(HitAccuracy::judge(0.21), HitAccuracy::Miss);
+    }
+
+    #[test]
+    fn test_combo_bonus() {
+        assert_eq!(HitAccuracy::Perfect.points_with_combo(0), 300);
+        assert_eq!(HitAccuracy::Great.points_with_combo(25), 202);
+        assert_eq!(HitAccuracy::Ok.points_with_combo(5000), 150);
+        assert_eq!(HitAccuracy::Miss.points_with_combo(40), 0);
+    }
+
+    #[test]
+    fn test_chart_hash() {
+        assert_eq!(chart_hash(b""), "cbf29ce484222325");
+        assert_ne!(chart_hash(b"{\"notes\":[]}"), chart_hash(b"{\"notes\": []}"));
+    }
+}
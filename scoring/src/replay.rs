@@ -0,0 +1,258 @@
+use crate::{ChartNote, HitAccuracy, HIT_WINDOW, MISS_AFTER};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Every key press of a play, in song time (seconds)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Replay {
+    pub inputs: Vec<ReplayInput>,
+}
+
+/// One press of a lane key. Serialized compactly as `[lane, press, release]`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(from = "(u32, f32, Option<f32>)", into = "(u32, f32, Option<f32>)")]
+pub struct ReplayInput {
+    pub lane: u32,
+    pub press: f32,
+    pub release: Option<f32>, // None if the song ended with the key held
+}
+
+impl From<(u32, f32, Option<f32>)> for ReplayInput {
+    fn from((lane, press, release): (u32, f32, Option<f32>)) -> Self {
+        Self { lane, press, release }
+    }
+}
+
+impl From<ReplayInput> for (u32, f32, Option<f32>) {
+    fn from(input: ReplayInput) -> Self {
+        (input.lane, input.press, input.release)
+    }
+}
+
+impl Replay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn press(&mut self, lane: u32, time: f32) {
+        self.inputs.push(ReplayInput { lane, press: time, release: None });
+    }
+
+    /// Close the latest open press on `lane`
+    pub fn release(&mut self, lane: u32, time: f32) {
+        if let Some(input) = self
+            .inputs
+            .iter_mut()
+            .rev()
+            .find(|i| i.lane == lane && i.release.is_none())
+        {
+            input.release = Some(time);
+        }
+    }
+}
+
+/// Outcome of replaying inputs against a chart
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReplayResult {
+    pub score: u32,
+    pub combo: u32,
+    pub max_combo: u32,
+    pub perfect: u32,
+    pub great: u32,
+    pub good: u32,
+    pub ok: u32,
+    pub miss: u32,
+}
+
+impl ReplayResult {
+    /// Score one judgement, the same way `GameState::record_hit` does on the client
+    pub fn record(&mut self, accuracy: HitAccuracy) {
+        match accuracy {
+            HitAccuracy::Perfect => self.perfect += 1,
+            HitAccuracy::Great => self.great += 1,
+            HitAccuracy::Good => self.good += 1,
+            HitAccuracy::Ok => self.ok += 1,
+            HitAccuracy::Miss => {
+                self.miss += 1;
+                self.combo = 0;
+            }
+        }
+
+        self.score += accuracy.points_with_combo(self.combo);
+
+        if accuracy != HitAccuracy::Miss {
+            self.combo += 1;
+            self.max_combo = self.max_combo.max(self.combo);
+        }
+    }
+}
+
+/// Re-run judgement for a replay the way the client does during play:
+/// both the press and the release of a key try to hit the first unhit note
+/// (in chart order) on that lane within [`HIT_WINDOW`], and notes left unhit
+/// for more than [`MISS_AFTER`] count as misses.
+pub fn simulate(notes: &[ChartNote], replay: &Replay) -> ReplayResult {
+    let mut attempts: Vec<(f32, u32)> = replay
+        .inputs
+        .iter()
+        .flat_map(|i| std::iter::once(i.press).chain(i.release).map(move |t| (t, i.lane)))
+        .collect();
+    attempts.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut by_time: Vec<usize> = (0..notes.len()).collect();
+    by_time.sort_by(|&a, &b| notes[a].time.total_cmp(&notes[b].time));
+
+    // each lane's notes by time, so the ones in reach of an attempt are found by binary
+    // search instead of a walk through the whole chart
+    let mut lanes: HashMap<u32, Vec<usize>> = HashMap::new();
+    for &index in &by_time {
+        lanes.entry(notes[index].col).or_default().push(index);
+    }
+
+    let mut result = ReplayResult::default();
+    let mut hit = vec![false; notes.len()];
+    let mut next_expiry = 0;
+
+    for (time, lane) in attempts {
+        while next_expiry < by_time.len() && time - notes[by_time[next_expiry]].time > MISS_AFTER {
+            let index = by_time[next_expiry];
+            if !hit[index] {
+                hit[index] = true;
+                result.record(HitAccuracy::Miss);
+            }
+            next_expiry += 1;
+        }
+
+        // of the unhit notes in reach, the first in chart order
+        let candidate = lanes.get(&lane).and_then(|lane| {
+            let from = lane.partition_point(|&i| notes[i].time - time < -HIT_WINDOW);
+            lane[from..]
+                .iter()
+                .take_while(|&&i| notes[i].time - time <= HIT_WINDOW)
+                .filter(|&&i| !hit[i])
+                .min()
+                .copied()
+        });
+        if let Some(index) = candidate {
+            hit[index] = true;
+            result.record(HitAccuracy::judge(time - notes[index].time));
+        }
+    }
+
+    // the rest passed by without being hit
+    for index in by_time.into_iter().skip(next_expiry) {
+        if !hit[index] {
+            result.record(HitAccuracy::Miss);
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(time: f32, col: u32) -> ChartNote {
+        ChartNote { time, col, duration: 0.0 }
+    }
+
+    #[test]
+    fn test_replay_serializes_compactly() {
+        let mut replay = Replay::new();
+        replay.press(2, 1.5);
+        replay.press(0, 1.75);
+        replay.release(2, 1.625);
+
+        let json = serde_json::to_string(&replay).unwrap();
+        assert_eq!(json, r#"{"inputs":[[2,1.5,1.625],[0,1.75,null]]}"#);
+        assert_eq!(serde_json::from_str::<Replay>(&json).unwrap(), replay);
+    }
+
+    #[test]
+    fn test_simulate_scores_like_the_client() {
+        let notes = vec![note(1.0, 0), note(2.0, 1), note(3.0, 1), note(4.0, 2)];
+        let mut replay = Replay::new();
+        replay.press(0, 1.02); // perfect
+        replay.release(0, 1.12);
+        replay.press(1, 2.09); // great, combo 1
+        replay.release(1, 2.19);
+        // 3.0 is never pressed: miss
+        replay.press(2, 3.87); // good after the miss reset the combo
+        replay.release(2, 3.97); // nothing left to hit
+
+        let result = simulate(&notes, &replay);
+        assert_eq!((result.perfect, result.great, result.good, result.miss), (1, 1, 1, 1));
+        assert_eq!(result.score, 300 + 200 + 100);
+        assert_eq!(result.max_combo, 2);
+        assert_eq!(result.combo, 1);
+    }
+
+    #[test]
+    fn test_release_can_hit_the_next_note() {
+        // a held key whose release lands on the following note hits it, as on the client
+        let notes = vec![note(1.0, 0), note(1.3, 0)];
+        let mut replay = Replay::new();
+        replay.press(0, 1.0);
+        replay.release(0, 1.3);
+
+        let result = simulate(&notes, &replay);
+        assert_eq!(result.perfect, 2);
+        assert_eq!(result.score, 300 + 300);
+    }
+
+    #[test]
+    fn test_judges_like_a_scan_of_every_note() {
+        // an unsorted chart with chords and close notes, and presses all over it
+        let mut seed = 7u32;
+        let mut next = |n: u32| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (seed >> 8) % n
+        };
+        let notes: Vec<ChartNote> = (0..300).map(|_| note(next(60_000) as f32 / 1000.0, next(4))).collect();
+        let mut replay = Replay::new();
+        for _ in 0..600 {
+            let (lane, press) = (next(4), next(60_000) as f32 / 1000.0);
+            replay.inputs.push(ReplayInput { lane, press, release: Some(press + next(300) as f32 / 1000.0) });
+        }
+
+        // the first unhit note in chart order in reach of each attempt, found the slow way
+        let mut attempts: Vec<(f32, u32)> =
+            replay.inputs.iter().flat_map(|i| [(i.press, i.lane), (i.release.unwrap(), i.lane)]).collect();
+        attempts.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut expected = ReplayResult::default();
+        let mut judged = vec![false; notes.len()];
+        let mut by_time: Vec<usize> = (0..notes.len()).collect();
+        by_time.sort_by(|&a, &b| notes[a].time.total_cmp(&notes[b].time));
+        for (time, lane) in attempts {
+            for &i in &by_time {
+                if !judged[i] && time - notes[i].time > MISS_AFTER {
+                    judged[i] = true;
+                    expected.record(HitAccuracy::Miss);
+                }
+            }
+            let hit = notes.iter().enumerate().find(|(i, n)| !judged[*i] && n.col == lane && (n.time - time).abs() <= HIT_WINDOW);
+            if let Some((i, n)) = hit {
+                judged[i] = true;
+                expected.record(HitAccuracy::judge(time - n.time));
+            }
+        }
+        for i in by_time {
+            if !judged[i] {
+                expected.record(HitAccuracy::Miss);
+            }
+        }
+
+        let result = simulate(&notes, &replay);
+        assert!(result.perfect + result.great + result.good + result.ok > 20, "{:?}", result);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_empty_replay_misses_everything() {
+        let notes = vec![note(1.0, 0), note(2.0, 1)];
+        let result = simulate(&notes, &Replay::new());
+        assert_eq!(result.miss, 2);
+        assert_eq!(result.score, 0);
+    }
+}
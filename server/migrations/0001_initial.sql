@@ -0,0 +1,28 @@
+-- Tables created by the original init_db. IF NOT EXISTS lets databases from before
+-- schema versioning adopt this as version 1 without changes.
+
+-- Only scores with online = 1 are considered for leaderboards
+CREATE TABLE IF NOT EXISTS scores (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    song_id TEXT NOT NULL,
+    player TEXT NOT NULL,
+    score INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    online INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL
+);
+
+-- Registered songs and file mtimes so changes can be detected
+CREATE TABLE IF NOT EXISTS songs (
+    id TEXT PRIMARY KEY,
+    filename TEXT NOT NULL,
+    title TEXT,
+    artist TEXT,
+    mtime INTEGER,
+    registered_at INTEGER NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Typed song metadata (see song_meta::SongMetadata); artists and tags are JSON arrays
+ALTER TABLE songs ADD COLUMN artists TEXT;
+ALTER TABLE songs ADD COLUMN album TEXT;
+ALTER TABLE songs ADD COLUMN year INTEGER;
+ALTER TABLE songs ADD COLUMN genre TEXT;
+ALTER TABLE songs ADD COLUMN offset_ms INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE songs ADD COLUMN preview_start_ms INTEGER;
+ALTER TABLE songs ADD COLUMN duration_ms INTEGER;
+ALTER TABLE songs ADD COLUMN bpm REAL;
+ALTER TABLE songs ADD COLUMN tags TEXT;
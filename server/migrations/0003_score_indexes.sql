@@ -0,0 +1,2 @@
+-- Leaderboard queries filter by song and online flag and sort by score
+CREATE INDEX IF NOT EXISTS idx_scores_song_online_score ON scores (song_id, online, score DESC);
@@ -0,0 +1,14 @@
+-- Richer score records for per-instrument, per-difficulty leaderboards.
+-- Scores submitted before this migration have NULL instrument/difficulty.
+ALTER TABLE scores ADD COLUMN instrument TEXT;
+ALTER TABLE scores ADD COLUMN difficulty TEXT;
+ALTER TABLE scores ADD COLUMN chart_hash TEXT;
+ALTER TABLE scores ADD COLUMN max_combo INTEGER;
+ALTER TABLE scores ADD COLUMN perfect INTEGER;
+ALTER TABLE scores ADD COLUMN great INTEGER;
+ALTER TABLE scores ADD COLUMN good INTEGER;
+ALTER TABLE scores ADD COLUMN ok INTEGER;
+ALTER TABLE scores ADD COLUMN miss INTEGER;
+ALTER TABLE scores ADD COLUMN accuracy REAL;
+
+CREATE INDEX IF NOT EXISTS idx_scores_leaderboard ON scores (song_id, instrument, difficulty, online, score DESC);
@@ -0,0 +1,5 @@
+-- Replay verification. `score` holds the server's recomputed score when a replay was sent;
+-- `claimed_score` is what the client reported. `verified` is NULL without a replay,
+-- 1 when the claim matched and 0 when it did not (hidden from leaderboards).
+ALTER TABLE scores ADD COLUMN claimed_score INTEGER;
+ALTER TABLE scores ADD COLUMN verified INTEGER;
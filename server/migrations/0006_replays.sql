@@ -0,0 +1,10 @@
+-- Uploaded replays, one per score. `data` is the gzip-compressed replay JSON;
+-- `size` is its uncompressed length.
+CREATE TABLE IF NOT EXISTS replays (
+    score_id INTEGER PRIMARY KEY REFERENCES scores(id) ON DELETE CASCADE,
+    data BLOB NOT NULL,
+    size INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_replays_created_at ON replays (created_at);
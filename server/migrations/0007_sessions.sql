@@ -0,0 +1,20 @@
+-- Login sessions with rotating refresh tokens. Access tokens name their session,
+-- so revoking a session (logout, refresh token reuse) invalidates them too.
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    revoked_at INTEGER
+);
+
+-- Only SHA-256 hashes of refresh tokens are stored. A token is single use:
+-- `used_at` is set when it is exchanged for a new one.
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    token_hash TEXT PRIMARY KEY,
+    session_id TEXT NOT NULL REFERENCES sessions(id),
+    expires_at INTEGER NOT NULL,
+    used_at INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_username ON sessions (username);
+CREATE INDEX IF NOT EXISTS idx_refresh_tokens_session ON refresh_tokens (session_id);
@@ -0,0 +1,2 @@
+-- Authorization roles: player (default), moderator, admin
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'player';
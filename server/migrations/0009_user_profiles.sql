@@ -0,0 +1,12 @@
+-- Player profiles. created_at is NULL for accounts made before profiles existed;
+-- avatar is a file name in AVATARS_DIR.
+ALTER TABLE users ADD COLUMN display_name TEXT;
+ALTER TABLE users ADD COLUMN country TEXT;
+ALTER TABLE users ADD COLUMN avatar TEXT;
+ALTER TABLE users ADD COLUMN created_at INTEGER;
+
+-- Online scores belong to a user account; offline scores keep only the free-text player name
+ALTER TABLE scores ADD COLUMN user_id INTEGER REFERENCES users(id);
+UPDATE scores SET user_id = (SELECT id FROM users WHERE users.username = scores.player) WHERE online = 1;
+
+CREATE INDEX IF NOT EXISTS idx_scores_user ON scores (user_id);
@@ -0,0 +1,17 @@
+-- Charts uploaded by players. `path` is a file name in CUSTOM_CHARTS_DIR; each upload
+-- for the same song, instrument, difficulty and author gets the next version.
+CREATE TABLE IF NOT EXISTS custom_charts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    song_id TEXT NOT NULL,
+    instrument TEXT NOT NULL,
+    difficulty TEXT NOT NULL,
+    author_id INTEGER NOT NULL REFERENCES users(id),
+    version INTEGER NOT NULL,
+    path TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    note_count INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    UNIQUE (song_id, instrument, difficulty, author_id, version)
+);
+
+CREATE INDEX IF NOT EXISTS idx_custom_charts_hash ON custom_charts (song_id, hash);
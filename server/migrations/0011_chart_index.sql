@@ -0,0 +1,12 @@
+-- Generated charts found in CHARTS_DIR, kept up to date by the song watcher so chart
+-- lookups never scan the directory. `path` is the file name in CHARTS_DIR.
+CREATE TABLE IF NOT EXISTS charts (
+    song_id TEXT NOT NULL,
+    instrument TEXT NOT NULL COLLATE NOCASE,
+    difficulty TEXT NOT NULL COLLATE NOCASE,
+    path TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    note_count INTEGER NOT NULL,
+    indexed_at INTEGER NOT NULL,
+    PRIMARY KEY (song_id, instrument, difficulty)
+);
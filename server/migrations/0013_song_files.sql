@@ -0,0 +1,9 @@
+-- Real state of each song's files, so the watcher can tell what changed.
+-- From here on songs.filename is the audio file (it used to be the metadata file) and
+-- songs.mtime its modification time. Hashes are SHA-256 of the file contents.
+ALTER TABLE songs ADD COLUMN audio_size INTEGER;
+ALTER TABLE songs ADD COLUMN audio_hash TEXT;
+ALTER TABLE songs ADD COLUMN meta_mtime INTEGER;
+ALTER TABLE songs ADD COLUMN meta_hash TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_songs_audio_hash ON songs (audio_hash);
@@ -0,0 +1,13 @@
+-- Transcoded copies of each song for streaming, one per quality. Files live in
+-- SONGS_DIR/.variants and are named after the audio they were made from, so a variant
+-- made from older audio is easy to spot (source_hash differs from songs.audio_hash).
+CREATE TABLE IF NOT EXISTS song_variants (
+    song_id TEXT NOT NULL,
+    quality TEXT NOT NULL,
+    path TEXT NOT NULL,
+    source_hash TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (song_id, quality)
+);
@@ -1,15 +1,66 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
 use actix_web::http::header::HeaderMap;
-use anyhow::Result;
+use actix_web::{web, FromRequest, HttpRequest};
+use anyhow::{bail, Result};
 use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use futures::future::LocalBoxFuture;
 use jsonwebtoken::{encode, decode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use rand::rngs::OsRng;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
 use std::env;
 
-#[derive(Serialize, Deserialize)]
-struct Claims {
-    sub: String,
-    exp: usize,
+use crate::db;
+
+/// Lifetime of an access token; clients renew it with their refresh token
+pub const ACCESS_TOKEN_TTL: i64 = 15 * 60;
+/// Lifetime of a refresh token (each refresh issues a new one)
+pub const REFRESH_TOKEN_TTL: i64 = 30 * 24 * 60 * 60;
+
+const DEV_SECRET: &str = "dev-secret-change-me";
+/// Secrets shipped in examples, refused like a missing one
+const PLACEHOLDER_SECRETS: &[&str] = &[DEV_SECRET, "change-me-please"];
+const MIN_SECRET_LEN: usize = 32;
+
+/// What a user may do; each role includes the ones before it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Player,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "player" => Some(Role::Player),
+            "moderator" => Some(Role::Moderator),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Player => "player",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// Access token claims. `sid` is the login session, checked for revocation on every request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Claims {
+    pub sub: String,
+    pub sid: String,
+    pub iat: usize,
+    pub exp: usize,
 }
 
 pub fn hash_password(password: &str) -> Result<String> {
@@ -27,24 +78,58 @@ pub fn verify_password(hash: &str, password: &str) -> Result<bool> {
     Ok(Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
 }
 
+/// `RHYTHM_DEV=1` allows running without a real JWT secret
+fn dev_mode() -> bool {
+    matches!(env::var("RHYTHM_DEV").as_deref(), Ok("1") | Ok("true"))
+}
+
 fn jwt_secret() -> Vec<u8> {
-    env::var("JWT_SECRET").unwrap_or_else(|_| "dev-secret-change-me".to_string()).into_bytes()
+    env::var("JWT_SECRET").unwrap_or_else(|_| DEV_SECRET.to_string()).into_bytes()
+}
+
+/// Refuse to serve with a missing, placeholder or short `JWT_SECRET` unless in dev mode
+pub fn check_secret() -> Result<()> {
+    if let Some(problem) = secret_problem(env::var("JWT_SECRET").ok().as_deref()) {
+        if !dev_mode() {
+            bail!(
+                "{}; set it to a random value of at least {} characters (or RHYTHM_DEV=1 for local development)",
+                problem,
+                MIN_SECRET_LEN
+            );
+        }
+        log::warn!("{}; continuing because RHYTHM_DEV is set", problem);
+    }
+    Ok(())
 }
 
-pub fn create_token(username: &str, expires_seconds: i64) -> Result<String> {
+fn secret_problem(secret: Option<&str>) -> Option<&'static str> {
+    match secret {
+        None => Some("JWT_SECRET is not set"),
+        Some(s) if PLACEHOLDER_SECRETS.contains(&s) => Some("JWT_SECRET is a placeholder value"),
+        Some(s) if s.len() < MIN_SECRET_LEN => Some("JWT_SECRET is too short"),
+        Some(_) => None,
+    }
+}
+
+pub fn create_token(username: &str, session_id: &str, expires_seconds: i64) -> Result<String> {
     let now = chrono::Utc::now().timestamp();
-    let exp = (now + expires_seconds) as usize;
-    let claims = Claims { sub: username.to_string(), exp };
+    let claims = Claims {
+        sub: username.to_string(),
+        sid: session_id.to_string(),
+        iat: now as usize,
+        exp: (now + expires_seconds) as usize,
+    };
     let header = Header::new(Algorithm::HS256);
     let token = encode(&header, &claims, &EncodingKey::from_secret(&jwt_secret()))?;
     Ok(token)
 }
 
-pub fn decode_token(token: &str) -> Result<String> {
+/// Check an access token's signature and expiry. Does not check revocation; see `authenticate`.
+pub fn decode_token(token: &str) -> Result<Claims> {
     let mut v = Validation::new(Algorithm::HS256);
     v.validate_exp = true;
     let token_data = decode::<Claims>(token, &DecodingKey::from_secret(&jwt_secret()), &v)?;
-    Ok(token_data.claims.sub)
+    Ok(token_data.claims)
 }
 
 pub fn extract_bearer(headers: &HeaderMap) -> Option<String> {
@@ -57,3 +142,183 @@ pub fn extract_bearer(headers: &HeaderMap) -> Option<String> {
         }
     })
 }
+
+fn random_token(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut buf);
+    to_hex(&buf)
+}
+
+/// Refresh tokens are stored as their SHA-256
+fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+}
+
+/// Open a session for a user who just logged in
+pub async fn start_session(pool: &SqlitePool, username: &str) -> Result<TokenPair> {
+    let now = chrono::Utc::now().timestamp();
+    let session_id = random_token(16);
+    db::create_session(pool, &session_id, username, now).await?;
+    issue_tokens(pool, username, &session_id, now).await
+}
+
+async fn issue_tokens(pool: &SqlitePool, username: &str, session_id: &str, now: i64) -> Result<TokenPair> {
+    let refresh_token = random_token(32);
+    db::insert_refresh_token(pool, &hash_token(&refresh_token), session_id, now + REFRESH_TOKEN_TTL).await?;
+    Ok(TokenPair {
+        access_token: create_token(username, session_id, ACCESS_TOKEN_TTL)?,
+        refresh_token,
+        token_type: "Bearer",
+        expires_in: ACCESS_TOKEN_TTL,
+    })
+}
+
+/// Exchange a refresh token for a new pair; None if it is unknown, expired or revoked.
+/// Each refresh token works once. Presenting a used one again revokes the whole
+/// session, since it means the token was copied.
+pub async fn refresh_session(pool: &SqlitePool, refresh_token: &str) -> Result<Option<TokenPair>> {
+    let now = chrono::Utc::now().timestamp();
+    let hash = hash_token(refresh_token);
+    let Some(stored) = db::find_refresh_token(pool, &hash).await? else {
+        return Ok(None);
+    };
+    if stored.session_revoked || stored.expires_at <= now {
+        return Ok(None);
+    }
+    if stored.used || !db::use_refresh_token(pool, &hash, now).await? {
+        log::warn!("refresh token reused for {}; revoking session", stored.username);
+        db::revoke_session(pool, &stored.session_id, now).await?;
+        return Ok(None);
+    }
+    Ok(Some(issue_tokens(pool, &stored.username, &stored.session_id, now).await?))
+}
+
+/// Validate an access token and check that its session is still open.
+/// The role is read from the database, so promotions and demotions apply at once.
+pub async fn authenticate(pool: &SqlitePool, token: &str) -> Result<AuthUser> {
+    let claims = decode_token(token)?;
+    let Some((user_id, role)) = db::session_user(pool, &claims.sid).await? else {
+        bail!("session revoked");
+    };
+    Ok(AuthUser {
+        user_id,
+        username: claims.sub,
+        session_id: claims.sid,
+        role: Role::parse(&role).unwrap_or(Role::Player),
+    })
+}
+
+/// The user behind a request's `Authorization: Bearer` access token.
+/// Extraction fails with 401 for missing, invalid, expired or revoked tokens.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub user_id: i64,
+    pub username: String,
+    pub session_id: String,
+    pub role: Role,
+}
+
+impl AuthUser {
+    pub fn has_role(&self, role: Role) -> bool {
+        self.role >= role
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let pool = req.app_data::<web::Data<SqlitePool>>().cloned();
+        let token = extract_bearer(req.headers());
+        Box::pin(async move {
+            let pool = pool.ok_or_else(|| actix_web::error::ErrorInternalServerError("no database"))?;
+            let token = token.ok_or_else(|| actix_web::error::ErrorUnauthorized("missing token"))?;
+            authenticate(&pool, &token).await.map_err(|e| {
+                log::warn!("token rejected: {}", e);
+                actix_web::error::ErrorUnauthorized("invalid token")
+            })
+        })
+    }
+}
+
+/// Middleware for the `/api/admin` scope: 401 without a valid token, 403 unless admin
+pub async fn require_admin(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let user = match req.extract::<AuthUser>().await {
+        Ok(user) => user,
+        Err(e) => return Ok(req.error_response(e).map_into_right_body()),
+    };
+    if !user.has_role(Role::Admin) {
+        log::warn!("{} ({}) denied {}", user.username, user.role.as_str(), req.path());
+        let e = actix_web::error::ErrorForbidden("admin role required");
+        return Ok(req.error_response(e).map_into_right_body());
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::memory_pool;
+
+    #[test]
+    fn rejects_weak_secrets() {
+        assert!(secret_problem(None).is_some());
+        assert!(secret_problem(Some(DEV_SECRET)).is_some());
+        assert!(secret_problem(Some("change-me-please")).is_some());
+        assert!(secret_problem(Some("short")).is_some());
+        assert!(secret_problem(Some("f3b1c0a9d8e7f6a5b4c3d2e1f0a9b8c7d6")).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn refresh_rotates_and_reuse_revokes() {
+        let pool = memory_pool().await;
+        db::create_user(&pool, "alice", "hash").await.unwrap();
+
+        let first = start_session(&pool, "alice").await.unwrap();
+        let user = authenticate(&pool, &first.access_token).await.unwrap();
+        assert_eq!(user.username, "alice");
+
+        let second = refresh_session(&pool, &first.refresh_token).await.unwrap().unwrap();
+        assert_ne!(second.refresh_token, first.refresh_token);
+        assert!(authenticate(&pool, &second.access_token).await.is_ok());
+        assert!(refresh_session(&pool, "not-a-token").await.unwrap().is_none());
+
+        // replaying the first refresh token kills the session and every token in it
+        assert!(refresh_session(&pool, &first.refresh_token).await.unwrap().is_none());
+        assert!(refresh_session(&pool, &second.refresh_token).await.unwrap().is_none());
+        assert!(authenticate(&pool, &second.access_token).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn logout_revokes_sessions() {
+        let pool = memory_pool().await;
+        db::create_user(&pool, "bob", "hash").await.unwrap();
+
+        let phone = start_session(&pool, "bob").await.unwrap();
+        let laptop = start_session(&pool, "bob").await.unwrap();
+        let user = authenticate(&pool, &phone.access_token).await.unwrap();
+
+        db::revoke_session(&pool, &user.session_id, 1).await.unwrap();
+        assert!(authenticate(&pool, &phone.access_token).await.is_err());
+        assert!(authenticate(&pool, &laptop.access_token).await.is_ok());
+
+        assert_eq!(db::revoke_user_sessions(&pool, "bob", 2).await.unwrap(), 1);
+        assert!(refresh_session(&pool, &laptop.refresh_token).await.unwrap().is_none());
+    }
+}
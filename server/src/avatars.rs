@@ -0,0 +1,72 @@
+use anyhow::{Result, bail};
+use std::path::{Path, PathBuf};
+
+/// Largest accepted avatar upload
+pub const MAX_AVATAR_BYTES: usize = 512 * 1024;
+
+const EXTENSIONS: [&str; 3] = ["png", "jpg", "webp"];
+
+/// Where uploaded avatars are kept (`AVATARS_DIR`)
+pub fn avatars_dir() -> PathBuf {
+    PathBuf::from(std::env::var("AVATARS_DIR").unwrap_or_else(|_| "server/data/avatars".to_string()))
+}
+
+/// File extension for PNG, JPEG and WebP images, recognised by their magic bytes
+pub fn image_extension(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("png")
+    } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("jpg")
+    } else if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("webp")
+    } else {
+        None
+    }
+}
+
+/// Store a user's avatar as `{user_id}.{ext}`, replacing any previous one. Returns the file name.
+pub fn save(dir: &Path, user_id: i64, bytes: &[u8]) -> Result<String> {
+    let Some(ext) = image_extension(bytes) else {
+        bail!("avatar must be a PNG, JPEG or WebP image");
+    };
+    std::fs::create_dir_all(dir)?;
+    remove(dir, user_id)?;
+
+    let name = format!("{}.{}", user_id, ext);
+    let tmp = dir.join(format!("{}.tmp", user_id));
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(&tmp, dir.join(&name))?;
+    Ok(name)
+}
+
+/// Delete a user's avatar files, if any
+pub fn remove(dir: &Path, user_id: i64) -> Result<()> {
+    for ext in EXTENSIONS {
+        let path = dir.join(format!("{}.{}", user_id, ext));
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_only_images_and_replaces_old_ones() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let png = b"\x89PNG\r\n\x1a\n rest of the image";
+        let jpeg = [0xff, 0xd8, 0xff, 0xe0, 0, 0];
+
+        assert!(save(dir.path(), 7, b"<svg onload=alert(1)>").is_err());
+        assert_eq!(save(dir.path(), 7, png).unwrap(), "7.png");
+        assert_eq!(save(dir.path(), 7, &jpeg).unwrap(), "7.jpg");
+        assert!(!dir.path().join("7.png").exists());
+        assert_eq!(image_extension(b"RIFF\0\0\0\0WEBPVP8 "), Some("webp"));
+
+        remove(dir.path(), 7).unwrap();
+        assert!(!dir.path().join("7.jpg").exists());
+    }
+}
@@ -1,6 +1,38 @@
+use rhythm_pi_server::chart_gen;
+use std::path::Path;
 use std::process::exit;
 
+/// Generate charts for the given song ids (or every song in SONGS_DIR) into CHARTS_DIR,
+/// applying each song's `charter` settings from `{id}.json`. Existing charts are replaced.
 fn main() {
-    eprintln!("Chart generation is disabled. Please use Moonscraper and place the charts in server/assets/charts.");
-    exit(1);
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let songs_dir = std::env::var("SONGS_DIR").unwrap_or_else(|_| "server/assets/songs".to_string());
+    let charts_dir = std::env::var("CHARTS_DIR").unwrap_or_else(|_| "server/assets/charts".to_string());
+
+    let mut song_ids: Vec<String> = std::env::args().skip(1).collect();
+    if song_ids.is_empty() {
+        song_ids = match rhythm_pi_charter::batch::song_ids(Path::new(&songs_dir)) {
+            Ok(ids) => ids,
+            Err(e) => {
+                eprintln!("Failed to list songs in {}: {}", songs_dir, e);
+                exit(1);
+            }
+        };
+    }
+
+    let mut failed = 0;
+    for song_id in &song_ids {
+        match chart_gen::generate_charts_for_song(song_id, Path::new(&songs_dir), Path::new(&charts_dir), true) {
+            Ok(written) => println!("{}: {} charts", song_id, written.len()),
+            Err(e) => {
+                eprintln!("{}: {}", song_id, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        exit(1);
+    }
 }
@@ -0,0 +1,54 @@
+use rhythm_pi_server::song_meta::{self, SongMetadata};
+use std::process::exit;
+
+/// Rewrite every `{id}.json` in SONGS_DIR with the canonical metadata keys.
+/// Pass `--check` to only report invalid or legacy files.
+fn main() {
+    let check = std::env::args().any(|a| a == "--check");
+    let songs_dir = std::env::var("SONGS_DIR").unwrap_or_else(|_| "server/assets/songs".to_string());
+
+    let entries = match std::fs::read_dir(&songs_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", songs_dir, e);
+            exit(1);
+        }
+    };
+
+    let mut failed = 0;
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let name = path.display();
+
+        match SongMetadata::load(&path) {
+            Ok(meta) => {
+                for problem in meta.validate() {
+                    println!("{}: {}", name, problem);
+                }
+            }
+            Err(e) => {
+                eprintln!("{:#}", e);
+                failed += 1;
+                continue;
+            }
+        }
+
+        if check {
+            continue;
+        }
+        match song_meta::migrate_file(&path) {
+            Ok(true) => println!("{}: migrated", name),
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        exit(1);
+    }
+}
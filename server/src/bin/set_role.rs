@@ -0,0 +1,38 @@
+use rhythm_pi_server::auth::Role;
+use rhythm_pi_server::db;
+use sqlx::SqlitePool;
+use std::process::exit;
+
+/// Set a user's role directly in the database, e.g. to create the first admin:
+/// `set_role alice admin`. Uses DATABASE_URL like the server.
+#[actix_web::main]
+async fn main() {
+    let _ = dotenvy::dotenv();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [username, role] = args.as_slice() else {
+        eprintln!("usage: set_role <username> <player|moderator|admin>");
+        exit(2);
+    };
+    let Some(role) = Role::parse(role) else {
+        eprintln!("unknown role {}", role);
+        exit(2);
+    };
+
+    let db_url = std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:server/data/rhythm.db".to_string());
+    let result = async {
+        let pool = SqlitePool::connect(&db_url).await?;
+        db::init_db(&pool).await?;
+        db::set_user_role(&pool, username, role.as_str()).await
+    };
+    match result.await {
+        Ok(true) => println!("{} is now {}", username, role.as_str()),
+        Ok(false) => {
+            eprintln!("no user named {}", username);
+            exit(1);
+        }
+        Err(e) => {
+            eprintln!("failed: {}", e);
+            exit(1);
+        }
+    }
+}
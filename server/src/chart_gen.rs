@@ -1,4 +1,5 @@
-use anyhow::{Result, bail};
+use anyhow::Result;
+use rhythm_pi_charter::{CharterConfig, batch, exporter::ChartFormat};
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::path::{Path, PathBuf};
@@ -9,11 +10,14 @@ pub struct Chart {
     pub instrument: String,
     pub difficulty: String,
     pub columns: u8,
-    pub generated_at: i64,
+    #[serde(default)]
+    pub generated_at: Option<i64>,
     pub notes: Vec<serde_json::Value>,
 }
 
-pub fn generate_charts_for_song(song_id: &str, songs_dir: &Path, charts_dir: &Path) -> Result<Vec<PathBuf>> {
-    let _ = (song_id, songs_dir, charts_dir);
-    bail!("chart generation disabled (use external tool)")
+/// Generate every instrument and difficulty for `{song_id}.wav`, applying the `charter`
+/// block of `{song_id}.json` so manual tweaks survive regeneration. Charts that already
+/// exist are only replaced when `force` is set.
+pub fn generate_charts_for_song(song_id: &str, songs_dir: &Path, charts_dir: &Path, force: bool) -> Result<Vec<PathBuf>> {
+    batch::write_song_charts(songs_dir, song_id, charts_dir, &CharterConfig::default(), &batch::INSTRUMENTS, ChartFormat::Json, force)
 }
@@ -0,0 +1,239 @@
+use anyhow::{bail, Context, Result};
+use rhythm_pi_scoring::ChartNote;
+use serde_json::Value;
+use sqlx::SqlitePool;
+use std::path::{Path, PathBuf};
+
+use crate::db;
+use crate::verify::StoredChart;
+
+/// Most lanes a chart may declare
+pub const MAX_COLUMNS: u64 = 8;
+
+/// Most problems reported for one chart; past this the rest is noise
+const MAX_PROBLEMS: usize = 10;
+
+/// Where player-made charts are stored (`CUSTOM_CHARTS_DIR`, default `server/data/charts`).
+/// Files are named by content hash; the `custom_charts` table says what they are.
+pub fn custom_charts_dir() -> PathBuf {
+    PathBuf::from(std::env::var("CUSTOM_CHARTS_DIR").unwrap_or_else(|_| "server/data/charts".to_string()))
+}
+
+/// Instrument and difficulty of every generated chart file of a song, from the names
+/// `{song_id}_{instrument}_{difficulty}.json` (or the older `.chart.json`) in `charts_dir`
+fn chart_files(charts_dir: &Path, song_id: &str) -> Vec<(String, String, String)> {
+    let Ok(entries) = std::fs::read_dir(charts_dir) else {
+        return Vec::new();
+    };
+    let prefix = format!("{}_", song_id);
+    let mut found: Vec<_> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().to_str()?.to_string();
+            let rest = name.strip_prefix(&prefix)?;
+            let rest = rest.strip_suffix(".chart.json").or_else(|| rest.strip_suffix(".json"))?;
+            // exactly two parts left, so `song` does not pick up `song_remix_drums_Hard.json`
+            let (instrument, difficulty) = rest.split_once('_')?;
+            if instrument.is_empty() || difficulty.is_empty() || difficulty.contains('_') {
+                return None;
+            }
+            Some((instrument.to_string(), difficulty.to_string(), name))
+        })
+        .collect();
+    // `.json` sorts before `.chart.json` for the same chart, and wins
+    found.sort_by_key(|(instrument, difficulty, name)| (instrument.to_lowercase(), difficulty.to_lowercase(), name.len()));
+    found.dedup_by(|a, b| a.0.eq_ignore_ascii_case(&b.0) && a.1.eq_ignore_ascii_case(&b.1));
+    found
+}
+
+/// Index entries for a song's generated charts on disk; unreadable charts are skipped
+pub fn scan_song(charts_dir: &Path, song_id: &str) -> Vec<db::IndexedChart> {
+    chart_files(charts_dir, song_id)
+        .into_iter()
+        .filter_map(|(instrument, difficulty, path)| match StoredChart::load(&charts_dir.join(&path)) {
+            Ok(chart) => Some(db::IndexedChart {
+                song_id: song_id.to_string(),
+                instrument,
+                difficulty,
+                path,
+                hash: chart.hash,
+                note_count: chart.notes.len() as i64,
+            }),
+            Err(e) => {
+                log::warn!("not indexing chart: {:#}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Re-read a song's generated charts into the chart index. Returns how many there are.
+pub async fn reindex_song(pool: &SqlitePool, charts_dir: &Path, song_id: &str) -> Result<usize> {
+    let charts = scan_song(charts_dir, song_id);
+    db::replace_song_charts(pool, song_id, &charts, chrono::Utc::now().timestamp()).await?;
+    Ok(charts.len())
+}
+
+/// Rename a song's generated chart files from `{old}_…` to `{new}_…`. Returns how many moved.
+pub fn rename_song_files(charts_dir: &Path, old: &str, new: &str) -> Result<usize> {
+    let files = chart_files(charts_dir, old);
+    for (_, _, name) in &files {
+        let renamed = format!("{}{}", new, &name[old.len()..]);
+        std::fs::rename(charts_dir.join(name), charts_dir.join(&renamed))
+            .with_context(|| format!("renaming chart {} to {}", name, renamed))?;
+    }
+    Ok(files.len())
+}
+
+/// A song's indexed charts. A song the watcher has not indexed yet is indexed first.
+pub async fn song_charts(pool: &SqlitePool, charts_dir: &Path, song_id: &str) -> Result<Vec<db::IndexedChart>> {
+    let charts = db::song_charts(pool, song_id).await?;
+    if !charts.is_empty() {
+        return Ok(charts);
+    }
+    reindex_song(pool, charts_dir, song_id).await?;
+    db::song_charts(pool, song_id).await
+}
+
+/// The first of `charts` matching the instrument and difficulty given, ignoring case
+pub fn select<'a>(charts: &'a [db::IndexedChart], instrument: Option<&str>, difficulty: Option<&str>) -> Option<&'a db::IndexedChart> {
+    charts.iter().find(|c| {
+        instrument.is_none_or(|i| c.instrument.eq_ignore_ascii_case(i))
+            && difficulty.is_none_or(|d| c.difficulty.eq_ignore_ascii_case(d))
+    })
+}
+
+/// A chart sent by a player, either with a song upload or on its own
+#[derive(Debug)]
+pub struct UploadedChart {
+    pub instrument: String,
+    pub difficulty: String,
+    pub columns: u64,
+    pub notes: Vec<ChartNote>,
+    json: Value,
+}
+
+impl UploadedChart {
+    /// Parse chart JSON; it needs an `instrument`, a `difficulty` and a `notes` array
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let json: Value = serde_json::from_slice(bytes).context("chart is not valid JSON")?;
+        let name = |key: &str| -> Result<String> {
+            let value = json.get(key).and_then(Value::as_str).unwrap_or_default();
+            if value.is_empty() || value.len() > 32 || !value.chars().all(|c| c.is_ascii_alphanumeric()) {
+                bail!("chart {} must be 1-32 letters or digits", key);
+            }
+            Ok(value.to_string())
+        };
+        let (instrument, difficulty) = (name("instrument")?, name("difficulty")?);
+        let columns = match json.get("columns") {
+            None => 4,
+            Some(c) => c.as_u64().context("chart columns must be a whole number")?,
+        };
+        let notes = json.get("notes").filter(|n| n.is_array()).context("chart has no notes array")?;
+        let notes: Vec<ChartNote> = serde_json::from_value(notes.clone()).context("invalid notes")?;
+        Ok(Self { instrument, difficulty, columns, notes, json })
+    }
+
+    /// Problems that make the chart unplayable; empty when valid. Notes must be in time
+    /// order, in a lane the chart declares and, when the song's length is known, end
+    /// before the song does.
+    pub fn problems(&self, song_duration_ms: Option<i64>) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !(1..=MAX_COLUMNS).contains(&self.columns) {
+            problems.push(format!("columns must be between 1 and {}", MAX_COLUMNS));
+        }
+        if self.notes.is_empty() {
+            problems.push("chart has no notes".to_string());
+        }
+        let song_end = song_duration_ms.map(|ms| ms as f32 / 1000.0);
+        let mut previous = 0.0;
+        for (i, note) in self.notes.iter().enumerate() {
+            if !note.time.is_finite() || note.time < 0.0 {
+                problems.push(format!("note {} has invalid time {}", i, note.time));
+            } else if note.time < previous {
+                problems.push(format!("note {} at {}s comes before the note ahead of it", i, note.time));
+            } else {
+                previous = note.time;
+            }
+            if !note.duration.is_finite() || note.duration < 0.0 {
+                problems.push(format!("note {} has invalid duration {}", i, note.duration));
+            }
+            if u64::from(note.col) >= self.columns {
+                problems.push(format!("note {} is in column {} of {}", i, note.col, self.columns));
+            }
+            if let Some(end) = song_end.filter(|end| note.time + note.duration > *end) {
+                problems.push(format!("note {} at {}s ends after the song ({}s)", i, note.time, end));
+            }
+            if problems.len() >= MAX_PROBLEMS {
+                break;
+            }
+        }
+        problems
+    }
+
+    /// `{song_id}_{instrument}_{difficulty}.json`, the name the charter writes
+    pub fn file_name(&self, song_id: &str) -> String {
+        format!("{}_{}_{}.json", song_id, self.instrument, self.difficulty)
+    }
+
+    /// The chart JSON pointing at `song_id` (whatever id the uploader used), with its author
+    pub fn to_bytes(&self, song_id: &str, author: Option<&str>) -> Result<Vec<u8>> {
+        let mut json = self.json.clone();
+        json["song_id"] = Value::String(song_id.to_string());
+        if let Some(author) = author {
+            json["author"] = Value::String(author.to_string());
+        }
+        Ok(serde_json::to_vec_pretty(&json)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn charts_are_checked_and_renamed() {
+        let chart = UploadedChart::parse(br#"{"song_id":"x","instrument":"drums","difficulty":"Hard","notes":[{"time":1,"col":0}]}"#).unwrap();
+        assert_eq!(chart.file_name("song"), "song_drums_Hard.json");
+        assert!(chart.problems(None).is_empty());
+        let stored: Value = serde_json::from_slice(&chart.to_bytes("song", Some("alice")).unwrap()).unwrap();
+        assert_eq!((stored["song_id"].as_str(), stored["author"].as_str()), (Some("song"), Some("alice")));
+
+        assert!(UploadedChart::parse(br#"{"instrument":"../x","difficulty":"Hard","notes":[]}"#).is_err());
+        assert!(UploadedChart::parse(br#"{"instrument":"drums","difficulty":"Hard"}"#).is_err());
+        assert!(UploadedChart::parse(br#"{"instrument":"drums","difficulty":"Hard","notes":[{"time":1,"col":-1}]}"#).is_err());
+    }
+
+    #[test]
+    fn finds_unplayable_notes() {
+        let json = br#"{"instrument":"drums","difficulty":"Hard","columns":4,"notes":[
+            {"time":1.0,"col":0},{"time":0.5,"col":1},{"time":2.0,"col":4},{"time":9.5,"col":0,"duration":1.0}]}"#;
+        let problems = UploadedChart::parse(json).unwrap().problems(Some(10_000));
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].contains("note 1"));
+        assert!(problems[1].contains("column 4"));
+        assert!(problems[2].contains("after the song"));
+
+        let empty = UploadedChart::parse(br#"{"instrument":"drums","difficulty":"Hard","columns":0,"notes":[]}"#).unwrap();
+        assert_eq!(empty.problems(None).len(), 2);
+    }
+
+    #[test]
+    fn indexes_generated_charts_by_exact_name() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let chart = r#"{"notes": [{"time": 1.0, "col": 0}]}"#;
+        for name in ["song_drums_Hard.json", "song_drums_Hard.chart.json", "song_bass_Easy.chart.json", "song_remix_drums_Hard.json", "songs_drums_Hard.json"] {
+            std::fs::write(dir.path().join(name), chart).unwrap();
+        }
+        std::fs::write(dir.path().join("song_lead_Hard.json"), "not json").unwrap();
+
+        let found = scan_song(dir.path(), "song");
+        let names: Vec<_> = found.iter().map(|c| (c.instrument.as_str(), c.difficulty.as_str(), c.path.as_str())).collect();
+        assert_eq!(names, [("bass", "Easy", "song_bass_Easy.chart.json"), ("drums", "Hard", "song_drums_Hard.json")]);
+        assert_eq!(found[1].note_count, 1);
+
+        assert_eq!(select(&found, Some("DRUMS"), Some("hard")).map(|c| c.path.as_str()), Some("song_drums_Hard.json"));
+        assert_eq!(select(&found, None, Some("easy")).map(|c| c.instrument.as_str()), Some("bass"));
+        assert!(select(&found, Some("drums"), Some("Easy")).is_none());
+    }
+}
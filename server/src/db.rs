@@ -1,127 +1,681 @@
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqliteRow;
 use sqlx::{SqlitePool, Row};
+use std::collections::HashMap;
 
+use crate::song_meta::SongMetadata;
+
+/// Bring the database schema up to date (see `migrations`)
 pub async fn init_db(pool: &SqlitePool) -> Result<()> {
-    // simple scores table. Only scores with online = 1 are considered for leaderboards
+    let version = crate::migrations::run(pool).await?;
+    log::info!("database schema at version {}", version);
+    Ok(())
+}
+
+pub async fn upsert_song(
+    pool: &SqlitePool,
+    id: &str,
+    filename: &str,
+    meta: &SongMetadata,
+    mtime: i64,
+) -> Result<()> {
+    let now = chrono::Utc::now().timestamp();
     sqlx::query(
-        r#"CREATE TABLE IF NOT EXISTS scores (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            song_id TEXT NOT NULL,
-            player TEXT NOT NULL,
-            score INTEGER NOT NULL,
-            timestamp INTEGER NOT NULL,
-            online INTEGER NOT NULL
-        );"#,
+        "INSERT INTO songs (id, filename, title, artist, artists, album, year, genre, offset_ms, preview_start_ms, duration_ms, bpm, tags, mtime, registered_at) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) \
+         ON CONFLICT(id) DO UPDATE SET filename = excluded.filename, title = excluded.title, artist = excluded.artist, \
+         artists = excluded.artists, album = excluded.album, year = excluded.year, genre = excluded.genre, \
+         offset_ms = excluded.offset_ms, preview_start_ms = excluded.preview_start_ms, duration_ms = excluded.duration_ms, \
+         bpm = excluded.bpm, tags = excluded.tags, mtime = excluded.mtime"
     )
+    .bind(id)
+    .bind(filename)
+    .bind(&meta.title)
+    .bind(meta.artist_line())
+    .bind(serde_json::to_string(&meta.artists)?)
+    .bind(&meta.album)
+    .bind(meta.year)
+    .bind(&meta.genre)
+    .bind(meta.offset_ms)
+    .bind(meta.preview_start_ms)
+    .bind(meta.duration_ms)
+    .bind(meta.bpm)
+    .bind(serde_json::to_string(&meta.tags)?)
+    .bind(mtime)
+    .bind(now)
     .execute(pool)
     .await?;
+    Ok(())
+}
 
-    // users table for simple auth
-    sqlx::query(
-        r#"CREATE TABLE IF NOT EXISTS users (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            username TEXT NOT NULL UNIQUE,
-            password_hash TEXT NOT NULL
-        );"#,
+/// What the watcher last saw of a song's files
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SongFiles {
+    pub filename: String, // audio file name in SONGS_DIR
+    pub mtime: i64,
+    pub audio_size: i64,
+    pub audio_hash: Option<String>,
+    pub meta_mtime: Option<i64>,
+    pub meta_hash: Option<String>,
+}
+
+fn song_files_from_row(r: &SqliteRow) -> Result<SongFiles> {
+    Ok(SongFiles {
+        filename: r.try_get("filename")?,
+        mtime: r.try_get::<Option<i64>, _>("mtime")?.unwrap_or(0),
+        audio_size: r.try_get::<Option<i64>, _>("audio_size")?.unwrap_or(0),
+        audio_hash: r.try_get("audio_hash")?,
+        meta_mtime: r.try_get("meta_mtime")?,
+        meta_hash: r.try_get("meta_hash")?,
+    })
+}
+
+/// File state of every registered song, by id
+pub async fn song_files(pool: &SqlitePool) -> Result<HashMap<String, SongFiles>> {
+    let rows = sqlx::query("SELECT id, filename, mtime, audio_size, audio_hash, meta_mtime, meta_hash FROM songs")
+        .fetch_all(pool)
+        .await?;
+    let mut files = HashMap::new();
+    for r in rows {
+        files.insert(r.try_get("id")?, song_files_from_row(&r)?);
+    }
+    Ok(files)
+}
+
+/// File state of one song, if it is registered
+pub async fn get_song_files(pool: &SqlitePool, id: &str) -> Result<Option<SongFiles>> {
+    let row = sqlx::query("SELECT filename, mtime, audio_size, audio_hash, meta_mtime, meta_hash FROM songs WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    row.as_ref().map(song_files_from_row).transpose()
+}
+
+/// When anything in the song list last changed: the newest registration or file mtime
+pub async fn songs_last_modified(pool: &SqlitePool) -> Result<Option<i64>> {
+    let newest: Option<i64> = sqlx::query_scalar(
+        "SELECT MAX(MAX(registered_at, COALESCE(mtime, 0), COALESCE(meta_mtime, 0))) FROM songs"
     )
-    .execute(pool)
+    .fetch_one(pool)
     .await?;
+    Ok(newest)
+}
 
-    // songs table: store registered songs and file mtimes so we can detect changes
+pub async fn set_song_files(pool: &SqlitePool, id: &str, files: &SongFiles) -> Result<()> {
     sqlx::query(
-        r#"CREATE TABLE IF NOT EXISTS songs (
-            id TEXT PRIMARY KEY,
-            filename TEXT NOT NULL,
-            title TEXT,
-            artist TEXT,
-            mtime INTEGER,
-            registered_at INTEGER NOT NULL
-        );"#,
+        "UPDATE songs SET filename = ?, mtime = ?, audio_size = ?, audio_hash = ?, meta_mtime = ?, meta_hash = ? WHERE id = ?"
     )
+    .bind(&files.filename)
+    .bind(files.mtime)
+    .bind(files.audio_size)
+    .bind(&files.audio_hash)
+    .bind(files.meta_mtime)
+    .bind(&files.meta_hash)
+    .bind(id)
     .execute(pool)
     .await?;
+    Ok(())
+}
 
+/// Move a song and everything that refers to it to a new id. Indexed chart paths are
+/// renamed the same way as the files (`{old}_…` to `{new}_…`).
+pub async fn rename_song(pool: &SqlitePool, old: &str, new: &str) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    for table in ["songs", "scores", "custom_charts", "jobs", "song_variants"] {
+        let column = if table == "songs" { "id" } else { "song_id" };
+        sqlx::query(&format!("UPDATE {} SET {} = ? WHERE {} = ?", table, column, column))
+            .bind(new)
+            .bind(old)
+            .execute(&mut *tx)
+            .await?;
+    }
+    sqlx::query("UPDATE charts SET song_id = ?, path = ? || substr(path, ?) WHERE song_id = ?")
+        .bind(new)
+        .bind(new)
+        .bind(old.chars().count() as i64 + 1)
+        .bind(old)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
     Ok(())
 }
 
-pub async fn upsert_song(
-    pool: &SqlitePool,
-    id: &str,
-    filename: &str,
-    title: Option<&str>,
-    artist: Option<&str>,
-    mtime: i64,
-) -> Result<()> {
-    let now = chrono::Utc::now().timestamp();
+/// Forget a song whose audio is gone. Scores and custom charts are kept in case it returns.
+pub async fn delete_song(pool: &SqlitePool, id: &str) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM charts WHERE song_id = ?").bind(id).execute(&mut *tx).await?;
+    sqlx::query("DELETE FROM song_variants WHERE song_id = ?").bind(id).execute(&mut *tx).await?;
+    sqlx::query("DELETE FROM songs WHERE id = ?").bind(id).execute(&mut *tx).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// A transcoded copy of a song for streaming
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SongVariant {
+    pub song_id: String,
+    pub quality: String,
+    #[serde(skip)]
+    pub path: String, // file name in the variants directory
+    pub source_hash: String,
+    pub size: i64,
+    pub hash: String,
+    pub created_at: i64,
+}
+
+pub async fn song_variants(pool: &SqlitePool, song_id: &str) -> Result<Vec<SongVariant>> {
+    let rows = sqlx::query(
+        "SELECT song_id, quality, path, source_hash, size, hash, created_at FROM song_variants WHERE song_id = ? ORDER BY size"
+    )
+    .bind(song_id)
+    .fetch_all(pool)
+    .await?;
+    rows.iter()
+        .map(|r| {
+            Ok(SongVariant {
+                song_id: r.try_get("song_id")?,
+                quality: r.try_get("quality")?,
+                path: r.try_get("path")?,
+                source_hash: r.try_get("source_hash")?,
+                size: r.try_get("size")?,
+                hash: r.try_get("hash")?,
+                created_at: r.try_get("created_at")?,
+            })
+        })
+        .collect()
+}
+
+/// Record a variant, replacing the song's previous one of that quality
+pub async fn set_song_variant(pool: &SqlitePool, variant: &SongVariant) -> Result<()> {
     sqlx::query(
-        "INSERT INTO songs (id, filename, title, artist, mtime, registered_at) VALUES (?, ?, ?, ?, ?, ?) ON CONFLICT(id) DO UPDATE SET filename = excluded.filename, title = excluded.title, artist = excluded.artist, mtime = excluded.mtime"
+        "INSERT INTO song_variants (song_id, quality, path, source_hash, size, hash, created_at) VALUES (?, ?, ?, ?, ?, ?, ?) \
+         ON CONFLICT(song_id, quality) DO UPDATE SET path = excluded.path, source_hash = excluded.source_hash, \
+         size = excluded.size, hash = excluded.hash, created_at = excluded.created_at"
     )
-    .bind(id)
-    .bind(filename)
-    .bind(title)
-    .bind(artist)
-    .bind(mtime)
-    .bind(now)
+    .bind(&variant.song_id)
+    .bind(&variant.quality)
+    .bind(&variant.path)
+    .bind(&variant.source_hash)
+    .bind(variant.size)
+    .bind(&variant.hash)
+    .bind(variant.created_at)
     .execute(pool)
     .await?;
     Ok(())
 }
 
-pub async fn list_songs_db(pool: &SqlitePool) -> Result<Vec<(String, String)>> {
-    let rows = sqlx::query("SELECT id, filename FROM songs ORDER BY registered_at DESC")
-        .fetch_all(pool)
+/// Whether a song other than `song_id` has a variant stored in `path`
+pub async fn variant_file_shared(pool: &SqlitePool, path: &str, song_id: &str) -> Result<bool> {
+    let found: Option<i64> = sqlx::query_scalar("SELECT 1 FROM song_variants WHERE path = ? AND song_id != ? LIMIT 1")
+        .bind(path)
+        .bind(song_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(found.is_some())
+}
+
+pub async fn song_exists(pool: &SqlitePool, id: &str) -> Result<bool> {
+    let found: Option<i64> = sqlx::query_scalar("SELECT 1 FROM songs WHERE id = ? COLLATE NOCASE")
+        .bind(id)
+        .fetch_optional(pool)
         .await?;
+    Ok(found.is_some())
+}
+
+pub async fn list_songs_db(pool: &SqlitePool) -> Result<Vec<(String, String, SongMetadata)>> {
+    let rows = sqlx::query(
+        "SELECT id, filename, title, artists, album, year, genre, offset_ms, preview_start_ms, duration_ms, bpm, tags \
+         FROM songs ORDER BY registered_at DESC"
+    )
+    .fetch_all(pool)
+    .await?;
     let mut v = Vec::new();
     for r in rows {
         let id: String = r.try_get("id")?;
         let filename: String = r.try_get("filename")?;
-        v.push((id, filename));
+        let artists: Option<String> = r.try_get("artists")?;
+        let tags: Option<String> = r.try_get("tags")?;
+        let meta = SongMetadata {
+            title: r.try_get("title")?,
+            artists: artists.and_then(|a| serde_json::from_str(&a).ok()).unwrap_or_default(),
+            album: r.try_get("album")?,
+            year: r.try_get("year")?,
+            genre: r.try_get("genre")?,
+            offset_ms: r.try_get("offset_ms")?,
+            preview_start_ms: r.try_get("preview_start_ms")?,
+            duration_ms: r.try_get("duration_ms")?,
+            bpm: r.try_get::<Option<f64>, _>("bpm")?.map(|b| b as f32),
+            tags: tags.and_then(|t| serde_json::from_str(&t).ok()).unwrap_or_default(),
+        };
+        v.push((id, filename, meta));
     }
     Ok(v)
 }
 
-pub async fn insert_score(
-    pool: &SqlitePool,
-    song_id: &str,
-    player: &str,
-    score: i64,
-    timestamp: i64,
-    online: bool,
-) -> Result<()> {
-    sqlx::query("INSERT INTO scores (song_id, player, score, timestamp, online) VALUES (?, ?, ?, ?, ?)")
-        .bind(song_id)
-        .bind(player)
-        .bind(score)
-        .bind(timestamp)
-        .bind(if online { 1 } else { 0 })
+/// Judgement counts for one play, in the client's HitAccuracy order
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Judgements {
+    pub perfect: i64,
+    pub great: i64,
+    pub good: i64,
+    pub ok: i64,
+    pub miss: i64,
+}
+
+impl Judgements {
+    /// No negative counts
+    pub fn is_valid(&self) -> bool {
+        [self.perfect, self.great, self.good, self.ok, self.miss].iter().all(|&n| n >= 0)
+    }
+
+    pub fn total(&self) -> i64 {
+        self.perfect + self.great + self.good + self.ok + self.miss
+    }
+
+    /// Points earned as a percentage of all-perfect (300/200/100/50/0 per judgement)
+    pub fn accuracy(&self) -> Option<f64> {
+        let total = self.total();
+        if total <= 0 {
+            return None;
+        }
+        let points = self.perfect * 300 + self.great * 200 + self.good * 100 + self.ok * 50;
+        Some(points as f64 / (total * 300) as f64 * 100.0)
+    }
+}
+
+/// One score row. Optional fields are NULL for scores from older clients.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ScoreRecord {
+    pub song_id: String,
+    pub player: String,       // username for online scores, free text offline
+    pub user_id: Option<i64>, // the account behind an online score
+    pub score: i64,
+    pub timestamp: i64,
+    pub online: bool,
+    pub instrument: Option<String>,
+    pub difficulty: Option<String>,
+    pub chart_hash: Option<String>,
+    pub max_combo: Option<i64>,
+    pub judgements: Option<Judgements>,
+    pub accuracy: Option<f64>,
+    pub claimed_score: Option<i64>, // what the client reported, when `score` was recomputed
+    pub verified: Option<bool>,     // None when no replay was submitted
+}
+
+/// Filters and paging for `leaderboard`
+#[derive(Debug, Clone, Default)]
+pub struct LeaderboardQuery {
+    pub instrument: Option<String>,
+    pub difficulty: Option<String>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+/// A player's best score on a leaderboard
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LeaderboardEntry {
+    pub rank: i64,
+    pub score_id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replay_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(flatten)]
+    pub record: ScoreRecord,
+}
+
+/// Insert a score and return its id
+pub async fn insert_score(pool: &SqlitePool, record: &ScoreRecord) -> Result<i64> {
+    let j = record.judgements;
+    let result = sqlx::query(
+        "INSERT INTO scores (song_id, player, user_id, score, timestamp, online, instrument, difficulty, chart_hash, max_combo, perfect, great, good, ok, miss, accuracy, claimed_score, verified) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+        .bind(&record.song_id)
+        .bind(&record.player)
+        .bind(record.user_id)
+        .bind(record.score)
+        .bind(record.timestamp)
+        .bind(if record.online { 1 } else { 0 })
+        .bind(&record.instrument)
+        .bind(&record.difficulty)
+        .bind(&record.chart_hash)
+        .bind(record.max_combo)
+        .bind(j.map(|j| j.perfect))
+        .bind(j.map(|j| j.great))
+        .bind(j.map(|j| j.good))
+        .bind(j.map(|j| j.ok))
+        .bind(j.map(|j| j.miss))
+        .bind(record.accuracy)
+        .bind(record.claimed_score)
+        .bind(record.verified)
         .execute(pool)
         .await?;
 
+    Ok(result.last_insert_rowid())
+}
+
+/// Scores are grouped per player by account, or by name for scores without one
+const PER_PLAYER: &str = "PARTITION BY user_id, CASE WHEN user_id IS NULL THEN player END";
+
+/// Online leaderboard for a song with each player's best score only.
+/// Scores whose replay did not match are left out; scores sent without a replay count.
+/// Instrument and difficulty filters are case-insensitive.
+pub async fn leaderboard(pool: &SqlitePool, song_id: &str, query: &LeaderboardQuery) -> Result<Vec<LeaderboardEntry>> {
+    let sql = format!(
+        "SELECT * FROM ( \
+            SELECT scores.*, users.display_name, \
+                   ROW_NUMBER() OVER ({} ORDER BY score DESC, timestamp ASC) AS player_rank, \
+                   EXISTS (SELECT 1 FROM replays WHERE replays.score_id = scores.id) AS has_replay \
+            FROM scores LEFT JOIN users ON users.id = scores.user_id \
+            WHERE song_id = ? AND online = 1 AND (verified IS NULL OR verified = 1) \
+              AND (? IS NULL OR lower(instrument) = lower(?)) \
+              AND (? IS NULL OR lower(difficulty) = lower(?)) \
+         ) WHERE player_rank = 1 \
+         ORDER BY score DESC, timestamp ASC \
+         LIMIT ? OFFSET ?",
+        PER_PLAYER
+    );
+    let rows = sqlx::query(&sql)
+    .bind(song_id)
+    .bind(&query.instrument)
+    .bind(&query.instrument)
+    .bind(&query.difficulty)
+    .bind(&query.difficulty)
+    .bind(query.limit)
+    .bind(query.offset)
+    .fetch_all(pool)
+    .await?;
+
+    let mut v = Vec::new();
+    for (i, r) in rows.iter().enumerate() {
+        let score_id: i64 = r.try_get("id")?;
+        let has_replay: bool = r.try_get("has_replay")?;
+        v.push(LeaderboardEntry {
+            rank: query.offset + i as i64 + 1,
+            score_id,
+            replay_url: has_replay.then(|| format!("/api/scores/{}/replay", score_id)),
+            display_name: r.try_get("display_name")?,
+            record: score_from_row(r)?,
+        });
+    }
+    Ok(v)
+}
+
+pub async fn get_score(pool: &SqlitePool, id: i64) -> Result<Option<ScoreRecord>> {
+    let row = sqlx::query("SELECT * FROM scores WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    row.as_ref().map(score_from_row).transpose()
+}
+
+/// Store the compressed replay of a score (`size` is the uncompressed length)
+pub async fn insert_replay(pool: &SqlitePool, score_id: i64, data: &[u8], size: usize, created_at: i64) -> Result<()> {
+    sqlx::query("INSERT OR REPLACE INTO replays (score_id, data, size, created_at) VALUES (?, ?, ?, ?)")
+        .bind(score_id)
+        .bind(data)
+        .bind(size as i64)
+        .bind(created_at)
+        .execute(pool)
+        .await?;
     Ok(())
 }
 
-pub async fn top_scores(pool: &SqlitePool, song_id: &str, limit: i64) -> Result<Vec<(String, i64, i64)>> {
-    let rows = sqlx::query("SELECT player, score, timestamp FROM scores WHERE song_id = ? AND online = 1 ORDER BY score DESC LIMIT ?")
+/// Compressed replay of a score, if one was uploaded and not pruned
+pub async fn get_replay(pool: &SqlitePool, score_id: i64) -> Result<Option<Vec<u8>>> {
+    Ok(sqlx::query_scalar("SELECT data FROM replays WHERE score_id = ?")
+        .bind(score_id)
+        .fetch_optional(pool)
+        .await?)
+}
+
+/// Delete replays uploaded before `before`, except each player's best score per
+/// song, instrument and difficulty (the ones leaderboards link to). Returns how many went.
+pub async fn prune_replays(pool: &SqlitePool, before: i64) -> Result<u64> {
+    let sql = format!(
+        "DELETE FROM replays WHERE created_at < ? AND score_id NOT IN ( \
+            SELECT id FROM ( \
+                SELECT id, ROW_NUMBER() OVER ( \
+                    {}, song_id, lower(instrument), lower(difficulty) \
+                    ORDER BY score DESC, timestamp ASC) AS player_rank \
+                FROM scores \
+                WHERE online = 1 AND (verified IS NULL OR verified = 1) \
+            ) WHERE player_rank = 1 \
+         )",
+        PER_PLAYER
+    );
+    let result = sqlx::query(&sql)
+    .bind(before)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+fn score_from_row(r: &SqliteRow) -> Result<ScoreRecord> {
+    let perfect: Option<i64> = r.try_get("perfect")?;
+    let judgements = match perfect {
+        Some(perfect) => Some(Judgements {
+            perfect,
+            great: r.try_get::<Option<i64>, _>("great")?.unwrap_or(0),
+            good: r.try_get::<Option<i64>, _>("good")?.unwrap_or(0),
+            ok: r.try_get::<Option<i64>, _>("ok")?.unwrap_or(0),
+            miss: r.try_get::<Option<i64>, _>("miss")?.unwrap_or(0),
+        }),
+        None => None,
+    };
+
+    Ok(ScoreRecord {
+        song_id: r.try_get("song_id")?,
+        player: r.try_get("player")?,
+        user_id: r.try_get("user_id")?,
+        score: r.try_get("score")?,
+        timestamp: r.try_get("timestamp")?,
+        online: r.try_get::<i64, _>("online")? != 0,
+        instrument: r.try_get("instrument")?,
+        difficulty: r.try_get("difficulty")?,
+        chart_hash: r.try_get("chart_hash")?,
+        max_combo: r.try_get("max_combo")?,
+        judgements,
+        accuracy: r.try_get("accuracy")?,
+        claimed_score: r.try_get("claimed_score")?,
+        verified: r.try_get("verified")?,
+    })
+}
+
+/// A generated chart in the chart index
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct IndexedChart {
+    pub song_id: String,
+    pub instrument: String,
+    pub difficulty: String,
+    #[serde(skip)]
+    pub path: String, // file name in CHARTS_DIR
+    pub hash: String,
+    pub note_count: i64,
+}
+
+/// Replace a song's entries in the chart index with `charts`
+pub async fn replace_song_charts(pool: &SqlitePool, song_id: &str, charts: &[IndexedChart], now: i64) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM charts WHERE song_id = ?").bind(song_id).execute(&mut *tx).await?;
+    for chart in charts {
+        sqlx::query(
+            "INSERT OR REPLACE INTO charts (song_id, instrument, difficulty, path, hash, note_count, indexed_at) \
+             VALUES (?, ?, ?, ?, ?, ?, ?)"
+        )
         .bind(song_id)
-        .bind(limit)
-        .fetch_all(pool)
+        .bind(&chart.instrument)
+        .bind(&chart.difficulty)
+        .bind(&chart.path)
+        .bind(&chart.hash)
+        .bind(chart.note_count)
+        .bind(now)
+        .execute(&mut *tx)
         .await?;
-
-    let mut v = Vec::new();
-    for r in rows {
-        let player: String = r.try_get("player")?;
-        let score: i64 = r.try_get("score")?;
-        let timestamp: i64 = r.try_get("timestamp")?;
-        v.push((player, score, timestamp));
     }
+    tx.commit().await?;
+    Ok(())
+}
 
-    Ok(v)
+/// Indexed charts of a song, by instrument then difficulty
+pub async fn song_charts(pool: &SqlitePool, song_id: &str) -> Result<Vec<IndexedChart>> {
+    let rows = sqlx::query(
+        "SELECT song_id, instrument, difficulty, path, hash, note_count FROM charts \
+         WHERE song_id = ? ORDER BY instrument, difficulty"
+    )
+    .bind(song_id)
+    .fetch_all(pool)
+    .await?;
+    rows.iter()
+        .map(|r| {
+            Ok(IndexedChart {
+                song_id: r.try_get("song_id")?,
+                instrument: r.try_get("instrument")?,
+                difficulty: r.try_get("difficulty")?,
+                path: r.try_get("path")?,
+                hash: r.try_get("hash")?,
+                note_count: r.try_get("note_count")?,
+            })
+        })
+        .collect()
+}
+
+/// A player-made chart
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CustomChart {
+    pub id: i64,
+    pub song_id: String,
+    pub instrument: String,
+    pub difficulty: String,
+    pub author: String,
+    pub version: i64,
+    #[serde(skip)]
+    pub path: String, // file name in CUSTOM_CHARTS_DIR
+    pub hash: String,
+    pub note_count: i64,
+    pub created_at: i64,
+}
+
+const CUSTOM_CHART_COLUMNS: &str = "c.id, c.song_id, c.instrument, c.difficulty, u.username AS author, c.version, \
+     c.path, c.hash, c.note_count, c.created_at";
+
+fn custom_chart_from_row(r: &SqliteRow) -> Result<CustomChart> {
+    Ok(CustomChart {
+        id: r.try_get("id")?,
+        song_id: r.try_get("song_id")?,
+        instrument: r.try_get("instrument")?,
+        difficulty: r.try_get("difficulty")?,
+        author: r.try_get("author")?,
+        version: r.try_get("version")?,
+        path: r.try_get("path")?,
+        hash: r.try_get("hash")?,
+        note_count: r.try_get("note_count")?,
+        created_at: r.try_get("created_at")?,
+    })
+}
+
+/// A custom chart to store; the version is assigned on insert
+pub struct NewCustomChart<'a> {
+    pub song_id: &'a str,
+    pub instrument: &'a str,
+    pub difficulty: &'a str,
+    pub author_id: i64,
+    pub path: &'a str,
+    pub hash: &'a str,
+    pub note_count: i64,
+    pub created_at: i64,
+}
+
+/// Store a custom chart as the author's next version for its song, instrument and difficulty
+pub async fn insert_custom_chart(pool: &SqlitePool, chart: &NewCustomChart<'_>) -> Result<CustomChart> {
+    let id: i64 = sqlx::query_scalar(
+        "INSERT INTO custom_charts (song_id, instrument, difficulty, author_id, version, path, hash, note_count, created_at) \
+         SELECT ?, ?, ?, ?, COALESCE(MAX(version), 0) + 1, ?, ?, ?, ? FROM custom_charts \
+         WHERE song_id = ? AND lower(instrument) = lower(?) AND lower(difficulty) = lower(?) AND author_id = ? \
+         RETURNING id"
+    )
+    .bind(chart.song_id)
+    .bind(chart.instrument)
+    .bind(chart.difficulty)
+    .bind(chart.author_id)
+    .bind(chart.path)
+    .bind(chart.hash)
+    .bind(chart.note_count)
+    .bind(chart.created_at)
+    .bind(chart.song_id)
+    .bind(chart.instrument)
+    .bind(chart.difficulty)
+    .bind(chart.author_id)
+    .fetch_one(pool)
+    .await?;
+
+    let row = sqlx::query(&format!(
+        "SELECT {} FROM custom_charts c JOIN users u ON u.id = c.author_id WHERE c.id = ?",
+        CUSTOM_CHART_COLUMNS
+    ))
+    .bind(id)
+    .fetch_one(pool)
+    .await?;
+    custom_chart_from_row(&row)
+}
+
+/// Latest version of every custom chart of a song
+pub async fn custom_charts(pool: &SqlitePool, song_id: &str) -> Result<Vec<CustomChart>> {
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM ( \
+             SELECT *, ROW_NUMBER() OVER (PARTITION BY lower(instrument), lower(difficulty), author_id ORDER BY version DESC) AS n \
+             FROM custom_charts WHERE song_id = ? \
+         ) c JOIN users u ON u.id = c.author_id \
+         WHERE c.n = 1 ORDER BY lower(c.instrument), lower(c.difficulty), lower(u.username)",
+        CUSTOM_CHART_COLUMNS
+    ))
+    .bind(song_id)
+    .fetch_all(pool)
+    .await?;
+    rows.iter().map(custom_chart_from_row).collect()
+}
+
+pub async fn get_custom_chart(pool: &SqlitePool, id: i64) -> Result<Option<CustomChart>> {
+    let row = sqlx::query(&format!(
+        "SELECT {} FROM custom_charts c JOIN users u ON u.id = c.author_id WHERE c.id = ?",
+        CUSTOM_CHART_COLUMNS
+    ))
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    row.as_ref().map(custom_chart_from_row).transpose()
+}
+
+/// The custom chart of a song a client hashed to `hash`, if any
+pub async fn custom_chart_by_hash(pool: &SqlitePool, song_id: &str, hash: &str) -> Result<Option<CustomChart>> {
+    let row = sqlx::query(&format!(
+        "SELECT {} FROM custom_charts c JOIN users u ON u.id = c.author_id \
+         WHERE c.song_id = ? AND c.hash = lower(?) ORDER BY c.version DESC LIMIT 1",
+        CUSTOM_CHART_COLUMNS
+    ))
+    .bind(song_id)
+    .bind(hash)
+    .fetch_optional(pool)
+    .await?;
+    row.as_ref().map(custom_chart_from_row).transpose()
+}
+
+/// Length of a registered song in milliseconds: None for an unknown song,
+/// Some(None) when its metadata has no duration
+pub async fn song_duration_ms(pool: &SqlitePool, id: &str) -> Result<Option<Option<i64>>> {
+    Ok(sqlx::query_scalar("SELECT duration_ms FROM songs WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?)
 }
 
 pub async fn create_user(pool: &SqlitePool, username: &str, password_hash: &str) -> Result<()> {
-    sqlx::query("INSERT INTO users (username, password_hash) VALUES (?, ?)")
+    sqlx::query("INSERT INTO users (username, password_hash, created_at) VALUES (?, ?, ?)")
         .bind(username)
         .bind(password_hash)
+        .bind(chrono::Utc::now().timestamp())
         .execute(pool)
         .await?;
     Ok(())
@@ -140,3 +694,604 @@ pub async fn get_password_hash(pool: &SqlitePool, username: &str) -> Result<Opti
         Ok(None)
     }
 }
+
+/// Public profile of a user
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct UserProfile {
+    pub id: i64,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub country: Option<String>,
+    #[serde(skip)]
+    pub avatar: Option<String>, // file name in AVATARS_DIR
+    pub role: String,
+    pub created_at: Option<i64>,
+}
+
+pub async fn get_user(pool: &SqlitePool, username: &str) -> Result<Option<UserProfile>> {
+    let row = sqlx::query(
+        "SELECT id, username, display_name, country, avatar, role, created_at FROM users WHERE username = ?"
+    )
+    .bind(username)
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(r) = row else {
+        return Ok(None);
+    };
+    Ok(Some(UserProfile {
+        id: r.try_get("id")?,
+        username: r.try_get("username")?,
+        display_name: r.try_get("display_name")?,
+        country: r.try_get("country")?,
+        avatar: r.try_get("avatar")?,
+        role: r.try_get("role")?,
+        created_at: r.try_get("created_at")?,
+    }))
+}
+
+/// Update the editable profile fields; None leaves a field unchanged
+pub async fn update_profile(
+    pool: &SqlitePool,
+    user_id: i64,
+    display_name: Option<Option<&str>>,
+    country: Option<Option<&str>>,
+) -> Result<()> {
+    if let Some(display_name) = display_name {
+        sqlx::query("UPDATE users SET display_name = ? WHERE id = ?")
+            .bind(display_name)
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+    }
+    if let Some(country) = country {
+        sqlx::query("UPDATE users SET country = ? WHERE id = ?")
+            .bind(country)
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn set_avatar(pool: &SqlitePool, user_id: i64, avatar: Option<&str>) -> Result<()> {
+    sqlx::query("UPDATE users SET avatar = ? WHERE id = ?")
+        .bind(avatar)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// A player's best score on one chart
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BestScore {
+    pub score_id: i64,
+    pub song_id: String,
+    pub title: Option<String>,
+    pub instrument: Option<String>,
+    pub difficulty: Option<String>,
+    pub score: i64,
+    pub accuracy: Option<f64>,
+    pub timestamp: i64,
+}
+
+/// Totals computed from a user's online scores
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct PlayerStats {
+    pub total_plays: i64,
+    /// sum of the played songs' durations, for songs whose duration is known
+    pub play_time_ms: i64,
+    pub average_accuracy: Option<f64>,
+    pub favourite_instrument: Option<String>,
+    pub best_scores: Vec<BestScore>,
+}
+
+/// Stats over a player's scores; scores flagged by replay verification don't count
+pub async fn player_stats(pool: &SqlitePool, user_id: i64) -> Result<PlayerStats> {
+    let totals = sqlx::query(
+        "SELECT COUNT(*) AS plays, COALESCE(SUM(songs.duration_ms), 0) AS play_time, AVG(scores.accuracy) AS accuracy \
+         FROM scores LEFT JOIN songs ON songs.id = scores.song_id \
+         WHERE scores.user_id = ? AND (scores.verified IS NULL OR scores.verified = 1)"
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+
+    let favourite_instrument: Option<String> = sqlx::query_scalar(
+        "SELECT lower(instrument) FROM scores \
+         WHERE user_id = ? AND instrument IS NOT NULL AND (verified IS NULL OR verified = 1) \
+         GROUP BY lower(instrument) ORDER BY COUNT(*) DESC, lower(instrument) LIMIT 1"
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    let rows = sqlx::query(
+        "SELECT * FROM ( \
+            SELECT scores.id, scores.song_id, songs.title, scores.instrument, scores.difficulty, \
+                   scores.score, scores.accuracy, scores.timestamp, \
+                   ROW_NUMBER() OVER ( \
+                       PARTITION BY scores.song_id, lower(scores.instrument), lower(scores.difficulty) \
+                       ORDER BY scores.score DESC, scores.timestamp ASC) AS chart_rank \
+            FROM scores LEFT JOIN songs ON songs.id = scores.song_id \
+            WHERE scores.user_id = ? AND (scores.verified IS NULL OR scores.verified = 1) \
+         ) WHERE chart_rank = 1 \
+         ORDER BY song_id, lower(instrument), lower(difficulty)"
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    let mut best_scores = Vec::new();
+    for r in &rows {
+        best_scores.push(BestScore {
+            score_id: r.try_get("id")?,
+            song_id: r.try_get("song_id")?,
+            title: r.try_get("title")?,
+            instrument: r.try_get("instrument")?,
+            difficulty: r.try_get("difficulty")?,
+            score: r.try_get("score")?,
+            accuracy: r.try_get("accuracy")?,
+            timestamp: r.try_get("timestamp")?,
+        });
+    }
+
+    Ok(PlayerStats {
+        total_plays: totals.try_get("plays")?,
+        play_time_ms: totals.try_get("play_time")?,
+        average_accuracy: totals.try_get("accuracy")?,
+        favourite_instrument,
+        best_scores,
+    })
+}
+
+pub async fn create_session(pool: &SqlitePool, id: &str, username: &str, now: i64) -> Result<()> {
+    sqlx::query("INSERT INTO sessions (id, username, created_at) VALUES (?, ?, ?)")
+        .bind(id)
+        .bind(username)
+        .bind(now)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Id and role of the user behind a session, or None if the session is unknown or revoked
+pub async fn session_user(pool: &SqlitePool, id: &str) -> Result<Option<(i64, String)>> {
+    Ok(sqlx::query_as(
+        "SELECT u.id, u.role FROM sessions s JOIN users u ON u.username = s.username \
+         WHERE s.id = ? AND s.revoked_at IS NULL"
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?)
+}
+
+/// Set a user's role. False if there is no such user.
+pub async fn set_user_role(pool: &SqlitePool, username: &str, role: &str) -> Result<bool> {
+    let result = sqlx::query("UPDATE users SET role = ? WHERE username = ?")
+        .bind(role)
+        .bind(username)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn revoke_session(pool: &SqlitePool, id: &str, now: i64) -> Result<()> {
+    sqlx::query("UPDATE sessions SET revoked_at = ? WHERE id = ? AND revoked_at IS NULL")
+        .bind(now)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Revoke every open session of a user, returning how many there were
+pub async fn revoke_user_sessions(pool: &SqlitePool, username: &str, now: i64) -> Result<u64> {
+    let result = sqlx::query("UPDATE sessions SET revoked_at = ? WHERE username = ? AND revoked_at IS NULL")
+        .bind(now)
+        .bind(username)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+pub async fn insert_refresh_token(pool: &SqlitePool, token_hash: &str, session_id: &str, expires_at: i64) -> Result<()> {
+    sqlx::query("INSERT INTO refresh_tokens (token_hash, session_id, expires_at) VALUES (?, ?, ?)")
+        .bind(token_hash)
+        .bind(session_id)
+        .bind(expires_at)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// A stored refresh token with its session
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefreshToken {
+    pub session_id: String,
+    pub username: String,
+    pub expires_at: i64,
+    pub used: bool,
+    pub session_revoked: bool,
+}
+
+pub async fn find_refresh_token(pool: &SqlitePool, token_hash: &str) -> Result<Option<RefreshToken>> {
+    let row = sqlx::query(
+        "SELECT t.session_id, s.username, t.expires_at, t.used_at, s.revoked_at \
+         FROM refresh_tokens t JOIN sessions s ON s.id = t.session_id \
+         WHERE t.token_hash = ?"
+    )
+    .bind(token_hash)
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(r) = row else {
+        return Ok(None);
+    };
+    Ok(Some(RefreshToken {
+        session_id: r.try_get("session_id")?,
+        username: r.try_get("username")?,
+        expires_at: r.try_get("expires_at")?,
+        used: r.try_get::<Option<i64>, _>("used_at")?.is_some(),
+        session_revoked: r.try_get::<Option<i64>, _>("revoked_at")?.is_some(),
+    }))
+}
+
+/// Mark a refresh token used. False if it already was (e.g. a concurrent refresh won).
+pub async fn use_refresh_token(pool: &SqlitePool, token_hash: &str, now: i64) -> Result<bool> {
+    let result = sqlx::query("UPDATE refresh_tokens SET used_at = ? WHERE token_hash = ? AND used_at IS NULL")
+        .bind(now)
+        .bind(token_hash)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// A background job as stored
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Job {
+    pub id: i64,
+    pub kind: String,
+    pub song_id: String,
+    pub force: bool,
+    pub status: String,
+    pub progress: f64,
+    pub message: Option<String>,
+    pub error: Option<String>,
+    pub attempts: i64,
+    pub created_by: Option<String>,
+    pub created_at: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    /// Only queued jobs can be cancelled; a running job always runs to the end
+    pub cancellable: bool,
+}
+
+const JOB_COLUMNS: &str = "id, kind, song_id, force, status, progress, message, error, attempts, created_by, \
+     created_at, started_at, finished_at";
+
+fn job_from_row(r: &SqliteRow) -> Result<Job> {
+    let status: String = r.try_get("status")?;
+    Ok(Job {
+        id: r.try_get("id")?,
+        kind: r.try_get("kind")?,
+        song_id: r.try_get("song_id")?,
+        force: r.try_get("force")?,
+        cancellable: status == "queued",
+        status,
+        progress: r.try_get("progress")?,
+        message: r.try_get("message")?,
+        error: r.try_get("error")?,
+        attempts: r.try_get("attempts")?,
+        created_by: r.try_get("created_by")?,
+        created_at: r.try_get("created_at")?,
+        started_at: r.try_get("started_at")?,
+        finished_at: r.try_get("finished_at")?,
+    })
+}
+
+/// Queue a job, unless one of the same kind for the song is already queued or running;
+/// then that one is returned instead. A forced request upgrades a queued job to forced,
+/// and is queued after a running job that was not forced.
+pub async fn insert_job(pool: &SqlitePool, kind: &str, song_id: &str, force: bool, created_by: Option<&str>, now: i64) -> Result<Job> {
+    let mut tx = pool.begin().await?;
+    let existing = sqlx::query(&format!(
+        "SELECT {} FROM jobs WHERE kind = ? AND song_id = ? AND status IN ('queued', 'running') \
+         AND (force = 1 OR ? = 0 OR status = 'queued') ORDER BY force DESC, id LIMIT 1",
+        JOB_COLUMNS
+    ))
+    .bind(kind)
+    .bind(song_id)
+    .bind(force)
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some(row) = existing {
+        let job = job_from_row(&row)?;
+        if !force || job.force {
+            return Ok(job);
+        }
+        let row = sqlx::query(&format!("UPDATE jobs SET force = 1 WHERE id = ? RETURNING {}", JOB_COLUMNS))
+            .bind(job.id)
+            .fetch_one(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        return job_from_row(&row);
+    }
+
+    let row = sqlx::query(&format!(
+        "INSERT INTO jobs (kind, song_id, force, status, created_by, created_at) VALUES (?, ?, ?, 'queued', ?, ?) RETURNING {}",
+        JOB_COLUMNS
+    ))
+    .bind(kind)
+    .bind(song_id)
+    .bind(force)
+    .bind(created_by)
+    .bind(now)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    job_from_row(&row)
+}
+
+pub async fn get_job(pool: &SqlitePool, id: i64) -> Result<Option<Job>> {
+    let row = sqlx::query(&format!("SELECT {} FROM jobs WHERE id = ?", JOB_COLUMNS))
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    row.as_ref().map(job_from_row).transpose()
+}
+
+/// The newest job of `kind` for a song, whatever its state
+pub async fn latest_job(pool: &SqlitePool, kind: &str, song_id: &str) -> Result<Option<Job>> {
+    let row = sqlx::query(&format!("SELECT {} FROM jobs WHERE kind = ? AND song_id = ? ORDER BY id DESC LIMIT 1", JOB_COLUMNS))
+        .bind(kind)
+        .bind(song_id)
+        .fetch_optional(pool)
+        .await?;
+    row.as_ref().map(job_from_row).transpose()
+}
+
+/// Newest jobs first, optionally only those with `status`
+pub async fn list_jobs(pool: &SqlitePool, status: Option<&str>, limit: i64, offset: i64) -> Result<Vec<Job>> {
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM jobs WHERE (? IS NULL OR status = ?) ORDER BY id DESC LIMIT ? OFFSET ?",
+        JOB_COLUMNS
+    ))
+    .bind(status)
+    .bind(status)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+    rows.iter().map(job_from_row).collect()
+}
+
+/// Mark the oldest queued job running and return it
+pub async fn claim_job(pool: &SqlitePool, now: i64) -> Result<Option<Job>> {
+    let row = sqlx::query(&format!(
+        "UPDATE jobs SET status = 'running', started_at = ?, attempts = attempts + 1, progress = 0, error = NULL \
+         WHERE id = (SELECT id FROM jobs WHERE status = 'queued' ORDER BY id LIMIT 1) RETURNING {}",
+        JOB_COLUMNS
+    ))
+    .bind(now)
+    .fetch_optional(pool)
+    .await?;
+    row.as_ref().map(job_from_row).transpose()
+}
+
+pub async fn set_job_progress(pool: &SqlitePool, id: i64, progress: f64, message: &str) -> Result<()> {
+    sqlx::query("UPDATE jobs SET progress = ?, message = ? WHERE id = ? AND status = 'running'")
+        .bind(progress)
+        .bind(message)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Record how a running job ended: done with a message, or failed with an error
+pub async fn finish_job(pool: &SqlitePool, id: i64, outcome: std::result::Result<&str, &str>, now: i64) -> Result<()> {
+    let (status, message, error, progress) = match outcome {
+        Ok(message) => ("done", Some(message), None, 1.0),
+        Err(error) => ("failed", None, Some(error), 0.0),
+    };
+    sqlx::query(
+        "UPDATE jobs SET status = ?, message = COALESCE(?, message), error = ?, progress = MAX(progress, ?), finished_at = ? \
+         WHERE id = ? AND status = 'running'"
+    )
+    .bind(status)
+    .bind(message)
+    .bind(error)
+    .bind(progress)
+    .bind(now)
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Cancel a queued job. Returns false if it is not queued (running jobs cannot be stopped).
+pub async fn cancel_job(pool: &SqlitePool, id: i64, now: i64) -> Result<bool> {
+    let result = sqlx::query("UPDATE jobs SET status = 'cancelled', finished_at = ? WHERE id = ? AND status = 'queued'")
+        .bind(now)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Queue a failed or cancelled job again. Returns false for jobs in any other state.
+pub async fn retry_job(pool: &SqlitePool, id: i64) -> Result<bool> {
+    let result = sqlx::query(
+        "UPDATE jobs SET status = 'queued', progress = 0, message = NULL, error = NULL, started_at = NULL, finished_at = NULL \
+         WHERE id = ? AND status IN ('failed', 'cancelled')"
+    )
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Put jobs left running by a previous process back in the queue. Returns how many.
+pub async fn requeue_running_jobs(pool: &SqlitePool) -> Result<u64> {
+    let result = sqlx::query("UPDATE jobs SET status = 'queued', progress = 0 WHERE status = 'running'")
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::memory_pool;
+
+    #[actix_rt::test]
+    async fn song_metadata_round_trip() {
+        let pool = memory_pool().await;
+        // running again on an existing database must not fail
+        init_db(&pool).await.unwrap();
+
+        let meta = SongMetadata::parse(
+            r#"{ "SongTitle": "Song", "Artists": ["A", "B"], "start_offset_Ms": 40, "year": 2021, "tags": ["x"] }"#,
+        )
+        .unwrap();
+        upsert_song(&pool, "song", "song.wav", &meta, 1).await.unwrap();
+
+        let rows = list_songs_db(&pool).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].2, meta);
+
+        let artist: Option<String> = sqlx::query_scalar("SELECT artist FROM songs WHERE id = 'song'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(artist.as_deref(), Some("A, B"));
+    }
+
+    fn score(player: &str, score: i64, instrument: &str, difficulty: &str) -> ScoreRecord {
+        ScoreRecord {
+            song_id: "song".to_string(),
+            player: player.to_string(),
+            score,
+            timestamp: score,
+            online: true,
+            instrument: Some(instrument.to_string()),
+            difficulty: Some(difficulty.to_string()),
+            judgements: Some(Judgements { perfect: 3, great: 1, ..Default::default() }),
+            verified: Some(true),
+            ..Default::default()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn leaderboard_keeps_best_score_per_player() {
+        let pool = memory_pool().await;
+
+        for record in [
+            score("alice", 500, "drums", "Hard"),
+            score("alice", 900, "drums", "Hard"),
+            score("bob", 700, "drums", "Hard"),
+            score("bob", 990, "bass", "Hard"),
+            score("carol", 800, "drums", "Easy"),
+            ScoreRecord { online: false, ..score("dave", 9999, "drums", "Hard") },
+            ScoreRecord { verified: Some(false), ..score("erin", 9999, "drums", "Hard") },
+            // sent without a replay, so never checked, which still counts
+            ScoreRecord { verified: None, ..score("frank", 600, "drums", "Hard") },
+        ] {
+            insert_score(&pool, &record).await.unwrap();
+        }
+
+        let query = LeaderboardQuery {
+            instrument: Some("DRUMS".to_string()),
+            difficulty: Some("hard".to_string()),
+            limit: 10,
+            offset: 0,
+        };
+        let board = leaderboard(&pool, "song", &query).await.unwrap();
+        let players: Vec<(&str, i64)> = board.iter().map(|e| (e.record.player.as_str(), e.record.score)).collect();
+        assert_eq!(players, vec![("alice", 900), ("bob", 700), ("frank", 600)]);
+        assert_eq!(board[0].record.judgements.unwrap().perfect, 3);
+
+        // without filters each player appears once with their overall best
+        let all = LeaderboardQuery { limit: 10, ..Default::default() };
+        let board = leaderboard(&pool, "song", &all).await.unwrap();
+        assert_eq!(board.len(), 4);
+        assert_eq!(board[0].record.player, "bob");
+
+        let page = LeaderboardQuery { limit: 1, offset: 1, ..Default::default() };
+        let board = leaderboard(&pool, "song", &page).await.unwrap();
+        assert_eq!((board[0].rank, board[0].record.player.as_str()), (2, "alice"));
+    }
+
+    #[actix_rt::test]
+    async fn player_stats_use_user_id() {
+        let pool = memory_pool().await;
+        create_user(&pool, "alice", "hash").await.unwrap();
+        let alice = get_user(&pool, "alice").await.unwrap().unwrap();
+        assert!(alice.created_at.is_some());
+
+        let meta = SongMetadata { title: Some("Song".to_string()), duration_ms: Some(120_000), ..Default::default() };
+        upsert_song(&pool, "song", "song.json", &meta, 1).await.unwrap();
+
+        let mine = |s: i64, instrument: &str, accuracy: f64| ScoreRecord {
+            user_id: Some(alice.id),
+            accuracy: Some(accuracy),
+            ..score("alice", s, instrument, "Hard")
+        };
+        for record in [
+            mine(500, "drums", 80.0),
+            mine(900, "Drums", 90.0),
+            mine(700, "bass", 100.0),
+            ScoreRecord { verified: Some(false), ..mine(9999, "drums", 50.0) },
+            // same name, no account: not alice's
+            score("alice", 100, "lead", "Hard"),
+        ] {
+            insert_score(&pool, &record).await.unwrap();
+        }
+
+        let stats = player_stats(&pool, alice.id).await.unwrap();
+        // the flagged score is not a play
+        assert_eq!(stats.total_plays, 3);
+        assert_eq!(stats.play_time_ms, 3 * 120_000);
+        assert_eq!(stats.average_accuracy, Some(90.0));
+        assert_eq!(stats.favourite_instrument.as_deref(), Some("drums"));
+        let bests: Vec<(Option<&str>, i64)> = stats.best_scores.iter().map(|b| (b.instrument.as_deref(), b.score)).collect();
+        assert_eq!(bests, vec![(Some("bass"), 700), (Some("Drums"), 900)]);
+        assert_eq!(stats.best_scores[0].title.as_deref(), Some("Song"));
+
+        update_profile(&pool, alice.id, Some(Some("Alice")), Some(Some("NZ"))).await.unwrap();
+        update_profile(&pool, alice.id, None, Some(None)).await.unwrap();
+        let alice = get_user(&pool, "alice").await.unwrap().unwrap();
+        assert_eq!((alice.display_name.as_deref(), alice.country), (Some("Alice"), None));
+    }
+
+    #[actix_rt::test]
+    async fn replays_are_linked_and_pruned() {
+        let pool = memory_pool().await;
+
+        let worse = insert_score(&pool, &score("alice", 500, "drums", "Hard")).await.unwrap();
+        let best = insert_score(&pool, &score("alice", 900, "drums", "Hard")).await.unwrap();
+        insert_replay(&pool, worse, b"old", 3, 100).await.unwrap();
+        insert_replay(&pool, best, b"best", 4, 100).await.unwrap();
+
+        let board = leaderboard(&pool, "song", &LeaderboardQuery { limit: 10, ..Default::default() }).await.unwrap();
+        assert_eq!(board[0].replay_url.as_deref(), Some(format!("/api/scores/{}/replay", best).as_str()));
+
+        // the player's best survives retention, older runs go
+        assert_eq!(prune_replays(&pool, 200).await.unwrap(), 1);
+        assert!(get_replay(&pool, worse).await.unwrap().is_none());
+        assert_eq!(get_replay(&pool, best).await.unwrap().as_deref(), Some(&b"best"[..]));
+        assert_eq!(get_score(&pool, best).await.unwrap().unwrap().score, 900);
+    }
+
+    #[test]
+    fn judgement_accuracy() {
+        let all_perfect = Judgements { perfect: 10, ..Default::default() };
+        assert_eq!(all_perfect.accuracy(), Some(100.0));
+        let half = Judgements { perfect: 1, miss: 1, ..Default::default() };
+        assert_eq!(half.accuracy(), Some(50.0));
+        assert_eq!(Judgements::default().accuracy(), None);
+        assert!(!Judgements { miss: -1, ..Default::default() }.is_valid());
+    }
+}
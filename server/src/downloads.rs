@@ -0,0 +1,319 @@
+use actix_web::http::header::{self, EntityTag, Header, HttpDate};
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt};
+use sha2::{Digest, Sha256};
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Most ranges honoured in one request; asking for more gets the whole body
+const MAX_RANGES: usize = 16;
+
+/// Size of the chunks files are streamed in
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// A body to send, whole or in ranges
+pub enum Content {
+    Bytes(Bytes),
+    /// Streamed from disk; `len` is the size the validators were made for
+    File { path: PathBuf, len: u64 },
+}
+
+impl Content {
+    fn len(&self) -> u64 {
+        match self {
+            Content::Bytes(bytes) => bytes.len() as u64,
+            Content::File { len, .. } => *len,
+        }
+    }
+}
+
+/// What identifies one version of a download: a strong ETag from a content hash, and the
+/// modification time to the second (as precise as HTTP dates get)
+pub struct Validators {
+    etag: EntityTag,
+    last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// `hash` must change whenever the bytes do
+    pub fn new(hash: &str, last_modified: Option<SystemTime>) -> Self {
+        let last_modified = last_modified
+            .map(|t| UNIX_EPOCH + Duration::from_secs(t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)));
+        Self { etag: EntityTag::new_strong(hash.to_string()), last_modified }
+    }
+
+    /// Weak validators for a file nobody has hashed, from its size and modification time.
+    /// Good for 304s; `If-Range` needs a strong ETag, so resumes go by date instead.
+    pub fn for_file(len: u64, last_modified: Option<SystemTime>) -> Self {
+        let mut validators = Self::new("", last_modified);
+        let secs = validators.last_modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs());
+        validators.etag = EntityTag::new_weak(format!("{:x}-{:x}", len, secs));
+        validators
+    }
+
+    /// Validators for a body built in memory, hashed with SHA-256
+    pub fn for_bytes(bytes: &[u8], last_modified: Option<SystemTime>) -> Self {
+        let hash: String = Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect();
+        Self::new(&hash, last_modified)
+    }
+
+    /// Whether the client's copy is current. `If-None-Match` decides when sent;
+    /// `If-Modified-Since` only counts without it.
+    fn not_modified(&self, req: &HttpRequest) -> bool {
+        if req.headers().contains_key(header::IF_NONE_MATCH) {
+            return match header::IfNoneMatch::parse(req) {
+                Ok(header::IfNoneMatch::Any) => true,
+                Ok(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&self.etag)),
+                Err(_) => false,
+            };
+        }
+        match (header::IfModifiedSince::parse(req), self.last_modified) {
+            (Ok(header::IfModifiedSince(since)), Some(modified)) => SystemTime::from(since) >= modified,
+            _ => false,
+        }
+    }
+
+    /// Whether a `Range` may be honoured: without `If-Range`, or when it names this version
+    fn range_allowed(&self, req: &HttpRequest) -> bool {
+        if !req.headers().contains_key(header::IF_RANGE) {
+            return true;
+        }
+        match header::IfRange::parse(req) {
+            Ok(header::IfRange::EntityTag(tag)) => tag.strong_eq(&self.etag),
+            Ok(header::IfRange::Date(date)) => self.last_modified == Some(SystemTime::from(date)),
+            Err(_) => false,
+        }
+    }
+
+    fn headers(&self, mut builder: HttpResponseBuilder) -> HttpResponseBuilder {
+        builder.insert_header(header::ETag(self.etag.clone()));
+        if let Some(modified) = self.last_modified {
+            builder.insert_header(header::LastModified(HttpDate::from(modified)));
+        }
+        builder.insert_header((header::ACCEPT_RANGES, "bytes"));
+        builder
+    }
+}
+
+/// Answer a GET for `content`: 304 when the client's copy is current, otherwise the whole
+/// body, one range (206), several ranges as `multipart/byteranges` (206) or 416 when no
+/// range asked for lies inside the body
+pub fn respond(req: &HttpRequest, validators: &Validators, content: Content, content_type: &str) -> HttpResponse {
+    if validators.not_modified(req) {
+        return validators.headers(HttpResponse::NotModified()).finish();
+    }
+    let len = content.len();
+    let ranges = match req.headers().get(header::RANGE).and_then(|v| v.to_str().ok()) {
+        Some(range) if validators.range_allowed(req) => parse_range(range, len),
+        _ => Ranges::Full,
+    };
+
+    match ranges {
+        Ranges::Full => validators
+            .headers(HttpResponse::Ok())
+            .content_type(content_type)
+            .no_chunking(len)
+            .streaming(body(content, vec![Part::Slice(0, len)])),
+        Ranges::Unsatisfiable => validators
+            .headers(HttpResponse::RangeNotSatisfiable())
+            .insert_header((header::CONTENT_RANGE, format!("bytes */{}", len)))
+            .finish(),
+        Ranges::Partial(ranges) if ranges.len() == 1 => {
+            let (start, end) = ranges[0];
+            validators
+                .headers(HttpResponse::PartialContent())
+                .content_type(content_type)
+                .insert_header((header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end - 1, len)))
+                .no_chunking(end - start)
+                .streaming(body(content, vec![Part::Slice(start, end)]))
+        }
+        Ranges::Partial(ranges) => {
+            let boundary = format!("rhythm-pi-{:016x}", rand::random::<u64>());
+            let mut parts = Vec::new();
+            for (i, (start, end)) in ranges.into_iter().enumerate() {
+                let head = format!(
+                    "{}--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    if i == 0 { "" } else { "\r\n" },
+                    boundary,
+                    content_type,
+                    start,
+                    end - 1,
+                    len
+                );
+                parts.push(Part::Data(Bytes::from(head)));
+                parts.push(Part::Slice(start, end));
+            }
+            parts.push(Part::Data(Bytes::from(format!("\r\n--{}--\r\n", boundary))));
+            let total = parts.iter().map(Part::len).sum();
+            validators
+                .headers(HttpResponse::PartialContent())
+                .content_type(format!("multipart/byteranges; boundary={}", boundary))
+                .no_chunking(total)
+                .streaming(body(content, parts))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Ranges {
+    Full,
+    /// Half-open `(start, end)` byte ranges, sorted and not overlapping
+    Partial(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
+
+/// Byte ranges of a `Range` header for a body of `len` bytes. Overlapping and adjacent
+/// ranges are merged. Headers that do not parse, or ask for other units or too many
+/// ranges, are ignored and the whole body is sent.
+fn parse_range(header: &str, len: u64) -> Ranges {
+    let Some(specs) = header.trim().strip_prefix("bytes=") else {
+        return Ranges::Full;
+    };
+    let number = |s: &str| -> Option<u64> {
+        let s = s.trim();
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        s.parse().ok()
+    };
+
+    let specs: Vec<&str> = specs.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return Ranges::Full;
+    }
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let Some((first, last)) = spec.split_once('-') else {
+            return Ranges::Full;
+        };
+        if first.trim().is_empty() {
+            // the last `suffix` bytes
+            let Some(suffix) = number(last) else {
+                return Ranges::Full;
+            };
+            if suffix > 0 && len > 0 {
+                ranges.push((len - suffix.min(len), len));
+            }
+            continue;
+        }
+        let Some(start) = number(first) else {
+            return Ranges::Full;
+        };
+        let end = if last.trim().is_empty() {
+            len
+        } else {
+            match number(last) {
+                Some(last) if last >= start => last.saturating_add(1).min(len),
+                _ => return Ranges::Full,
+            }
+        };
+        if start < len {
+            ranges.push((start, end));
+        }
+    }
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    Ranges::Partial(merged)
+}
+
+/// A piece of a response body: literal bytes or a half-open slice of the content
+enum Part {
+    Data(Bytes),
+    Slice(u64, u64),
+}
+
+impl Part {
+    fn len(&self) -> u64 {
+        match self {
+            Part::Data(bytes) => bytes.len() as u64,
+            Part::Slice(start, end) => end - start,
+        }
+    }
+}
+
+fn body(content: Content, parts: Vec<Part>) -> BoxStream<'static, std::io::Result<Bytes>> {
+    match content {
+        Content::Bytes(bytes) => stream::iter(parts.into_iter().map(move |part| {
+            Ok(match part {
+                Part::Data(data) => data,
+                Part::Slice(start, end) => bytes.slice(start as usize..end as usize),
+            })
+        }))
+        .boxed(),
+        Content::File { path, .. } => stream::iter(parts)
+            .flat_map(move |part| match part {
+                Part::Data(data) => stream::once(async move { Ok(data) }).boxed(),
+                Part::Slice(start, end) => file_slice(path.clone(), start, end),
+            })
+            .boxed(),
+    }
+}
+
+/// Bytes `start..end` of a file, read in chunks
+fn file_slice(path: PathBuf, start: u64, end: u64) -> BoxStream<'static, std::io::Result<Bytes>> {
+    stream::try_unfold((None::<tokio::fs::File>, start), move |(file, pos)| {
+        let path = path.clone();
+        async move {
+            if pos >= end {
+                return Ok(None);
+            }
+            let mut file = match file {
+                Some(file) => file,
+                None => {
+                    let mut file = tokio::fs::File::open(&path).await?;
+                    file.seek(SeekFrom::Start(pos)).await?;
+                    file
+                }
+            };
+            let mut buf = vec![0; CHUNK_BYTES.min((end - pos) as usize)];
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, format!("{} shrank while being sent", path.display())));
+            }
+            buf.truncate(n);
+            Ok(Some((Bytes::from(buf), (Some(file), pos + n as u64))))
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100), Ranges::Partial(vec![(0, 10)]));
+        assert_eq!(parse_range("bytes=90-", 100), Ranges::Partial(vec![(90, 100)]));
+        assert_eq!(parse_range("bytes=-10", 100), Ranges::Partial(vec![(90, 100)]));
+        assert_eq!(parse_range("bytes=-500", 100), Ranges::Partial(vec![(0, 100)]));
+        assert_eq!(parse_range("bytes=50-500", 100), Ranges::Partial(vec![(50, 100)]));
+        assert_eq!(parse_range("bytes= 20-29 , 0-4", 100), Ranges::Partial(vec![(0, 5), (20, 30)]));
+        // overlapping and adjacent ranges merge
+        assert_eq!(parse_range("bytes=0-9,5-14,15-19", 100), Ranges::Partial(vec![(0, 20)]));
+        // ranges outside the body are dropped, and if that is all of them nothing fits
+        assert_eq!(parse_range("bytes=0-9,200-300", 100), Ranges::Partial(vec![(0, 10)]));
+        assert_eq!(parse_range("bytes=100-", 100), Ranges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 100), Ranges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), Ranges::Unsatisfiable);
+        // nonsense is ignored
+        for header in ["items=0-9", "bytes=9-0", "bytes=a-b", "bytes=+1-2", "bytes=5", "bytes="] {
+            assert_eq!(parse_range(header, 100), Ranges::Full, "{}", header);
+        }
+        let many = format!("bytes={}", (0..20).map(|i| format!("{}-{}", i * 2, i * 2)).collect::<Vec<_>>().join(","));
+        assert_eq!(parse_range(&many, 100), Ranges::Full);
+    }
+}
@@ -1,174 +1,325 @@
 use actix_files::NamedFile;
+use actix_multipart::Multipart;
 use actix_web::{web, HttpResponse, Result, HttpRequest};
 use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
+use futures::TryStreamExt;
+use std::collections::HashSet;
 use std::path::{PathBuf, Path};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
 
+use crate::auth::{AuthUser, Role, TokenPair};
+use crate::avatars;
+use crate::charts;
 use crate::db;
+use crate::downloads;
+use crate::jobs::JobQueue;
+use crate::song_meta::SongMetadata;
+use crate::song_watcher::AUDIO_EXTENSIONS;
+use crate::transcode;
+use crate::replays::{self, ReplayConfig};
+use crate::uploads;
+use crate::verify;
+use rhythm_pi_scoring::{Replay, ReplayResult};
 
 #[derive(Serialize)]
 struct SongInfo {
     id: String,
     filename: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    title: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    artists: Option<Vec<String>>,
-}
-
-fn read_song_meta(songs_dir: &str, id: &str) -> (Option<String>, Option<Vec<String>>) {
-    let mut jp = PathBuf::from(songs_dir);
-    jp.push(format!("{}.json", id));
-    if jp.exists() {
-        if let Ok(s) = std::fs::read_to_string(&jp) {
-            if let Ok(v) = serde_json::from_str::<serde_json::Value>(&s) {
-                let title = v.get("SongTitle").and_then(|t| t.as_str()).map(|s| s.to_string());
-                let artists = v.get("Artists").and_then(|a| a.as_array()).map(|arr| {
-                    arr.iter().filter_map(|it| it.as_str().map(|s| s.to_string())).collect()
-                });
-                return (title, artists);
-            }
-        }
+    #[serde(flatten)]
+    meta: SongMetadata,
+}
+
+/// Metadata from `{id}.json`, for songs listed straight from the directory
+fn read_song_meta(songs_dir: &str, id: &str) -> SongMetadata {
+    let jp = PathBuf::from(songs_dir).join(format!("{}.json", id));
+    if !jp.exists() {
+        return SongMetadata::default();
     }
-    (None, None)
+    SongMetadata::load(&jp).unwrap_or_else(|e| {
+        log::warn!("{:#}", e);
+        SongMetadata::default()
+    })
 }
 
-pub async fn list_songs(pool: web::Data<SqlitePool>) -> Result<HttpResponse> {
-    // prefer DB-registered songs
-    let songs_dir = std::env::var("SONGS_DIR").unwrap_or_else(|_| "server/assets/songs".to_string());
-    match db::list_songs_db(&pool).await {
-        Ok(rows) => {
-            let mut songs = Vec::new();
-            for (id, filename) in rows {
-                let (title, artists) = read_song_meta(&songs_dir, &id);
-                songs.push(SongInfo { id, filename, title, artists });
-            }
-            if songs.is_empty() {
-                // fallback to directory listing for compatibility
-                let songs_dir = std::env::var("SONGS_DIR").unwrap_or_else(|_| "server/assets/songs".to_string());
-                let mut fallback = Vec::new();
-                if let Ok(entries) = std::fs::read_dir(&songs_dir) {
-                    use std::collections::HashSet;
-                    let mut seen = HashSet::new();
-                    for e in entries.filter_map(|r| r.ok()) {
-                        if let Some(name) = e.file_name().to_str() {
-                            // skip non-audio and metadata files
-                            if name.ends_with(".json") { continue; }
-                            if !(name.ends_with(".mp3") || name.ends_with(".wav") || name.ends_with(".ogg") || name.ends_with(".flac")) { continue; }
-                            let id = name.split('.').next().unwrap_or(name).to_string();
-                            if seen.contains(&id) { continue; }
-                            seen.insert(id.clone());
-                            let (title, artists) = read_song_meta(&songs_dir, &id);
-                            fallback.push(SongInfo { id, filename: name.to_string(), title, artists });
-                        }
-                    }
-                }
-                return Ok(HttpResponse::Ok().json(fallback));
-            }
-            Ok(HttpResponse::Ok().json(songs))
+/// Songs straight from the directory, for when none are registered yet, and when any
+/// file there last changed
+fn list_song_dir(songs_dir: &str) -> (Vec<SongInfo>, Option<SystemTime>) {
+    let mut songs = Vec::new();
+    let mut newest = None;
+    let Ok(entries) = std::fs::read_dir(songs_dir) else {
+        return (songs, newest);
+    };
+    // sorted, so the listing (and its ETag) only changes when the files do
+    let mut entries: Vec<_> = entries.filter_map(|r| r.ok()).collect();
+    entries.sort_by_key(|e| e.file_name());
+    let mut seen = HashSet::new();
+    for e in entries {
+        newest = newest.max(e.metadata().and_then(|m| m.modified()).ok());
+        let Some(name) = e.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        // skip non-audio and metadata files
+        let ext = Path::new(&name).extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+        if !AUDIO_EXTENSIONS.contains(&ext.as_str()) {
+            continue;
         }
-        Err(_) => {
-            // fallback to directory listing
-            let songs_dir = std::env::var("SONGS_DIR").unwrap_or_else(|_| "server/assets/songs".to_string());
-            let mut songs = Vec::new();
-            if let Ok(entries) = std::fs::read_dir(&songs_dir) {
-                    use std::collections::HashSet;
-                    let mut seen = HashSet::new();
-                    for e in entries.filter_map(|r| r.ok()) {
-                        if let Some(name) = e.file_name().to_str() {
-                            if name.ends_with(".json") { continue; }
-                            if !(name.ends_with(".mp3") || name.ends_with(".wav") || name.ends_with(".ogg") || name.ends_with(".flac")) { continue; }
-                            let id = name.split('.').next().unwrap_or(name).to_string();
-                            if seen.contains(&id) { continue; }
-                            seen.insert(id.clone());
-                            let (title, artists) = read_song_meta(&songs_dir, &id);
-                            songs.push(SongInfo { id, filename: name.to_string(), title, artists });
-                        }
-                    }
-                }
-            Ok(HttpResponse::Ok().json(songs))
+        let id = name.split('.').next().unwrap_or(&name).to_string();
+        if !seen.insert(id.clone()) {
+            continue;
         }
+        let meta = read_song_meta(songs_dir, &id);
+        songs.push(SongInfo { id, filename: name, meta });
     }
+    (songs, newest)
+}
+
+/// `GET /api/songs`, with an ETag of the listing so unchanged lists come back as 304
+pub async fn list_songs(req: HttpRequest, pool: web::Data<SqlitePool>) -> Result<HttpResponse> {
+    // prefer DB-registered songs; their metadata was stored by the watcher
+    let registered = db::list_songs_db(&pool).await.unwrap_or_else(|e| {
+        log::warn!("listing songs from the db failed: {}", e);
+        Vec::new()
+    });
+    let (songs, modified) = if registered.is_empty() {
+        // fallback to directory listing for compatibility
+        let songs_dir = std::env::var("SONGS_DIR").unwrap_or_else(|_| "server/assets/songs".to_string());
+        list_song_dir(&songs_dir)
+    } else {
+        let modified = db::songs_last_modified(&pool).await.ok().flatten();
+        let songs = registered.into_iter().map(|(id, filename, meta)| SongInfo { id, filename, meta }).collect();
+        (songs, modified.map(|t| UNIX_EPOCH + Duration::from_secs(t.max(0) as u64)))
+    };
+    let body = serde_json::to_vec(&songs)?;
+    let validators = downloads::Validators::for_bytes(&body, modified);
+    Ok(downloads::respond(&req, &validators, downloads::Content::Bytes(body.into()), "application/json"))
 }
 
-pub async fn stream_song(req: HttpRequest, path: web::Path<String>) -> Result<HttpResponse> {
+/// `GET /api/songs/{id}/stream[?quality=]`: the song's audio, or a transcoded variant
+/// picked by `quality` or `Accept` (see `transcode::negotiate`), with range and
+/// conditional requests answered. The ETag is the SHA-256 of the file sent, or a weak
+/// one from its size and mtime until the watcher has hashed it.
+pub async fn stream_song(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<String>,
+    query: web::Query<StreamQuery>,
+) -> Result<HttpResponse> {
     let id = path.into_inner();
-    let songs_dir = std::env::var("SONGS_DIR").unwrap_or_else(|_| "server/assets/songs".to_string());
-    let mut fpath = PathBuf::from(&songs_dir);
-    
-    // try to find file that starts with id
-    if let Ok(entries) = std::fs::read_dir(&songs_dir) {
-        for e in entries.filter_map(|r| r.ok()) {
-            if let Some(name) = e.file_name().to_str() {
-                if name.starts_with(&id) {
-                    fpath.push(name);
-                    let named = NamedFile::open(fpath.clone())?;
-                    
-                    // Log the file being served
-                    log::info!("Streaming audio file: {:?}", fpath);
-                    
-                    // Build response with proper headers
-                    let mut response = named.into_response(&req);
-                    
-                    // Ensure proper content-type based on file extension
-                    if let Some(ext) = fpath.extension() {
-                        let ext_str = ext.to_string_lossy().to_lowercase();
-                        let content_type = match ext_str.as_str() {
-                            "mp3" => "audio/mpeg",
-                            "wav" => "audio/wav",
-                            "ogg" => "audio/ogg",
-                            "flac" => "audio/flac",
-                            _ => "application/octet-stream",
-                        };
-                        response.headers_mut().insert(
-                            actix_web::http::header::CONTENT_TYPE,
-                            actix_web::http::header::HeaderValue::from_static(content_type),
-                        );
-                    }
-                    
-                    // Add CORS headers for browser audio
-                    response.headers_mut().insert(
-                        actix_web::http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
-                        actix_web::http::header::HeaderValue::from_static("*"),
-                    );
-                    
-                    return Ok(response);
-                }
-            }
+    let songs_dir = PathBuf::from(std::env::var("SONGS_DIR").unwrap_or_else(|_| "server/assets/songs".to_string()));
+    let accept = req.headers().get(actix_web::http::header::ACCEPT).and_then(|v| v.to_str().ok());
+    let wanted = transcode::negotiate(query.quality.as_deref(), accept)
+        .map_err(|e| actix_web::error::ErrorBadRequest(e.to_string()))?;
+    let audio = transcode::song_audio(&pool, &songs_dir, &id, wanted)
+        .await
+        .map_err(|e| {
+            log::error!("cannot find audio for {}: {:#}", id, e);
+            actix_web::error::ErrorInternalServerError("cannot read song")
+        })?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("song not found"))?;
+
+    log::info!("Streaming audio file: {:?} ({})", audio.path, audio.quality);
+    let validators = match &audio.hash {
+        Some(hash) => downloads::Validators::new(hash, audio.modified),
+        None => downloads::Validators::for_file(audio.len, audio.modified),
+    };
+    let content = downloads::Content::File { path: audio.path, len: audio.len };
+    let mut response = downloads::respond(&req, &validators, content, audio.content_type);
+
+    let headers = response.headers_mut();
+    // Add CORS headers for browser audio
+    headers.insert(
+        actix_web::http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        actix_web::http::header::HeaderValue::from_static("*"),
+    );
+    headers.insert(actix_web::http::header::VARY, actix_web::http::header::HeaderValue::from_static("Accept"));
+    headers.insert(
+        actix_web::http::header::HeaderName::from_static("x-audio-quality"),
+        actix_web::http::header::HeaderValue::from_static(audio.quality),
+    );
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+pub struct StreamQuery {
+    /// `low`, `medium`, `high` or `original`
+    pub quality: Option<String>,
+}
+
+/// `GET /api/songs/{id}/chart?instrument=&difficulty=`: a generated chart, looked up in
+/// the chart index. Both parameters are optional and case-insensitive; without them the
+/// song's first chart is served. A miss lists what the song does have.
+pub async fn get_chart(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<String>,
+    query: web::Query<ChartQuery>,
+) -> Result<HttpResponse> {
+    let id = path.into_inner();
+    let charts_dir = std::env::var("CHARTS_DIR").unwrap_or_else(|_| "server/assets/charts".to_string());
+    let available = charts::song_charts(&pool, Path::new(&charts_dir), &id).await.map_err(|e| {
+        log::error!("chart index error: {}", e);
+        actix_web::error::ErrorInternalServerError("db error")
+    })?;
+
+    match charts::select(&available, query.instrument.as_deref(), query.difficulty.as_deref()) {
+        Some(chart) => {
+            let path = Path::new(&charts_dir).join(&chart.path);
+            let bytes = tokio::fs::read(&path).await.map_err(|e| {
+                log::error!("cannot read chart {}: {}", path.display(), e);
+                actix_web::error::ErrorNotFound("chart not found")
+            })?;
+            let modified = tokio::fs::metadata(&path).await.and_then(|m| m.modified()).ok();
+            // the hash clients send back with their scores doubles as the ETag
+            let validators = downloads::Validators::new(&rhythm_pi_scoring::chart_hash(&bytes), modified);
+            Ok(downloads::respond(&req, &validators, downloads::Content::Bytes(bytes.into()), "application/json"))
+        }
+        None => {
+            let wanted = [query.instrument.as_deref(), query.difficulty.as_deref()]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join(" ");
+            let error = if available.is_empty() {
+                format!("song {} has no charts", id)
+            } else {
+                format!("song {} has no {} chart", id, wanted)
+            };
+            let listing: Vec<_> = available
+                .iter()
+                .map(|c| serde_json::json!({"instrument": c.instrument, "difficulty": c.difficulty}))
+                .collect();
+            Ok(HttpResponse::NotFound().json(serde_json::json!({"error": error, "available": listing})))
         }
     }
-    // not found
-    Err(actix_web::error::ErrorNotFound("song not found"))
 }
 
-pub async fn get_chart(path: web::Path<String>, query: web::Query<std::collections::HashMap<String, String>>) -> Result<NamedFile> {
+#[derive(Deserialize)]
+pub struct ChartQuery {
+    pub instrument: Option<String>,
+    pub difficulty: Option<String>,
+}
+
+/// One playable chart of a song: generated ones have no author
+#[derive(Serialize)]
+struct ChartListing {
+    instrument: String,
+    difficulty: String,
+    author: Option<String>,
+    version: Option<i64>,
+    note_count: i64,
+    hash: String,
+    url: String,
+}
+
+/// `GET /api/songs/{id}/charts`: every instrument, difficulty and author available
+pub async fn list_song_charts(pool: web::Data<SqlitePool>, path: web::Path<String>) -> Result<HttpResponse> {
     let id = path.into_inner();
     let charts_dir = std::env::var("CHARTS_DIR").unwrap_or_else(|_| "server/assets/charts".to_string());
+    let db_error = |e: anyhow::Error| {
+        log::error!("db error: {}", e);
+        actix_web::error::ErrorInternalServerError("db error")
+    };
 
-    // try instrument/difficulty exact name first: {id}_{instrument}_{difficulty}.chart.json
-    if let Some(inst) = query.get("instrument") {
-        if let Some(diff) = query.get("difficulty") {
-            let fname = format!("{}_{}_{}.chart.json", id, inst, diff);
-            let cand = PathBuf::from(&charts_dir).join(&fname);
-            if cand.exists() {
-                return Ok(NamedFile::open(cand)?);
-            }
-        }
+    let mut listing: Vec<ChartListing> = charts::song_charts(&pool, Path::new(&charts_dir), &id)
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .map(|chart| ChartListing {
+            url: format!("/api/songs/{}/chart?instrument={}&difficulty={}", id, chart.instrument, chart.difficulty),
+            instrument: chart.instrument,
+            difficulty: chart.difficulty,
+            author: None,
+            version: None,
+            note_count: chart.note_count,
+            hash: chart.hash,
+        })
+        .collect();
+    for chart in db::custom_charts(&pool, &id).await.map_err(db_error)? {
+        listing.push(ChartListing {
+            url: format!("/api/charts/{}", chart.id),
+            instrument: chart.instrument,
+            difficulty: chart.difficulty,
+            author: Some(chart.author),
+            version: Some(chart.version),
+            note_count: chart.note_count,
+            hash: chart.hash,
+        });
     }
 
-    // fallback to first file that starts with id
-    let mut fpath = PathBuf::from(&charts_dir);
-    if let Ok(entries) = std::fs::read_dir(&charts_dir) {
-        for e in entries.filter_map(|r| r.ok()) {
-            if let Some(name) = e.file_name().to_str() {
-                if name.starts_with(&id) {
-                    fpath.push(name);
-                    return Ok(NamedFile::open(fpath)?);
-                }
-            }
-        }
+    if listing.is_empty() && !db::song_exists(&pool, &id).await.map_err(db_error)? {
+        return Err(actix_web::error::ErrorNotFound("song not found"));
     }
-    Err(actix_web::error::ErrorNotFound("chart not found"))
+    Ok(HttpResponse::Ok().json(listing))
+}
+
+/// `POST /api/songs/{id}/charts` with chart JSON as the body. The chart is checked
+/// against the song and stored as the caller's next version of it.
+pub async fn upload_custom_chart(
+    pool: web::Data<SqlitePool>,
+    user: AuthUser,
+    path: web::Path<String>,
+    body: web::Bytes,
+) -> Result<HttpResponse> {
+    let song_id = path.into_inner();
+    let db_error = |e: anyhow::Error| {
+        log::error!("db error: {}", e);
+        actix_web::error::ErrorInternalServerError("db error")
+    };
+    let duration_ms = db::song_duration_ms(&pool, &song_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("song not found"))?;
+
+    let chart = charts::UploadedChart::parse(&body)
+        .map_err(|e| actix_web::error::ErrorBadRequest(format!("invalid chart: {:#}", e)))?;
+    let problems = chart.problems(duration_ms);
+    if !problems.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest(format!("invalid chart: {}", problems.join("; "))));
+    }
+
+    let save_error = |e: anyhow::Error| {
+        log::error!("custom chart save failed: {:#}", e);
+        actix_web::error::ErrorInternalServerError("chart error")
+    };
+    let bytes = chart.to_bytes(&song_id, Some(&user.username)).map_err(save_error)?;
+    let hash = rhythm_pi_scoring::chart_hash(&bytes);
+    let file = format!("{}.json", hash);
+    let dir = charts::custom_charts_dir();
+    std::fs::create_dir_all(&dir)?;
+    // files are named by content, so an identical upload can reuse the existing one
+    if !dir.join(&file).exists() {
+        uploads::write_atomic(&dir.join(&file), &bytes).map_err(save_error)?;
+    }
+
+    let new = db::NewCustomChart {
+        song_id: &song_id,
+        instrument: &chart.instrument,
+        difficulty: &chart.difficulty,
+        author_id: user.user_id,
+        path: &file,
+        hash: &hash,
+        note_count: chart.notes.len() as i64,
+        created_at: chrono::Utc::now().timestamp(),
+    };
+    let stored = db::insert_custom_chart(&pool, &new).await.map_err(db_error)?;
+    log::info!("{} uploaded {} {} chart v{} for {}", user.username, stored.instrument, stored.difficulty, stored.version, song_id);
+
+    let url = format!("/api/charts/{}", stored.id);
+    Ok(HttpResponse::Created().json(serde_json::json!({ "chart": stored, "url": url })))
+}
+
+/// `GET /api/charts/{id}`: a custom chart file
+pub async fn get_custom_chart(pool: web::Data<SqlitePool>, path: web::Path<i64>) -> Result<NamedFile> {
+    let chart = db::get_custom_chart(&pool, path.into_inner())
+        .await
+        .map_err(|e| {
+            log::error!("db error: {}", e);
+            actix_web::error::ErrorInternalServerError("db error")
+        })?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("chart not found"))?;
+    Ok(NamedFile::open(charts::custom_charts_dir().join(chart.path))?)
 }
 
 #[derive(Deserialize)]
@@ -178,6 +329,110 @@ pub struct ScoreSubmission {
     pub score: i64,
     pub timestamp: Option<i64>,
     pub online: bool,
+    // optional details; older clients send only the fields above
+    pub instrument: Option<String>,
+    pub difficulty: Option<String>,
+    pub chart_hash: Option<String>,
+    pub max_combo: Option<i64>,
+    pub judgements: Option<db::Judgements>,
+    pub accuracy: Option<f64>,
+    /// Inputs of the play; when present the server scores it itself
+    pub replay: Option<Replay>,
+}
+
+impl ScoreSubmission {
+    /// Re-judge the replay (if any) against the chart named by `chart_hash`: a custom
+    /// chart with that hash, otherwise the generated one
+    async fn rescore(&self, pool: &SqlitePool) -> Result<Option<ReplayResult>> {
+        let Some(replay) = &self.replay else {
+            return Ok(None);
+        };
+        let (Some(instrument), Some(difficulty), Some(hash)) = (&self.instrument, &self.difficulty, &self.chart_hash) else {
+            return Err(actix_web::error::ErrorBadRequest("a replay needs instrument, difficulty and chart_hash"));
+        };
+        if replay.inputs.len() > verify::MAX_REPLAY_INPUTS {
+            return Err(actix_web::error::ErrorBadRequest("replay too long"));
+        }
+
+        let custom = db::custom_chart_by_hash(pool, &self.song_id, hash).await.map_err(|e| {
+            log::error!("db error: {}", e);
+            actix_web::error::ErrorInternalServerError("db error")
+        })?;
+        let custom = custom
+            .filter(|c| c.instrument.eq_ignore_ascii_case(instrument) && c.difficulty.eq_ignore_ascii_case(difficulty))
+            .map(|c| charts::custom_charts_dir().join(c.path));
+        let path = match custom {
+            Some(path) => path,
+            None => {
+                let charts_dir = std::env::var("CHARTS_DIR").unwrap_or_else(|_| "server/assets/charts".to_string());
+                let indexed = charts::song_charts(pool, Path::new(&charts_dir), &self.song_id).await.map_err(|e| {
+                    log::error!("chart index error: {}", e);
+                    actix_web::error::ErrorInternalServerError("db error")
+                })?;
+                let chart = charts::select(&indexed, Some(instrument), Some(difficulty))
+                    .ok_or_else(|| actix_web::error::ErrorNotFound("chart not found"))?;
+                Path::new(&charts_dir).join(&chart.path)
+            }
+        };
+        // judging a long replay keeps a core busy for a while, so not on this thread
+        let (replay, hash) = (replay.clone(), hash.clone());
+        let rescored = web::block(move || {
+            let chart = verify::StoredChart::load(&path)?;
+            anyhow::Ok(chart.hash.eq_ignore_ascii_case(&hash).then(|| chart.rescore(&replay)))
+        })
+        .await?
+        .map_err(|e| {
+            log::error!("{:#}", e);
+            actix_web::error::ErrorInternalServerError("chart error")
+        })?;
+        rescored.map(Some).ok_or_else(|| actix_web::error::ErrorConflict("chart has changed since it was played"))
+    }
+
+    /// Score row for `player`. Accuracy is recomputed from judgements when they are sent;
+    /// with a rescored replay, score, combo and judgements all come from the server.
+    fn to_record(&self, player: &str, user_id: Option<i64>, timestamp: i64, online: bool, rescored: Option<&ReplayResult>) -> db::ScoreRecord {
+        let mut record = db::ScoreRecord {
+            song_id: self.song_id.clone(),
+            player: player.to_string(),
+            user_id,
+            score: self.score,
+            timestamp,
+            online,
+            instrument: self.instrument.as_ref().map(|i| i.to_lowercase()),
+            difficulty: self.difficulty.clone(),
+            chart_hash: self.chart_hash.clone(),
+            max_combo: self.max_combo,
+            judgements: self.judgements,
+            accuracy: self.judgements.and_then(|j| j.accuracy()).or(self.accuracy),
+            claimed_score: None,
+            verified: None,
+        };
+
+        if let Some(result) = rescored {
+            let judgements = db::Judgements {
+                perfect: result.perfect as i64,
+                great: result.great as i64,
+                good: result.good as i64,
+                ok: result.ok as i64,
+                miss: result.miss as i64,
+            };
+            record.score = result.score as i64;
+            record.claimed_score = Some(self.score);
+            record.verified = Some(verify::score_matches(self.score, record.score));
+            record.max_combo = Some(result.max_combo as i64);
+            record.judgements = Some(judgements);
+            record.accuracy = judgements.accuracy();
+        }
+        record
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LeaderboardParams {
+    pub instrument: Option<String>,
+    pub difficulty: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
 }
 
 #[derive(Deserialize)]
@@ -192,6 +447,13 @@ pub struct LoginRequest {
     pub password: String,
 }
 
+#[derive(Serialize)]
+struct LoginResponse {
+    token: String, // same as `access_token`, for older clients
+    #[serde(flatten)]
+    tokens: TokenPair,
+}
+
 pub async fn register_user(
     pool: web::Data<SqlitePool>,
     payload: web::Json<RegisterRequest>,
@@ -229,97 +491,487 @@ pub async fn login_user(
         })?;
 
         if ok {
-            let token = crate::auth::create_token(&payload.username, 60 * 60 * 24 * 30).map_err(|e| {
+            let tokens = crate::auth::start_session(&pool, &payload.username).await.map_err(|e| {
                 log::error!("token error: {}", e);
                 actix_web::error::ErrorInternalServerError("token error")
             })?;
 
-            return Ok(HttpResponse::Ok().json(serde_json::json!({"token": token})));
+            return Ok(HttpResponse::Ok().json(LoginResponse { token: tokens.access_token.clone(), tokens }));
         }
     }
 
     Err(actix_web::error::ErrorUnauthorized("invalid credentials"))
 }
 
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// `POST /api/refresh`: trade a refresh token for a new access and refresh token
+pub async fn refresh_token(
+    pool: web::Data<SqlitePool>,
+    payload: web::Json<RefreshRequest>,
+) -> Result<HttpResponse> {
+    match crate::auth::refresh_session(&pool, &payload.refresh_token).await {
+        Ok(Some(tokens)) => Ok(HttpResponse::Ok().json(tokens)),
+        Ok(None) => Err(actix_web::error::ErrorUnauthorized("invalid refresh token")),
+        Err(e) => {
+            log::error!("refresh error: {}", e);
+            Err(actix_web::error::ErrorInternalServerError("token error"))
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct LogoutRequest {
+    /// end every session of the user, not just this one
+    #[serde(default)]
+    pub all: bool,
+}
+
+/// `POST /api/logout`: revoke the caller's session (or all of them)
+pub async fn logout_user(
+    pool: web::Data<SqlitePool>,
+    user: AuthUser,
+    payload: Option<web::Json<LogoutRequest>>,
+) -> Result<HttpResponse> {
+    let now = chrono::Utc::now().timestamp();
+    let all = payload.is_some_and(|p| p.all);
+    let result = if all {
+        db::revoke_user_sessions(&pool, &user.username, now).await.map(|_| ())
+    } else {
+        db::revoke_session(&pool, &user.session_id, now).await
+    };
+    result.map_err(|e| {
+        log::error!("logout error: {}", e);
+        actix_web::error::ErrorInternalServerError("db error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({"status":"ok"})))
+}
+
 pub async fn post_score(
     pool: web::Data<SqlitePool>,
+    replay_config: web::Data<ReplayConfig>,
     payload: web::Json<ScoreSubmission>,
-    req: HttpRequest,
+    auth: Result<AuthUser>,
 ) -> Result<HttpResponse> {
+    // online scores need a valid token and are credited to its user;
+    // offline scores accept any player name
+    let (player, user_id, online) = if payload.online {
+        let user = auth?;
+        (user.username, Some(user.user_id), true)
+    } else {
+        (payload.player.clone(), None, false)
+    };
     let ts = payload.timestamp.unwrap_or_else(|| chrono::Utc::now().timestamp());
 
-    // if online submission, require auth
-    if payload.online {
-        if let Some(token) = crate::auth::extract_bearer(req.headers()) {
-            match crate::auth::decode_token(&token) {
-                Ok(username) => {
-                    // replace player with username from token for trust
-                    db::insert_score(
-                        &pool,
-                        &payload.song_id,
-                        &username,
-                        payload.score,
-                        ts,
-                        true,
-                    )
-                    .await
-                    .map_err(|e| {
-                        log::error!("db insert error: {}", e);
-                        actix_web::error::ErrorInternalServerError("db error")
-                    })?;
-
-                    return Ok(HttpResponse::Ok()
-                        .json(serde_json::json!({"status":"ok","message":"score recorded and eligible for leaderboard"})));
-                }
-                Err(e) => {
-                    log::warn!("token decode error: {}", e);
-                    return Err(actix_web::error::ErrorUnauthorized("invalid token"));
-                }
+    if payload.judgements.is_some_and(|j| !j.is_valid()) {
+        return Err(actix_web::error::ErrorBadRequest("judgement counts must not be negative"));
+    }
+    // offline scores are never ranked, so their replays are not worth judging
+    let rescored = if online { payload.rescore(&pool).await? } else { None };
+
+    // replays are kept for online scores only
+    let replay_blob = match (&payload.replay, online) {
+        (Some(replay), true) => {
+            let (data, size) = replays::compress(replay).map_err(|e| {
+                log::error!("replay compression error: {}", e);
+                actix_web::error::ErrorInternalServerError("replay error")
+            })?;
+            if data.len() > replay_config.max_bytes {
+                return Err(actix_web::error::ErrorPayloadTooLarge("replay too large"));
             }
-        } else {
-            return Err(actix_web::error::ErrorUnauthorized("missing token"));
+            Some((data, size))
         }
-    } else {
-        // offline: accept any player name and store
-        db::insert_score(
-            &pool,
-            &payload.song_id,
-            &payload.player,
-            payload.score,
-            ts,
-            false,
-        )
+        _ => None,
+    };
+
+    let record = payload.to_record(&player, user_id, ts, online, rescored.as_ref());
+    let id = db::insert_score(&pool, &record).await.map_err(|e| {
+        log::error!("db insert error: {}", e);
+        actix_web::error::ErrorInternalServerError("db error")
+    })?;
+    if let Some((data, size)) = &replay_blob {
+        // the score stands even if its replay can't be kept. Retention runs on the
+        // server's clock, not the timestamp the client sent.
+        if let Err(e) = db::insert_replay(&pool, id, data, *size, chrono::Utc::now().timestamp()).await {
+            log::error!("replay insert error: {}", e);
+        }
+    }
+
+    if !online {
+        return Ok(HttpResponse::Accepted().json(serde_json::json!({"status":"accepted","id":id,"score":record.score,"message":"score recorded offline; not shown on leaderboard"})));
+    }
+    if record.verified == Some(false) {
+        log::warn!("score {} from {} does not match its replay ({})", payload.score, player, record.score);
+        return Ok(HttpResponse::Ok()
+            .json(serde_json::json!({"status":"flagged","id":id,"score":record.score,"message":"score does not match its replay; not shown on leaderboard"})));
+    }
+    if record.verified.is_none() {
+        return Ok(HttpResponse::Ok()
+            .json(serde_json::json!({"status":"unverified","id":id,"score":record.score,"message":"score recorded without a replay, so unchecked"})));
+    }
+    Ok(HttpResponse::Ok()
+        .json(serde_json::json!({"status":"ok","id":id,"score":record.score,"message":"score recorded and eligible for leaderboard"})))
+}
+
+#[derive(Serialize)]
+struct ReplayResponse {
+    score_id: i64,
+    #[serde(flatten)]
+    record: db::ScoreRecord,
+    inputs: Vec<rhythm_pi_scoring::ReplayInput>,
+}
+
+/// `GET /api/scores/{id}/replay`: the score's details with its recorded inputs
+pub async fn get_replay(pool: web::Data<SqlitePool>, path: web::Path<i64>) -> Result<HttpResponse> {
+    let score_id = path.into_inner();
+    let db_error = |e: anyhow::Error| {
+        log::error!("db error: {}", e);
+        actix_web::error::ErrorInternalServerError("db error")
+    };
+
+    let data = db::get_replay(&pool, score_id).await.map_err(db_error)?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("replay not found"))?;
+    let record = db::get_score(&pool, score_id).await.map_err(db_error)?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("score not found"))?;
+    let replay = replays::decompress(&data).map_err(|e| {
+        log::error!("replay {}: {:#}", score_id, e);
+        actix_web::error::ErrorInternalServerError("replay error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(ReplayResponse { score_id, record, inputs: replay.inputs }))
+}
+
+/// `GET /api/leaderboard/{song_id}?instrument=&difficulty=&limit=&offset=`:
+/// each player's best online score, highest first
+pub async fn get_leaderboard(
+    pool: web::Data<SqlitePool>,
+    path: web::Path<String>,
+    params: web::Query<LeaderboardParams>,
+) -> Result<HttpResponse> {
+    let song_id = path.into_inner();
+    let params = params.into_inner();
+    let query = db::LeaderboardQuery {
+        instrument: params.instrument.filter(|s| !s.is_empty()),
+        difficulty: params.difficulty.filter(|s| !s.is_empty()),
+        limit: params.limit.unwrap_or(10).clamp(1, 100),
+        offset: params.offset.unwrap_or(0).max(0),
+    };
+
+    let entries = db::leaderboard(&pool, &song_id, &query).await.map_err(|e| {
+        log::error!("db query error: {}", e);
+        actix_web::error::ErrorInternalServerError("db error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+#[derive(Serialize)]
+struct ProfileResponse {
+    #[serde(flatten)]
+    profile: db::UserProfile,
+    avatar_url: Option<String>,
+    stats: db::PlayerStats,
+}
+
+async fn profile_response(pool: &SqlitePool, username: &str) -> Result<HttpResponse> {
+    let db_error = |e: anyhow::Error| {
+        log::error!("db error: {}", e);
+        actix_web::error::ErrorInternalServerError("db error")
+    };
+    let profile = db::get_user(pool, username).await.map_err(db_error)?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("user not found"))?;
+    let stats = db::player_stats(pool, profile.id).await.map_err(db_error)?;
+    let avatar_url = profile.avatar.as_ref().map(|_| format!("/api/users/{}/avatar", profile.username));
+
+    Ok(HttpResponse::Ok().json(ProfileResponse { profile, avatar_url, stats }))
+}
+
+/// `GET /api/users/{name}`: public profile and stats
+pub async fn get_user_profile(pool: web::Data<SqlitePool>, path: web::Path<String>) -> Result<HttpResponse> {
+    profile_response(&pool, &path.into_inner()).await
+}
+
+/// `GET /api/me`: the caller's own profile and stats
+pub async fn get_my_profile(pool: web::Data<SqlitePool>, user: AuthUser) -> Result<HttpResponse> {
+    profile_response(&pool, &user.username).await
+}
+
+/// Editable profile fields. Omitted fields stay as they are; an empty string clears one.
+#[derive(Deserialize)]
+pub struct ProfileUpdate {
+    pub display_name: Option<String>,
+    pub country: Option<String>,
+}
+
+impl ProfileUpdate {
+    /// Trimmed display name: up to 32 characters, no control characters
+    fn display_name(&self) -> Result<Option<Option<&str>>> {
+        let Some(name) = self.display_name.as_deref().map(str::trim) else {
+            return Ok(None);
+        };
+        if name.chars().count() > 32 || name.chars().any(char::is_control) {
+            return Err(actix_web::error::ErrorBadRequest("display_name must be at most 32 printable characters"));
+        }
+        Ok(Some((!name.is_empty()).then_some(name)))
+    }
+
+    /// Upper-cased ISO 3166-1 alpha-2 code such as "NZ"
+    fn country(&self) -> Result<Option<Option<String>>> {
+        let Some(code) = self.country.as_deref().map(str::trim) else {
+            return Ok(None);
+        };
+        if code.is_empty() {
+            return Ok(Some(None));
+        }
+        if code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(actix_web::error::ErrorBadRequest("country must be a two-letter country code"));
+        }
+        Ok(Some(Some(code.to_ascii_uppercase())))
+    }
+}
+
+/// `PATCH /api/me`
+pub async fn update_my_profile(
+    pool: web::Data<SqlitePool>,
+    user: AuthUser,
+    payload: web::Json<ProfileUpdate>,
+) -> Result<HttpResponse> {
+    let display_name = payload.display_name()?;
+    let country = payload.country()?;
+    db::update_profile(&pool, user.user_id, display_name, country.as_ref().map(|c| c.as_deref()))
         .await
         .map_err(|e| {
-            log::error!("db insert error: {}", e);
+            log::error!("db error: {}", e);
             actix_web::error::ErrorInternalServerError("db error")
         })?;
+    profile_response(&pool, &user.username).await
+}
 
-        return Ok(HttpResponse::Accepted().json(serde_json::json!({"status":"accepted","message":"score recorded offline; not shown on leaderboard"})));
+/// `PUT /api/me/avatar` with the raw PNG, JPEG or WebP image as the body
+pub async fn upload_avatar(pool: web::Data<SqlitePool>, user: AuthUser, body: web::Bytes) -> Result<HttpResponse> {
+    if avatars::image_extension(&body).is_none() {
+        return Err(actix_web::error::ErrorUnsupportedMediaType("avatar must be a PNG, JPEG or WebP image"));
     }
+    let dir = avatars::avatars_dir();
+    let file = web::block(move || avatars::save(&dir, user.user_id, &body)).await?.map_err(|e| {
+        log::error!("avatar save error: {}", e);
+        actix_web::error::ErrorInternalServerError("avatar error")
+    })?;
+    db::set_avatar(&pool, user.user_id, Some(&file)).await.map_err(|e| {
+        log::error!("db error: {}", e);
+        actix_web::error::ErrorInternalServerError("db error")
+    })?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({"avatar_url": format!("/api/users/{}/avatar", user.username)})))
 }
 
-pub async fn get_leaderboard(
+/// `DELETE /api/me/avatar`
+pub async fn delete_avatar(pool: web::Data<SqlitePool>, user: AuthUser) -> Result<HttpResponse> {
+    let dir = avatars::avatars_dir();
+    web::block(move || avatars::remove(&dir, user.user_id)).await?.map_err(|e| {
+        log::error!("avatar delete error: {}", e);
+        actix_web::error::ErrorInternalServerError("avatar error")
+    })?;
+    db::set_avatar(&pool, user.user_id, None).await.map_err(|e| {
+        log::error!("db error: {}", e);
+        actix_web::error::ErrorInternalServerError("db error")
+    })?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// `GET /api/users/{name}/avatar`
+pub async fn get_avatar(pool: web::Data<SqlitePool>, path: web::Path<String>) -> Result<NamedFile> {
+    let profile = db::get_user(&pool, &path.into_inner()).await.map_err(|e| {
+        log::error!("db error: {}", e);
+        actix_web::error::ErrorInternalServerError("db error")
+    })?;
+    let file = profile.and_then(|p| p.avatar).ok_or_else(|| actix_web::error::ErrorNotFound("no avatar"))?;
+    Ok(NamedFile::open(avatars::avatars_dir().join(file))?)
+}
+
+#[derive(Deserialize)]
+pub struct RoleRequest {
+    pub role: String,
+}
+
+/// `PUT /api/admin/users/{username}/role` with `{"role": "player" | "moderator" | "admin"}`
+pub async fn set_user_role(
     pool: web::Data<SqlitePool>,
+    admin: AuthUser,
     path: web::Path<String>,
+    payload: web::Json<RoleRequest>,
 ) -> Result<HttpResponse> {
-    let song_id = path.into_inner();
-    let rows = db::top_scores(&pool, &song_id, 10).await.map_err(|e| {
-        log::error!("db query error: {}", e);
+    let username = path.into_inner();
+    let role = Role::parse(&payload.role)
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("role must be player, moderator or admin"))?;
+    // keeps the last admin from locking everyone out
+    if username == admin.username && role < Role::Admin {
+        return Err(actix_web::error::ErrorBadRequest("admins cannot demote themselves"));
+    }
+
+    let found = db::set_user_role(&pool, &username, role.as_str()).await.map_err(|e| {
+        log::error!("db error: {}", e);
         actix_web::error::ErrorInternalServerError("db error")
     })?;
+    if !found {
+        return Err(actix_web::error::ErrorNotFound("user not found"));
+    }
+
+    log::info!("{} set role of {} to {}", admin.username, username, role.as_str());
+    Ok(HttpResponse::Ok().json(serde_json::json!({"username": username, "role": role})))
+}
+
+/// Read a whole multipart field, refusing more than `limit` bytes
+async fn read_field(field: &mut actix_multipart::Field, limit: usize) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    while let Some(chunk) = field.try_next().await? {
+        if data.len() + chunk.len() > limit {
+            return Err(actix_web::error::ErrorPayloadTooLarge(format!("{} part is larger than {} bytes", field.name().unwrap_or("a"), limit)));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// `POST /api/songs`: multipart upload of an `audio` file (WAV, FLAC, Ogg or MP3), a
+/// `metadata` JSON part and any number of `chart` JSON parts. The song id is derived
+/// from the title. Charts are generated in the background when none are uploaded.
+pub async fn upload_song(
+    pool: web::Data<SqlitePool>,
+    jobs: web::Data<JobQueue>,
+    user: AuthUser,
+    mut payload: Multipart,
+) -> Result<HttpResponse> {
+    let songs_dir = PathBuf::from(std::env::var("SONGS_DIR").unwrap_or_else(|_| "server/assets/songs".to_string()));
+    let charts_dir = PathBuf::from(std::env::var("CHARTS_DIR").unwrap_or_else(|_| "server/assets/charts".to_string()));
+    let upload_error = |e: anyhow::Error| {
+        log::error!("song upload failed: {:#}", e);
+        actix_web::error::ErrorInternalServerError("upload failed")
+    };
+    std::fs::create_dir_all(&songs_dir)?;
+    std::fs::create_dir_all(&charts_dir)?;
+
+    let mut audio: Option<(uploads::PartFile, &'static str, Option<String>)> = None;
+    let mut meta: Option<SongMetadata> = None;
+    let mut chart_uploads: Vec<charts::UploadedChart> = Vec::new();
+
+    while let Some(mut field) = payload.try_next().await? {
+        let name = field.name().unwrap_or_default().to_string();
+        match name.as_str() {
+            "audio" if audio.is_none() => {
+                let original = field.content_disposition().and_then(|cd| cd.get_filename()).map(str::to_string);
+                // stream to a hidden part file; it is removed again unless the upload completes
+                let part = uploads::PartFile::new(&songs_dir);
+                let mut file = tokio::fs::File::create(part.path()).await?;
+                let (mut head, mut size) = (Vec::new(), 0);
+                while let Some(chunk) = field.try_next().await? {
+                    size += chunk.len();
+                    if size > uploads::MAX_AUDIO_BYTES {
+                        return Err(actix_web::error::ErrorPayloadTooLarge(format!("audio is larger than {} bytes", uploads::MAX_AUDIO_BYTES)));
+                    }
+                    if head.len() < 12 {
+                        head.extend_from_slice(&chunk[..chunk.len().min(12 - head.len())]);
+                    }
+                    file.write_all(&chunk).await?;
+                }
+                file.sync_all().await?;
+                let ext = uploads::audio_extension(&head)
+                    .ok_or_else(|| actix_web::error::ErrorUnsupportedMediaType("audio must be WAV, FLAC, Ogg or MP3"))?;
+                audio = Some((part, ext, original));
+            }
+            "metadata" if meta.is_none() => {
+                let data = read_field(&mut field, uploads::MAX_JSON_BYTES).await?;
+                let parsed = std::str::from_utf8(&data)
+                    .map_err(anyhow::Error::from)
+                    .and_then(SongMetadata::parse)
+                    .map_err(|e| actix_web::error::ErrorBadRequest(format!("invalid metadata: {}", e)))?;
+                let problems = parsed.validate();
+                if !problems.is_empty() {
+                    return Err(actix_web::error::ErrorBadRequest(format!("invalid metadata: {}", problems.join("; "))));
+                }
+                meta = Some(parsed);
+            }
+            "chart" if chart_uploads.len() < uploads::MAX_CHARTS => {
+                let data = read_field(&mut field, uploads::MAX_JSON_BYTES).await?;
+                let chart = charts::UploadedChart::parse(&data)
+                    .map_err(|e| actix_web::error::ErrorBadRequest(format!("invalid chart: {}", e)))?;
+                if chart_uploads.iter().any(|c| c.instrument == chart.instrument && c.difficulty == chart.difficulty) {
+                    return Err(actix_web::error::ErrorBadRequest(format!("duplicate {} {} chart", chart.instrument, chart.difficulty)));
+                }
+                chart_uploads.push(chart);
+            }
+            _ => return Err(actix_web::error::ErrorBadRequest(format!("unexpected or repeated part '{}'", name))),
+        }
+    }
 
-    let mut resp = Vec::new();
-    for (player, score, ts) in rows {
-        resp.push(serde_json::json!({"player": player, "score": score, "timestamp": ts}));
+    let (part, ext, original) = audio.ok_or_else(|| actix_web::error::ErrorBadRequest("missing audio part"))?;
+    let meta = meta.ok_or_else(|| actix_web::error::ErrorBadRequest("missing metadata part"))?;
+    for chart in &chart_uploads {
+        let problems = chart.problems(meta.duration_ms);
+        if !problems.is_empty() {
+            return Err(actix_web::error::ErrorBadRequest(format!("invalid {} {} chart: {}", chart.instrument, chart.difficulty, problems.join("; "))));
+        }
     }
+    let original_stem = original.as_deref().map(|f| Path::new(f).file_stem().and_then(|s| s.to_str()).unwrap_or_default());
+    let base = [meta.title.as_deref(), original_stem]
+        .into_iter()
+        .flatten()
+        .map(uploads::slugify)
+        .find(|s| !s.is_empty())
+        .unwrap_or_else(|| "song".to_string());
 
-    Ok(HttpResponse::Ok().json(resp))
+    let guard = uploads::UPLOAD_LOCK.lock().await;
+    let id = uploads::unique_song_id(&pool, &songs_dir, &charts_dir, &base).await.map_err(upload_error)?;
+    let filename = format!("{}.{}", id, ext);
+    let mut chart_files = Vec::new();
+    // charts and audio first: the metadata file is what the watcher registers songs by
+    for chart in &chart_uploads {
+        let chart_file = chart.file_name(&id);
+        let bytes = chart.to_bytes(&id, None).map_err(upload_error)?;
+        uploads::write_atomic(&charts_dir.join(&chart_file), &bytes).map_err(upload_error)?;
+        chart_files.push(chart_file);
+    }
+    part.persist(&songs_dir.join(&filename)).map_err(upload_error)?;
+    let meta_json = serde_json::to_vec_pretty(&meta)?;
+    uploads::write_atomic(&songs_dir.join(format!("{}.json", id)), &meta_json).map_err(upload_error)?;
+    db::upsert_song(&pool, &id, &filename, &meta, chrono::Utc::now().timestamp()).await.map_err(upload_error)?;
+    drop(guard);
+    log::info!("{} uploaded song {} ({})", user.username, id, filename);
+
+    let job = if !chart_files.is_empty() {
+        charts::reindex_song(&pool, &charts_dir, &id).await.map_err(upload_error)?;
+        serde_json::json!({"status": "skipped", "reason": "charts uploaded"})
+    } else if ext == "wav" {
+        let job = jobs.enqueue(crate::jobs::GENERATE_CHARTS, &id, false, Some(&user.username)).await.map_err(upload_error)?;
+        serde_json::to_value(job)?
+    } else {
+        serde_json::json!({"status": "skipped", "reason": "chart generation needs WAV audio"})
+    };
+
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "id": id,
+        "filename": filename,
+        "charts": chart_files,
+        "job": job,
+    })))
 }
 
-pub async fn admin_scan(pool: web::Data<SqlitePool>) -> Result<HttpResponse> {
-    match crate::song_watcher::scan_once(&pool).await {
-        Ok(_) => Ok(HttpResponse::Ok().json(serde_json::json!({"status":"ok","message":"scan completed"}))),
+/// `POST /api/admin/scan`: reconcile the songs directory with the database now and
+/// report what was added, changed, renamed and removed; chart generation is queued
+pub async fn admin_scan(pool: web::Data<SqlitePool>, jobs: web::Data<JobQueue>) -> Result<HttpResponse> {
+    match crate::song_watcher::scan_once(&pool, &jobs).await {
+        Ok(report) => Ok(HttpResponse::Ok().json(serde_json::json!({
+            "status": "ok",
+            "message": "scan completed",
+            "added": report.added,
+            "changed": report.changed,
+            "renamed": report.renamed,
+            "removed": report.removed,
+            "jobs": report.jobs,
+        }))),
         Err(e) => {
             log::error!("manual scan failed: {}", e);
             Err(actix_web::error::ErrorInternalServerError("scan failed"))
@@ -327,8 +979,11 @@ pub async fn admin_scan(pool: web::Data<SqlitePool>) -> Result<HttpResponse> {
     }
 }
 
+/// `POST /api/admin/generate_hq/{song_id}[?force=1]`: queue chart generation for a song
+/// and return the job without waiting for it
 pub async fn admin_generate_hq(
-    _pool: web::Data<SqlitePool>,
+    admin: AuthUser,
+    jobs: web::Data<JobQueue>,
     path: web::Path<String>,
     query: web::Query<std::collections::HashMap<String, String>>,
 ) -> Result<HttpResponse> {
@@ -336,31 +991,95 @@ pub async fn admin_generate_hq(
     let force = query.get("force").map(|v| v == "1" || v.to_lowercase() == "true").unwrap_or(false);
 
     let songs_dir = std::env::var("SONGS_DIR").unwrap_or_else(|_| "server/assets/songs".to_string());
-    let charts_dir = std::env::var("CHARTS_DIR").unwrap_or_else(|_| "server/assets/charts".to_string());
     let song_wav = PathBuf::from(&songs_dir).join(format!("{}.wav", song_id));
 
     if !song_wav.exists() {
         return Err(actix_web::error::ErrorNotFound("song file not found (wav required)"));
     }
 
-    // call Rust HQ generator synchronously via spawn_blocking
-    match tokio::task::spawn_blocking({
-        let sid = song_id.clone();
-        let charts_dir = charts_dir.clone();
-        let wav = song_wav.clone();
-        move || crate::hq_rust::generate_hq_charts_rust(&sid, &wav, Path::new(&charts_dir), force)
-    }).await {
-        Ok(Ok(written)) => {
-            let generated: Vec<String> = written.into_iter().map(|p| p.to_string_lossy().into_owned()).collect::<Vec<String>>();
-            Ok(HttpResponse::Ok().json(serde_json::json!({"status":"ok","generated": generated })))
-        }
-        Ok(Err(e)) => {
-            log::error!("HQ generation failed: {}", e);
-            Err(actix_web::error::ErrorInternalServerError("hq generation failed"))
-        }
-        Err(e) => {
-            log::error!("HQ task join failed: {}", e);
-            Err(actix_web::error::ErrorInternalServerError("hq generation failed"))
-        }
+    let job = jobs.enqueue(crate::jobs::GENERATE_CHARTS, &song_id, force, Some(&admin.username)).await.map_err(|e| {
+        log::error!("failed to queue job: {}", e);
+        actix_web::error::ErrorInternalServerError("db error")
+    })?;
+    Ok(HttpResponse::Accepted().json(job))
+}
+
+#[derive(Deserialize)]
+pub struct JobListParams {
+    pub status: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// `GET /api/jobs?status=&limit=&offset=`: newest jobs first
+pub async fn list_jobs(pool: web::Data<SqlitePool>, _user: AuthUser, query: web::Query<JobListParams>) -> Result<HttpResponse> {
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let jobs = db::list_jobs(&pool, query.status.as_deref(), limit, offset).await.map_err(|e| {
+        log::error!("db error: {}", e);
+        actix_web::error::ErrorInternalServerError("db error")
+    })?;
+    Ok(HttpResponse::Ok().json(jobs))
+}
+
+async fn find_job(pool: &SqlitePool, id: i64) -> Result<db::Job> {
+    db::get_job(pool, id)
+        .await
+        .map_err(|e| {
+            log::error!("db error: {}", e);
+            actix_web::error::ErrorInternalServerError("db error")
+        })?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("job not found"))
+}
+
+/// `GET /api/jobs/{id}`: status, progress and error of one job
+pub async fn get_job(pool: web::Data<SqlitePool>, _user: AuthUser, path: web::Path<i64>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(find_job(&pool, path.into_inner()).await?))
+}
+
+/// Only admins and whoever queued a job may retry or cancel it
+fn check_job_owner(user: &AuthUser, job: &db::Job) -> Result<()> {
+    if user.has_role(Role::Admin) || job.created_by.as_deref() == Some(user.username.as_str()) {
+        Ok(())
+    } else {
+        Err(actix_web::error::ErrorForbidden("not your job"))
+    }
+}
+
+/// `POST /api/jobs/{id}/retry`: queue a failed or cancelled job again
+pub async fn retry_job(
+    pool: web::Data<SqlitePool>,
+    jobs: web::Data<JobQueue>,
+    user: AuthUser,
+    path: web::Path<i64>,
+) -> Result<HttpResponse> {
+    let job = find_job(&pool, path.into_inner()).await?;
+    check_job_owner(&user, &job)?;
+    let retried = jobs.retry(job.id).await.map_err(|e| {
+        log::error!("db error: {}", e);
+        actix_web::error::ErrorInternalServerError("db error")
+    })?;
+    if !retried {
+        return Err(actix_web::error::ErrorConflict(format!("job is {}; only failed or cancelled jobs can be retried", job.status)));
+    }
+    Ok(HttpResponse::Ok().json(find_job(&pool, job.id).await?))
+}
+
+/// `POST /api/jobs/{id}/cancel`: drop a job that has not started yet. Running jobs can't
+/// be stopped: they answer 409 with the job, whose `cancellable` is false.
+pub async fn cancel_job(pool: web::Data<SqlitePool>, user: AuthUser, path: web::Path<i64>) -> Result<HttpResponse> {
+    let job = find_job(&pool, path.into_inner()).await?;
+    check_job_owner(&user, &job)?;
+    let cancelled = db::cancel_job(&pool, job.id, chrono::Utc::now().timestamp()).await.map_err(|e| {
+        log::error!("db error: {}", e);
+        actix_web::error::ErrorInternalServerError("db error")
+    })?;
+    if !cancelled {
+        let job = find_job(&pool, job.id).await?;
+        return Ok(HttpResponse::Conflict().json(serde_json::json!({
+            "error": format!("job is {}; only queued jobs can be cancelled", job.status),
+            "job": job,
+        })));
     }
+    Ok(HttpResponse::Ok().json(find_job(&pool, job.id).await?))
 }
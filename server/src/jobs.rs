@@ -0,0 +1,255 @@
+use anyhow::{anyhow, Context, Result};
+use sqlx::SqlitePool;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::chart_gen;
+use crate::charts;
+use crate::db;
+use crate::transcode;
+
+/// Job kind: generate every missing chart of a song (with `force`, regenerate them all)
+pub const GENERATE_CHARTS: &str = "generate_charts";
+
+/// Job kind: encode the song's streaming variants (with `force`, even those up to date)
+pub const TRANSCODE: &str = "transcode";
+
+/// How often idle workers look for jobs even without being woken
+const IDLE_POLL: Duration = Duration::from_secs(30);
+
+/// Number of job workers, from `JOB_WORKERS` (default 1: chart generation is CPU heavy
+/// and the server usually runs on a Pi)
+pub fn workers_from_env() -> usize {
+    std::env::var("JOB_WORKERS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(1)
+}
+
+/// Queues jobs in the `jobs` table and wakes the workers. Cheap to clone.
+#[derive(Clone)]
+pub struct JobQueue {
+    pool: SqlitePool,
+    wake: Arc<Notify>,
+}
+
+impl JobQueue {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool, wake: Arc::new(Notify::new()) }
+    }
+
+    /// Queue a job; a job for the same song that is still queued or running is returned
+    /// instead, unless this one is forced and that one is already running unforced
+    pub async fn enqueue(&self, kind: &str, song_id: &str, force: bool, created_by: Option<&str>) -> Result<db::Job> {
+        let job = db::insert_job(&self.pool, kind, song_id, force, created_by, chrono::Utc::now().timestamp()).await?;
+        self.wake.notify_one();
+        Ok(job)
+    }
+
+    /// Queue a failed or cancelled job again. Returns false for jobs in any other state.
+    pub async fn retry(&self, id: i64) -> Result<bool> {
+        let retried = db::retry_job(&self.pool, id).await?;
+        if retried {
+            self.wake.notify_one();
+        }
+        Ok(retried)
+    }
+
+    /// Requeue jobs interrupted by a restart and start `count` workers
+    pub async fn start_workers(&self, count: usize) -> Result<()> {
+        let requeued = db::requeue_running_jobs(&self.pool).await?;
+        if requeued > 0 {
+            log::info!("requeued {} interrupted jobs", requeued);
+        }
+        for n in 0..count {
+            tokio::spawn(worker(self.clone(), n));
+        }
+        log::info!("started {} job workers", count);
+        Ok(())
+    }
+}
+
+async fn worker(queue: JobQueue, n: usize) {
+    loop {
+        match db::claim_job(&queue.pool, chrono::Utc::now().timestamp()).await {
+            Ok(Some(job)) => {
+                log::info!("worker {} running job {} ({} {})", n, job.id, job.kind, job.song_id);
+                let outcome = run(&queue.pool, &job).await.map_err(|e| format!("{:#}", e));
+                if let Err(e) = &outcome {
+                    log::warn!("job {} failed: {}", job.id, e);
+                }
+                let outcome = outcome.as_deref().map_err(String::as_str);
+                if let Err(e) = db::finish_job(&queue.pool, job.id, outcome, chrono::Utc::now().timestamp()).await {
+                    log::error!("failed to record result of job {}: {}", job.id, e);
+                }
+            }
+            Ok(None) => {
+                tokio::select! {
+                    _ = queue.wake.notified() => {}
+                    _ = tokio::time::sleep(IDLE_POLL) => {}
+                }
+            }
+            Err(e) => {
+                log::error!("worker {} could not claim a job: {}", n, e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// Run one claimed job; the Ok message is stored with the finished job
+async fn run(pool: &SqlitePool, job: &db::Job) -> Result<String> {
+    match job.kind.as_str() {
+        GENERATE_CHARTS => generate_charts(pool, job).await,
+        TRANSCODE => transcode_song(pool, job).await,
+        other => Err(anyhow!("unknown job kind {}", other)),
+    }
+}
+
+/// The Rust HQ generator unless `RUST_HQ` is turned off, with the simple charter as
+/// fallback, then re-index the song's charts
+async fn generate_charts(pool: &SqlitePool, job: &db::Job) -> Result<String> {
+    let songs_dir = PathBuf::from(std::env::var("SONGS_DIR").unwrap_or_else(|_| "server/assets/songs".to_string()));
+    let charts_dir = PathBuf::from(std::env::var("CHARTS_DIR").unwrap_or_else(|_| "server/assets/charts".to_string()));
+    let song_id = job.song_id.clone();
+
+    let rust_hq = std::env::var("RUST_HQ").map(|v| v == "1" || v.to_lowercase() == "true").unwrap_or(true);
+    let mut written = None;
+    if rust_hq {
+        db::set_job_progress(pool, job.id, 0.1, "running HQ generator").await?;
+        let (sid, wav, dir, force) = (song_id.clone(), songs_dir.join(format!("{}.wav", song_id)), charts_dir.clone(), job.force);
+        match tokio::task::spawn_blocking(move || crate::hq_rust::generate_hq_charts_rust(&sid, &wav, &dir, force)).await {
+            Ok(Ok(files)) => written = Some(files.len()),
+            Ok(Err(e)) => log::warn!("Rust HQ generation failed for {}: {}, falling back to simple generator", song_id, e),
+            Err(e) => log::warn!("Rust HQ task failed for {}: {}, falling back", song_id, e),
+        }
+    }
+    let written = match written {
+        Some(n) => n,
+        None => {
+            db::set_job_progress(pool, job.id, 0.5, "running simple charter").await?;
+            let (sid, songs, dir, force) = (song_id.clone(), songs_dir.clone(), charts_dir.clone(), job.force);
+            tokio::task::spawn_blocking(move || chart_gen::generate_charts_for_song(&sid, &songs, &dir, force))
+                .await
+                .context("chart generation task failed")??
+                .len()
+        }
+    };
+
+    db::set_job_progress(pool, job.id, 0.9, "indexing charts").await?;
+    let indexed = charts::reindex_song(pool, Path::new(&charts_dir), &song_id).await?;
+    log::info!("generated {} charts for {} ({} indexed)", written, song_id, indexed);
+    Ok(format!("generated {} charts, {} indexed", written, indexed))
+}
+
+/// Encode whichever variants are missing or were made from older audio
+async fn transcode_song(pool: &SqlitePool, job: &db::Job) -> Result<String> {
+    let songs_dir = PathBuf::from(std::env::var("SONGS_DIR").unwrap_or_else(|_| "server/assets/songs".to_string()));
+    let files = db::get_song_files(pool, &job.song_id).await?.context("song is not registered")?;
+    let source = songs_dir.join(&files.filename);
+    let source_hash = {
+        let source = source.clone();
+        tokio::task::spawn_blocking(move || crate::song_watcher::hash_file(&source)).await.context("hashing task failed")??
+    };
+    let qualities = if job.force {
+        transcode::Quality::ALL.to_vec()
+    } else {
+        transcode::missing_variants(pool, &job.song_id, &source_hash).await?
+    };
+    if qualities.is_empty() {
+        return Ok("variants up to date".to_string());
+    }
+
+    db::set_job_progress(pool, job.id, 0.1, "transcoding").await?;
+    let previous = db::song_variants(pool, &job.song_id).await?;
+    let dir = transcode::variants_dir(&songs_dir);
+    let encoded = {
+        let (source, dir, id, hash, qualities) = (source.clone(), dir.clone(), job.song_id.clone(), source_hash.clone(), qualities.clone());
+        tokio::task::spawn_blocking(move || transcode::transcode(&source, &dir, &id, &hash, &qualities))
+            .await
+            .context("transcoding task failed")??
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    for variant in &encoded {
+        // the variant this one replaces, if made from other audio, is not needed any more
+        if let Some(old) = previous.iter().find(|v| v.quality == variant.quality.name() && v.path != variant.path)
+            && !db::variant_file_shared(pool, &old.path, &job.song_id).await?
+        {
+            let _ = std::fs::remove_file(dir.join(&old.path));
+        }
+        db::set_song_variant(pool, &db::SongVariant {
+            song_id: job.song_id.clone(),
+            quality: variant.quality.name().to_string(),
+            path: variant.path.clone(),
+            source_hash: source_hash.clone(),
+            size: variant.size as i64,
+            hash: variant.hash.clone(),
+            created_at: now,
+        })
+        .await?;
+    }
+    let sizes: Vec<String> = encoded.iter().map(|v| format!("{} {} KiB", v.quality.name(), v.size / 1024)).collect();
+    log::info!("transcoded {} ({})", job.song_id, sizes.join(", "));
+    Ok(format!("transcoded {}", sizes.join(", ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::memory_pool;
+
+    #[actix_rt::test]
+    async fn queue_lifecycle() {
+        let pool = memory_pool().await;
+        let queue = JobQueue::new(pool.clone());
+
+        let job = queue.enqueue(GENERATE_CHARTS, "song", false, Some("alice")).await.unwrap();
+        assert_eq!((job.status.as_str(), job.force, job.cancellable), ("queued", false, true));
+        // a second request for the same song joins the queued job, which it can make forced
+        assert!(!queue.enqueue(GENERATE_CHARTS, "song", false, None).await.unwrap().force);
+        let forced = queue.enqueue(GENERATE_CHARTS, "song", true, None).await.unwrap();
+        assert_eq!((forced.id, forced.force), (job.id, true));
+        assert!(queue.enqueue(GENERATE_CHARTS, "song", false, None).await.unwrap().force);
+        let other = queue.enqueue(GENERATE_CHARTS, "other", false, None).await.unwrap();
+        assert!(db::cancel_job(&pool, other.id, 1).await.unwrap());
+
+        let claimed = db::claim_job(&pool, 1).await.unwrap().unwrap();
+        assert_eq!((claimed.id, claimed.status.as_str(), claimed.attempts), (job.id, "running", 1));
+        assert!(!claimed.cancellable);
+        assert!(db::claim_job(&pool, 1).await.unwrap().is_none());
+        assert!(!db::cancel_job(&pool, job.id, 1).await.unwrap());
+
+        assert!(!queue.retry(job.id).await.unwrap());
+
+        db::finish_job(&pool, job.id, Err("no audio"), 2).await.unwrap();
+        let failed = db::get_job(&pool, job.id).await.unwrap().unwrap();
+        assert_eq!((failed.status.as_str(), failed.error.as_deref()), ("failed", Some("no audio")));
+
+        assert!(queue.retry(job.id).await.unwrap());
+        assert!(queue.retry(other.id).await.unwrap());
+        let claimed = db::claim_job(&pool, 3).await.unwrap().unwrap();
+        assert_eq!((claimed.id, claimed.attempts), (job.id, 2));
+        db::finish_job(&pool, job.id, Ok("generated 4 charts"), 4).await.unwrap();
+
+        let jobs = db::list_jobs(&pool, Some("done"), 10, 0).await.unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!((jobs[0].progress, jobs[0].message.as_deref()), (1.0, Some("generated 4 charts")));
+        assert_eq!(db::list_jobs(&pool, None, 10, 0).await.unwrap()[0].id, other.id);
+
+        // a forced request does not join a running job that was not forced
+        assert!(db::cancel_job(&pool, other.id, 5).await.unwrap());
+        let unforced = queue.enqueue(TRANSCODE, "song", false, None).await.unwrap();
+        let running = db::claim_job(&pool, 5).await.unwrap().unwrap();
+        assert_eq!(running.id, unforced.id);
+        let forced = queue.enqueue(TRANSCODE, "song", true, None).await.unwrap();
+        assert_ne!(forced.id, running.id);
+        assert_eq!(queue.enqueue(TRANSCODE, "song", true, None).await.unwrap().id, forced.id);
+        assert_eq!(queue.enqueue(TRANSCODE, "song", false, None).await.unwrap().id, forced.id);
+        db::finish_job(&pool, running.id, Ok("done"), 5).await.unwrap();
+        assert!(db::cancel_job(&pool, forced.id, 5).await.unwrap());
+    }
+}
@@ -1,8 +1,26 @@
 pub mod db;
+pub mod migrations;
 pub mod handlers;
+pub mod routes;
 pub mod auth;
 pub mod chart_gen;
 pub mod song_watcher;
+pub mod jobs;
+pub mod song_meta;
+pub mod verify;
+pub mod replays;
+pub mod avatars;
+pub mod charts;
+pub mod uploads;
+pub mod downloads;
+pub mod transcode;
+pub mod seek;
+pub mod pcm;
 pub mod hq;
 pub mod hq_rust;
 pub mod websocket;
+
+#[cfg(test)]
+mod test_util;
+#[cfg(test)]
+mod tests;
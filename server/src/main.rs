@@ -1,7 +1,7 @@
 use actix_web::{App, HttpServer, middleware::Logger};
 use actix_cors::Cors;
 
-use rhythm_pi_server::{db, handlers, song_watcher, websocket};
+use rhythm_pi_server::{auth, db, jobs, replays, routes, song_watcher};
 use sqlx::SqlitePool;
 use env_logger::Env;
 use std::path::Path;
@@ -45,12 +45,32 @@ async fn main() -> std::io::Result<()> {
     let pool = SqlitePool::connect(&db_url).await.expect("failed to connect to sqlite DB");
     db::init_db(&pool).await.expect("failed to init db");
 
+    // --migrate-only: upgrade the schema and exit without serving
+    if std::env::args().any(|a| a == "--migrate-only") {
+        log::info!("migrations complete; exiting (--migrate-only)");
+        return Ok(());
+    }
+
+    // refuse the default/placeholder JWT secret unless RHYTHM_DEV=1
+    if let Err(e) = auth::check_secret() {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
+
+    // chart generation runs on a pool of JOB_WORKERS workers fed from the jobs table
+    let job_queue = jobs::JobQueue::new(pool.clone());
+    job_queue.start_workers(jobs::workers_from_env()).await.expect("failed to start job workers");
+
     // spawn background watcher task to detect new/changed songs every 5 minutes
-    let pool_clone = pool.clone();
+    let (pool_clone, jobs_clone) = (pool.clone(), job_queue.clone());
     tokio::spawn(async move {
-        crate::song_watcher::start_watcher(pool_clone).await;
+        crate::song_watcher::start_watcher(pool_clone, jobs_clone).await;
     });
 
+    // prune old replays daily when REPLAY_RETENTION_DAYS is set
+    let replay_config = replays::ReplayConfig::from_env();
+    tokio::spawn(replays::start_pruner(pool.clone(), replay_config.clone()));
+
     let addr = ("0.0.0.0", 8080);
     log::info!("Starting server on {}:{}", addr.0, addr.1);
 
@@ -60,21 +80,9 @@ async fn main() -> std::io::Result<()> {
             .wrap(Cors::permissive())
             .wrap(Logger::default())
             .app_data(actix_web::web::Data::new(pool.clone()))
-            .service(
-                actix_web::web::scope("/api")
-                    .route("/songs", actix_web::web::get().to(handlers::list_songs))
-                    .route("/songs/{id}/stream", actix_web::web::get().to(handlers::stream_song))
-                    .route("/songs/{id}/chart", actix_web::web::get().to(handlers::get_chart))
-                    .route("/leaderboard/{song_id}", actix_web::web::get().to(handlers::get_leaderboard))
-                    .route("/scores", actix_web::web::post().to(handlers::post_score))
-                    .route("/register", actix_web::web::post().to(handlers::register_user))
-                    .route("/login", actix_web::web::post().to(handlers::login_user))
-                    // admin: trigger immediate scan (helpful for dev/testing)
-                    .route("/admin/scan", actix_web::web::post().to(handlers::admin_scan))
-                    .route("/admin/generate_hq/{song_id}", actix_web::web::post().to(handlers::admin_generate_hq))
-            )
-            // WebSocket endpoint for audio streaming
-            .route("/ws/audio/{song_id}", actix_web::web::get().to(websocket::ws_audio_stream))
+            .app_data(actix_web::web::Data::new(replay_config.clone()))
+            .app_data(actix_web::web::Data::new(job_queue.clone()))
+            .configure(routes::configure)
             // expose raw static files for downloads
             .service(actix_files::Files::new("/files/songs", "server/assets/songs").show_files_listing())
             .service(actix_files::Files::new("/files/charts", "server/assets/charts").show_files_listing())
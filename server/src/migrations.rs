@@ -0,0 +1,201 @@
+use anyhow::{Result, bail};
+use sqlx::{Executor, SqlitePool};
+
+/// A schema change, applied once in version order
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every migration in order. Released entries must never be edited; add a new one instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial", sql: include_str!("../migrations/0001_initial.sql") },
+    Migration { version: 2, name: "song_metadata", sql: include_str!("../migrations/0002_song_metadata.sql") },
+    Migration { version: 3, name: "score_indexes", sql: include_str!("../migrations/0003_score_indexes.sql") },
+    Migration { version: 4, name: "score_details", sql: include_str!("../migrations/0004_score_details.sql") },
+    Migration { version: 5, name: "score_verification", sql: include_str!("../migrations/0005_score_verification.sql") },
+    Migration { version: 6, name: "replays", sql: include_str!("../migrations/0006_replays.sql") },
+    Migration { version: 7, name: "sessions", sql: include_str!("../migrations/0007_sessions.sql") },
+    Migration { version: 8, name: "user_roles", sql: include_str!("../migrations/0008_user_roles.sql") },
+    Migration { version: 9, name: "user_profiles", sql: include_str!("../migrations/0009_user_profiles.sql") },
+    Migration { version: 10, name: "custom_charts", sql: include_str!("../migrations/0010_custom_charts.sql") },
+    Migration { version: 11, name: "chart_index", sql: include_str!("../migrations/0011_chart_index.sql") },
+    Migration { version: 12, name: "jobs", sql: include_str!("../migrations/0012_jobs.sql") },
+    Migration { version: 13, name: "song_files", sql: include_str!("../migrations/0013_song_files.sql") },
+    Migration { version: 14, name: "song_variants", sql: include_str!("../migrations/0014_song_variants.sql") },
+];
+
+/// Schema version this build expects
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// Current schema version of the database (0 for a new or pre-versioning database)
+pub async fn current_version(pool: &SqlitePool) -> Result<i64> {
+    create_version_table(pool).await?;
+    let version: Option<i64> = sqlx::query_scalar("SELECT MAX(version) FROM schema_version")
+        .fetch_one(pool)
+        .await?;
+    Ok(version.unwrap_or(0))
+}
+
+/// Apply all pending migrations, each in its own transaction. Returns the new version.
+pub async fn run(pool: &SqlitePool) -> Result<i64> {
+    let current = current_version(pool).await?;
+    let latest = latest_version();
+    if current > latest {
+        bail!("database schema version {} is newer than this server supports ({})", current, latest);
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        log::info!("applying migration {} ({})", migration.version, migration.name);
+        let mut tx = pool.begin().await?;
+        let sql = without_existing_columns(&mut tx, migration.sql).await?;
+        tx.execute(sql.as_str()).await.map_err(|e| {
+            anyhow::anyhow!("migration {} ({}) failed: {}", migration.version, migration.name, e)
+        })?;
+        sqlx::query("INSERT INTO schema_version (version, name, applied_at) VALUES (?, ?, ?)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .bind(chrono::Utc::now().timestamp())
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+    }
+
+    Ok(latest)
+}
+
+/// `sql` without its `ALTER TABLE t ADD COLUMN c …;` lines for columns that are there
+/// already. Servers before versioning added the song metadata columns themselves, so
+/// their databases are at version 0 but have some of migration 2.
+async fn without_existing_columns(conn: &mut sqlx::SqliteConnection, sql: &str) -> Result<String> {
+    let mut kept = Vec::new();
+    for line in sql.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if let ["ALTER", "TABLE", table, "ADD", "COLUMN", column, ..] = words[..] {
+            let exists: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?")
+                .bind(table)
+                .bind(column)
+                .fetch_one(&mut *conn)
+                .await?;
+            if exists > 0 {
+                log::info!("{}.{} is already there", table, column);
+                continue;
+            }
+        }
+        kept.push(line);
+    }
+    Ok(kept.join("\n"))
+}
+
+async fn create_version_table(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        );"#,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::Row;
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+    const V1_FIXTURE: &str = include_str!("../tests/fixtures/v1.sql");
+
+    async fn file_pool(dir: &tempfile::TempDir) -> SqlitePool {
+        let options = SqliteConnectOptions::new()
+            .filename(dir.path().join("rhythm.db"))
+            .create_if_missing(true);
+        SqlitePoolOptions::new().connect_with(options).await.unwrap()
+    }
+
+    async fn columns(pool: &SqlitePool, table: &str) -> Vec<String> {
+        sqlx::query(&format!("PRAGMA table_info({})", table))
+            .fetch_all(pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| r.get::<String, _>("name"))
+            .collect()
+    }
+
+    #[test]
+    fn versions_are_ordered() {
+        assert!(MIGRATIONS.windows(2).all(|w| w[1].version == w[0].version + 1));
+        assert_eq!(MIGRATIONS[0].version, 1);
+    }
+
+    #[actix_rt::test]
+    async fn upgrades_v1_fixture_and_keeps_data() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let pool = file_pool(&dir).await;
+        pool.execute(V1_FIXTURE).await.unwrap();
+        assert_eq!(current_version(&pool).await.unwrap(), 0);
+
+        assert_eq!(run(&pool).await.unwrap(), latest_version());
+        assert_eq!(current_version(&pool).await.unwrap(), latest_version());
+
+        let scores: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM scores").fetch_one(&pool).await.unwrap();
+        let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users").fetch_one(&pool).await.unwrap();
+        assert_eq!((scores, users), (2, 1));
+
+        let song_columns = columns(&pool, "songs").await;
+        assert!(song_columns.contains(&"offset_ms".to_string()));
+        assert!(song_columns.contains(&"tags".to_string()));
+        let offset: i64 = sqlx::query_scalar("SELECT offset_ms FROM songs").fetch_one(&pool).await.unwrap();
+        assert_eq!(offset, 0);
+
+        // a second run (next startup) has nothing to do
+        assert_eq!(run(&pool).await.unwrap(), latest_version());
+        let applied: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM schema_version").fetch_one(&pool).await.unwrap();
+        assert_eq!(applied, MIGRATIONS.len() as i64);
+    }
+
+    #[actix_rt::test]
+    async fn upgrades_database_that_added_metadata_columns_itself() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let pool = file_pool(&dir).await;
+        pool.execute(V1_FIXTURE).await.unwrap();
+        // as left by the server that added the columns on startup
+        pool.execute("ALTER TABLE songs ADD COLUMN artists TEXT; ALTER TABLE songs ADD COLUMN offset_ms INTEGER NOT NULL DEFAULT 0;")
+            .await
+            .unwrap();
+
+        assert_eq!(run(&pool).await.unwrap(), latest_version());
+        let song_columns = columns(&pool, "songs").await;
+        assert!(song_columns.contains(&"artists".to_string()) && song_columns.contains(&"tags".to_string()));
+    }
+
+    #[actix_rt::test]
+    async fn creates_fresh_database() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let pool = file_pool(&dir).await;
+        run(&pool).await.unwrap();
+
+        assert!(columns(&pool, "users").await.contains(&"password_hash".to_string()));
+        assert!(columns(&pool, "songs").await.contains(&"bpm".to_string()));
+    }
+
+    #[actix_rt::test]
+    async fn refuses_newer_database() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let pool = file_pool(&dir).await;
+        run(&pool).await.unwrap();
+        sqlx::query("INSERT INTO schema_version (version, name, applied_at) VALUES (?, 'future', 0)")
+            .bind(latest_version() + 1)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(run(&pool).await.is_err());
+    }
+}
@@ -0,0 +1,344 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use crate::transcode::AudioDecoder;
+
+/// Sample rates a client may ask for
+const SAMPLE_RATES: RangeInclusive<u32> = 8_000..=192_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// Signed 16-bit little-endian
+    S16le,
+    /// 32-bit little-endian floats from -1 to 1
+    F32le,
+}
+
+impl Encoding {
+    fn sample_bytes(self) -> usize {
+        match self {
+            Encoding::S16le => 2,
+            Encoding::F32le => 4,
+        }
+    }
+}
+
+/// Raw interleaved audio a client wants in place of the file, to feed straight to its
+/// output device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PcmFormat {
+    pub encoding: Encoding,
+    pub sample_rate: u32,
+    /// 1 or 2
+    pub channels: u16,
+}
+
+impl PcmFormat {
+    pub fn check(&self) -> Result<()> {
+        if !SAMPLE_RATES.contains(&self.sample_rate) {
+            bail!("sample_rate must be between {} and {}", SAMPLE_RATES.start(), SAMPLE_RATES.end());
+        }
+        if !(1..=2).contains(&self.channels) {
+            bail!("channels must be 1 or 2");
+        }
+        Ok(())
+    }
+
+    /// Bytes of one frame: a sample for every channel
+    pub fn frame_bytes(&self) -> usize {
+        self.encoding.sample_bytes() * usize::from(self.channels)
+    }
+}
+
+/// Linear-interpolating sample rate converter for interleaved audio fed in blocks. When
+/// lowering the rate it filters first, since interpolation alone folds everything above
+/// the new Nyquist frequency back into the audio.
+struct Resampler {
+    channels: usize,
+    /// Input frames per output frame
+    step: f64,
+    /// Where the next output frame falls, in input frames after `last`
+    pos: f64,
+    /// The final frame of the previous block
+    last: Option<Vec<f32>>,
+    low_pass: Option<LowPass>,
+}
+
+impl Resampler {
+    fn new(channels: usize, from: u32, to: u32) -> Self {
+        let step = f64::from(from) / f64::from(to);
+        let low_pass = (step > 1.0).then(|| LowPass::new(channels, step));
+        Self { channels, step, pos: 0.0, last: None, low_pass }
+    }
+
+    fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        match &mut self.low_pass {
+            Some(low_pass) => {
+                let mut filtered = Vec::with_capacity(input.len());
+                low_pass.process(input, &mut filtered);
+                self.interpolate(&filtered, out);
+            }
+            None => self.interpolate(input, out),
+        }
+    }
+
+    /// Convert what the filter still holds back, at the end of the audio
+    fn finish(&mut self, out: &mut Vec<f32>) {
+        if let Some(low_pass) = &mut self.low_pass {
+            let mut filtered = Vec::new();
+            low_pass.finish(&mut filtered);
+            self.interpolate(&filtered, out);
+        }
+    }
+
+    fn interpolate(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        let ch = self.channels;
+        let (last, input) = match self.last.take() {
+            Some(last) => (last, input),
+            None if input.len() >= ch => (input[..ch].to_vec(), &input[ch..]),
+            None => return,
+        };
+        // frame 0 is `last`, frame i the (i-1)th of the input
+        let frames = input.len() / ch;
+        let frame = |i: usize| if i == 0 { &last[..] } else { &input[(i - 1) * ch..i * ch] };
+        while self.pos < frames as f64 {
+            let i = self.pos as usize;
+            let t = (self.pos - i as f64) as f32;
+            let (a, b) = (frame(i), frame(i + 1));
+            out.extend((0..ch).map(|c| a[c] + (b[c] - a[c]) * t));
+            self.pos += self.step;
+        }
+        self.pos -= frames as f64;
+        self.last = Some(frame(frames).to_vec());
+    }
+}
+
+/// Windowed-sinc low-pass filter for interleaved audio fed in blocks. It is centred on
+/// each frame, so nothing is delayed: every frame waits for the `half` frames after it,
+/// and the first and last frames stand in for the audio before and after the song.
+struct LowPass {
+    channels: usize,
+    /// `2 * half + 1` coefficients, adding up to 1
+    taps: Vec<f32>,
+    /// Frames not filtered yet, after the `half` frames before them
+    buffered: Vec<f32>,
+    started: bool,
+}
+
+impl LowPass {
+    /// A filter for lowering the rate to `1 / step` of the input's
+    fn new(channels: usize, step: f64) -> Self {
+        use std::f64::consts::PI;
+        // longer filters for bigger drops keep the cut equally sharp against the new rate
+        let half = ((16.0 * step).ceil() as usize).min(512);
+        // in cycles per input frame: a little below the new Nyquist frequency, so the
+        // filter has stopped almost everything by the time it reaches it
+        let cutoff = 0.42 / step;
+        let len = (2 * half) as f64;
+        let mut taps: Vec<f64> = (0..=2 * half)
+            .map(|k| {
+                let x = k as f64 - half as f64;
+                let sinc = if x == 0.0 { 2.0 * cutoff } else { (2.0 * PI * cutoff * x).sin() / (PI * x) };
+                // Blackman window
+                let window = 0.42 - 0.5 * (2.0 * PI * k as f64 / len).cos() + 0.08 * (4.0 * PI * k as f64 / len).cos();
+                sinc * window
+            })
+            .collect();
+        let sum: f64 = taps.iter().sum();
+        taps.iter_mut().for_each(|t| *t /= sum);
+        Self { channels, taps: taps.into_iter().map(|t| t as f32).collect(), buffered: Vec::new(), started: false }
+    }
+
+    fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        let ch = self.channels;
+        if !self.started {
+            if input.len() < ch {
+                return;
+            }
+            for _ in 0..self.taps.len() / 2 {
+                self.buffered.extend_from_slice(&input[..ch]);
+            }
+            self.started = true;
+        }
+        self.buffered.extend_from_slice(input);
+        let ready = (self.buffered.len() / ch).saturating_sub(self.taps.len() - 1);
+        for frame in 0..ready {
+            let window = &self.buffered[frame * ch..(frame + self.taps.len()) * ch];
+            out.extend((0..ch).map(|c| self.taps.iter().zip(window[c..].iter().step_by(ch)).map(|(t, s)| t * s).sum::<f32>()));
+        }
+        self.buffered.drain(..ready * ch);
+    }
+
+    /// Filter the frames still waiting for the ones after them
+    fn finish(&mut self, out: &mut Vec<f32>) {
+        let ch = self.channels;
+        if self.buffered.len() < ch {
+            return;
+        }
+        let last = self.buffered[self.buffered.len() - ch..].to_vec();
+        let padding: Vec<f32> = last.iter().copied().cycle().take(self.taps.len() / 2 * ch).collect();
+        self.process(&padding, out);
+    }
+}
+
+/// A song decoded into a `PcmFormat`, read a block at a time
+pub struct PcmReader {
+    decoder: AudioDecoder,
+    format: PcmFormat,
+    /// None when the song is already at the rate asked for
+    resampler: Option<Resampler>,
+    /// Index of the next frame returned, counted from the start of the song
+    next_frame: u64,
+    /// Converted samples not returned yet
+    pending: Vec<f32>,
+    scratch: Vec<f32>,
+    done: bool,
+}
+
+impl PcmReader {
+    /// Decode `path` into `format` from frame `start_frame` of the output
+    pub fn open(path: &Path, format: PcmFormat, start_frame: u64) -> Result<Self> {
+        format.check()?;
+        let mut decoder = AudioDecoder::open(path)?;
+        let done = start_frame > 0 && !decoder.seek(start_frame as f64 / f64::from(format.sample_rate))?;
+        let resampler = (decoder.sample_rate != format.sample_rate)
+            .then(|| Resampler::new(usize::from(format.channels), decoder.sample_rate, format.sample_rate));
+        Ok(Self { decoder, format, resampler, next_frame: start_frame, pending: Vec::new(), scratch: Vec::new(), done })
+    }
+
+    /// Up to `frames` frames, fewer only at the end, with the index of the first. None
+    /// once the song is over.
+    pub fn read(&mut self, frames: usize) -> Result<Option<(u64, Vec<u8>)>> {
+        let channels = usize::from(self.format.channels);
+        let wanted = frames.max(1) * channels;
+        let source_channels = self.decoder.channels;
+        while self.pending.len() < wanted && !self.done {
+            let Some(samples) = self.decoder.next_samples()? else {
+                self.done = true;
+                if let Some(resampler) = &mut self.resampler {
+                    resampler.finish(&mut self.pending);
+                }
+                break;
+            };
+            self.scratch.clear();
+            for frame in samples.chunks(source_channels) {
+                let left = f32::from(frame[0]) / 32768.0;
+                let right = frame.get(1).map_or(left, |&s| f32::from(s) / 32768.0);
+                match channels {
+                    1 => self.scratch.push((left + right) / 2.0),
+                    _ => self.scratch.extend([left, right]),
+                }
+            }
+            match &mut self.resampler {
+                Some(resampler) => resampler.process(&self.scratch, &mut self.pending),
+                None => self.pending.extend_from_slice(&self.scratch),
+            }
+        }
+
+        let take = wanted.min(self.pending.len() / channels * channels);
+        if take == 0 {
+            return Ok(None);
+        }
+        let mut bytes = Vec::with_capacity(take * self.format.encoding.sample_bytes());
+        for &sample in &self.pending[..take] {
+            let sample = sample.clamp(-1.0, 1.0);
+            match self.format.encoding {
+                Encoding::S16le => bytes.extend_from_slice(&((sample * 32767.0).round() as i16).to_le_bytes()),
+                Encoding::F32le => bytes.extend_from_slice(&sample.to_le_bytes()),
+            }
+        }
+        self.pending.drain(..take);
+        let first = self.next_frame;
+        self.next_frame += (take / channels) as u64;
+        Ok(Some((first, bytes)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_wav;
+
+    #[test]
+    fn resamples_between_rates() {
+        // a ramp comes out a ramp, at twice the frames and then back
+        let input: Vec<f32> = (0..100).map(|i| i as f32).collect();
+        let mut up = Resampler::new(1, 22050, 44100);
+        let mut out = Vec::new();
+        up.process(&input[..37], &mut out);
+        up.process(&input[37..], &mut out);
+        assert_eq!(out.len(), 198);
+        assert!(out.iter().enumerate().all(|(i, &s)| (s - i as f32 / 2.0).abs() < 1e-4), "{:?}", &out[..8]);
+
+        // the low-pass filter in front leaves a ramp as it is, away from the ends where it
+        // stands the first and last frames in for the audio beyond them
+        let mut down = Resampler::new(2, 44100, 22050);
+        let stereo: Vec<f32> = (0..200).flat_map(|i| [i as f32, -(i as f32)]).collect();
+        let mut out = Vec::new();
+        down.process(&stereo[..74], &mut out);
+        down.process(&stereo[74..], &mut out);
+        down.finish(&mut out);
+        assert_eq!(out.len(), 2 * 100);
+        for frame in 20..80 {
+            let (left, right) = (out[frame * 2], out[frame * 2 + 1]);
+            assert!((left - 2.0 * frame as f32).abs() < 1e-3 && (right + left).abs() < 1e-3, "{} {} at {}", left, right, frame);
+        }
+    }
+
+    #[test]
+    fn filters_out_what_a_lower_rate_cannot_hold() {
+        let rms = |samples: &[f32]| (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
+        let resample = |freq: f32| {
+            let tone: Vec<f32> = (0..44100).map(|i| (i as f32 * freq * std::f32::consts::TAU / 44100.0).sin() * 0.5).collect();
+            let mut resampler = Resampler::new(1, 44100, 8000);
+            let mut out = Vec::new();
+            for block in tone.chunks(1000) {
+                resampler.process(block, &mut out);
+            }
+            resampler.finish(&mut out);
+            assert!(out.len().abs_diff(8000) <= 1, "{} frames", out.len());
+            out
+        };
+        // 1 kHz passes; 6 kHz is above the 4 kHz an 8 kHz rate holds and would come back
+        // as 2 kHz if it were not filtered out
+        let kept = rms(&resample(1000.0)[500..7500]);
+        assert!((kept - 0.5 / 2f32.sqrt()).abs() < 0.01, "1 kHz at {}", kept);
+        let folded = rms(&resample(6000.0)[500..7500]);
+        assert!(folded < 0.005, "6 kHz folded back at {}", folded);
+    }
+
+    #[test]
+    fn decodes_songs_into_the_format_asked_for() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let source = dir.path().join("song.wav");
+        write_wav(&source, 1, 22050, 22050 * 2, |i, _| ((i as f32 * 0.05).sin() * 8000.0) as i16);
+
+        let format = PcmFormat { encoding: Encoding::S16le, sample_rate: 44100, channels: 2 };
+        let mut reader = PcmReader::open(&source, format, 0).unwrap();
+        let mut frames = 0u64;
+        while let Some((first, bytes)) = reader.read(4096).unwrap() {
+            assert_eq!(first, frames);
+            let samples: Vec<i16> = bytes.chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+            // mono goes to both channels
+            assert!(samples.chunks(2).all(|f| f[0] == f[1]));
+            frames += (bytes.len() / format.frame_bytes()) as u64;
+        }
+        assert!(frames.abs_diff(88200) <= 2, "{} frames", frames);
+
+        // from a second in, as floats at the song's own rate
+        let format = PcmFormat { encoding: Encoding::F32le, sample_rate: 22050, channels: 1 };
+        let mut reader = PcmReader::open(&source, format, 22050).unwrap();
+        let (first, bytes) = reader.read(100).unwrap().unwrap();
+        assert_eq!((first, bytes.len()), (22050, 400));
+        let sample = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let expected = ((22050.0f32 * 0.05).sin() * 8000.0) as i16 as f32 / 32768.0;
+        assert!((sample - expected).abs() < 1e-3, "{} vs {}", sample, expected);
+
+        // past the end there is nothing
+        assert!(PcmReader::open(&source, format, 22050 * 3).unwrap().read(100).unwrap().is_none());
+        assert!(PcmReader::open(&source, PcmFormat { channels: 6, ..format }, 0).is_err());
+    }
+}
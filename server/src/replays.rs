@@ -0,0 +1,92 @@
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rhythm_pi_scoring::Replay;
+use sqlx::SqlitePool;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use crate::db;
+
+/// Largest accepted score submission body, replay included
+pub const MAX_SUBMISSION_BYTES: usize = 4 * 1024 * 1024;
+
+/// Replay storage settings, from the environment:
+/// `REPLAY_MAX_BYTES` caps a compressed replay (default 256 KiB) and
+/// `REPLAY_RETENTION_DAYS` prunes older replays (unset or 0 keeps them forever).
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    pub max_bytes: usize,
+    pub retention_days: Option<u64>,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self { max_bytes: 256 * 1024, retention_days: None }
+    }
+}
+
+impl ReplayConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let max_bytes = std::env::var("REPLAY_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.max_bytes);
+        let retention_days = std::env::var("REPLAY_RETENTION_DAYS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|days| *days > 0);
+        Self { max_bytes, retention_days }
+    }
+}
+
+/// Gzip the replay JSON. Returns the compressed bytes and the uncompressed length.
+pub fn compress(replay: &Replay) -> Result<(Vec<u8>, usize)> {
+    let json = serde_json::to_vec(replay)?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&json)?;
+    Ok((encoder.finish()?, json.len()))
+}
+
+pub fn decompress(data: &[u8]) -> Result<Replay> {
+    let mut json = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut json).context("corrupt replay")?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Prune expired replays once a day, if a retention period is configured
+pub async fn start_pruner(pool: SqlitePool, config: ReplayConfig) {
+    let Some(days) = config.retention_days else {
+        return;
+    };
+    loop {
+        let before = chrono::Utc::now().timestamp() - (days * 24 * 60 * 60) as i64;
+        match db::prune_replays(&pool, before).await {
+            Ok(0) => {}
+            Ok(n) => log::info!("pruned {} replays older than {} days", n, days),
+            Err(e) => log::error!("replay pruning failed: {}", e),
+        }
+        tokio::time::sleep(Duration::from_secs(24 * 60 * 60)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compresses_and_restores() {
+        let mut replay = Replay::new();
+        for i in 0..500 {
+            replay.press(i % 4, i as f32 * 0.25);
+            replay.release(i % 4, i as f32 * 0.25 + 0.1);
+        }
+
+        let (data, size) = compress(&replay).unwrap();
+        assert!(data.len() < size);
+        assert_eq!(decompress(&data).unwrap(), replay);
+        assert!(decompress(b"not gzip").is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use actix_web::middleware::from_fn;
+use actix_web::web;
+
+use crate::{auth, avatars, handlers, replays, uploads, websocket};
+
+/// API and WebSocket routes, shared by the server binary and the tests.
+/// Expects `Data<SqlitePool>`, `Data<ReplayConfig>` and `Data<JobQueue>` app data.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/api")
+            .route("/songs", web::get().to(handlers::list_songs))
+            .route("/songs", web::post().to(handlers::upload_song))
+            .route("/songs/{id}/stream", web::get().to(handlers::stream_song))
+            .route("/songs/{id}/chart", web::get().to(handlers::get_chart))
+            .route("/songs/{id}/charts", web::get().to(handlers::list_song_charts))
+            .service(
+                web::resource("/songs/{id}/charts")
+                    .app_data(web::PayloadConfig::new(uploads::MAX_JSON_BYTES))
+                    .route(web::post().to(handlers::upload_custom_chart))
+            )
+            .route("/charts/{id}", web::get().to(handlers::get_custom_chart))
+            .route("/leaderboard/{song_id}", web::get().to(handlers::get_leaderboard))
+            .service(
+                web::resource("/scores")
+                    .app_data(web::JsonConfig::default().limit(replays::MAX_SUBMISSION_BYTES))
+                    .route(web::post().to(handlers::post_score))
+            )
+            .route("/scores/{id}/replay", web::get().to(handlers::get_replay))
+            .route("/register", web::post().to(handlers::register_user))
+            .route("/login", web::post().to(handlers::login_user))
+            .route("/refresh", web::post().to(handlers::refresh_token))
+            .route("/logout", web::post().to(handlers::logout_user))
+            .route("/me", web::get().to(handlers::get_my_profile))
+            .route("/me", web::patch().to(handlers::update_my_profile))
+            .service(
+                web::resource("/me/avatar")
+                    .app_data(web::PayloadConfig::new(avatars::MAX_AVATAR_BYTES))
+                    .route(web::put().to(handlers::upload_avatar))
+                    .route(web::delete().to(handlers::delete_avatar))
+            )
+            .route("/jobs", web::get().to(handlers::list_jobs))
+            .route("/jobs/{id}", web::get().to(handlers::get_job))
+            .route("/jobs/{id}/retry", web::post().to(handlers::retry_job))
+            .route("/jobs/{id}/cancel", web::post().to(handlers::cancel_job))
+            .route("/users/{name}", web::get().to(handlers::get_user_profile))
+            .route("/users/{name}/avatar", web::get().to(handlers::get_avatar))
+            // everything under /admin needs an admin token
+            .service(
+                web::scope("/admin")
+                    .wrap(from_fn(auth::require_admin))
+                    .route("/scan", web::post().to(handlers::admin_scan))
+                    .route("/generate_hq/{song_id}", web::post().to(handlers::admin_generate_hq))
+                    .route("/users/{username}/role", web::put().to(handlers::set_user_role))
+            )
+    )
+    // WebSocket endpoint for audio streaming
+    .route("/ws/audio/{song_id}", web::get().to(websocket::ws_audio_stream));
+}
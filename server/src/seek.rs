@@ -0,0 +1,285 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::transcode;
+
+/// What a player needs to know about an audio file to start it anywhere
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioInfo {
+    /// Codec short name: `vorbis`, `flac`, `mp3`, `pcm_s16le`…
+    pub codec: &'static str,
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub duration_ms: Option<u64>,
+    pub total_bytes: u64,
+    /// Bytes before the first audio. Audio started mid-file is sent after these, so a
+    /// decoder sees the stream's headers first.
+    pub header_bytes: u64,
+    #[serde(skip)]
+    points: SeekPoints,
+}
+
+/// Where audio started from a time begins
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Start {
+    pub offset: u64,
+    /// Header bytes to send ahead of `offset`; 0 when the offset is inside the header anyway
+    pub header: u64,
+    /// Time of the first audio from `offset`: exact for WAV, the end of the page before
+    /// for Ogg, an estimate for files with no index
+    pub ms: u64,
+}
+
+/// A file as it was when indexed: path, length and modification time
+type Version = (PathBuf, u64, Option<SystemTime>);
+
+/// Files indexed lately, oldest first, so an Ogg file's pages are walked once rather than
+/// for every connection
+static INDEXED: Mutex<Vec<(Version, Arc<AudioInfo>)>> = Mutex::new(Vec::new());
+
+/// Most files kept in `INDEXED`
+const INDEXED_MAX: usize = 32;
+
+/// How a time maps to a byte offset
+#[derive(Debug, Clone)]
+enum SeekPoints {
+    /// Uncompressed frames of `block_align` bytes, `byte_rate` bytes a second, up to `data_end`
+    Pcm { byte_rate: u64, block_align: u64, data_end: u64 },
+    /// `(offset, granule position)` of every Ogg page that ends audio, in order
+    Pages(Vec<(u64, u64)>),
+    /// No index: a time is the same fraction of the audio bytes as of the duration, and
+    /// the decoder finds the next frame itself (MP3 and FLAC frames have sync codes)
+    Proportional,
+}
+
+impl AudioInfo {
+    /// `read`, reusing the index of a file that has not changed since it was last read
+    pub fn cached(path: &Path) -> Result<Arc<Self>> {
+        let meta = std::fs::metadata(path).with_context(|| format!("reading {}", path.display()))?;
+        let version = (path.to_path_buf(), meta.len(), meta.modified().ok());
+        let indexed = || INDEXED.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((_, info)) = indexed().iter().find(|(v, _)| *v == version) {
+            return Ok(info.clone());
+        }
+        let info = Arc::new(Self::read(path)?);
+        let mut indexed = indexed();
+        indexed.retain(|(v, _)| v.0 != version.0);
+        indexed.push((version, info.clone()));
+        if indexed.len() > INDEXED_MAX {
+            indexed.remove(0);
+        }
+        Ok(info)
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let total_bytes = std::fs::metadata(path).with_context(|| format!("reading {}", path.display()))?.len();
+        let format = transcode::probe(path)?;
+        let params = &format.default_track().context("no audio track")?.codec_params;
+        let sample_rate = params.sample_rate.context("unknown sample rate")?;
+        let channels = params.channels.map(|c| c.count()).unwrap_or(0);
+        let codec = symphonia::default::get_codecs().get_codec(params.codec).map(|c| c.short_name).unwrap_or("unknown");
+        let frames = params.n_frames;
+
+        let mut file = BufReader::new(std::fs::File::open(path)?);
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic).context("audio file is too short")?;
+        let (header_bytes, points) = match &magic {
+            b"RIFF" => wav_layout(&mut file, total_bytes)?,
+            b"OggS" => (0, ogg_pages(&mut file, total_bytes)?),
+            b"fLaC" => (flac_header(&mut file)?, SeekPoints::Proportional),
+            _ => (0, SeekPoints::Proportional),
+        };
+        let header_bytes = match &points {
+            // Vorbis headers are on pages at granule position 0, ahead of the first page of audio
+            SeekPoints::Pages(pages) => pages.first().map(|p| p.0).unwrap_or(total_bytes),
+            _ => header_bytes,
+        };
+
+        let duration_ms = match &points {
+            SeekPoints::Pcm { byte_rate, data_end, .. } if *byte_rate > 0 => Some((data_end - header_bytes) * 1000 / byte_rate),
+            SeekPoints::Pages(pages) => pages.last().map(|p| p.1 * 1000 / u64::from(sample_rate)),
+            _ => frames.map(|n| n * 1000 / u64::from(sample_rate)),
+        };
+        Ok(Self { codec, sample_rate, channels, duration_ms, total_bytes, header_bytes, points })
+    }
+
+    /// Where to start sending for playback from `ms`. None when the duration of audio with
+    /// no index is unknown.
+    pub fn start_at_ms(&self, ms: u64) -> Option<Start> {
+        let rate = u64::from(self.sample_rate).max(1);
+        let (offset, start_ms) = match &self.points {
+            SeekPoints::Pcm { byte_rate, block_align, data_end } => {
+                let block = (*block_align).max(1);
+                let bytes = ms.saturating_mul(*byte_rate) / 1000 / block * block;
+                let offset = self.header_bytes.saturating_add(bytes).min(*data_end);
+                (offset, (offset - self.header_bytes) * 1000 / (*byte_rate).max(1))
+            }
+            SeekPoints::Pages(pages) => {
+                let target = ms.saturating_mul(rate) / 1000;
+                // the first page whose audio runs past the target starts just before it,
+                // where the page before it ends
+                match pages.iter().position(|p| p.1 > target) {
+                    Some(0) => (pages[0].0, 0),
+                    Some(n) => (pages[n].0, pages[n - 1].1 * 1000 / rate),
+                    None => (self.total_bytes, pages.last().map_or(0, |p| p.1 * 1000 / rate)),
+                }
+            }
+            SeekPoints::Proportional => {
+                let duration = self.duration_ms.filter(|d| *d > 0)?;
+                let audio = self.total_bytes - self.header_bytes;
+                let fraction = ms.min(duration) as f64 / duration as f64;
+                (self.header_bytes + (audio as f64 * fraction) as u64, ms.min(duration))
+            }
+        };
+        Some(if offset <= self.header_bytes {
+            Start { offset: 0, header: 0, ms: 0 }
+        } else {
+            Start { offset, header: self.header_bytes, ms: start_ms }
+        })
+    }
+}
+
+/// Header size and sample layout of a RIFF WAVE file, read after its `RIFF` tag
+fn wav_layout(file: &mut BufReader<std::fs::File>, total_bytes: u64) -> Result<(u64, SeekPoints)> {
+    let mut riff = [0u8; 8];
+    file.read_exact(&mut riff)?;
+    if &riff[4..] != b"WAVE" {
+        return Ok((0, SeekPoints::Proportional));
+    }
+    let mut pos = 12u64;
+    let (mut byte_rate, mut block_align) = (0, 1);
+    loop {
+        let mut chunk = [0u8; 8];
+        file.read_exact(&mut chunk).context("WAVE file has no data chunk")?;
+        let size = u64::from(u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]));
+        pos += 8;
+        match &chunk[..4] {
+            b"fmt " => {
+                let mut fmt = [0u8; 16];
+                file.read_exact(&mut fmt)?;
+                byte_rate = u64::from(u32::from_le_bytes([fmt[8], fmt[9], fmt[10], fmt[11]]));
+                block_align = u64::from(u16::from_le_bytes([fmt[12], fmt[13]]));
+                file.seek(SeekFrom::Start(pos + size + size % 2))?;
+            }
+            b"data" => {
+                let data_end = pos.saturating_add(size).min(total_bytes);
+                return Ok((pos, SeekPoints::Pcm { byte_rate, block_align, data_end }));
+            }
+            _ => {
+                file.seek(SeekFrom::Start(pos + size + size % 2))?;
+            }
+        }
+        pos += size + size % 2;
+    }
+}
+
+/// Offsets and granule positions of the audio pages of an Ogg file, read after its first
+/// `OggS`. Scanning stops at anything that is not a page.
+fn ogg_pages(file: &mut BufReader<std::fs::File>, total_bytes: u64) -> Result<SeekPoints> {
+    let mut pages = Vec::new();
+    let mut pos = 0u64;
+    file.seek(SeekFrom::Start(0))?;
+    while pos < total_bytes {
+        let mut header = [0u8; 27];
+        if file.read_exact(&mut header).is_err() || &header[..4] != b"OggS" {
+            break;
+        }
+        let granule = u64::from_le_bytes(header[6..14].try_into()?);
+        let mut segments = vec![0u8; usize::from(header[26])];
+        file.read_exact(&mut segments)?;
+        let body: u64 = segments.iter().map(|&s| u64::from(s)).sum();
+        // granule position -1 means no packet ends on the page, and 0 marks headers
+        if granule != u64::MAX && granule > 0 {
+            pages.push((pos, granule));
+        }
+        pos += 27 + segments.len() as u64 + body;
+        file.seek(SeekFrom::Start(pos))?;
+    }
+    Ok(SeekPoints::Pages(pages))
+}
+
+/// Size of the `fLaC` tag and metadata blocks that open a FLAC file
+fn flac_header(file: &mut BufReader<std::fs::File>) -> Result<u64> {
+    let mut pos = 4u64;
+    loop {
+        let mut block = [0u8; 4];
+        file.read_exact(&mut block).context("FLAC metadata is cut short")?;
+        let len = u64::from(u32::from_be_bytes([0, block[1], block[2], block[3]]));
+        pos += 4 + len;
+        if block[0] & 0x80 != 0 {
+            return Ok(pos);
+        }
+        file.seek(SeekFrom::Start(pos))?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_wav;
+    use crate::transcode::{transcode, AudioDecoder, Quality};
+
+    #[test]
+    fn finds_times_in_wav_and_ogg() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let source = dir.path().join("song.wav");
+        // noise, so the Ogg version spans many pages
+        let mut seed = 1u32;
+        write_wav(&source, 2, 22050, 22050 * 4, |_, channel| {
+            if channel == 0 {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            }
+            (seed >> 16) as i16 / 4
+        });
+
+        let info = AudioInfo::read(&source).unwrap();
+        assert_eq!((info.codec, info.sample_rate, info.channels, info.header_bytes), ("pcm_s16le", 22050, 2, 44));
+        assert_eq!(info.duration_ms, Some(4000));
+        assert_eq!(info.start_at_ms(0), Some(Start { offset: 0, header: 0, ms: 0 }));
+        // 22050 frames of 4 bytes a second, so a second in is 88200 bytes past the header
+        assert_eq!(info.start_at_ms(1000), Some(Start { offset: 44 + 88200, header: 44, ms: 1000 }));
+        assert_eq!(info.start_at_ms(60_000), Some(Start { offset: info.total_bytes, header: 44, ms: 4000 }));
+
+        let encoded = transcode(&source, dir.path(), "song", "abc", &[Quality::Low]).unwrap();
+        let ogg = dir.path().join(&encoded[0].path);
+        let info = AudioInfo::read(&ogg).unwrap();
+        assert_eq!((info.codec, info.sample_rate), ("vorbis", 22050));
+        assert!(info.duration_ms.is_some_and(|d| d.abs_diff(4000) < 50), "{:?}", info.duration_ms);
+        let Start { offset, header, ms } = info.start_at_ms(3000).unwrap();
+        assert!(header > 0 && offset > header && offset < info.total_bytes, "{} {} {:?}", offset, header, info);
+        // the page holding the three second mark starts before it
+        assert!(ms <= 3000 && ms > 2500, "starts at {} ms", ms);
+
+        // the header followed by the audio from the offset decodes: the last second, and
+        // whatever of the page holding the three second mark comes before it
+        let bytes = std::fs::read(&ogg).unwrap();
+        let cut = dir.path().join("cut.ogg");
+        std::fs::write(&cut, [&bytes[..header as usize], &bytes[offset as usize..]].concat()).unwrap();
+        let mut decoded = AudioDecoder::open(&cut).unwrap();
+        let mut frames = 0;
+        while let Some(samples) = decoded.next_samples().unwrap() {
+            frames += samples.len() / 2;
+        }
+        assert!(frames > 22050 - 2048 && frames < 44100, "{} frames", frames);
+        // which is about as much as the reported start leaves
+        let left = (4000 - ms) * 22050 / 1000;
+        assert!(frames.abs_diff(left as usize) < 2048, "{} frames, {} expected", frames, left);
+    }
+
+    #[test]
+    fn reuses_indexes_of_unchanged_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("song.wav");
+        let write = |frames| write_wav(&path, 1, 8000, frames, |_, _| 0);
+        write(8000);
+        let first = AudioInfo::cached(&path).unwrap();
+        assert!(Arc::ptr_eq(&first, &AudioInfo::cached(&path).unwrap()));
+        write(16000);
+        let rewritten = AudioInfo::cached(&path).unwrap();
+        assert_eq!((first.duration_ms, rewritten.duration_ms), (Some(1000), Some(2000)));
+    }
+}
@@ -0,0 +1,234 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::path::Path;
+
+/// Song metadata stored in `{id}.json` next to the audio.
+///
+/// Serializes with the canonical snake_case keys below. Older files use `SongTitle`,
+/// `Artists` and `start_offset_Ms`; those (and a few other spellings) are accepted on read,
+/// and `parse` converts the legacy `duration` and `offset`, which are in seconds.
+/// Unknown keys such as the charter's `charter` block are ignored here.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SongMetadata {
+    #[serde(alias = "SongTitle", alias = "Title", alias = "song_title")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(alias = "Artists", alias = "artist", alias = "Artist")]
+    #[serde(deserialize_with = "one_or_many", skip_serializing_if = "Vec::is_empty")]
+    pub artists: Vec<String>,
+    #[serde(alias = "Album")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    #[serde(alias = "Year")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub year: Option<i32>,
+    #[serde(alias = "Genre")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub genre: Option<String>,
+    /// Milliseconds between the start of the audio and the chart's time zero
+    #[serde(alias = "start_offset_Ms", alias = "start_offset_ms")]
+    pub offset_ms: i64,
+    /// Where the song select screen starts its preview
+    #[serde(alias = "PreviewStart", alias = "preview_start")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview_start_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<i64>,
+    #[serde(alias = "BPM", alias = "Bpm")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bpm: Option<f32>,
+    #[serde(alias = "Tags")]
+    #[serde(deserialize_with = "one_or_many", skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+/// Keys written by older tools that `migrate_file` replaces with canonical ones
+const LEGACY_KEYS: &[&str] = &[
+    "SongTitle", "Title", "song_title", "Artists", "artist", "Artist", "Album", "Year", "Genre",
+    "start_offset_Ms", "start_offset_ms", "offset", "PreviewStart", "preview_start", "Duration",
+    "duration", "BPM", "Bpm", "Tags",
+];
+
+/// Legacy keys in seconds, and the millisecond fields they fill when those are missing
+const LEGACY_SECONDS: &[(&str, &str)] = &[("Duration", "duration_ms"), ("duration", "duration_ms"), ("offset", "offset_ms")];
+
+impl SongMetadata {
+    /// Parse metadata JSON, accepting legacy key spellings
+    pub fn parse(json: &str) -> Result<Self> {
+        let mut value: serde_json::Value = serde_json::from_str(json)?;
+        if let Some(object) = value.as_object_mut() {
+            for (key, ms_key) in LEGACY_SECONDS {
+                let Some(seconds) = object.remove(*key).filter(|v| !v.is_null()) else {
+                    continue;
+                };
+                let Some(seconds) = seconds.as_f64() else {
+                    anyhow::bail!("`{}` must be a number of seconds", key);
+                };
+                object.entry(*ms_key).or_insert_with(|| ((seconds * 1000.0).round() as i64).into());
+            }
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("invalid metadata in {}", path.display()))
+    }
+
+    /// Problems that make the metadata unusable or suspicious; empty when valid
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.title.as_deref().is_some_and(|t| t.trim().is_empty()) {
+            errors.push("title is empty".to_string());
+        }
+        if self.artists.iter().any(|a| a.trim().is_empty()) {
+            errors.push("artists contains an empty name".to_string());
+        }
+        if let Some(year) = self.year.filter(|y| !(1900..=2100).contains(y)) {
+            errors.push(format!("year {} is out of range", year));
+        }
+        if self.offset_ms.abs() > 60_000 {
+            errors.push(format!("offset_ms {} is more than a minute", self.offset_ms));
+        }
+        if self.duration_ms.is_some_and(|d| d <= 0) {
+            errors.push("duration_ms must be positive".to_string());
+        }
+        if let Some(preview) = self.preview_start_ms {
+            if preview < 0 {
+                errors.push("preview_start_ms must not be negative".to_string());
+            } else if self.duration_ms.is_some_and(|d| preview >= d) {
+                errors.push("preview_start_ms is past the end of the song".to_string());
+            }
+        }
+        if self.bpm.is_some_and(|bpm| bpm <= 0.0 || bpm > 1000.0 || bpm.is_nan()) {
+            errors.push("bpm must be between 0 and 1000".to_string());
+        }
+
+        errors
+    }
+
+    /// Artists joined for display and for the `artist` column
+    pub fn artist_line(&self) -> Option<String> {
+        if self.artists.is_empty() {
+            None
+        } else {
+            Some(self.artists.join(", "))
+        }
+    }
+}
+
+/// Rewrite a metadata file with canonical keys, keeping unknown keys (e.g. `charter`).
+/// Returns false if the file already used the canonical schema.
+pub fn migrate_file(path: &Path) -> Result<bool> {
+    let content = std::fs::read_to_string(path)?;
+    let mut value: serde_json::Value = serde_json::from_str(&content)?;
+    let meta = SongMetadata::parse(&content)?;
+
+    let Some(object) = value.as_object_mut() else {
+        anyhow::bail!("{} is not a JSON object", path.display());
+    };
+    let before = object.clone();
+    for key in LEGACY_KEYS {
+        object.remove(*key);
+    }
+    if let serde_json::Value::Object(canonical) = serde_json::to_value(&meta)? {
+        object.extend(canonical);
+    }
+    if *object == before {
+        return Ok(false);
+    }
+
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_string_pretty(&value)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(true)
+}
+
+/// Accept either a single string or an array of strings
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+        Some(OneOrMany::One(s)) => vec![s],
+        Some(OneOrMany::Many(v)) => v,
+        None => Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_legacy_keys() {
+        let meta = SongMetadata::parse(
+            r#"{ "SongTitle": "Paradisus-Paradoxum", "Artists": ["MYTH & ROID"], "start_offset_Ms": 120 }"#,
+        )
+        .unwrap();
+        assert_eq!(meta.title.as_deref(), Some("Paradisus-Paradoxum"));
+        assert_eq!(meta.artists, vec!["MYTH & ROID"]);
+        assert_eq!(meta.offset_ms, 120);
+        assert!(meta.validate().is_empty());
+    }
+
+    #[test]
+    fn converts_legacy_seconds_to_milliseconds() {
+        let meta = SongMetadata::parse(r#"{ "Duration": 183.5, "offset": -0.25 }"#).unwrap();
+        assert_eq!(meta.duration_ms, Some(183_500));
+        assert_eq!(meta.offset_ms, -250);
+
+        // the millisecond keys win when a file has both
+        let meta = SongMetadata::parse(r#"{ "duration": 183.5, "duration_ms": 183000 }"#).unwrap();
+        assert_eq!(meta.duration_ms, Some(183_000));
+        assert!(SongMetadata::parse(r#"{ "duration": "3:03" }"#).is_err());
+    }
+
+    #[test]
+    fn accepts_single_artist_and_canonical_keys() {
+        let meta = SongMetadata::parse(
+            r#"{ "title": "Song", "artist": "Someone", "year": 2020, "bpm": 128.0, "tags": ["rock"], "charter": {} }"#,
+        )
+        .unwrap();
+        assert_eq!(meta.artists, vec!["Someone"]);
+        assert_eq!(meta.year, Some(2020));
+        assert_eq!(meta.tags, vec!["rock"]);
+    }
+
+    #[test]
+    fn reports_validation_errors() {
+        let meta = SongMetadata {
+            title: Some(" ".to_string()),
+            year: Some(1066),
+            duration_ms: Some(1000),
+            preview_start_ms: Some(5000),
+            bpm: Some(-1.0),
+            ..Default::default()
+        };
+        assert_eq!(meta.validate().len(), 4);
+        assert!(SongMetadata::parse(r#"{ "year": "last year" }"#).is_err());
+    }
+
+    #[test]
+    fn migrates_file_to_canonical_keys() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("song.json");
+        std::fs::write(&path, r#"{ "SongTitle": "Song", "Artists": ["A"], "start_offset_Ms": 0, "charter": { "bpm": 100.0 } }"#).unwrap();
+
+        assert!(migrate_file(&path).unwrap());
+        let value: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(value["title"], "Song");
+        assert_eq!(value["artists"][0], "A");
+        assert_eq!(value["charter"]["bpm"], 100.0);
+        assert!(value.get("SongTitle").is_none());
+
+        assert!(!migrate_file(&path).unwrap());
+    }
+}
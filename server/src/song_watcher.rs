@@ -1,133 +1,479 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use sqlx::SqlitePool;
-use std::path::Path;
-use std::time::Duration;
-use tokio::time::sleep;
-use crate::chart_gen;
-use crate::db;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
 
-pub async fn start_watcher(pool: SqlitePool) {
-    // run a quick scan at startup then schedule periodic scans
-    if let Err(e) = scan_once(&pool).await {
-        log::warn!("initial scan failed: {}", e);
+use crate::charts;
+use crate::db::{self, SongFiles};
+use crate::jobs::{self, JobQueue};
+use crate::song_meta::SongMetadata;
+use crate::transcode;
+
+/// Audio files that make a song, most preferred first when a song has several
+pub const AUDIO_EXTENSIONS: [&str; 4] = ["wav", "flac", "ogg", "mp3"];
+
+/// Quiet time after the last file event before the changes are applied
+const DEBOUNCE: Duration = Duration::from_secs(1);
+/// Longest a steady stream of events (a slow copy) can hold changes back
+const MAX_DEBOUNCE: Duration = Duration::from_secs(30);
+/// Full reconcile, catching anything the file events missed
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Full reconcile when file events are not available
+const POLL_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Generated charts with fewer notes than this are regenerated
+const MIN_CHART_NOTES: i64 = 8;
+
+/// Where songs and generated charts live (`SONGS_DIR`, `CHARTS_DIR`)
+#[derive(Debug, Clone)]
+pub struct SongDirs {
+    pub songs: PathBuf,
+    pub charts: PathBuf,
+}
+
+impl SongDirs {
+    pub fn from_env() -> Self {
+        Self {
+            songs: PathBuf::from(std::env::var("SONGS_DIR").unwrap_or_else(|_| "server/assets/songs".to_string())),
+            charts: PathBuf::from(std::env::var("CHARTS_DIR").unwrap_or_else(|_| "server/assets/charts".to_string())),
+        }
     }
 
-    loop {
-        sleep(Duration::from_secs(60 * 5)).await; // 5 minutes
-        if let Err(e) = scan_once(&pool).await {
-            log::warn!("periodic scan failed: {}", e);
+    /// Song a changed file belongs to: `{id}.{ext}` in the songs directory or
+    /// `{id}_{instrument}_{difficulty}.json` in the charts directory
+    fn song_id_for(&self, path: &Path) -> Option<String> {
+        let name = path.file_name()?.to_str()?;
+        if name.starts_with('.') {
+            return None;
+        }
+        let dir = path.parent()?;
+        if dir == self.songs {
+            return Some(Path::new(name).file_stem()?.to_str()?.to_string());
+        }
+        if dir == self.charts {
+            let base = name.strip_suffix(".chart.json").or_else(|| name.strip_suffix(".json"))?;
+            let mut parts = base.rsplitn(3, '_');
+            let (_, _, id) = (parts.next()?, parts.next()?, parts.next()?);
+            return Some(id.to_string());
         }
+        None
+    }
+}
+
+/// What a sync changed
+#[derive(Debug, Default, Serialize)]
+pub struct SyncReport {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    /// (old id, new id)
+    pub renamed: Vec<(String, String)>,
+    pub removed: Vec<String>,
+    pub jobs: Vec<db::Job>,
+}
+
+impl SyncReport {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.renamed.is_empty() && self.removed.is_empty() && self.jobs.is_empty()
     }
 }
 
-pub async fn scan_once(pool: &SqlitePool) -> Result<()> {
-    let songs_dir = std::env::var("SONGS_DIR").unwrap_or_else(|_| "server/assets/songs".to_string());
-    let charts_dir = std::env::var("CHARTS_DIR").unwrap_or_else(|_| "server/assets/charts".to_string());
+/// Sync once at startup, then apply file events as they settle, with a periodic full
+/// reconcile in case events were missed (or could not be watched at all)
+pub async fn start_watcher(pool: SqlitePool, jobs: JobQueue) {
+    let dirs = SongDirs::from_env();
+    for dir in [&dirs.songs, &dirs.charts] {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            log::warn!("cannot create {}: {}", dir.display(), e);
+        }
+    }
+    // events carry absolute paths
+    let dirs = SongDirs {
+        songs: dirs.songs.canonicalize().unwrap_or(dirs.songs),
+        charts: dirs.charts.canonicalize().unwrap_or(dirs.charts),
+    };
 
-    let mut entries = std::fs::read_dir(&songs_dir)?.filter_map(|r| r.ok()).collect::<Vec<_>>();
-    // process json metadata files first
-    entries.sort_by_key(|e| e.file_name());
+    log_sync("initial scan", sync_songs(&pool, &jobs, &dirs, None).await);
 
-    for e in entries {
-        let fname = e.file_name();
-        let name = fname.to_string_lossy().to_string();
-        if !name.ends_with(".json") {
-            continue;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let watcher = match watch(&dirs, tx) {
+        Ok(watcher) => {
+            log::info!("watching {} and {}", dirs.songs.display(), dirs.charts.display());
+            Some(watcher)
         }
-        let json_path = e.path();
-        let song_id = json_path.file_stem().and_then(|s| s.to_str()).unwrap_or("").to_string();
-        if song_id.is_empty() { continue; }
-
-        // get mtime
-        let _ = std::fs::metadata(&json_path)?;
-        // store simple timestamp for now
-        let mtime_ts = chrono::Utc::now().timestamp();
-
-        // read metadata
-        let content = std::fs::read_to_string(&json_path)?;
-        let parsed: serde_json::Value = serde_json::from_str(&content).unwrap_or(serde_json::json!({}));
-        let title = parsed.get("title").and_then(|v| v.as_str());
-        let artist = parsed.get("artist").and_then(|v| v.as_str());
-
-        // upsert to db
-        db::upsert_song(&pool, &song_id, &name, title, artist, mtime_ts).await?;
-
-        // ensure charts exist; generate if none or if existing charts look empty/too small
-        let mut have_chart = false;
-        let mut need_regen = false;
-        if let Ok(ch_entries) = std::fs::read_dir(&charts_dir) {
-            for ch in ch_entries.filter_map(|r| r.ok()) {
-                if let Some(fname) = ch.file_name().to_str() {
-                    if fname.starts_with(&song_id) {
-                        have_chart = true;
-                        // inspect for empty or tiny note arrays
-                        if let Ok(txt) = std::fs::read_to_string(ch.path()) {
-                            if let Ok(jsonv) = serde_json::from_str::<serde_json::Value>(&txt) {
-                                if let Some(notes) = jsonv.get("notes") {
-                                    if notes.is_array() {
-                                        let n = notes.as_array().unwrap().len();
-                                        if n == 0 || n < 8 {
-                                            log::info!("chart {} has {} notes; will regenerate", fname, n);
-                                            need_regen = true;
-                                            break;
-                                        }
-                                    } else {
-                                        need_regen = true;
-                                        break;
-                                    }
-                                } else {
-                                    need_regen = true;
-                                    break;
-                                }
-                            } else {
-                                need_regen = true;
-                                break;
-                            }
-                        } else {
-                            need_regen = true;
-                            break;
-                        }
-                    }
+        Err(e) => {
+            log::warn!("file events unavailable ({}), rescanning every {}s", e, POLL_INTERVAL.as_secs());
+            None
+        }
+    };
+    let period = if watcher.is_some() { RECONCILE_INTERVAL } else { POLL_INTERVAL };
+    let mut reconcile = tokio::time::interval_at(Instant::now() + period, period);
+
+    loop {
+        tokio::select! {
+            Some(path) = rx.recv() => {
+                let ids: HashSet<String> = settle(&mut rx, path).await.iter().filter_map(|p| dirs.song_id_for(p)).collect();
+                if !ids.is_empty() {
+                    log_sync("update", sync_songs(&pool, &jobs, &dirs, Some(&ids)).await);
                 }
             }
+            _ = reconcile.tick() => log_sync("reconcile", sync_songs(&pool, &jobs, &dirs, None).await),
         }
+    }
+}
 
-        if !have_chart || need_regen {
-            if need_regen {
-                log::info!("Detected empty/insufficient charts for {}; regenerating (force)", song_id);
+/// Full reconcile of the songs directory with the database, now
+pub async fn scan_once(pool: &SqlitePool, jobs: &JobQueue) -> Result<SyncReport> {
+    sync_songs(pool, jobs, &SongDirs::from_env(), None).await
+}
+
+fn log_sync(what: &str, result: Result<SyncReport>) {
+    match result {
+        Ok(report) if !report.is_empty() => log::info!(
+            "{}: {} added, {} changed, {} renamed, {} removed, {} jobs queued",
+            what,
+            report.added.len(),
+            report.changed.len(),
+            report.renamed.len(),
+            report.removed.len(),
+            report.jobs.len()
+        ),
+        Ok(_) => {}
+        Err(e) => log::warn!("{} failed: {:#}", what, e),
+    }
+}
+
+fn watch(dirs: &SongDirs, tx: mpsc::UnboundedSender<PathBuf>) -> notify::Result<notify::RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+        Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+            for path in event.paths {
+                let _ = tx.send(path);
             }
-            // prefer Rust HQ generator when RUST_HQ env var is true (default true)
-            let rust_hq = std::env::var("RUST_HQ").map(|v| v == "1" || v.to_lowercase() == "true").unwrap_or(true);
-            if rust_hq {
-                match tokio::task::spawn_blocking({
-                    let sid = song_id.clone();
-                    let charts_dir = charts_dir.clone();
-                    let wav = json_path.with_extension("wav");
-                    move || {
-                        crate::hq_rust::generate_hq_charts_rust(&sid, &wav, std::path::Path::new(&charts_dir), true)
-                    }
-                }).await {
-                    Ok(Ok(written)) => {
-                        let cnt = written.len();
-                        log::info!("Rust HQ generated {} charts for {}", cnt, song_id);
-                    }
-                    Ok(Err(e)) => {
-                        log::warn!("Rust HQ generation failed: {}, falling back to simple generator", e);
-                        let written = chart_gen::generate_charts_for_song(&song_id, Path::new(&songs_dir), Path::new(&charts_dir))?;
-                        log::info!("generated {} charts for {}", written.len(), song_id);
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("file watch error: {}", e),
+    })?;
+    watcher.watch(&dirs.songs, RecursiveMode::NonRecursive)?;
+    watcher.watch(&dirs.charts, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
+
+/// Collect paths until events stop for `DEBOUNCE`, or for at most `MAX_DEBOUNCE`
+async fn settle(rx: &mut mpsc::UnboundedReceiver<PathBuf>, first: PathBuf) -> HashSet<PathBuf> {
+    let mut paths = HashSet::from([first]);
+    let deadline = Instant::now() + MAX_DEBOUNCE;
+    while let Ok(Some(path)) = tokio::time::timeout_at(deadline.min(Instant::now() + DEBOUNCE), rx.recv()).await {
+        paths.insert(path);
+    }
+    paths
+}
+
+/// A song's files as they are on disk
+struct ScannedSong {
+    files: SongFiles,
+    meta_path: Option<PathBuf>,
+}
+
+/// Bring the database in line with the songs directory: register new songs, update
+/// changed ones, follow renames (matched by audio hash) and forget deleted ones, then
+/// re-index charts and queue generation where charts are missing or too small, and
+/// transcoding where streaming variants are missing.
+/// With `only`, just those song ids are looked at.
+pub async fn sync_songs(pool: &SqlitePool, jobs: &JobQueue, dirs: &SongDirs, only: Option<&HashSet<String>>) -> Result<SyncReport> {
+    let mut known = db::song_files(pool).await?;
+    if let Some(only) = only {
+        known.retain(|id, _| only.contains(id));
+    }
+    let scanned = {
+        let (songs_dir, known, only) = (dirs.songs.clone(), known.clone(), only.cloned());
+        tokio::task::spawn_blocking(move || scan_songs(&songs_dir, only.as_ref(), &known))
+            .await
+            .context("song scan task failed")??
+    };
+
+    let mut report = SyncReport::default();
+    let mut gone: Vec<String> = known.keys().filter(|id| !scanned.contains_key(*id)).cloned().collect();
+    gone.sort();
+
+    for (id, song) in &scanned {
+        let previous = known.get(id);
+        if previous != Some(&song.files) {
+            let meta = song.meta_path.as_deref().map(load_meta).unwrap_or_default();
+            for problem in meta.validate() {
+                log::warn!("metadata for {}: {}", id, problem);
+            }
+            match previous {
+                Some(previous) => {
+                    report.changed.push(id.clone());
+                    // new audio under the same name: the old charts are for a different song
+                    if previous.audio_hash.is_some() && previous.audio_hash != song.files.audio_hash {
+                        log::info!("audio of {} changed; regenerating charts", id);
+                        report.jobs.push(jobs.enqueue(jobs::GENERATE_CHARTS, id, true, None).await?);
+                        if transcode::enabled() {
+                            report.jobs.push(jobs.enqueue(jobs::TRANSCODE, id, false, None).await?);
+                        }
                     }
-                    Err(e) => {
-                        log::warn!("Rust HQ task join failed: {}, falling back", e);
-                        let written = chart_gen::generate_charts_for_song(&song_id, Path::new(&songs_dir), Path::new(&charts_dir))?;
-                        log::info!("generated {} charts for {}", written.len(), song_id);
+                }
+                None => {
+                    // new id with the audio of a song that just disappeared: it was renamed
+                    let renamed_from = gone
+                        .iter()
+                        .position(|old| known[old].audio_hash.is_some() && known[old].audio_hash == song.files.audio_hash)
+                        .map(|i| gone.remove(i));
+                    match renamed_from {
+                        Some(old) => {
+                            log::info!("song {} renamed to {}", old, id);
+                            db::rename_song(pool, &old, id).await?;
+                            if let Err(e) = charts::rename_song_files(&dirs.charts, &old, id) {
+                                log::warn!("{:#}", e);
+                            }
+                            report.renamed.push((old, id.clone()));
+                        }
+                        None => report.added.push(id.clone()),
                     }
                 }
-            } else {
-                let written = chart_gen::generate_charts_for_song(&song_id, Path::new(&songs_dir), Path::new(&charts_dir))?;
-                log::info!("generated {} charts for {}", written.len(), song_id);
             }
+            db::upsert_song(pool, id, &song.files.filename, &meta, song.files.mtime).await?;
+            db::set_song_files(pool, id, &song.files).await?;
+        }
+
+        charts::reindex_song(pool, &dirs.charts, id).await?;
+        if song.files.filename.ends_with(".wav") && needs_charts(pool, id).await? {
+            report.jobs.push(jobs.enqueue(jobs::GENERATE_CHARTS, id, true, None).await?);
         }
+        if let Some(hash) = song.files.audio_hash.as_deref()
+            && transcode::enabled()
+            && needs_variants(pool, id, hash).await?
+        {
+            report.jobs.push(jobs.enqueue(jobs::TRANSCODE, id, false, None).await?);
+        }
+    }
+
+    for id in gone {
+        log::info!("song {} is gone from disk", id);
+        transcode::remove_variants(pool, &dirs.songs, &id).await?;
+        db::delete_song(pool, &id).await?;
+        report.removed.push(id);
+    }
+    Ok(report)
+}
+
+/// Whether the song's charts are missing or too small and nothing has been tried yet.
+/// After a finished generation only missing charts count, so a song that only yields
+/// sparse charts is not regenerated forever; failed or cancelled jobs are left for an
+/// admin to retry.
+async fn needs_charts(pool: &SqlitePool, id: &str) -> Result<bool> {
+    let indexed = db::song_charts(pool, id).await?;
+    let sparse = indexed.iter().any(|c| c.note_count < MIN_CHART_NOTES);
+    let latest = db::latest_job(pool, jobs::GENERATE_CHARTS, id).await?;
+    Ok(match latest.as_ref().map(|job| job.status.as_str()) {
+        None => indexed.is_empty() || sparse,
+        Some("done") => indexed.is_empty(),
+        _ => false,
+    })
+}
+
+/// Whether streaming variants of the current audio are missing. As with charts, a failed
+/// or cancelled job is not retried on its own.
+async fn needs_variants(pool: &SqlitePool, id: &str, audio_hash: &str) -> Result<bool> {
+    let latest = db::latest_job(pool, jobs::TRANSCODE, id).await?;
+    if !matches!(latest.as_ref().map(|job| job.status.as_str()), None | Some("done")) {
+        return Ok(false);
+    }
+    Ok(!transcode::missing_variants(pool, id, audio_hash).await?.is_empty())
+}
+
+/// Metadata for a song; a broken file still registers the song, just without metadata
+fn load_meta(path: &Path) -> SongMetadata {
+    SongMetadata::load(path).unwrap_or_else(|e| {
+        log::warn!("{:#}", e);
+        SongMetadata::default()
+    })
+}
+
+/// Songs in `songs_dir` by id, a song being an audio file with an optional `{id}.json`
+/// beside it. Audio hashes in `known` are reused while name, size and mtime are unchanged.
+fn scan_songs(songs_dir: &Path, only: Option<&HashSet<String>>, known: &HashMap<String, SongFiles>) -> Result<BTreeMap<String, ScannedSong>> {
+    let mut audio: BTreeMap<String, (usize, PathBuf)> = BTreeMap::new();
+    let mut metas: HashMap<String, PathBuf> = HashMap::new();
+    let entries = std::fs::read_dir(songs_dir).with_context(|| format!("reading {}", songs_dir.display()))?;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let (Some(id), Some(ext)) = (path.file_stem().and_then(|s| s.to_str()), path.extension().and_then(|e| e.to_str())) else {
+            continue;
+        };
+        if id.is_empty() || id.starts_with('.') || only.is_some_and(|only| !only.contains(id)) {
+            continue;
+        }
+        let ext = ext.to_ascii_lowercase();
+        if ext == "json" {
+            metas.insert(id.to_string(), path);
+        } else if let Some(rank) = AUDIO_EXTENSIONS.iter().position(|e| *e == ext)
+            && audio.get(id).is_none_or(|(best, _)| rank < *best)
+        {
+            audio.insert(id.to_string(), (rank, path));
+        }
+    }
+
+    let mut songs = BTreeMap::new();
+    for (id, (_, path)) in audio {
+        // files can vanish mid-scan; whatever is left is picked up by the next event
+        match scan_song(&path, metas.remove(&id), known.get(&id)) {
+            Ok(song) => {
+                songs.insert(id, song);
+            }
+            Err(e) => log::warn!("skipping song {}: {:#}", id, e),
+        }
+    }
+    Ok(songs)
+}
+
+fn scan_song(audio: &Path, meta_path: Option<PathBuf>, previous: Option<&SongFiles>) -> Result<ScannedSong> {
+    let filename = audio.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string();
+    let metadata = std::fs::metadata(audio).with_context(|| format!("reading {}", audio.display()))?;
+    let (mtime, audio_size) = (mtime_secs(&metadata), metadata.len() as i64);
+    let audio_hash = match previous {
+        Some(p) if p.filename == filename && p.mtime == mtime && p.audio_size == audio_size && p.audio_hash.is_some() => p.audio_hash.clone(),
+        _ => Some(hash_file(audio)?),
+    };
+
+    let (mut meta_mtime, mut meta_hash) = (None, None);
+    if let Some(meta) = &meta_path {
+        // metadata is small enough to hash every time, which also catches edits within
+        // the same second as the last scan
+        meta_mtime = Some(mtime_secs(&std::fs::metadata(meta).with_context(|| format!("reading {}", meta.display()))?));
+        meta_hash = Some(hash_file(meta)?);
     }
 
-    Ok(())
+    Ok(ScannedSong {
+        files: SongFiles { filename, mtime, audio_size, audio_hash, meta_mtime, meta_hash },
+        meta_path,
+    })
+}
+
+pub(crate) fn mtime_secs(metadata: &std::fs::Metadata) -> i64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Hex SHA-256 of a file's contents
+pub(crate) fn hash_file(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).with_context(|| format!("hashing {}", path.display()))?;
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::memory_pool;
+    use tempfile::TempDir;
+
+    async fn setup() -> (SqlitePool, JobQueue, TempDir, SongDirs) {
+        let pool = memory_pool().await;
+        let tmp = TempDir::new().unwrap();
+        let dirs = SongDirs { songs: tmp.path().join("songs"), charts: tmp.path().join("charts") };
+        std::fs::create_dir_all(&dirs.songs).unwrap();
+        std::fs::create_dir_all(&dirs.charts).unwrap();
+        (pool.clone(), JobQueue::new(pool), tmp, dirs)
+    }
+
+    fn kinds(report: &SyncReport) -> Vec<&str> {
+        report.jobs.iter().map(|job| job.kind.as_str()).collect()
+    }
+
+    #[actix_rt::test]
+    async fn follows_added_changed_renamed_and_deleted_songs() {
+        let (pool, jobs, _tmp, dirs) = setup().await;
+        std::fs::write(dirs.songs.join("song.wav"), b"RIFF one").unwrap();
+        std::fs::write(dirs.songs.join("song.json"), r#"{"title":"One"}"#).unwrap();
+        std::fs::write(dirs.songs.join(".upload-1.part"), b"partial").unwrap();
+
+        let report = sync_songs(&pool, &jobs, &dirs, None).await.unwrap();
+        assert_eq!(report.added, ["song"]);
+        assert_eq!(kinds(&report), [jobs::GENERATE_CHARTS, jobs::TRANSCODE], "a new song gets charts and variants");
+        let files = db::song_files(&pool).await.unwrap();
+        assert_eq!(files["song"].filename, "song.wav");
+        assert_eq!(files["song"].audio_size, 8);
+        assert!(files["song"].audio_hash.is_some() && files["song"].meta_hash.is_some());
+
+        // nothing changed: nothing to do, and the queued job is not duplicated
+        let report = sync_songs(&pool, &jobs, &dirs, None).await.unwrap();
+        assert!(report.is_empty(), "{:?}", report);
+
+        std::fs::write(dirs.songs.join("song.json"), r#"{"title":"Uno"}"#).unwrap();
+        let report = sync_songs(&pool, &jobs, &dirs, Some(&HashSet::from(["song".to_string()]))).await.unwrap();
+        assert_eq!(report.changed, ["song"]);
+        let title: Option<String> = sqlx::query_scalar("SELECT title FROM songs WHERE id = 'song'").fetch_one(&pool).await.unwrap();
+        assert_eq!(title.as_deref(), Some("Uno"));
+
+        sqlx::query("INSERT INTO scores (song_id, player, score, timestamp, online) VALUES ('song', 'alice', 100, 1, 1)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        std::fs::write(dirs.charts.join("song_drums_Hard.json"), r#"{"notes":[{"time":1.0,"col":0}]}"#).unwrap();
+        std::fs::rename(dirs.songs.join("song.wav"), dirs.songs.join("renamed.wav")).unwrap();
+        std::fs::rename(dirs.songs.join("song.json"), dirs.songs.join("renamed.json")).unwrap();
+        let report = sync_songs(&pool, &jobs, &dirs, None).await.unwrap();
+        assert_eq!(report.renamed, [("song".to_string(), "renamed".to_string())]);
+        assert!(report.added.is_empty() && report.removed.is_empty());
+        assert!(dirs.charts.join("renamed_drums_Hard.json").exists());
+        let indexed = db::song_charts(&pool, "renamed").await.unwrap();
+        assert_eq!(indexed.iter().map(|c| c.path.as_str()).collect::<Vec<_>>(), ["renamed_drums_Hard.json"]);
+        for table in ["scores", "jobs"] {
+            let song: String = sqlx::query_scalar(&format!("SELECT song_id FROM {}", table)).fetch_one(&pool).await.unwrap();
+            assert_eq!(song, "renamed", "{} follow the rename", table);
+        }
+
+        std::fs::remove_file(dirs.songs.join("renamed.wav")).unwrap();
+        let report = sync_songs(&pool, &jobs, &dirs, None).await.unwrap();
+        assert_eq!(report.removed, ["renamed"]);
+        assert!(db::song_files(&pool).await.unwrap().is_empty());
+        assert!(db::song_charts(&pool, "renamed").await.unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn new_audio_regenerates_charts_once() {
+        let (pool, jobs, _tmp, dirs) = setup().await;
+        std::fs::write(dirs.songs.join("song.wav"), b"RIFF one").unwrap();
+        let chart: String = format!(r#"{{"notes":[{}]}}"#, (0..8).map(|i| format!(r#"{{"time":{}.0,"col":0}}"#, i)).collect::<Vec<_>>().join(","));
+        std::fs::write(dirs.charts.join("song_drums_Hard.json"), chart).unwrap();
+        let report = sync_songs(&pool, &jobs, &dirs, None).await.unwrap();
+        assert_eq!(kinds(&report), [jobs::TRANSCODE], "full charts need no generation");
+
+        std::fs::write(dirs.songs.join("song.wav"), b"RIFF two, longer").unwrap();
+        let report = sync_songs(&pool, &jobs, &dirs, None).await.unwrap();
+        assert_eq!(report.changed, ["song"]);
+        assert_eq!(kinds(&report), [jobs::GENERATE_CHARTS, jobs::TRANSCODE]);
+        assert!(report.jobs[0].force);
+
+        // once generation is done, sparse charts it produced are left alone; nor is a
+        // failed transcode retried until the audio changes again
+        while let Some(job) = db::claim_job(&pool, 1).await.unwrap() {
+            let outcome = if job.kind == jobs::GENERATE_CHARTS { Ok("generated") } else { Err("not audio") };
+            db::finish_job(&pool, job.id, outcome, 2).await.unwrap();
+        }
+        std::fs::write(dirs.charts.join("song_drums_Hard.json"), r#"{"notes":[{"time":1.0,"col":0}]}"#).unwrap();
+        assert!(sync_songs(&pool, &jobs, &dirs, None).await.unwrap().jobs.is_empty());
+    }
+
+    #[test]
+    fn maps_changed_files_to_songs() {
+        let dirs = SongDirs { songs: PathBuf::from("/s"), charts: PathBuf::from("/c") };
+        assert_eq!(dirs.song_id_for(Path::new("/s/my_song.wav")).as_deref(), Some("my_song"));
+        assert_eq!(dirs.song_id_for(Path::new("/c/my_song_drums_Hard.json")).as_deref(), Some("my_song"));
+        assert_eq!(dirs.song_id_for(Path::new("/c/my_song_drums_Hard.chart.json")).as_deref(), Some("my_song"));
+        assert_eq!(dirs.song_id_for(Path::new("/s/.upload-3.part")), None);
+        assert_eq!(dirs.song_id_for(Path::new("/elsewhere/x.wav")), None);
+    }
 }
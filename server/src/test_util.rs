@@ -0,0 +1,26 @@
+//! Fixtures shared by the unit and integration tests
+
+use std::path::Path;
+
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqlitePoolOptions;
+
+/// An in-memory database with the schema in place
+pub async fn memory_pool() -> SqlitePool {
+    // one connection, or every connection would get its own empty in-memory database
+    let pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.expect("connect");
+    crate::db::init_db(&pool).await.expect("init db");
+    pool
+}
+
+/// Write a 16-bit WAV of `frames` sample frames; `sample(frame, channel)` gives each sample
+pub fn write_wav(path: &Path, channels: u16, sample_rate: u32, frames: u32, mut sample: impl FnMut(u32, u16) -> i16) {
+    let spec = hound::WavSpec { channels, sample_rate, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+    let mut wav = hound::WavWriter::create(path, spec).unwrap();
+    for frame in 0..frames {
+        for channel in 0..channels {
+            wav.write_sample(sample(frame, channel)).unwrap();
+        }
+    }
+    wav.finalize().unwrap();
+}
@@ -1,74 +1,1052 @@
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use actix_web::{test, web, App};
-    use sqlx::SqlitePool;
-    use tempfile::TempDir;
-
-    // helper to construct app with a given pool
-    async fn make_app(pool: SqlitePool) -> actix_web::App<impl actix_web::dev::HttpServiceFactory> {
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App};
+use sqlx::SqlitePool;
+use tempfile::TempDir;
+
+use crate::jobs::JobQueue;
+use crate::replays::ReplayConfig;
+use crate::test_util::{memory_pool, write_wav};
+
+// helper to construct app with a given pool
+async fn make_app(
+    pool: SqlitePool,
+) -> impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error> {
+    make_app_with(pool, ReplayConfig::default()).await
+}
+
+async fn make_app_with(
+    pool: SqlitePool,
+    replay_config: ReplayConfig,
+) -> impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error> {
+    test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(replay_config))
+            .app_data(web::Data::new(JobQueue::new(pool.clone())))
+            .configure(crate::routes::configure),
+    )
+    .await
+}
+
+/// Register `username` and return an access token
+async fn register_and_login(
+    app: &impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>,
+    username: &str,
+) -> String {
+    let creds = serde_json::json!({"username": username, "password": "pass123"});
+    let req = test::TestRequest::post().uri("/api/register").set_json(&creds).to_request();
+    assert_eq!(test::call_service(app, req).await.status(), StatusCode::CREATED);
+
+    let req = test::TestRequest::post().uri("/api/login").set_json(&creds).to_request();
+    let resp = test::call_and_read_body_json::<_, _, serde_json::Value>(app, req).await;
+    resp["access_token"].as_str().expect("access_token").to_string()
+}
+
+/// Register `filename` in `songs_dir` as `id` with the mtime, size and hash the watcher
+/// would record
+async fn watched_song(pool: &SqlitePool, songs_dir: &std::path::Path, id: &str, filename: &str) {
+    let path = songs_dir.join(filename);
+    let metadata = std::fs::metadata(&path).unwrap();
+    let mtime = crate::song_watcher::mtime_secs(&metadata);
+    crate::db::upsert_song(pool, id, filename, &Default::default(), mtime).await.unwrap();
+    let files = crate::db::SongFiles {
+        filename: filename.to_string(),
+        mtime,
+        audio_size: metadata.len() as i64,
+        audio_hash: Some(crate::song_watcher::hash_file(&path).unwrap()),
+        meta_mtime: None,
+        meta_hash: None,
+    };
+    crate::db::set_song_files(pool, id, &files).await.unwrap();
+}
+
+/// Queue a transcode of the song and wait for it; returns how the job ended
+async fn transcoded(pool: &SqlitePool, queue: &JobQueue, song_id: &str) -> String {
+    finished_job(pool, queue, crate::jobs::TRANSCODE, song_id, false).await
+}
+
+/// Queue a job and wait for it; returns how it ended
+async fn finished_job(pool: &SqlitePool, queue: &JobQueue, kind: &str, song_id: &str, force: bool) -> String {
+    let job = queue.enqueue(kind, song_id, force, None).await.unwrap();
+    let mut status = String::new();
+    for _ in 0..300 {
+        status = crate::db::get_job(pool, job.id).await.unwrap().unwrap().status;
+        if status != "queued" && status != "running" {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    status
+}
+
+fn bearer(token: &str) -> (&'static str, String) {
+    ("Authorization", format!("Bearer {}", token))
+}
+
+/// Held by tests that point SONGS_DIR or CHARTS_DIR at their own directories
+static SONGS_ENV: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// `multipart/form-data` body from (field name, file name, content) parts
+fn multipart(parts: &[(&str, Option<&str>, &[u8])]) -> (String, Vec<u8>) {
+    let boundary = "rhythm-pi-test-boundary";
+    let mut body = Vec::new();
+    for (name, filename, content) in parts {
+        body.extend_from_slice(format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"", boundary, name).as_bytes());
+        if let Some(filename) = filename {
+            body.extend_from_slice(format!("; filename=\"{}\"", filename).as_bytes());
+        }
+        body.extend_from_slice(b"\r\n\r\n");
+        body.extend_from_slice(content);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
+
+/// Serve the app on a free local port, for tests that need a real socket
+fn serve(pool: SqlitePool) -> (std::net::SocketAddr, actix_web::dev::ServerHandle) {
+    let queue = JobQueue::new(pool.clone());
+    let server = actix_web::HttpServer::new(move || {
         App::new()
-            .app_data(actix_web::web::Data::new(pool))
-            .service(
-                actix_web::web::scope("/api")
-                    .route("/register", actix_web::web::post().to(crate::handlers::register_user))
-                    .route("/login", actix_web::web::post().to(crate::handlers::login_user))
-                    .route("/scores", actix_web::web::post().to(crate::handlers::post_score))
-                    .route("/leaderboard/{song_id}", actix_web::web::get().to(crate::handlers::get_leaderboard))
-                    .route("/songs", actix_web::web::get().to(crate::handlers::list_songs))
-                    .route("/songs/{id}/stream", actix_web::web::get().to(crate::handlers::stream_song))
-            )
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(ReplayConfig::default()))
+            .app_data(web::Data::new(queue.clone()))
+            .configure(crate::routes::configure)
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(("127.0.0.1", 0))
+    .expect("bind");
+    let addr = server.addrs()[0];
+    let server = server.run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+    (addr, handle)
+}
+
+/// Four seconds of 44.1 kHz mono 16-bit tone: 16 KiB socket frames hold about 0.19 s
+fn write_tone(path: &std::path::Path) {
+    write_wav(path, 1, 44100, 44100 * 4, |i, _| ((i as f32 * 0.05).sin() * 8000.0) as i16);
+}
+
+type AudioSocket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Something the audio socket sent
+#[derive(Debug)]
+enum Sent {
+    Message(serde_json::Value),
+    /// Sequence number and the rest of a binary frame
+    Frame(u32, Vec<u8>),
+    Closed,
+}
+
+/// Open the audio socket of `song_id` and read its `meta` message
+async fn audio_socket(addr: std::net::SocketAddr, song_id: &str) -> (AudioSocket, serde_json::Value) {
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws/audio/{}?quality=original", addr, song_id)).await.expect("connect");
+    let Some(Sent::Message(meta)) = drain(&mut ws).await.into_iter().next() else {
+        panic!("no meta message");
+    };
+    assert_eq!(meta["type"], "meta");
+    (ws, meta)
+}
+
+async fn command(ws: &mut AudioSocket, mut command: serde_json::Value) {
+    use futures::SinkExt;
+    command["v"] = 1.into();
+    ws.send(tokio_tungstenite::tungstenite::Message::Text(command.to_string())).await.expect("send");
+}
+
+/// What the socket sends until it goes quiet or closes
+async fn drain(ws: &mut AudioSocket) -> Vec<Sent> {
+    use futures::StreamExt;
+    use tokio_tungstenite::tungstenite::Message;
+    let mut sent = Vec::new();
+    while let Ok(message) = tokio::time::timeout(std::time::Duration::from_millis(250), ws.next()).await {
+        match message {
+            Some(Ok(Message::Text(text))) => sent.push(Sent::Message(serde_json::from_str(&text).expect("json"))),
+            Some(Ok(Message::Binary(data))) => {
+                sent.push(Sent::Frame(u32::from_be_bytes(data[..4].try_into().unwrap()), data[4..].to_vec()));
+            }
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                sent.push(Sent::Closed);
+                break;
+            }
+            Some(Ok(_)) => {}
+        }
+    }
+    sent
+}
+
+fn frames(sent: &[Sent]) -> Vec<(u32, &[u8])> {
+    sent.iter().filter_map(|s| if let Sent::Frame(seq, data) = s { Some((*seq, data.as_slice())) } else { None }).collect()
+}
+
+fn messages<'a>(sent: &'a [Sent], kind: &str) -> Vec<&'a serde_json::Value> {
+    sent.iter().filter_map(|s| if let Sent::Message(m) = s { Some(m) } else { None }).filter(|m| m["type"] == kind).collect()
+}
+
+/// Acknowledge frames as they come until the stream goes quiet; everything sent meanwhile
+async fn acknowledge_all(ws: &mut AudioSocket, mut sent: Vec<Sent>) -> Vec<Sent> {
+    let mut acked = None;
+    while let Some(&(last, _)) = frames(&sent).last().filter(|f| Some(f.0) != acked) {
+        command(ws, serde_json::json!({"type": "ack", "seq": last})).await;
+        acked = Some(last);
+        sent.extend(drain(ws).await);
+    }
+    sent
+}
+
+/// Handles this process has open on `path`, to see that a stream's reader is gone.
+/// Always 0 where there is no `/proc`.
+fn open_handles(path: &std::path::Path) -> usize {
+    let path = path.canonicalize().unwrap();
+    let Ok(fds) = std::fs::read_dir("/proc/self/fd") else {
+        return 0;
+    };
+    fds.flatten().filter(|fd| std::fs::read_link(fd.path()).is_ok_and(|p| p == path)).count()
+}
+
+async fn handles_closed(path: &std::path::Path) -> bool {
+    for _ in 0..40 {
+        if open_handles(path) == 0 {
+            return true;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
     }
+    false
+}
+
+#[actix_rt::test]
+async fn register_login_and_score_flow() {
+    let pool = memory_pool().await;
+    let app = make_app(pool.clone()).await;
+
+    // register
+    let reg = serde_json::json!({"username":"alice","password":"pass123"});
+    let req = test::TestRequest::post().uri("/api/register").set_json(&reg).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    // login
+    let req = test::TestRequest::post().uri("/api/login").set_json(&reg).to_request();
+    let resp = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, req).await;
+    let token = resp.get("token").expect("token").as_str().expect("str");
+
+    // post online score; sent without a replay, it is ranked unchecked
+    let score = serde_json::json!({"song_id":"song1","player":"ignored","score":9000,"online":true});
+    let req = test::TestRequest::post().uri("/api/scores").insert_header(bearer(token)).set_json(&score).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["status"], "unverified");
+
+    // offline scores are kept without judging their replay, so need no chart
+    let offline = serde_json::json!({"song_id":"song1","player":"bob","score":100,"online":false,
+        "instrument":"drums","difficulty":"Hard","chart_hash":"none","replay":{"inputs":[[0,1.0,1.1]]}});
+    let req = test::TestRequest::post().uri("/api/scores").set_json(&offline).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+
+    // online scores without a valid token are refused
+    let req = test::TestRequest::post().uri("/api/scores").set_json(&score).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    let req = test::TestRequest::post().uri("/api/scores").insert_header(bearer("garbage")).set_json(&score).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    // get leaderboard
+    let req = test::TestRequest::get().uri("/api/leaderboard/song1").to_request();
+    let body = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, req).await;
+    assert!(!body.as_array().unwrap().is_empty());
+    assert_eq!(body[0]["player"], "alice");
+}
+
+#[actix_rt::test]
+async fn list_and_stream_songs() {
+    let pool = memory_pool().await;
+
+    // create temp songs dir
+    let tmp = TempDir::new().expect("tempdir");
+    let song_path = tmp.path().join("foo.wav");
+    std::fs::write(&song_path, b"RIFF....").expect("write");
+    let _env = SONGS_ENV.lock().await;
+    // SAFETY: tests touching SONGS_DIR hold SONGS_ENV
+    unsafe { std::env::set_var("SONGS_DIR", tmp.path().to_str().expect("str")) };
+
+    let app = make_app(pool.clone()).await;
+
+    let req = test::TestRequest::get().uri("/api/songs").to_request();
+    let list = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, req).await;
+    assert!(list.as_array().unwrap().iter().any(|v| v.get("filename").unwrap().as_str().unwrap().contains("foo.wav")));
+
+    let req = test::TestRequest::get().uri("/api/songs/foo/stream").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn admin_routes_require_admin_role() {
+    let pool = memory_pool().await;
+    let app = make_app(pool.clone()).await;
+    let alice = register_and_login(&app, "alice").await;
+    let bob = register_and_login(&app, "bob").await;
+    let set_role = |token: &str, username: &str, role: &str| {
+        test::TestRequest::put()
+            .uri(&format!("/api/admin/users/{}/role", username))
+            .insert_header(bearer(token))
+            .set_json(serde_json::json!({ "role": role }))
+            .to_request()
+    };
+
+    // anonymous and player requests never reach the handlers
+    let req = test::TestRequest::post().uri("/api/admin/scan").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    let req = test::TestRequest::post().uri("/api/admin/scan").insert_header(bearer(&alice)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+    assert_eq!(test::call_service(&app, set_role(&alice, "alice", "admin")).await.status(), StatusCode::FORBIDDEN);
+
+    // the role is looked up per request, so alice's existing token now works
+    assert!(crate::db::set_user_role(&pool, "alice", "admin").await.unwrap());
+    let resp = test::call_service(&app, set_role(&alice, "bob", "Moderator")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["role"], "moderator");
+
+    // moderators are not admins
+    assert_eq!(test::call_service(&app, set_role(&bob, "bob", "admin")).await.status(), StatusCode::FORBIDDEN);
+
+    assert_eq!(test::call_service(&app, set_role(&alice, "bob", "root")).await.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(test::call_service(&app, set_role(&alice, "nobody", "admin")).await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(test::call_service(&app, set_role(&alice, "alice", "player")).await.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(test::call_service(&app, set_role(&alice, "bob", "player")).await.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn profiles_and_avatars() {
+    let pool = memory_pool().await;
+    let tmp = TempDir::new().expect("tempdir");
+    // SAFETY: no other test reads or writes AVATARS_DIR
+    unsafe { std::env::set_var("AVATARS_DIR", tmp.path().to_str().expect("str")) };
+    let app = make_app(pool.clone()).await;
+    let alice = register_and_login(&app, "alice").await;
+
+    let score = serde_json::json!({"song_id":"song1","player":"ignored","score":500,"online":true,"instrument":"drums"});
+    let req = test::TestRequest::post().uri("/api/scores").insert_header(bearer(&alice)).set_json(&score).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get().uri("/api/me").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    let req = test::TestRequest::get().uri("/api/me").insert_header(bearer(&alice)).to_request();
+    let me = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, req).await;
+    assert_eq!(me["username"], "alice");
+    assert_eq!(me["role"], "player");
+    assert!(me["avatar_url"].is_null());
+    assert_eq!(me["stats"]["total_plays"], 1);
+    assert_eq!(me["stats"]["favourite_instrument"], "drums");
+
+    let update = |body: serde_json::Value| {
+        test::TestRequest::patch().uri("/api/me").insert_header(bearer(&alice)).set_json(body).to_request()
+    };
+    let resp = test::call_service(&app, update(serde_json::json!({"display_name": " Alice ", "country": "nz"}))).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!((body["display_name"].as_str(), body["country"].as_str()), (Some("Alice"), Some("NZ")));
+    assert_eq!(test::call_service(&app, update(serde_json::json!({"country": "NZL"}))).await.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(test::call_service(&app, update(serde_json::json!({"display_name": "a".repeat(33)}))).await.status(), StatusCode::BAD_REQUEST);
+
+    // the display name now shows on the leaderboard
+    let req = test::TestRequest::get().uri("/api/leaderboard/song1").to_request();
+    let board = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, req).await;
+    assert_eq!(board[0]["display_name"], "Alice");
+
+    let avatar = |body: &'static [u8]| {
+        test::TestRequest::put().uri("/api/me/avatar").insert_header(bearer(&alice)).set_payload(body).to_request()
+    };
+    assert_eq!(test::call_service(&app, avatar(b"<svg/>")).await.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(test::call_service(&app, avatar(b"\x89PNG\r\n\x1a\n...")).await.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get().uri("/api/users/alice").to_request();
+    let profile = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, req).await;
+    assert_eq!(profile["avatar_url"], "/api/users/alice/avatar");
+    let req = test::TestRequest::get().uri("/api/users/alice/avatar").to_request();
+    assert_eq!(test::call_and_read_body(&app, req).await.as_ref(), b"\x89PNG\r\n\x1a\n...");
+
+    let req = test::TestRequest::delete().uri("/api/me/avatar").insert_header(bearer(&alice)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+    let req = test::TestRequest::get().uri("/api/users/alice/avatar").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    let req = test::TestRequest::get().uri("/api/users/nobody").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+}
 
-    #[actix_rt::test]
-    async fn register_login_and_score_flow() {
-        let pool = SqlitePool::connect("sqlite::memory:").await.expect("connect");
-        crate::db::init_db(&pool).await.expect("init db");
-
-        let app = test::init_service(make_app(pool.clone()).await).await;
-
-        // register
-        let reg = serde_json::json!({"username":"alice","password":"pass123"});
-        let req = test::TestRequest::post().uri("/api/register").set_json(&reg).to_request();
-        let resp = test::call_service(&app, req).await;
-        assert_eq!(resp.status(), actix_web::http::StatusCode::CREATED);
-
-        // login
-        let req = test::TestRequest::post().uri("/api/login").set_json(&reg).to_request();
-        let resp = test::call_and_read_body_json::<_, serde_json::Value>(&app, req).await;
-        let token = resp.get("token").expect("token").as_str().expect("str");
-
-        // post online score
-        let score = serde_json::json!({"song_id":"song1","player":"ignored","score":9000,"online":true});
-        let req = test::TestRequest::post().uri("/api/scores").insert_header(("Authorization", format!("Bearer {}", token))).set_json(&score).to_request();
-        let resp = test::call_service(&app, req).await;
-        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
-
-        // get leaderboard
-        let req = test::TestRequest::get().uri("/api/leaderboard/song1").to_request();
-        let body = test::call_and_read_body_json::<_, serde_json::Value>(&app, req).await;
-        assert!(body.as_array().unwrap().len() >= 1);
+#[actix_rt::test]
+async fn upload_songs() {
+    let pool = memory_pool().await;
+    let (songs, charts) = (TempDir::new().expect("tempdir"), TempDir::new().expect("tempdir"));
+    let _env = SONGS_ENV.lock().await;
+    // SAFETY: tests touching SONGS_DIR hold SONGS_ENV
+    unsafe {
+        std::env::set_var("SONGS_DIR", songs.path().to_str().expect("str"));
+        std::env::set_var("CHARTS_DIR", charts.path().to_str().expect("str"));
     }
+    let app = make_app(pool.clone()).await;
+    let alice = register_and_login(&app, "alice").await;
+    let upload = |parts: &[(&str, Option<&str>, &[u8])], token: Option<&str>| {
+        let (content_type, body) = multipart(parts);
+        let mut req = test::TestRequest::post().uri("/api/songs").insert_header(("Content-Type", content_type));
+        if let Some(token) = token {
+            req = req.insert_header(bearer(token));
+        }
+        req.set_payload(body).to_request()
+    };
 
-    #[actix_rt::test]
-    async fn list_and_stream_songs() {
-        let pool = SqlitePool::connect("sqlite::memory:").await.expect("connect");
-        crate::db::init_db(&pool).await.expect("init db");
+    let mp3: &[u8] = b"ID3\x04\0\0\0\0\0\0 frames";
+    let meta: &[u8] = br#"{"title": "Don't Stop!", "artists": ["Band"]}"#;
+    let chart: &[u8] = br#"{"song_id": "whatever", "instrument": "drums", "difficulty": "Hard", "notes": [{"time": 1.0, "col": 2}]}"#;
+    let parts = [("metadata", None, meta), ("audio", Some("track.mp3"), mp3), ("chart", Some("c.json"), chart)];
 
-        // create temp songs dir
-        let tmp = TempDir::new().expect("tempdir");
-        let song_path = tmp.path().join("foo.wav");
-        std::fs::write(&song_path, b"RIFF....").expect("write");
-        std::env::set_var("SONGS_DIR", tmp.path().to_str().expect("str"));
+    let resp = test::call_service(&app, upload(&parts, None)).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = test::call_service(&app, upload(&parts, Some(&alice))).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["id"], "don-t-stop");
+    assert_eq!(body["job"]["status"], "skipped");
+    assert_eq!(std::fs::read(songs.path().join("don-t-stop.mp3")).unwrap(), mp3);
+    let stored: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(charts.path().join("don-t-stop_drums_Hard.json")).unwrap()).unwrap();
+    assert_eq!(stored["song_id"], "don-t-stop");
+
+    // the same title gets a fresh id, and the song is listed straight away
+    let resp = test::call_service(&app, upload(&parts[..2], Some(&alice))).await;
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["id"], "don-t-stop-2");
+    assert!(body["job"]["reason"].as_str().unwrap().contains("WAV"));
+    let req = test::TestRequest::get().uri("/api/songs").to_request();
+    let list = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, req).await;
+    assert_eq!(list.as_array().unwrap().len(), 2);
+
+    let bad_audio = [("metadata", None, meta), ("audio", Some("x.mp3"), b"<html>".as_slice())];
+    assert_eq!(test::call_service(&app, upload(&bad_audio, Some(&alice))).await.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let bad_meta = [("metadata", None, br#"{"year": 3000}"#.as_slice()), ("audio", Some("x.mp3"), mp3)];
+    assert_eq!(test::call_service(&app, upload(&bad_meta, Some(&alice))).await.status(), StatusCode::BAD_REQUEST);
+    let no_meta = [("audio", Some("x.mp3"), mp3)];
+    assert_eq!(test::call_service(&app, upload(&no_meta, Some(&alice))).await.status(), StatusCode::BAD_REQUEST);
+
+    // rejected uploads leave nothing behind
+    let files = std::fs::read_dir(songs.path()).unwrap().count();
+    assert_eq!(files, 4);
+}
+
+#[actix_rt::test]
+async fn custom_charts() {
+    let pool = memory_pool().await;
+    let tmp = TempDir::new().expect("tempdir");
+    // SAFETY: no other test reads or writes CUSTOM_CHARTS_DIR
+    unsafe { std::env::set_var("CUSTOM_CHARTS_DIR", tmp.path().to_str().expect("str")) };
+    let meta = crate::song_meta::SongMetadata { duration_ms: Some(10_000), ..Default::default() };
+    crate::db::upsert_song(&pool, "song", "song.wav", &meta, 0).await.unwrap();
+    let app = make_app(pool.clone()).await;
+    let alice = register_and_login(&app, "alice").await;
+    let upload = |song: &str, chart: serde_json::Value| {
+        test::TestRequest::post()
+            .uri(&format!("/api/songs/{}/charts", song))
+            .insert_header(bearer(&alice))
+            .set_json(chart)
+            .to_request()
+    };
+    let chart = |notes: serde_json::Value| serde_json::json!({"instrument": "drums", "difficulty": "Expert", "notes": notes});
+
+    let req = test::TestRequest::get().uri("/api/songs/song/charts").to_request();
+    let listing = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, req).await;
+    assert_eq!(listing, serde_json::json!([]));
+
+    // unsorted, out of range and past the end of the song
+    let bad = chart(serde_json::json!([{"time": 2.0, "col": 0}, {"time": 1.0, "col": 0}]));
+    assert_eq!(test::call_service(&app, upload("song", bad)).await.status(), StatusCode::BAD_REQUEST);
+    let bad = chart(serde_json::json!([{"time": 1.0, "col": 7}]));
+    assert_eq!(test::call_service(&app, upload("song", bad)).await.status(), StatusCode::BAD_REQUEST);
+    let bad = chart(serde_json::json!([{"time": 11.0, "col": 0}]));
+    assert_eq!(test::call_service(&app, upload("song", bad)).await.status(), StatusCode::BAD_REQUEST);
+    let good = chart(serde_json::json!([{"time": 1.0, "col": 0}, {"time": 2.0, "col": 3}]));
+    assert_eq!(test::call_service(&app, upload("nope", good.clone())).await.status(), StatusCode::NOT_FOUND);
+
+    for version in 1..=2 {
+        let resp = test::call_service(&app, upload("song", good.clone())).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["chart"]["version"], version);
+    }
+
+    let req = test::TestRequest::get().uri("/api/songs/song/charts").to_request();
+    let listing = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, req).await;
+    assert_eq!(listing.as_array().unwrap().len(), 1);
+    assert_eq!(listing[0]["author"], "alice");
+    assert_eq!(listing[0]["version"], 2);
+    assert_eq!(listing[0]["note_count"], 2);
+
+    // the served file carries the author, and scores against it verify by hash
+    let req = test::TestRequest::get().uri(listing[0]["url"].as_str().unwrap()).to_request();
+    let file = test::call_and_read_body(&app, req).await;
+    let served: serde_json::Value = serde_json::from_slice(&file).unwrap();
+    assert_eq!(served["author"], "alice");
+    let hash = rhythm_pi_scoring::chart_hash(&file);
+    assert_eq!(listing[0]["hash"], hash.as_str());
+
+    let mut replay = rhythm_pi_scoring::Replay::new();
+    replay.press(0, 1.0);
+    let score = serde_json::json!({
+        "song_id": "song", "player": "alice", "score": 300, "online": true,
+        "instrument": "drums", "difficulty": "expert", "chart_hash": hash, "replay": replay,
+    });
+    let req = test::TestRequest::post().uri("/api/scores").insert_header(bearer(&alice)).set_json(&score).to_request();
+    let body = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, req).await;
+    assert_eq!(body["status"], "ok");
+    assert_eq!(body["score"], 300);
+}
+
+#[actix_rt::test]
+async fn replays_are_stored_and_served() {
+    let pool = memory_pool().await;
+    let tmp = TempDir::new().expect("tempdir");
+    let _env = SONGS_ENV.lock().await;
+    let chart = br#"{"song_id":"song","instrument":"drums","difficulty":"Hard","columns":4,"notes":[{"time":1.0,"col":0}]}"#;
+    std::fs::write(tmp.path().join("song_drums_hard.json"), chart).unwrap();
+    // SAFETY: tests touching CHARTS_DIR hold SONGS_ENV
+    unsafe { std::env::set_var("CHARTS_DIR", tmp.path().to_str().expect("str")) };
+    let app = make_app(pool.clone()).await;
+    let alice = register_and_login(&app, "alice").await;
+
+    let mut replay = rhythm_pi_scoring::Replay::new();
+    replay.press(0, 1.0);
+    // a clock far in the future must not keep the replay past retention
+    let score = serde_json::json!({
+        "song_id": "song", "player": "alice", "score": 300, "online": true, "timestamp": 4_000_000_000i64,
+        "instrument": "drums", "difficulty": "hard", "chart_hash": rhythm_pi_scoring::chart_hash(chart), "replay": replay,
+    });
+    let req = test::TestRequest::post().uri("/api/scores").insert_header(bearer(&alice)).set_json(&score).to_request();
+    let body = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, req).await;
+    assert_eq!(body["status"], "ok");
+    let id = body["id"].as_i64().unwrap();
+    let created_at: i64 = sqlx::query_scalar("SELECT created_at FROM replays WHERE score_id = ?")
+        .bind(id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert!((created_at - chrono::Utc::now().timestamp()).abs() < 60, "{}", created_at);
+
+    let req = test::TestRequest::get().uri(&format!("/api/scores/{}/replay", id)).to_request();
+    let body = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, req).await;
+    assert_eq!((body["score_id"].as_i64(), body["score"].as_i64()), (Some(id), Some(300)));
+    assert_eq!(body["inputs"], serde_json::to_value(&replay.inputs).unwrap());
+    let req = test::TestRequest::get().uri(&format!("/api/scores/{}/replay", id + 1)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+    // replays over the configured size are refused, and the score with them
+    let small = make_app_with(pool.clone(), ReplayConfig { max_bytes: 16, retention_days: None }).await;
+    let req = test::TestRequest::post().uri("/api/scores").insert_header(bearer(&alice)).set_json(&score).to_request();
+    assert_eq!(test::call_service(&small, req).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let scores: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM scores").fetch_one(&pool).await.unwrap();
+    assert_eq!(scores, 1);
+}
+
+#[actix_rt::test]
+async fn charts_are_served_from_the_index() {
+    let pool = memory_pool().await;
+    let charts = TempDir::new().expect("tempdir");
+    let chart = |instrument: &str| serde_json::json!({"instrument": instrument, "notes": [{"time": 1.0, "col": 0}]}).to_string();
+    std::fs::write(charts.path().join("song_drums_Hard.json"), chart("drums")).unwrap();
+    std::fs::write(charts.path().join("song_vocals_Easy.chart.json"), chart("vocals")).unwrap();
+    std::fs::write(charts.path().join("song-remix_bass_Easy.json"), chart("bass")).unwrap();
+    let _env = SONGS_ENV.lock().await;
+    // SAFETY: tests touching CHARTS_DIR hold SONGS_ENV
+    unsafe { std::env::set_var("CHARTS_DIR", charts.path().to_str().expect("str")) };
+    let app = make_app(pool.clone()).await;
+    let get = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+
+    let body = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, get("/api/songs/song/chart?instrument=DRUMS&difficulty=hard")).await;
+    assert_eq!(body["instrument"], "drums");
+    let body = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, get("/api/songs/song/chart?difficulty=easy")).await;
+    assert_eq!(body["instrument"], "vocals");
+    let body = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, get("/api/songs/song-remix/chart")).await;
+    assert_eq!(body["instrument"], "bass");
+
+    // `song` never picks up the remix's bass chart, and says what it does have
+    let resp = test::call_service(&app, get("/api/songs/song/chart?instrument=bass&difficulty=Easy")).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["available"].as_array().unwrap().len(), 2);
+    assert!(body["error"].as_str().unwrap().contains("bass Easy"));
+
+    let indexed = crate::db::song_charts(&pool, "song").await.unwrap();
+    assert_eq!(indexed.iter().map(|c| c.note_count).collect::<Vec<_>>(), [1, 1]);
+    let listing = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, get("/api/songs/song/charts")).await;
+    assert_eq!(listing[0]["url"], "/api/songs/song/chart?instrument=drums&difficulty=Hard");
+}
+
+#[actix_rt::test]
+async fn uploads_queue_chart_jobs() {
+    let pool = memory_pool().await;
+    let (songs, charts) = (TempDir::new().expect("tempdir"), TempDir::new().expect("tempdir"));
+    let _env = SONGS_ENV.lock().await;
+    // SAFETY: tests touching SONGS_DIR hold SONGS_ENV
+    unsafe {
+        std::env::set_var("SONGS_DIR", songs.path().to_str().expect("str"));
+        std::env::set_var("CHARTS_DIR", charts.path().to_str().expect("str"));
+    }
+    // no workers run here, so jobs stay queued until cancelled
+    let app = make_app(pool.clone()).await;
+    let alice = register_and_login(&app, "alice").await;
+    let bob = register_and_login(&app, "bob").await;
+
+    let wav: &[u8] = b"RIFF\x24\0\0\0WAVEfmt ";
+    let (content_type, body) = multipart(&[("metadata", None, br#"{"title": "Wave"}"#), ("audio", Some("wave.wav"), wav)]);
+    let req = test::TestRequest::post()
+        .uri("/api/songs")
+        .insert_header(("Content-Type", content_type))
+        .insert_header(bearer(&alice))
+        .set_payload(body)
+        .to_request();
+    let body = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, req).await;
+    assert_eq!(body["job"]["status"], "queued");
+    let job_id = body["job"]["id"].as_i64().unwrap();
+    let job_uri = |action: &str| format!("/api/jobs/{}{}", job_id, action);
+
+    let req = test::TestRequest::get().uri(&job_uri("")).insert_header(bearer(&bob)).to_request();
+    let job = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, req).await;
+    assert_eq!((job["song_id"].as_str(), job["created_by"].as_str()), (Some("wave"), Some("alice")));
+    let req = test::TestRequest::get().uri("/api/jobs?status=queued").insert_header(bearer(&bob)).to_request();
+    let jobs = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, req).await;
+    assert_eq!(jobs.as_array().unwrap().len(), 1);
+
+    let post = |uri: String, token: &str| test::TestRequest::post().uri(&uri).insert_header(bearer(token)).to_request();
+    assert_eq!(test::call_service(&app, post(job_uri("/cancel"), &bob)).await.status(), StatusCode::FORBIDDEN);
+    assert_eq!(test::call_service(&app, post(job_uri("/retry"), &alice)).await.status(), StatusCode::CONFLICT);
+    let job = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, post(job_uri("/cancel"), &alice)).await;
+    assert_eq!(job["status"], "cancelled");
+    let job = test::call_and_read_body_json::<_, _, serde_json::Value>(&app, post(job_uri("/retry"), &alice)).await;
+    assert_eq!(job["status"], "queued");
+
+    // admins queue generation without waiting for it, joining the queued job and forcing it
+    assert!(crate::db::set_user_role(&pool, "bob", "admin").await.unwrap());
+    let resp = test::call_service(&app, post("/api/admin/generate_hq/wave?force=1".to_string(), &bob)).await;
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+    let job: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(job["id"], job_id);
+    assert_eq!(job["force"], true);
+
+    // once running, a job can't be cancelled, and says so
+    crate::db::claim_job(&pool, 0).await.unwrap().unwrap();
+    let resp = test::call_service(&app, post(job_uri("/cancel"), &alice)).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["job"]["status"], "running");
+    assert_eq!(body["job"]["cancellable"], false);
+    assert_eq!(test::call_service(&app, post("/api/jobs/999/cancel".to_string(), &bob)).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn chart_jobs_only_replace_charts_when_forced() {
+    let pool = memory_pool().await;
+    let (songs, charts) = (TempDir::new().expect("tempdir"), TempDir::new().expect("tempdir"));
+    write_tone(&songs.path().join("song.wav"));
+    let hand_made = br#"{"instrument":"drums","difficulty":"Hard","notes":[{"time":1.0,"col":0}]}"#;
+    let kept = charts.path().join("song_drums_hard.json");
+    std::fs::write(&kept, hand_made).unwrap();
+    let _env = SONGS_ENV.lock().await;
+    // SAFETY: tests touching SONGS_DIR hold SONGS_ENV
+    unsafe {
+        std::env::set_var("SONGS_DIR", songs.path().to_str().expect("str"));
+        std::env::set_var("CHARTS_DIR", charts.path().to_str().expect("str"));
+    }
+    let queue = JobQueue::new(pool.clone());
+    queue.start_workers(1).await.unwrap();
+
+    // an unforced job only fills in the charts that are missing
+    assert_eq!(finished_job(&pool, &queue, crate::jobs::GENERATE_CHARTS, "song", false).await, "done");
+    assert_eq!(std::fs::read(&kept).unwrap(), hand_made);
+    assert!(charts.path().join("song_drums_easy.json").is_file());
+
+    assert_eq!(finished_job(&pool, &queue, crate::jobs::GENERATE_CHARTS, "song", true).await, "done");
+    assert_ne!(std::fs::read(&kept).unwrap(), hand_made);
+}
+
+#[actix_rt::test]
+async fn partial_and_conditional_downloads() {
+    let pool = memory_pool().await;
+    let (songs, charts) = (TempDir::new().expect("tempdir"), TempDir::new().expect("tempdir"));
+    let audio: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(songs.path().join("song.wav"), &audio).unwrap();
+    let chart = br#"{"instrument":"drums","difficulty":"Hard","notes":[{"time":1.0,"col":0}]}"#;
+    std::fs::write(charts.path().join("song_drums_Hard.json"), chart).unwrap();
+    let _env = SONGS_ENV.lock().await;
+    // SAFETY: tests touching SONGS_DIR hold SONGS_ENV
+    unsafe {
+        std::env::set_var("SONGS_DIR", songs.path().to_str().expect("str"));
+        std::env::set_var("CHARTS_DIR", charts.path().to_str().expect("str"));
+    }
+    watched_song(&pool, songs.path(), "song", "song.wav").await;
+    let app = make_app(pool.clone()).await;
+    let get = |uri: &str, headers: &[(&str, &str)]| {
+        let mut req = test::TestRequest::get().uri(uri);
+        for (name, value) in headers {
+            req = req.insert_header((*name, *value));
+        }
+        req.to_request()
+    };
+    let header = |resp: &ServiceResponse, name: &str| resp.headers().get(name).map(|v| v.to_str().unwrap().to_string());
+
+    let resp = test::call_service(&app, get("/api/songs/song/stream", &[])).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "accept-ranges").as_deref(), Some("bytes"));
+    assert_eq!(header(&resp, "content-type").as_deref(), Some("audio/wav"));
+    let etag = header(&resp, "etag").expect("etag");
+    let last_modified = header(&resp, "last-modified").expect("last-modified");
+    assert_eq!(etag, format!("\"{}\"", crate::song_watcher::hash_file(&songs.path().join("song.wav")).unwrap()));
+    assert_eq!(test::read_body(resp).await, audio);
+
+    // a single range
+    let resp = test::call_service(&app, get("/api/songs/song/stream", &[("Range", "bytes=100-199")])).await;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(header(&resp, "content-range").as_deref(), Some("bytes 100-199/1000"));
+    assert_eq!(header(&resp, "content-length").as_deref(), Some("100"));
+    assert_eq!(test::read_body(resp).await, audio[100..200]);
+
+    // resuming a download: the rest of the file, as long as it is still the same file
+    let resp = test::call_service(&app, get("/api/songs/song/stream", &[("Range", "bytes=900-"), ("If-Range", &etag)])).await;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(test::read_body(resp).await, audio[900..]);
+    let resp = test::call_service(&app, get("/api/songs/song/stream", &[("Range", "bytes=900-"), ("If-Range", "\"stale\"")])).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(test::read_body(resp).await.len(), 1000);
+
+    // several ranges come back as multipart/byteranges
+    let resp = test::call_service(&app, get("/api/songs/song/stream", &[("Range", "bytes=0-9, -10")])).await;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    let content_type = header(&resp, "content-type").unwrap();
+    let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").expect("multipart").to_string();
+    let body = test::read_body(resp).await;
+    let mut expected = format!("--{}\r\nContent-Type: audio/wav\r\nContent-Range: bytes 0-9/1000\r\n\r\n", boundary).into_bytes();
+    expected.extend_from_slice(&audio[..10]);
+    expected.extend_from_slice(format!("\r\n--{}\r\nContent-Type: audio/wav\r\nContent-Range: bytes 990-999/1000\r\n\r\n", boundary).as_bytes());
+    expected.extend_from_slice(&audio[990..]);
+    expected.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    assert_eq!(body, expected);
+
+    let resp = test::call_service(&app, get("/api/songs/song/stream", &[("Range", "bytes=5000-")])).await;
+    assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(header(&resp, "content-range").as_deref(), Some("bytes */1000"));
+
+    // conditional fetches of an unchanged song
+    for condition in [("If-None-Match", etag.as_str()), ("If-None-Match", "*"), ("If-Modified-Since", last_modified.as_str())] {
+        let resp = test::call_service(&app, get("/api/songs/song/stream", &[condition])).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED, "{:?}", condition);
+        assert_eq!(header(&resp, "etag").as_ref(), Some(&etag));
+        assert!(test::read_body(resp).await.is_empty());
+    }
+    // If-None-Match wins over If-Modified-Since
+    let resp = test::call_service(&app, get("/api/songs/song/stream", &[("If-None-Match", "\"other\""), ("If-Modified-Since", &last_modified)])).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // a file the watcher has not hashed gets a weak ETag instead of being read through
+    std::fs::write(songs.path().join("loose.wav"), &audio).unwrap();
+    let resp = test::call_service(&app, get("/api/songs/loose/stream", &[])).await;
+    let weak = header(&resp, "etag").expect("etag");
+    assert!(weak.starts_with("W/\"3e8-"), "{}", weak);
+    let loose_modified = header(&resp, "last-modified").unwrap();
+    let resp = test::call_service(&app, get("/api/songs/loose/stream", &[("If-None-Match", &weak)])).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    // resumes go by date, as a weak ETag never matches If-Range
+    let resp = test::call_service(&app, get("/api/songs/loose/stream", &[("Range", "bytes=900-"), ("If-Range", &weak)])).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, get("/api/songs/loose/stream", &[("Range", "bytes=900-"), ("If-Range", &loose_modified)])).await;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+
+    // charts: the ETag is the chart hash scores are submitted with
+    let resp = test::call_service(&app, get("/api/songs/song/chart?instrument=drums", &[])).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let chart_etag = header(&resp, "etag").unwrap();
+    assert_eq!(chart_etag, format!("\"{}\"", rhythm_pi_scoring::chart_hash(chart)));
+    let resp = test::call_service(&app, get("/api/songs/song/chart?instrument=drums", &[("If-None-Match", &chart_etag)])).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    let resp = test::call_service(&app, get("/api/songs/song/chart?instrument=drums", &[("Range", "bytes=0-0")])).await;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(test::read_body(resp).await, &b"{"[..]);
+
+    // the song list changes its ETag when a song is registered
+    let resp = test::call_service(&app, get("/api/songs", &[])).await;
+    let list_etag = header(&resp, "etag").unwrap();
+    let resp = test::call_service(&app, get("/api/songs", &[("If-None-Match", &list_etag)])).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    let meta = crate::song_meta::SongMetadata { title: Some("Song".to_string()), ..Default::default() };
+    crate::db::upsert_song(&pool, "song", "song.wav", &meta, 1).await.unwrap();
+    let resp = test::call_service(&app, get("/api/songs", &[("If-None-Match", &list_etag)])).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_ne!(header(&resp, "etag").unwrap(), list_etag);
+    let list: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(list[0]["title"], "Song");
+}
+
+#[actix_rt::test]
+async fn streams_transcoded_variants() {
+    let pool = memory_pool().await;
+    let songs = TempDir::new().expect("tempdir");
+    let write_song = |pitch: f32| write_wav(&songs.path().join("song.wav"), 2, 22050, 22050 * 2, |i, _| ((i as f32 * pitch).sin() * 8000.0) as i16);
+    write_song(0.05);
+    let _env = SONGS_ENV.lock().await;
+    // SAFETY: tests touching SONGS_DIR hold SONGS_ENV
+    unsafe { std::env::set_var("SONGS_DIR", songs.path().to_str().expect("str")) };
+    watched_song(&pool, songs.path(), "song", "song.wav").await;
+
+    let queue = JobQueue::new(pool.clone());
+    queue.start_workers(1).await.unwrap();
+    assert_eq!(transcoded(&pool, &queue, "song").await, "done");
+    assert_eq!(crate::db::song_variants(&pool, "song").await.unwrap().len(), 3);
+
+    let app = make_app(pool.clone()).await;
+    let get = |uri: &str, accept: Option<&str>| {
+        let mut req = test::TestRequest::get().uri(uri);
+        if let Some(accept) = accept {
+            req = req.insert_header(("Accept", accept));
+        }
+        req.to_request()
+    };
+    let served = |resp: &ServiceResponse| {
+        let header = |name: &str| resp.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or_default().to_string();
+        (header("x-audio-quality"), header("content-type"))
+    };
+    let wav_len = std::fs::metadata(songs.path().join("song.wav")).unwrap().len() as usize;
+
+    let resp = test::call_service(&app, get("/api/songs/song/stream?quality=low", None)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(served(&resp), ("low".to_string(), "audio/ogg".to_string()));
+    assert_eq!(resp.headers().get("vary").unwrap(), "Accept");
+    let body = test::read_body(resp).await;
+    assert!(body.starts_with(b"OggS") && body.len() < wav_len / 4, "{} bytes", body.len());
+
+    let resp = test::call_service(&app, get("/api/songs/song/stream", Some("audio/ogg, audio/wav;q=0.5"))).await;
+    assert_eq!(served(&resp).0, "medium");
+    let resp = test::call_service(&app, get("/api/songs/song/stream", None)).await;
+    assert_eq!(served(&resp), ("original".to_string(), "audio/wav".to_string()));
+    assert_eq!(test::read_body(resp).await.len(), wav_len);
+    let resp = test::call_service(&app, get("/api/songs/song/stream?quality=lossless", None)).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    // variants of audio that has since changed are not served
+    write_song(0.07);
+    let later = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+    std::fs::File::options().write(true).open(songs.path().join("song.wav")).unwrap().set_modified(later).unwrap();
+    let resp = test::call_service(&app, get("/api/songs/song/stream?quality=low", None)).await;
+    assert_eq!(served(&resp).0, "original");
+}
+
+#[actix_rt::test]
+async fn songs_with_the_same_audio_keep_their_variants() {
+    let pool = memory_pool().await;
+    let songs = TempDir::new().expect("tempdir");
+    write_wav(&songs.path().join("song.wav"), 1, 22050, 22050, |i, _| ((i as f32 * 0.05).sin() * 8000.0) as i16);
+    std::fs::copy(songs.path().join("song.wav"), songs.path().join("twin.wav")).unwrap();
+    let _env = SONGS_ENV.lock().await;
+    // SAFETY: tests touching SONGS_DIR hold SONGS_ENV
+    unsafe { std::env::set_var("SONGS_DIR", songs.path().to_str().expect("str")) };
+    watched_song(&pool, songs.path(), "song", "song.wav").await;
+    watched_song(&pool, songs.path(), "twin", "twin.wav").await;
+
+    let queue = JobQueue::new(pool.clone());
+    queue.start_workers(1).await.unwrap();
+    assert_eq!(transcoded(&pool, &queue, "song").await, "done");
+    assert_eq!(transcoded(&pool, &queue, "twin").await, "done");
+    let variants = crate::transcode::variants_dir(songs.path());
+    let paths = |id: &str| {
+        let pool = pool.clone();
+        let id = id.to_string();
+        async move { crate::db::song_variants(&pool, &id).await.unwrap().into_iter().map(|v| v.path).collect::<Vec<_>>() }
+    };
+    let (kept, removed) = (paths("song").await, paths("twin").await);
+    assert_eq!(kept.len(), 3);
+    assert!(kept.iter().all(|p| !removed.contains(p)), "{:?} {:?}", kept, removed);
+
+    // the twin goes the way the watcher removes songs; the other song's files stay
+    crate::transcode::remove_variants(&pool, songs.path(), "twin").await.unwrap();
+    crate::db::delete_song(&pool, "twin").await.unwrap();
+    assert!(removed.iter().all(|p| !variants.join(p).exists()));
+    assert!(kept.iter().all(|p| variants.join(p).is_file()));
+
+    // files two songs share, as variants made before names carried the song id were,
+    // stay until the last song using them goes
+    let shared = crate::db::SongVariant { song_id: "twin".to_string(), ..crate::db::song_variants(&pool, "song").await.unwrap()[0].clone() };
+    crate::db::upsert_song(&pool, "twin", "twin.wav", &Default::default(), 0).await.unwrap();
+    crate::db::set_song_variant(&pool, &shared).await.unwrap();
+    crate::transcode::remove_variants(&pool, songs.path(), "twin").await.unwrap();
+    assert!(variants.join(&shared.path).is_file());
+    crate::db::delete_song(&pool, "twin").await.unwrap();
+    crate::transcode::remove_variants(&pool, songs.path(), "song").await.unwrap();
+    assert!(!variants.join(&shared.path).exists());
+}
+
+#[actix_rt::test]
+async fn audio_socket_waits_for_acknowledgements() {
+    let pool = memory_pool().await;
+    let songs = TempDir::new().expect("tempdir");
+    let path = songs.path().join("song.wav");
+    write_tone(&path);
+    let audio = std::fs::read(&path).unwrap();
+    let _env = SONGS_ENV.lock().await;
+    // SAFETY: tests touching SONGS_DIR hold SONGS_ENV
+    unsafe { std::env::set_var("SONGS_DIR", songs.path().to_str().expect("str")) };
+    watched_song(&pool, songs.path(), "song", "song.wav").await;
+    let (addr, server) = serve(pool.clone());
+    let watch_handles = cfg!(target_os = "linux");
+
+    let (mut ws, meta) = audio_socket(addr, "song").await;
+    assert_eq!((meta["window"].as_u64(), meta["chunk_size"].as_u64()), (Some(16), Some(16384)));
+    assert_eq!(meta["total_bytes"].as_u64(), Some(audio.len() as u64));
+    command(&mut ws, serde_json::json!({"type": "start"})).await;
+    let sent = drain(&mut ws).await;
+    assert_eq!(messages(&sent, "started")[0]["seq"], 0);
+    // a window's worth, then nothing until the client acknowledges some
+    let seqs: Vec<u32> = frames(&sent).iter().map(|f| f.0).collect();
+    assert_eq!(seqs, (0..16).collect::<Vec<_>>());
+    if watch_handles {
+        assert_eq!(open_handles(&path), 1, "the reader waits with the file open");
+    }
+    command(&mut ws, serde_json::json!({"type": "ack", "seq": 3})).await;
+    let more = drain(&mut ws).await;
+    assert_eq!(frames(&more).iter().map(|f| f.0).collect::<Vec<_>>(), (16..20).collect::<Vec<_>>());
+    let sent = acknowledge_all(&mut ws, [sent, more].into_iter().flatten().collect()).await;
+    let complete = messages(&sent, "complete");
+    assert_eq!(complete.len(), 1);
+    assert_eq!((complete[0]["frames"].as_u64(), complete[0]["total_bytes"].as_u64()), (Some(22), Some(audio.len() as u64)));
+    assert_eq!(frames(&sent).iter().flat_map(|f| f.1.iter().copied()).collect::<Vec<u8>>(), audio);
+    assert!(handles_closed(&path).await);
+
+    // `stop` and closing the socket both cancel a reader waiting on the window
+    for stop in [true, false] {
+        let (mut ws, _) = audio_socket(addr, "song").await;
+        command(&mut ws, serde_json::json!({"type": "start"})).await;
+        assert_eq!(frames(&drain(&mut ws).await).len(), 16);
+        if stop {
+            command(&mut ws, serde_json::json!({"type": "stop"})).await;
+        } else {
+            ws.close(None).await.expect("close");
+        }
+        let sent = drain(&mut ws).await;
+        assert!(frames(&sent).is_empty());
+        assert!(matches!(sent.last(), Some(Sent::Closed)), "{:?}", sent);
+        if watch_handles {
+            assert!(handles_closed(&path).await, "reader still running after {}", if stop { "stop" } else { "close" });
+        }
+    }
+    server.stop(true).await;
+}
+
+#[actix_rt::test]
+async fn audio_socket_seeks_and_pauses() {
+    let pool = memory_pool().await;
+    let songs = TempDir::new().expect("tempdir");
+    let path = songs.path().join("song.wav");
+    write_tone(&path);
+    let audio = std::fs::read(&path).unwrap();
+    let total = audio.len() as u64;
+    let _env = SONGS_ENV.lock().await;
+    // SAFETY: tests touching SONGS_DIR hold SONGS_ENV
+    unsafe { std::env::set_var("SONGS_DIR", songs.path().to_str().expect("str")) };
+    watched_song(&pool, songs.path(), "song", "song.wav").await;
+    let (addr, server) = serve(pool.clone());
+
+    let (mut ws, _) = audio_socket(addr, "song").await;
+    command(&mut ws, serde_json::json!({"type": "start"})).await;
+    assert_eq!(frames(&drain(&mut ws).await).len(), 16);
+
+    // frames still on their way when a seek arrives are numbered before it
+    command(&mut ws, serde_json::json!({"type": "ack", "seq": 15})).await;
+    command(&mut ws, serde_json::json!({"type": "seek", "offset_ms": 1000})).await;
+    let sent = drain(&mut ws).await;
+    let at = sent.iter().position(|s| matches!(s, Sent::Message(m) if m["type"] == "started")).expect("started");
+    let Sent::Message(started) = &sent[at] else { unreachable!() };
+    // 44100 two-byte frames a second after the 44 byte header
+    assert_eq!(started["offset_bytes"], 44 + 88200);
+    assert_eq!((started["header_bytes"].as_u64(), started["start_ms"].as_u64()), (Some(44), Some(1000)));
+    let seq = started["seq"].as_u64().unwrap() as u32;
+    assert!(frames(&sent[..at]).iter().all(|f| f.0 < seq));
+    let after = frames(&sent[at..]);
+    assert_eq!(after.iter().map(|f| f.0).collect::<Vec<_>>(), (seq..seq + after.len() as u32).collect::<Vec<_>>());
+    assert_eq!(after[0].1, &audio[..44]);
+    assert_eq!(after[1].1, &audio[88244..88244 + 16384]);
+
+    // nothing is sent while paused, however much room the client makes
+    let last = frames(&sent).last().unwrap().0;
+    command(&mut ws, serde_json::json!({"type": "pause"})).await;
+    let sent = drain(&mut ws).await;
+    let paused = messages(&sent, "paused")[0]["seq"].as_u64().unwrap() as u32;
+    assert!(frames(&sent).iter().all(|f| f.0 < paused));
+    let last = frames(&sent).last().map_or(last, |f| f.0);
+    command(&mut ws, serde_json::json!({"type": "ack", "seq": last})).await;
+    assert!(drain(&mut ws).await.is_empty());
+    command(&mut ws, serde_json::json!({"type": "resume"})).await;
+    let sent = drain(&mut ws).await;
+    assert_eq!(messages(&sent, "resumed")[0]["seq"].as_u64(), Some(u64::from(paused)));
+    assert_eq!(frames(&sent)[0].0, paused);
+    let sent = acknowledge_all(&mut ws, sent).await;
+    assert_eq!(messages(&sent, "complete").len(), 1);
+
+    // `complete` goes out once, whether a pause lands before or after the last frame
+    for wait in [false, true] {
+        command(&mut ws, serde_json::json!({"type": "seek", "offset_bytes": total - 100})).await;
+        let mut sent = if wait { drain(&mut ws).await } else { Vec::new() };
+        command(&mut ws, serde_json::json!({"type": "pause"})).await;
+        sent.extend(drain(&mut ws).await);
+        command(&mut ws, serde_json::json!({"type": "resume"})).await;
+        sent.extend(drain(&mut ws).await);
+        let sent = acknowledge_all(&mut ws, sent).await;
+        assert_eq!(messages(&sent, "complete").len(), 1, "{:?}", sent);
+        let seq = messages(&sent, "started")[0]["seq"].as_u64().unwrap() as u32;
+        let data: Vec<u8> = frames(&sent).iter().filter(|f| f.0 >= seq).flat_map(|f| f.1.iter().copied()).collect();
+        assert_eq!(data, &audio[audio.len() - 100..]);
+    }
+    ws.close(None).await.expect("close");
+    server.stop(true).await;
+}
 
-        let app = test::init_service(make_app(pool.clone()).await).await;
+#[actix_rt::test]
+async fn audio_socket_sends_decoded_audio() {
+    let pool = memory_pool().await;
+    let songs = TempDir::new().expect("tempdir");
+    let path = songs.path().join("song.wav");
+    write_tone(&path);
+    let audio = std::fs::read(&path).unwrap();
+    let source = |frame: usize| i16::from_le_bytes([audio[44 + frame * 2], audio[45 + frame * 2]]);
+    let _env = SONGS_ENV.lock().await;
+    // SAFETY: tests touching SONGS_DIR hold SONGS_ENV
+    unsafe { std::env::set_var("SONGS_DIR", songs.path().to_str().expect("str")) };
+    watched_song(&pool, songs.path(), "song", "song.wav").await;
+    let (addr, server) = serve(pool.clone());
 
-        let req = test::TestRequest::get().uri("/api/songs").to_request();
-        let list = test::call_and_read_body_json::<_, serde_json::Value>(&app, req).await;
-        assert!(list.as_array().unwrap().iter().any(|v| v.get("filename").unwrap().as_str().unwrap().contains("foo.wav")));
+    let (mut ws, _) = audio_socket(addr, "song").await;
+    command(&mut ws, serde_json::json!({"type": "format", "encoding": "s16le", "sample_rate": 44100, "channels": 1})).await;
+    let sent = drain(&mut ws).await;
+    let format = messages(&sent, "format")[0];
+    assert_eq!((format["frame_bytes"].as_u64(), format["total_frames"].as_u64()), (Some(2), Some(176_400)));
+    command(&mut ws, serde_json::json!({"type": "start", "offset_ms": 1000})).await;
+    let sent = drain(&mut ws).await;
+    let sent = acknowledge_all(&mut ws, sent).await;
+    let started = messages(&sent, "started")[0];
+    assert_eq!((started["frame"].as_u64(), started["offset_bytes"].as_u64(), started["start_ms"].as_u64()), (Some(44100), Some(88200), Some(1000)));
 
-        let req = test::TestRequest::get().uri("/api/songs/foo/stream").to_request();
-        let resp = test::call_service(&app, req).await;
-        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+    // each frame has the index of its first sample frame after the sequence number
+    let mut next = 44100;
+    for (_, data) in frames(&sent) {
+        let first = u64::from_be_bytes(data[..8].try_into().unwrap()) as usize;
+        assert_eq!(first, next);
+        let samples: Vec<i16> = data[8..].chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+        for (i, sample) in samples.iter().enumerate().step_by(97) {
+            assert!((sample - source(first + i)).abs() <= 1, "frame {}: {} for {}", first + i, sample, source(first + i));
+        }
+        next += samples.len();
     }
+    assert_eq!(next, 176_400);
+    let complete = messages(&sent, "complete");
+    assert_eq!((complete.len(), complete[0]["total_bytes"].as_u64()), (1, Some(176_400 * 2)));
+    ws.close(None).await.expect("close");
+    server.stop(true).await;
 }
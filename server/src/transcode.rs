@@ -0,0 +1,460 @@
+use anyhow::{anyhow, bail, Context, Result};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::errors::{Error as SymphoniaError, SeekErrorKind};
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::db;
+use crate::song_watcher::{self, AUDIO_EXTENSIONS};
+use crate::uploads::PartFile;
+
+/// Streaming variants of a song, all Ogg Vorbis at the original sample rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    Low,
+    Medium,
+    High,
+}
+
+impl Quality {
+    pub const ALL: [Quality; 3] = [Quality::Low, Quality::Medium, Quality::High];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Quality::Low => "low",
+            Quality::Medium => "medium",
+            Quality::High => "high",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|q| q.name().eq_ignore_ascii_case(name))
+    }
+
+    /// libvorbis VBR quality: roughly 64, 112 and 160 kbit/s for 44.1 kHz stereo
+    fn vorbis_quality(self) -> f32 {
+        match self {
+            Quality::Low => 0.0,
+            Quality::Medium => 0.3,
+            Quality::High => 0.5,
+        }
+    }
+}
+
+/// What a client wants streamed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wanted {
+    Original,
+    Variant(Quality),
+}
+
+/// Pick what to send from `?quality=` (`low`, `medium`, `high` or `original`), or failing
+/// that from `Accept`: a client that prefers `audio/ogg` or `audio/vorbis` over every
+/// other audio type it names gets the medium variant. Anything else gets the original,
+/// as before variants existed. Errors name an unknown quality.
+pub fn negotiate(quality: Option<&str>, accept: Option<&str>) -> Result<Wanted> {
+    if let Some(quality) = quality {
+        if quality.eq_ignore_ascii_case("original") {
+            return Ok(Wanted::Original);
+        }
+        return Quality::parse(quality)
+            .map(Wanted::Variant)
+            .with_context(|| format!("unknown quality {:?}; use low, medium, high or original", quality));
+    }
+    let Some(accept) = accept else {
+        return Ok(Wanted::Original);
+    };
+    let (mut vorbis, mut other) = (0.0f32, 0.0f32);
+    for range in accept.split(',') {
+        let mut params = range.split(';').map(str::trim);
+        let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+        let q = params
+            .filter_map(|p| p.strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match media_type.as_str() {
+            "audio/ogg" | "audio/vorbis" => vorbis = vorbis.max(q),
+            t if t.starts_with("audio/") && t != "audio/*" => other = other.max(q),
+            _ => {}
+        }
+    }
+    Ok(if vorbis > 0.0 && vorbis > other { Wanted::Variant(Quality::Medium) } else { Wanted::Original })
+}
+
+/// Whether songs get transcoded (`TRANSCODE`, on unless `0` or `false`)
+pub fn enabled() -> bool {
+    std::env::var("TRANSCODE").map(|v| v != "0" && !v.eq_ignore_ascii_case("false")).unwrap_or(true)
+}
+
+/// Where variants are cached: a hidden directory beside the originals, which the song
+/// watcher does not look into
+pub fn variants_dir(songs_dir: &Path) -> PathBuf {
+    songs_dir.join(".variants")
+}
+
+/// The song's variant of `quality` if it was made from the audio with `source_hash` and
+/// its file is still there
+pub async fn current_variant(pool: &SqlitePool, songs_dir: &Path, song_id: &str, quality: Quality, source_hash: &str) -> Result<Option<(PathBuf, db::SongVariant)>> {
+    let variant = db::song_variants(pool, song_id)
+        .await?
+        .into_iter()
+        .find(|v| v.quality == quality.name() && v.source_hash == source_hash);
+    Ok(variant.map(|v| (variants_dir(songs_dir).join(&v.path), v)).filter(|(path, _)| path.is_file()))
+}
+
+/// The audio file to send for a song, original or variant
+#[derive(Debug)]
+pub struct SongAudio {
+    pub path: PathBuf,
+    pub len: u64,
+    /// SHA-256 of the file, for ETags. None when the watcher has not hashed the file as it
+    /// is now; hashing it here would mean reading all of it for every request.
+    pub hash: Option<String>,
+    pub modified: Option<std::time::SystemTime>,
+    pub content_type: &'static str,
+    /// `original` or the variant's quality
+    pub quality: &'static str,
+}
+
+/// Find what to send for `wanted`: the variant when one made from the current audio is
+/// ready, otherwise the original (the registered file, or `{id}.{ext}` for songs the
+/// watcher has not seen yet). None when the song has no audio. Nothing is hashed here.
+pub async fn song_audio(pool: &SqlitePool, songs_dir: &Path, song_id: &str, wanted: Wanted) -> Result<Option<SongAudio>> {
+    let known = db::get_song_files(pool, song_id).await?;
+    let candidates = known
+        .iter()
+        .map(|f| f.filename.clone())
+        .chain(AUDIO_EXTENSIONS.iter().map(|ext| format!("{}.{}", song_id, ext)));
+    let mut found = None;
+    for filename in candidates {
+        let ext = Path::new(&filename).extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+        if !AUDIO_EXTENSIONS.contains(&ext.as_str()) {
+            continue;
+        }
+        if let Ok(metadata) = tokio::fs::metadata(songs_dir.join(&filename)).await
+            && metadata.is_file()
+        {
+            found = Some((filename, ext, metadata));
+            break;
+        }
+    }
+    let Some((filename, ext, metadata)) = found else {
+        return Ok(None);
+    };
+    let path = songs_dir.join(&filename);
+
+    // the watcher's hash only holds while the file is as it saw it. Without it, variants
+    // can't be matched to the audio, so the original is sent until the watcher catches up.
+    let (mtime, len) = (song_watcher::mtime_secs(&metadata), metadata.len());
+    let hash = match known {
+        Some(f) if f.filename == filename && f.mtime == mtime && f.audio_size == len as i64 => f.audio_hash,
+        _ => None,
+    };
+
+    if let Wanted::Variant(quality) = wanted
+        && let Some(hash) = &hash
+        && let Some((variant_path, variant)) = current_variant(pool, songs_dir, song_id, quality, hash).await?
+        && let Ok(metadata) = tokio::fs::metadata(&variant_path).await
+    {
+        return Ok(Some(SongAudio {
+            path: variant_path,
+            len: metadata.len(),
+            hash: Some(variant.hash),
+            modified: metadata.modified().ok(),
+            content_type: "audio/ogg",
+            quality: quality.name(),
+        }));
+    }
+
+    let content_type = match ext.as_str() {
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "ogg" => "audio/ogg",
+        "flac" => "audio/flac",
+        _ => "application/octet-stream",
+    };
+    Ok(Some(SongAudio { path, len, hash, modified: metadata.modified().ok(), content_type, quality: "original" }))
+}
+
+/// Qualities the song has no up-to-date variant of
+pub async fn missing_variants(pool: &SqlitePool, song_id: &str, source_hash: &str) -> Result<Vec<Quality>> {
+    let variants = db::song_variants(pool, song_id).await?;
+    Ok(Quality::ALL
+        .into_iter()
+        .filter(|q| !variants.iter().any(|v| v.quality == q.name() && v.source_hash == source_hash))
+        .collect())
+}
+
+/// Delete the files of a song's variants, except any another song's variant is stored
+/// in too; the rows go with the song
+pub async fn remove_variants(pool: &SqlitePool, songs_dir: &Path, song_id: &str) -> Result<()> {
+    for variant in db::song_variants(pool, song_id).await? {
+        if db::variant_file_shared(pool, &variant.path, song_id).await? {
+            continue;
+        }
+        let path = variants_dir(songs_dir).join(&variant.path);
+        if let Err(e) = std::fs::remove_file(&path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            log::warn!("cannot remove {}: {}", path.display(), e);
+        }
+    }
+    Ok(())
+}
+
+/// Decodes WAV, FLAC, MP3 or Ogg Vorbis into interleaved 16-bit samples
+pub struct AudioDecoder {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    pub sample_rate: u32,
+    /// Channels produced: the source's, but no more than two
+    pub channels: usize,
+    source_channels: usize,
+    buf: Option<SampleBuffer<i16>>,
+    out: Vec<i16>,
+    /// Frames still to drop after a seek landed short of where it was asked to
+    skip: u64,
+}
+
+/// A reader for the audio file at `path`, its format found from its contents
+pub fn probe(path: &Path) -> Result<Box<dyn FormatReader>> {
+    let file = std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    let probed = symphonia::default::get_probe()
+        .format(&hint, MediaSourceStream::new(Box::new(file), Default::default()), &FormatOptions::default(), &MetadataOptions::default())
+        .with_context(|| format!("{} is not audio we can read", path.display()))?;
+    Ok(probed.format)
+}
+
+impl AudioDecoder {
+    pub fn open(path: &Path) -> Result<Self> {
+        let format = probe(path)?;
+        let track = format.default_track().context("no audio track")?;
+        let params = track.codec_params.clone();
+        let sample_rate = params.sample_rate.context("unknown sample rate")?;
+        let source_channels = params.channels.context("unknown channel layout")?.count();
+        if source_channels == 0 {
+            bail!("audio has no channels");
+        }
+        let decoder = symphonia::default::get_codecs().make(&params, &DecoderOptions::default()).context("unsupported codec")?;
+        Ok(Self {
+            track_id: track.id,
+            format,
+            decoder,
+            sample_rate,
+            channels: source_channels.min(2),
+            source_channels,
+            buf: None,
+            out: Vec::new(),
+            skip: 0,
+        })
+    }
+
+    /// Move to `seconds` into the audio. Returns false when that is past the end.
+    pub fn seek(&mut self, seconds: f64) -> Result<bool> {
+        let to = SeekTo::Time { time: seconds.into(), track_id: Some(self.track_id) };
+        match self.format.seek(SeekMode::Accurate, to) {
+            Ok(seeked) => {
+                self.decoder.reset();
+                // audio timestamps count frames
+                self.skip = seeked.required_ts.saturating_sub(seeked.actual_ts);
+                Ok(true)
+            }
+            Err(SymphoniaError::SeekError(SeekErrorKind::OutOfRange)) => Ok(false),
+            Err(e) => Err(e).context("seeking in audio"),
+        }
+    }
+
+    /// The next block of interleaved samples, or None at the end
+    pub fn next_samples(&mut self) -> Result<Option<&[i16]>> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e).context("reading audio"),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // a damaged packet is skipped, as players do
+                Err(SymphoniaError::DecodeError(e)) => {
+                    log::debug!("skipping undecodable packet: {}", e);
+                    continue;
+                }
+                Err(e) => return Err(e).context("decoding audio"),
+            };
+            if decoded.frames() == 0 {
+                continue;
+            }
+            let frames = decoded.frames();
+            let skip = self.skip.min(frames as u64) as usize;
+            self.skip -= skip as u64;
+            if skip == frames {
+                continue;
+            }
+            let needed = decoded.capacity() * self.source_channels;
+            if self.buf.as_ref().is_none_or(|b| b.capacity() < needed) {
+                self.buf = Some(SampleBuffer::new(decoded.capacity() as u64, *decoded.spec()));
+            }
+            let buf = self.buf.as_mut().context("no sample buffer")?;
+            buf.copy_interleaved_ref(decoded);
+            if self.source_channels == self.channels {
+                return Ok(Some(&buf.samples()[skip * self.channels..]));
+            }
+            // surround: keep the front pair
+            self.out.clear();
+            for frame in buf.samples().chunks(self.source_channels).skip(skip) {
+                self.out.extend_from_slice(&frame[..self.channels]);
+            }
+            return Ok(Some(&self.out));
+        }
+    }
+}
+
+/// A variant written by `transcode`
+#[derive(Debug)]
+pub struct Encoded {
+    pub quality: Quality,
+    /// File name in the variants directory
+    pub path: String,
+    pub size: u64,
+    pub hash: String,
+}
+
+/// One variant being encoded
+struct Output {
+    quality: Quality,
+    encoder: vorbis_encoder::Encoder,
+    part: PartFile,
+    file: BufWriter<std::fs::File>,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl Output {
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.hasher.update(bytes);
+        self.size += bytes.len() as u64;
+        self.file.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+fn encoder_error(code: i32) -> anyhow::Error {
+    anyhow!("vorbis encoder failed ({})", code)
+}
+
+/// Decode `source` once and encode it as Ogg Vorbis at each of `qualities`, to files in
+/// `dir` named `{song_id}.{source_hash}.{quality}.ogg`, so songs with the same audio keep
+/// files of their own. Blocks for as long as the song plays on a slow machine; run it off
+/// the async threads.
+pub fn transcode(source: &Path, dir: &Path, song_id: &str, source_hash: &str, qualities: &[Quality]) -> Result<Vec<Encoded>> {
+    std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    let mut audio = AudioDecoder::open(source)?;
+    let mut outputs = qualities
+        .iter()
+        .map(|&quality| {
+            let encoder = vorbis_encoder::Encoder::new(audio.channels as u32, audio.sample_rate as u64, quality.vorbis_quality())
+                .map_err(|e| anyhow!("cannot encode {} Hz, {} channel audio ({})", audio.sample_rate, audio.channels, e))?;
+            let part = PartFile::new(dir);
+            let file = BufWriter::new(std::fs::File::create(part.path())?);
+            Ok(Output { quality, encoder, part, file, hasher: Sha256::new(), size: 0 })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut samples = Vec::new();
+    while let Some(block) = audio.next_samples()? {
+        samples.clear();
+        samples.extend_from_slice(block);
+        for output in &mut outputs {
+            let bytes = output.encoder.encode(&samples).map_err(encoder_error)?;
+            output.write(&bytes)?;
+        }
+    }
+
+    let mut encoded = Vec::new();
+    for mut output in outputs {
+        let bytes = output.encoder.flush().map_err(encoder_error)?;
+        output.write(&bytes)?;
+        output.file.flush()?;
+        let name = format!("{}.{}.{}.ogg", song_id, source_hash, output.quality.name());
+        let dest = dir.join(&name);
+        // re-encoding the same audio replaces what was there
+        if let Err(e) = std::fs::remove_file(&dest)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            return Err(e).with_context(|| format!("replacing {}", dest.display()));
+        }
+        output.part.persist(&dest)?;
+        encoded.push(Encoded {
+            quality: output.quality,
+            path: name,
+            size: output.size,
+            hash: output.hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect(),
+        });
+    }
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_wav;
+
+    #[test]
+    fn negotiates_variants() {
+        assert_eq!(negotiate(None, None).unwrap(), Wanted::Original);
+        assert_eq!(negotiate(Some("LOW"), Some("audio/wav")).unwrap(), Wanted::Variant(Quality::Low));
+        assert_eq!(negotiate(Some("original"), Some("audio/ogg")).unwrap(), Wanted::Original);
+        assert!(negotiate(Some("lossless"), None).is_err());
+
+        assert_eq!(negotiate(None, Some("*/*")).unwrap(), Wanted::Original);
+        assert_eq!(negotiate(None, Some("audio/ogg")).unwrap(), Wanted::Variant(Quality::Medium));
+        assert_eq!(negotiate(None, Some("audio/wav, audio/ogg;q=0.5")).unwrap(), Wanted::Original);
+        assert_eq!(negotiate(None, Some("audio/wav;q=0.4, audio/vorbis")).unwrap(), Wanted::Variant(Quality::Medium));
+        assert_eq!(negotiate(None, Some("audio/ogg;q=0")).unwrap(), Wanted::Original);
+    }
+
+    #[test]
+    fn transcodes_to_playable_vorbis() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let source = dir.path().join("song.wav");
+        write_wav(&source, 2, 22050, 22050 * 2, |i, channel| ((i as f32 * 0.06).sin() * 8000.0) as i16 / (1 + channel as i16));
+
+        let variants = variants_dir(dir.path());
+        let encoded = transcode(&source, &variants, "song", "abc", &[Quality::Low, Quality::High]).unwrap();
+        assert_eq!(encoded.iter().map(|e| e.path.as_str()).collect::<Vec<_>>(), ["song.abc.low.ogg", "song.abc.high.ogg"]);
+        let original = std::fs::metadata(&source).unwrap().len();
+        for variant in &encoded {
+            assert!(variant.size > 0 && variant.size < original / 4, "{:?} vs {} bytes", variant, original);
+            assert_eq!(std::fs::metadata(variants.join(&variant.path)).unwrap().len(), variant.size);
+        }
+        // nothing but the variants is left behind
+        assert_eq!(std::fs::read_dir(&variants).unwrap().count(), 2);
+
+        let mut decoded = AudioDecoder::open(&variants.join("song.abc.low.ogg")).unwrap();
+        assert_eq!((decoded.sample_rate, decoded.channels), (22050, 2));
+        let mut frames = 0;
+        while let Some(samples) = decoded.next_samples().unwrap() {
+            frames += samples.len() / 2;
+        }
+        assert!((frames as i64 - 44100).abs() < 2048, "{} frames", frames);
+
+        assert!(AudioDecoder::open(&dir.path().join("missing.wav")).is_err());
+        std::fs::write(dir.path().join("noise.wav"), b"not audio at all").unwrap();
+        assert!(transcode(&dir.path().join("noise.wav"), &variants, "noise", "x", &[Quality::Low]).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use anyhow::{bail, Context, Result};
+use sqlx::SqlitePool;
+use std::path::{Path, PathBuf};
+
+use crate::db;
+
+/// Largest accepted audio upload
+pub const MAX_AUDIO_BYTES: usize = 64 * 1024 * 1024;
+
+/// Largest accepted metadata or chart JSON
+pub const MAX_JSON_BYTES: usize = 1024 * 1024;
+
+/// Most charts accepted with one song
+pub const MAX_CHARTS: usize = 32;
+
+/// Serialises id selection and the final renames, so two uploads of the same
+/// title cannot both claim one id
+pub static UPLOAD_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Audio file extension for the format the bytes start with
+pub fn audio_extension(head: &[u8]) -> Option<&'static str> {
+    if head.len() >= 12 && &head[0..4] == b"RIFF" && &head[8..12] == b"WAVE" {
+        Some("wav")
+    } else if head.starts_with(b"fLaC") {
+        Some("flac")
+    } else if head.starts_with(b"OggS") {
+        Some("ogg")
+    } else if head.starts_with(b"ID3") || (head.len() >= 2 && head[0] == 0xff && head[1] & 0xe0 == 0xe0) {
+        Some("mp3")
+    } else {
+        None
+    }
+}
+
+/// Lowercase ASCII id from free text: runs of anything other than letters and
+/// digits become a single `-`. Empty when nothing usable is left.
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+        if slug.len() >= 48 {
+            break;
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// First of `base`, `base-2`, `base-3`, ... that is not registered and has no song
+/// files or leftover charts on disk
+pub async fn unique_song_id(pool: &SqlitePool, songs_dir: &Path, charts_dir: &Path, base: &str) -> Result<String> {
+    let names = |dir: &Path| -> Result<Vec<String>> {
+        Ok(std::fs::read_dir(dir)?
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().to_str().map(str::to_lowercase))
+            .collect())
+    };
+    let (songs, charts) = (names(songs_dir)?, names(charts_dir)?);
+
+    for n in 1..1000 {
+        let id = if n == 1 { base.to_string() } else { format!("{}-{}", base, n) };
+        let (song_prefix, chart_prefix) = (format!("{}.", id), format!("{}_", id));
+        let on_disk = songs.iter().any(|name| name.starts_with(&song_prefix))
+            || charts.iter().any(|name| name.starts_with(&chart_prefix));
+        if !on_disk && !db::song_exists(pool, &id).await? {
+            return Ok(id);
+        }
+    }
+    bail!("no free song id for {}", base)
+}
+
+/// A file being received, removed again on drop unless `persist` moved it into place
+pub struct PartFile {
+    path: PathBuf,
+    kept: bool,
+}
+
+impl PartFile {
+    /// Hidden temporary file in `dir`, so the final rename stays on one filesystem
+    pub fn new(dir: &Path) -> Self {
+        Self { path: dir.join(format!(".upload-{:016x}.part", rand::random::<u64>())), kept: false }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Rename into `dest`, failing rather than replacing an existing file
+    pub fn persist(mut self, dest: &Path) -> Result<()> {
+        if dest.exists() {
+            bail!("{} already exists", dest.display());
+        }
+        std::fs::rename(&self.path, dest).with_context(|| format!("failed to move upload to {}", dest.display()))?;
+        self.kept = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if !self.kept {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Write `bytes` to `dest` through a temporary file, so readers never see half a file
+pub fn write_atomic(dest: &Path, bytes: &[u8]) -> Result<()> {
+    let dir = dest.parent().context("destination has no directory")?;
+    let part = PartFile::new(dir);
+    std::fs::write(part.path(), bytes)?;
+    part.persist(dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_ids_and_detects_formats() {
+        assert_eq!(slugify("  Don't Stop Me Now! (Remix) "), "don-t-stop-me-now-remix");
+        assert_eq!(slugify("../../etc/passwd"), "etc-passwd");
+        assert_eq!(slugify("日本語"), "");
+        assert_eq!(slugify(&"a".repeat(100)).len(), 48);
+
+        assert_eq!(audio_extension(b"RIFF\0\0\0\0WAVEfmt "), Some("wav"));
+        assert_eq!(audio_extension(b"fLaC\0\0\0\x22"), Some("flac"));
+        assert_eq!(audio_extension(b"ID3\x04\0"), Some("mp3"));
+        assert_eq!(audio_extension(b"RIFF\0\0\0\0WEBPVP8 "), None);
+        assert_eq!(audio_extension(b"#!/bin/sh"), None);
+    }
+
+    #[test]
+    fn part_files_are_cleaned_up() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let dest = dir.path().join("song.json");
+        write_atomic(&dest, b"{}").unwrap();
+        assert!(write_atomic(&dest, b"[]").is_err());
+        assert_eq!(std::fs::read(&dest).unwrap(), b"{}");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,74 @@
+use anyhow::{Context, Result};
+use rhythm_pi_scoring::{chart_hash, simulate, ChartNote, Replay, ReplayResult};
+use serde::Deserialize;
+use std::path::Path;
+
+/// Longest replay accepted, in key presses
+pub const MAX_REPLAY_INPUTS: usize = 50_000;
+
+/// Claimed scores within this fraction of the recomputed one still count as matching.
+/// The client checks for misses on a frame timer, so edge cases can differ slightly.
+pub const SCORE_TOLERANCE: f64 = 0.01;
+
+/// A chart as stored, with the hash clients send back
+pub struct StoredChart {
+    pub hash: String,
+    pub notes: Vec<ChartNote>,
+}
+
+#[derive(Deserialize)]
+struct ChartFile {
+    #[serde(default)]
+    notes: Vec<ChartNote>,
+}
+
+impl StoredChart {
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let chart: ChartFile = serde_json::from_slice(&bytes)
+            .with_context(|| format!("invalid chart {}", path.display()))?;
+        Ok(Self { hash: chart_hash(&bytes), notes: chart.notes })
+    }
+
+    /// Judge a replay against this chart
+    pub fn rescore(&self, replay: &Replay) -> ReplayResult {
+        simulate(&self.notes, replay)
+    }
+}
+
+/// Whether a client's claimed score agrees with the recomputed one
+pub fn score_matches(claimed: i64, recomputed: i64) -> bool {
+    let allowed = (recomputed as f64 * SCORE_TOLERANCE).max(1.0);
+    ((claimed - recomputed) as f64).abs() <= allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rhythm_pi_scoring::HitAccuracy;
+
+    #[test]
+    fn rescores_stored_chart() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let json = r#"{ "song_id": "song", "notes": [ { "time": 1.0, "col": 0 }, { "time": 2.0, "fret": 1 } ] }"#;
+        let path = dir.path().join("song_drums_Hard.json");
+        std::fs::write(&path, json).unwrap();
+
+        let chart = StoredChart::load(&path).unwrap();
+        assert_eq!(chart.hash, chart_hash(json.as_bytes()));
+
+        let mut replay = Replay::new();
+        replay.press(0, 1.0);
+        let result = chart.rescore(&replay);
+        assert_eq!((result.perfect, result.miss), (1, 1));
+        assert_eq!(result.score, HitAccuracy::Perfect.points());
+    }
+
+    #[test]
+    fn tolerates_small_differences() {
+        assert!(score_matches(10_050, 10_000));
+        assert!(!score_matches(10_200, 10_000));
+        assert!(score_matches(1, 0));
+        assert!(!score_matches(500, 0));
+    }
+}
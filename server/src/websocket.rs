@@ -1,91 +1,497 @@
-use actix::{Actor, StreamHandler, AsyncContext, ActorContext};
+use actix::{Actor, Addr, StreamHandler, AsyncContext, ActorContext};
 use actix_web::{web, HttpRequest, HttpResponse, Error};
 use actix_web_actors::ws;
+use std::io::SeekFrom;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
-use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::Semaphore;
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::pcm::{PcmFormat, PcmReader};
+use crate::seek::{AudioInfo, Start};
+use crate::transcode::{self, SongAudio};
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
-const CHUNK_SIZE: usize = 8192; // 8KB chunks for streaming
+const CHUNK_SIZE: usize = 16 * 1024;
+/// Frames sent ahead of the client's acknowledgements, so at most
+/// `WINDOW * CHUNK_SIZE` bytes are in flight per connection
+const WINDOW: u32 = 16;
+/// How long a client may leave a full window unacknowledged before it is dropped
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Version of the JSON control protocol; clients name it in `v`
+pub const PROTOCOL_VERSION: u32 = 1;
 
-/// WebSocket actor for audio streaming
+/// WebSocket actor for audio streaming.
+///
+/// On connecting the client gets a `meta` message describing the audio. Control messages
+/// are JSON objects with a `type` and the protocol version `v`:
+///
+/// - `start` and `seek`, with `offset_bytes` (to resume a download) or `offset_ms` (to
+///   play from a time). The server answers `started` with the sequence number of the
+///   first frame from there, the byte offset and `header_bytes`: when playing from a
+///   time, frames carry that many bytes of the file's header before the audio at the
+///   offset, so a decoder can start there. Its `start_ms` is when the audio from the
+///   offset plays, which for compressed audio is at or a little before the time asked for.
+/// - `pause` and `resume`
+/// - `ack` with `seq`: the client has every frame up to `seq`. No more than `WINDOW`
+///   frames go unacknowledged.
+/// - `stop`, which like closing the socket cancels the stream
+/// - `format`, before `start`, with `encoding` (`s16le` or `f32le`), `sample_rate` and
+///   `channels`: the server decodes the audio into that and the answering `format`
+///   gives the size of a frame. Offsets then count decoded bytes, and each binary frame
+///   has a big-endian `u64` after its sequence number: the index of its first sample
+///   frame, so the time it plays at.
+///
+/// Each binary frame is a big-endian `u32` sequence number, counting from 0 over the
+/// whole connection, then audio. Frames sent before a `seek` may still arrive after its
+/// `started`; the client drops those by sequence number. At the end of the file the
+/// server sends `complete` and keeps the connection for more seeks.
+///
+/// The plain-text commands `start`, `stop` and `ack <seq>` of older clients still work;
+/// those streams end by closing the connection.
 pub struct AudioStreamWs {
     song_id: String,
-    hb: std::time::Instant,
+    /// What to send, picked when the client connected; None when the song has no audio
+    audio: Option<SongAudio>,
+    /// None when the audio could not be probed; it can then only be sought by bytes
+    info: Option<Arc<AudioInfo>>,
+    hb: Instant,
     started: bool,
+    /// The client started with a plain-text command
+    legacy: bool,
+    paused: bool,
+    /// Decoded audio asked for in place of the file
+    pcm: Option<PcmFormat>,
+    /// Byte ranges of the file still to send, in order
+    plan: Vec<(u64, u64)>,
+    /// The next frame of decoded audio to send
+    next_frame: u64,
+    /// `complete` has been sent for the current plan
+    done: bool,
+    window: Window,
+    /// Bumped whenever reading is cancelled, so chunks read before a seek are dropped
+    generation: u32,
+    reader: Option<Reader>,
+}
+
+/// A task reading the file for the current stream position
+struct Reader {
+    /// One permit per frame the reader may send; closed to cancel it
+    credit: Arc<Semaphore>,
+    task: tokio::task::JoinHandle<()>,
 }
 
 impl AudioStreamWs {
-    fn new(song_id: String) -> Self {
+    fn new(song_id: String, audio: Option<SongAudio>, info: Option<Arc<AudioInfo>>) -> Self {
         Self {
             song_id,
-            hb: std::time::Instant::now(),
+            audio,
+            info,
+            hb: Instant::now(),
             started: false,
+            legacy: false,
+            paused: false,
+            pcm: None,
+            plan: Vec::new(),
+            next_frame: 0,
+            done: false,
+            window: Window::new(),
+            generation: 0,
+            reader: None,
         }
     }
 
     /// Helper method to send heartbeat ping
     fn heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
         ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
-            if std::time::Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
                 log::warn!("WebSocket client heartbeat timeout, disconnecting");
                 ctx.stop();
                 return;
             }
+            if act.window.in_flight() >= WINDOW && act.window.last_ack.elapsed() > ACK_TIMEOUT {
+                log::warn!("WebSocket client stopped acknowledging audio, disconnecting");
+                ctx.stop();
+                return;
+            }
             ctx.ping(b"");
         });
     }
 
-    /// Stream audio file in chunks
-    fn stream_audio(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
-        let song_id = self.song_id.clone();
-        let songs_dir = std::env::var("SONGS_DIR")
-            .unwrap_or_else(|_| "server/assets/songs".to_string());
+    fn send(&self, ctx: &mut ws::WebsocketContext<Self>, message: ServerMessage) {
+        match serde_json::to_string(&Versioned { v: PROTOCOL_VERSION, message: &message }) {
+            Ok(text) => ctx.text(text),
+            Err(e) => log::error!("cannot encode {:?}: {}", message, e),
+        }
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.audio.as_ref().map(|a| a.len).unwrap_or(0)
+    }
 
-        // Find the audio file
-        let audio_path = match find_audio_file(&songs_dir, &song_id) {
-            Some(path) => path,
+    /// Where a `start` or `seek` position is in what this stream sends
+    fn resolve(&self, position: &Position) -> Result<Target, String> {
+        if let Some(format) = self.pcm {
+            // decoded audio is addressed by its own bytes and frames
+            return match (position.offset_bytes, position.offset_ms) {
+                (Some(_), Some(_)) => Err("give offset_bytes or offset_ms, not both".to_string()),
+                (Some(offset), None) => Ok(Target::Pcm(offset / format.frame_bytes() as u64)),
+                (None, Some(ms)) => Ok(Target::Pcm(ms.saturating_mul(u64::from(format.sample_rate)) / 1000)),
+                (None, None) => Ok(Target::Pcm(0)),
+            };
+        }
+        match (position.offset_bytes, position.offset_ms) {
+            (Some(_), Some(_)) => Err("give offset_bytes or offset_ms, not both".to_string()),
+            (Some(offset), None) if offset > self.total_bytes() => {
+                Err(format!("offset_bytes is past the end of the audio ({} bytes)", self.total_bytes()))
+            }
+            (Some(offset), None) => Ok(Target::File { offset, header: 0, ms: None }),
+            (None, Some(ms)) => self
+                .info
+                .as_ref()
+                .and_then(|info| info.start_at_ms(ms))
+                .map(|Start { offset, header, ms }| Target::File { offset, header, ms: Some(ms) })
+                .ok_or_else(|| "cannot find times in this audio; use offset_bytes".to_string()),
+            (None, None) => Ok(Target::File { offset: 0, header: 0, ms: Some(0) }),
+        }
+    }
+
+    /// Move the stream to `target` and tell the client where the frames from here start
+    fn move_to(&mut self, ctx: &mut ws::WebsocketContext<Self>, target: Target) {
+        self.cancel();
+        let started = match target {
+            Target::File { offset, header, ms } => {
+                self.plan = [(0, header), (offset, self.total_bytes())].into_iter().filter(|(start, end)| start < end).collect();
+                ServerMessage::Started { seq: self.window.sent, offset_bytes: offset, header_bytes: header, start_ms: ms, frame: None }
+            }
+            Target::Pcm(frame) => {
+                self.next_frame = frame;
+                let (frame_bytes, rate) = self.pcm.map_or((0, 1), |f| (f.frame_bytes() as u64, u64::from(f.sample_rate)));
+                let (offset_bytes, start_ms) = (frame * frame_bytes, frame * 1000 / rate);
+                ServerMessage::Started { seq: self.window.sent, offset_bytes, header_bytes: 0, start_ms: Some(start_ms), frame: Some(frame) }
+            }
+        };
+        self.done = false;
+        if !self.legacy {
+            self.send(ctx, started);
+        }
+        if !self.paused {
+            self.read(ctx);
+        }
+    }
+
+    /// Start reading from the stream position; frames go out as the client makes room
+    fn read(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let Some(audio) = &self.audio else {
+            return;
+        };
+        let credit = Arc::new(Semaphore::new(WINDOW.saturating_sub(self.window.in_flight()) as usize));
+        let (path, generation, addr) = (audio.path.clone(), self.generation, ctx.address());
+        let task = match self.pcm {
+            Some(format) => {
+                log::debug!("Streaming {:?} as {:?} from frame {}", path, format, self.next_frame);
+                let (start, credit) = (self.next_frame, credit.clone());
+                tokio::task::spawn_blocking(move || send_pcm(path, format, start, generation, credit, addr))
+            }
             None => {
-                log::error!("Audio file not found for song_id: {}", song_id);
-                ctx.text("{\"error\":\"audio file not found\"}");
-                ctx.stop();
-                return;
+                log::debug!("Streaming {:?} from {:?}", path, self.plan.first());
+                tokio::spawn(send_ranges(path, self.plan.clone(), generation, credit.clone(), addr))
             }
         };
+        self.reader = Some(Reader { credit, task });
+        self.window.last_ack = Instant::now();
+    }
+
+    /// Stop reading; chunks already read are dropped when they reach the actor
+    fn cancel(&mut self) {
+        self.generation += 1;
+        if let Some(reader) = self.reader.take() {
+            reader.credit.close();
+            reader.task.abort();
+        }
+    }
 
-        log::info!("Starting WebSocket audio stream for: {:?}", audio_path);
+    /// Tell the client the file has all been sent
+    fn finish(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        self.reader = None;
+        if self.done {
+            return;
+        }
+        self.done = true;
+        log::info!("Audio stream complete: {} frames", self.window.sent);
+        if self.legacy {
+            ctx.text(serde_json::json!({"status": "complete", "frames": self.window.sent}).to_string());
+            ctx.stop();
+        } else {
+            let total_bytes = match self.pcm {
+                Some(format) => self.next_frame * format.frame_bytes() as u64,
+                None => self.total_bytes(),
+            };
+            self.send(ctx, ServerMessage::Complete { frames: self.window.sent, total_bytes });
+        }
+    }
 
-        // Spawn async task to read and stream the file
-        let addr = ctx.address();
-        actix::spawn(async move {
-            match stream_file_chunks(audio_path).await {
-                Ok(chunks) => {
-                    for chunk in chunks {
-                        addr.do_send(AudioChunk(chunk));
+    fn command(&mut self, ctx: &mut ws::WebsocketContext<Self>, command: Command) {
+        match command {
+            Command::Start(_) if self.started => self.send(ctx, ServerMessage::error("already started; seek to move")),
+            Command::Start(position) => match self.resolve(&position) {
+                Ok(target) => {
+                    self.started = true;
+                    log::info!("Client requested audio stream start at {:?}", target);
+                    if self.legacy {
+                        let (quality, content_type, size) = self
+                            .audio
+                            .as_ref()
+                            .map(|a| (a.quality, a.content_type, a.len))
+                            .unwrap_or_default();
+                        ctx.text(
+                            serde_json::json!({
+                                "status": "streaming",
+                                "quality": quality,
+                                "content_type": content_type,
+                                "size": size,
+                                "window": WINDOW,
+                            })
+                            .to_string(),
+                        );
                     }
-                    addr.do_send(StreamComplete);
+                    self.move_to(ctx, target);
                 }
-                Err(e) => {
-                    log::error!("Error streaming audio file: {}", e);
-                    addr.do_send(StreamError(format!("{}", e)));
+                Err(e) => self.send(ctx, ServerMessage::error(e)),
+            },
+            Command::Seek(_) if !self.started => self.send(ctx, ServerMessage::error("not started")),
+            Command::Seek(position) => match self.resolve(&position) {
+                Ok(target) => self.move_to(ctx, target),
+                Err(e) => self.send(ctx, ServerMessage::error(e)),
+            },
+            Command::Format(_) if self.started => self.send(ctx, ServerMessage::error("choose the format before starting")),
+            Command::Format(format) => match format.check() {
+                Ok(()) => {
+                    log::info!("Client asked for {:?} audio", format);
+                    self.pcm = Some(format);
+                    let duration_ms = self.info.as_ref().and_then(|i| i.duration_ms);
+                    self.send(
+                        ctx,
+                        ServerMessage::Format {
+                            format,
+                            frame_bytes: format.frame_bytes(),
+                            total_frames: duration_ms.map(|ms| ms * u64::from(format.sample_rate) / 1000),
+                        },
+                    );
+                }
+                Err(e) => self.send(ctx, ServerMessage::error(e.to_string())),
+            },
+            Command::Pause => {
+                if !self.paused {
+                    self.paused = true;
+                    self.cancel();
                 }
+                self.send(ctx, ServerMessage::Paused { seq: self.window.sent });
             }
-        });
+            Command::Resume => {
+                if self.paused {
+                    self.paused = false;
+                    if self.started && (self.done || self.pcm.is_none() && self.plan.is_empty()) {
+                        // the last chunk went out before the pause caught the reader
+                        self.finish(ctx);
+                    } else if self.started {
+                        self.read(ctx);
+                    }
+                }
+                self.send(ctx, ServerMessage::Resumed { seq: self.window.sent });
+            }
+            Command::Ack { seq } => {
+                let freed = self.window.ack(seq);
+                if let Some(reader) = &self.reader {
+                    reader.credit.add_permits(freed as usize);
+                }
+            }
+            Command::Stop => {
+                log::info!("Client requested stream stop");
+                self.cancel();
+                ctx.close(Some(ws::CloseCode::Normal.into()));
+                ctx.stop();
+            }
+        }
+    }
+}
+
+/// Where a `start` or `seek` moves the stream to
+#[derive(Debug)]
+enum Target {
+    /// `header` bytes of the file's header, then the file from `offset`, which plays from
+    /// `ms` when that is known
+    File { offset: u64, header: u64, ms: Option<u64> },
+    /// Decoded audio from this frame
+    Pcm(u64),
+}
+
+/// Frames sent and acknowledged on one stream
+struct Window {
+    sent: u32,
+    acked: u32,
+    last_ack: Instant,
+}
+
+impl Window {
+    fn new() -> Self {
+        Self { sent: 0, acked: 0, last_ack: Instant::now() }
+    }
+
+    fn in_flight(&self) -> u32 {
+        self.sent - self.acked
+    }
+
+    /// The client has every frame up to and including `seq`. Returns how many frames that
+    /// frees; old, repeated and not yet sent sequence numbers free none.
+    fn ack(&mut self, seq: u32) -> u32 {
+        if seq >= self.sent || seq < self.acked {
+            return 0;
+        }
+        let freed = seq + 1 - self.acked;
+        self.acked = seq + 1;
+        self.last_ack = Instant::now();
+        freed
     }
 }
 
+/// Where to start streaming; at most one of the two
+#[derive(Debug, Default, PartialEq, Deserialize)]
+struct Position {
+    offset_bytes: Option<u64>,
+    offset_ms: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Command {
+    Start(Position),
+    Seek(Position),
+    Pause,
+    Resume,
+    Ack { seq: u32 },
+    Stop,
+    Format(PcmFormat),
+}
+
+#[derive(Deserialize)]
+struct ClientMessage {
+    #[serde(default = "protocol_version")]
+    v: u32,
+    #[serde(flatten)]
+    command: Command,
+}
+
+fn protocol_version() -> u32 {
+    PROTOCOL_VERSION
+}
+
+/// A control message and whether it was one of the plain-text commands
+fn parse_command(text: &str) -> Result<(Command, bool), String> {
+    match text {
+        "start" => return Ok((Command::Start(Position::default()), true)),
+        "stop" => return Ok((Command::Stop, true)),
+        _ => {}
+    }
+    if let Some(seq) = text.strip_prefix("ack ") {
+        let seq = seq.trim().parse().map_err(|_| format!("bad acknowledgement: {}", text))?;
+        return Ok((Command::Ack { seq }, true));
+    }
+    let message: ClientMessage = serde_json::from_str(text).map_err(|e| format!("bad control message: {}", e))?;
+    if message.v != PROTOCOL_VERSION {
+        return Err(format!("protocol version {} is not supported; this server speaks {}", message.v, PROTOCOL_VERSION));
+    }
+    Ok((message.command, false))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Meta {
+        codec: Option<&'static str>,
+        content_type: &'static str,
+        quality: &'static str,
+        sample_rate: Option<u32>,
+        channels: Option<usize>,
+        duration_ms: Option<u64>,
+        total_bytes: u64,
+        header_bytes: u64,
+        window: u32,
+        chunk_size: usize,
+    },
+    Started {
+        seq: u32,
+        offset_bytes: u64,
+        header_bytes: u64,
+        /// Time the audio sent from here starts at; None after a seek by bytes
+        start_ms: Option<u64>,
+        /// First frame of decoded audio
+        #[serde(skip_serializing_if = "Option::is_none")]
+        frame: Option<u64>,
+    },
+    Format {
+        #[serde(flatten)]
+        format: PcmFormat,
+        frame_bytes: usize,
+        total_frames: Option<u64>,
+    },
+    Paused { seq: u32 },
+    Resumed { seq: u32 },
+    Complete { frames: u32, total_bytes: u64 },
+    Error { error: String },
+}
+
+impl ServerMessage {
+    fn error(error: impl Into<String>) -> Self {
+        ServerMessage::Error { error: error.into() }
+    }
+}
+
+#[derive(Serialize)]
+struct Versioned<'a> {
+    v: u32,
+    #[serde(flatten)]
+    message: &'a ServerMessage,
+}
+
 impl Actor for AudioStreamWs {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
         log::info!("WebSocket connection started for song: {}", self.song_id);
         self.heartbeat(ctx);
+
+        let Some(audio) = &self.audio else {
+            log::error!("Audio file not found for song_id: {}", self.song_id);
+            self.send(ctx, ServerMessage::error("audio file not found"));
+            ctx.stop();
+            return;
+        };
+        let info = self.info.as_ref();
+        let meta = ServerMessage::Meta {
+            codec: info.map(|i| i.codec),
+            content_type: audio.content_type,
+            quality: audio.quality,
+            sample_rate: info.map(|i| i.sample_rate),
+            channels: info.map(|i| i.channels),
+            duration_ms: info.and_then(|i| i.duration_ms),
+            total_bytes: audio.len,
+            header_bytes: info.map(|i| i.header_bytes).unwrap_or(0),
+            window: WINDOW,
+            chunk_size: CHUNK_SIZE,
+        };
+        self.send(ctx, meta);
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.cancel();
         log::info!("WebSocket connection closed for song: {}", self.song_id);
     }
 }
@@ -95,28 +501,24 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for AudioStreamWs {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         match msg {
             Ok(ws::Message::Ping(msg)) => {
-                self.hb = std::time::Instant::now();
+                self.hb = Instant::now();
                 ctx.pong(&msg);
             }
             Ok(ws::Message::Pong(_)) => {
-                self.hb = std::time::Instant::now();
+                self.hb = Instant::now();
             }
-            Ok(ws::Message::Text(text)) => {
-                let text = text.trim();
-                
-                // Client sends "start" to begin streaming
-                if text == "start" && !self.started {
-                    self.started = true;
-                    log::info!("Client requested audio stream start");
-                    ctx.text("{\"status\":\"streaming\"}");
-                    self.stream_audio(ctx);
-                } else if text == "stop" {
-                    log::info!("Client requested stream stop");
-                    ctx.stop();
-                } else {
-                    log::debug!("Received text message: {}", text);
+            Ok(ws::Message::Text(text)) => match parse_command(text.trim()) {
+                Ok((command, legacy)) => {
+                    if legacy && !self.started {
+                        self.legacy = true;
+                    }
+                    self.command(ctx, command);
                 }
-            }
+                Err(e) => {
+                    log::debug!("Rejected control message: {}", e);
+                    self.send(ctx, ServerMessage::error(e));
+                }
+            },
             Ok(ws::Message::Binary(_)) => {
                 log::warn!("Received unexpected binary message from client");
             }
@@ -129,34 +531,60 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for AudioStreamWs {
     }
 }
 
-// Messages for actor communication
+// Messages for actor communication; each names the reader it came from
 #[derive(actix::Message)]
 #[rtype(result = "()")]
-struct AudioChunk(Bytes);
+struct AudioChunk {
+    generation: u32,
+    /// Index of the first frame, for decoded audio
+    frame: Option<u64>,
+    data: Bytes,
+}
 
 #[derive(actix::Message)]
 #[rtype(result = "()")]
-struct StreamComplete;
+struct StreamComplete(u32);
 
 #[derive(actix::Message)]
 #[rtype(result = "()")]
-struct StreamError(String);
+struct StreamError(u32, String);
 
 impl actix::Handler<AudioChunk> for AudioStreamWs {
     type Result = ();
 
     fn handle(&mut self, msg: AudioChunk, ctx: &mut Self::Context) {
-        ctx.binary(msg.0);
+        if msg.generation != self.generation {
+            return;
+        }
+        let mut frame = BytesMut::with_capacity(12 + msg.data.len());
+        frame.put_u32(self.window.sent);
+        if let Some(first) = msg.frame {
+            frame.put_u64(first);
+        }
+        frame.put_slice(&msg.data);
+        ctx.binary(frame.freeze());
+        self.window.sent += 1;
+
+        let len = msg.data.len() as u64;
+        if let (Some(first), Some(format)) = (msg.frame, self.pcm) {
+            self.next_frame = first + len / format.frame_bytes() as u64;
+        } else if let Some(first) = self.plan.first_mut() {
+            // chunks never span two ranges, so this only ever trims the first
+            first.0 = (first.0 + len).min(first.1);
+            if first.0 == first.1 {
+                self.plan.remove(0);
+            }
+        }
     }
 }
 
 impl actix::Handler<StreamComplete> for AudioStreamWs {
     type Result = ();
 
-    fn handle(&mut self, _msg: StreamComplete, ctx: &mut Self::Context) {
-        log::info!("Audio stream complete");
-        ctx.text("{\"status\":\"complete\"}");
-        ctx.stop();
+    fn handle(&mut self, msg: StreamComplete, ctx: &mut Self::Context) {
+        if msg.0 == self.generation {
+            self.finish(ctx);
+        }
     }
 }
 
@@ -164,64 +592,168 @@ impl actix::Handler<StreamError> for AudioStreamWs {
     type Result = ();
 
     fn handle(&mut self, msg: StreamError, ctx: &mut Self::Context) {
-        log::error!("Stream error: {}", msg.0);
-        ctx.text(format!("{{\"error\":\"{}\"}}", msg.0));
+        if msg.0 != self.generation {
+            return;
+        }
+        log::error!("Stream error: {}", msg.1);
+        self.send(ctx, ServerMessage::error(msg.1));
         ctx.stop();
     }
 }
 
-/// Find audio file in directory
-fn find_audio_file(songs_dir: &str, song_id: &str) -> Option<PathBuf> {
-    let dir = std::fs::read_dir(songs_dir).ok()?;
-    
-    for entry in dir.filter_map(|e| e.ok()) {
-        if let Some(name) = entry.file_name().to_str() {
-            if name.starts_with(song_id) {
-                let ext = name.rsplit('.').next().unwrap_or("");
-                if matches!(ext, "mp3" | "wav" | "ogg" | "flac") {
-                    return Some(entry.path());
+/// Read byte ranges of the file a chunk at a time, each once there is credit for it, and
+/// hand the chunks to the actor. Returns quietly when the stream is cancelled.
+async fn send_ranges(path: PathBuf, ranges: Vec<(u64, u64)>, generation: u32, credit: Arc<Semaphore>, addr: Addr<AudioStreamWs>) {
+    let result = async {
+        let mut file = File::open(&path).await?;
+        for (start, end) in ranges {
+            file.seek(SeekFrom::Start(start)).await?;
+            let mut pos = start;
+            while pos < end {
+                let Ok(permit) = credit.acquire().await else {
+                    return Ok(false);
+                };
+                permit.forget();
+                let mut buf = vec![0u8; CHUNK_SIZE.min((end - pos) as usize)];
+                let n = file.read(&mut buf).await?;
+                if n == 0 {
+                    return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "audio file shrank while being sent"));
+                }
+                buf.truncate(n);
+                pos += n as u64;
+                if addr.send(AudioChunk { generation, frame: None, data: Bytes::from(buf) }).await.is_err() {
+                    return Ok(false);
                 }
             }
         }
+        Ok(true)
+    }
+    .await;
+    match result {
+        Ok(true) => addr.do_send(StreamComplete(generation)),
+        Ok(false) => log::debug!("Audio stream of {:?} cancelled", path),
+        Err::<_, std::io::Error>(e) => addr.do_send(StreamError(generation, e.to_string())),
     }
-    None
 }
 
-/// Read file and split into chunks
-async fn stream_file_chunks(path: PathBuf) -> std::io::Result<Vec<Bytes>> {
-    let mut file = File::open(&path).await?;
-    let metadata = file.metadata().await?;
-    let file_size = metadata.len() as usize;
-    
-    log::info!("Streaming file: {:?}, size: {} bytes", path, file_size);
-    
-    let mut chunks = Vec::new();
-    let mut buffer = vec![0u8; CHUNK_SIZE];
-    
-    loop {
-        let n = file.read(&mut buffer).await?;
-        if n == 0 {
-            break;
+/// Decode the audio into `format` from frame `start`, a chunk at a time as there is
+/// credit, and hand the chunks to the actor. Runs on a blocking thread, since decoding
+/// and resampling keep the CPU busy.
+fn send_pcm(path: PathBuf, format: PcmFormat, start: u64, generation: u32, credit: Arc<Semaphore>, addr: Addr<AudioStreamWs>) {
+    let frames = CHUNK_SIZE / format.frame_bytes();
+    let result = (|| -> anyhow::Result<bool> {
+        let mut reader = PcmReader::open(&path, format, start)?;
+        loop {
+            let Ok(permit) = futures::executor::block_on(credit.acquire()) else {
+                return Ok(false);
+            };
+            permit.forget();
+            let Some((frame, data)) = reader.read(frames)? else {
+                return Ok(true);
+            };
+            let chunk = AudioChunk { generation, frame: Some(frame), data: Bytes::from(data) };
+            if futures::executor::block_on(addr.send(chunk)).is_err() {
+                return Ok(false);
+            }
         }
-        chunks.push(Bytes::copy_from_slice(&buffer[..n]));
+    })();
+    match result {
+        Ok(true) => addr.do_send(StreamComplete(generation)),
+        Ok(false) => log::debug!("Decoded stream of {:?} cancelled", path),
+        Err(e) => addr.do_send(StreamError(generation, format!("{:#}", e))),
     }
-    
-    log::info!("File split into {} chunks", chunks.len());
-    Ok(chunks)
 }
 
-/// WebSocket handler endpoint
+#[derive(Deserialize)]
+pub struct AudioQuery {
+    /// `low`, `medium`, `high` or `original`
+    pub quality: Option<String>,
+}
+
+/// WebSocket handler endpoint. The audio sent is picked like `/api/songs/{id}/stream`
+/// picks it, from `?quality=` or `Accept`.
 pub async fn ws_audio_stream(
     req: HttpRequest,
     stream: web::Payload,
+    pool: web::Data<SqlitePool>,
     path: web::Path<String>,
+    query: web::Query<AudioQuery>,
 ) -> Result<HttpResponse, Error> {
     let song_id = path.into_inner();
-    
+
     log::info!("WebSocket connection request for song: {}", song_id);
-    
-    let ws = AudioStreamWs::new(song_id);
+
+    let accept = req.headers().get(actix_web::http::header::ACCEPT).and_then(|v| v.to_str().ok());
+    let wanted = transcode::negotiate(query.quality.as_deref(), accept)
+        .map_err(|e| actix_web::error::ErrorBadRequest(e.to_string()))?;
+    let songs_dir = PathBuf::from(std::env::var("SONGS_DIR").unwrap_or_else(|_| "server/assets/songs".to_string()));
+    let audio = transcode::song_audio(&pool, &songs_dir, &song_id, wanted).await.map_err(|e| {
+        log::error!("cannot find audio for {}: {:#}", song_id, e);
+        actix_web::error::ErrorInternalServerError("cannot read song")
+    })?;
+    let info = match &audio {
+        Some(audio) => {
+            let path = audio.path.clone();
+            match web::block(move || AudioInfo::cached(&path)).await? {
+                Ok(info) => Some(info),
+                Err(e) => {
+                    log::warn!("cannot index audio of {}, so it can only be sought by bytes: {:#}", song_id, e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
+    let ws = AudioStreamWs::new(song_id, audio, info);
     let resp = ws::start(ws, &req, stream)?;
-    
+
     Ok(resp)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acknowledgements_free_the_window() {
+        let mut window = Window::new();
+        window.sent = 5;
+        assert_eq!(window.in_flight(), 5);
+        // nothing past what was sent, then acks are cumulative
+        assert_eq!(window.ack(5), 0);
+        assert_eq!(window.ack(1), 2);
+        assert_eq!(window.ack(3), 2);
+        assert_eq!(window.in_flight(), 1);
+        // late and repeated acks free nothing more
+        assert_eq!(window.ack(0), 0);
+        assert_eq!(window.ack(3), 0);
+        assert_eq!(window.ack(4), 1);
+        assert_eq!(window.in_flight(), 0);
+    }
+
+    #[test]
+    fn parses_control_messages() {
+        let parse = |text: &str| parse_command(text).map(|(command, _)| command);
+        assert_eq!(parse(r#"{"v":1,"type":"start"}"#), Ok(Command::Start(Position::default())));
+        assert_eq!(
+            parse(r#"{"v":1,"type":"seek","offset_ms":30000}"#),
+            Ok(Command::Seek(Position { offset_bytes: None, offset_ms: Some(30_000) }))
+        );
+        assert_eq!(parse(r#"{"type":"ack","seq":7}"#), Ok(Command::Ack { seq: 7 }));
+        assert_eq!(parse(r#"{"v":1,"type":"pause"}"#), Ok(Command::Pause));
+        assert!(parse(r#"{"v":2,"type":"pause"}"#).unwrap_err().contains("version 2"));
+        assert!(parse(r#"{"v":1,"type":"rewind"}"#).is_err());
+        assert!(parse(r#"{"v":1,"type":"ack"}"#).is_err());
+        assert_eq!(
+            parse(r#"{"v":1,"type":"format","encoding":"s16le","sample_rate":44100,"channels":2}"#),
+            Ok(Command::Format(PcmFormat { encoding: crate::pcm::Encoding::S16le, sample_rate: 44100, channels: 2 }))
+        );
+        assert!(parse(r#"{"v":1,"type":"format","encoding":"mp3","sample_rate":44100,"channels":2}"#).is_err());
+
+        // the plain-text commands of older clients
+        assert_eq!(parse_command("start"), Ok((Command::Start(Position::default()), true)));
+        assert_eq!(parse_command("ack 3"), Ok((Command::Ack { seq: 3 }, true)));
+        assert!(parse_command("ack three").is_err());
+    }
+}
@@ -0,0 +1,27 @@
+-- A database as written by the server before schema versioning (version 1, no schema_version table)
+CREATE TABLE scores (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            song_id TEXT NOT NULL,
+            player TEXT NOT NULL,
+            score INTEGER NOT NULL,
+            timestamp INTEGER NOT NULL,
+            online INTEGER NOT NULL
+        );
+CREATE TABLE users (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            username TEXT NOT NULL UNIQUE,
+            password_hash TEXT NOT NULL
+        );
+CREATE TABLE songs (
+            id TEXT PRIMARY KEY,
+            filename TEXT NOT NULL,
+            title TEXT,
+            artist TEXT,
+            mtime INTEGER,
+            registered_at INTEGER NOT NULL
+        );
+
+INSERT INTO users (username, password_hash) VALUES ('alice', '$argon2id$v=19$m=4096,t=3,p=1$c2FsdHNhbHQ$aGFzaGhhc2g');
+INSERT INTO scores (song_id, player, score, timestamp, online) VALUES ('Paradisus-Paradoxum', 'alice', 91000, 1700000000, 1);
+INSERT INTO scores (song_id, player, score, timestamp, online) VALUES ('Paradisus-Paradoxum', 'bob', 45000, 1700000100, 0);
+INSERT INTO songs (id, filename, title, artist, mtime, registered_at) VALUES ('Paradisus-Paradoxum', 'Paradisus-Paradoxum.json', NULL, NULL, 1700000000, 1700000000);